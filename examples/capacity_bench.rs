@@ -0,0 +1,102 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use filesentry::Watcher;
+
+const HELP: &str = r#"
+Compare the reallocations during the initial crawl of a wide directory with
+and without a capacity hint.
+
+Usage: capacity_bench [OPTIONS]
+
+Options:
+  -f, --files <n>
+          Number of files in the directory (default: 100000).
+"#;
+
+/// counts the calls to `realloc` and the bytes they had to move
+struct CountingAlloc;
+
+static REALLOCS: AtomicUsize = AtomicUsize::new(0);
+static REALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        REALLOCS.fetch_add(1, Ordering::Relaxed);
+        REALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+fn parse_args() -> Result<usize, lexopt::Error> {
+    use lexopt::prelude::*;
+
+    let mut files = 100_000;
+    let mut parser = lexopt::Parser::from_env();
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('f') | Long("files") => files = parser.value()?.parse()?,
+            Long("help") => {
+                println!("{HELP}");
+                std::process::exit(0);
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    Ok(files)
+}
+
+/// returns the reallocations, the bytes they moved and the time of the crawl
+fn crawl(root: &Path, capacity: Option<usize>) -> (usize, usize, Duration) {
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    if let Some(capacity) = capacity {
+        watcher.set_capacity_hint(capacity);
+    }
+    let (tx, rx) = mpsc::channel();
+    let reallocs = REALLOCS.load(Ordering::Relaxed);
+    let bytes = REALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    watcher
+        .add_root(root, true, move |success| {
+            let _ = tx.send(success);
+        })
+        .unwrap();
+    watcher.start().unwrap();
+    assert!(rx.recv().unwrap(), "failed to crawl {root:?}");
+    let elapsed = start.elapsed();
+    (
+        REALLOCS.load(Ordering::Relaxed) - reallocs,
+        REALLOCATED_BYTES.load(Ordering::Relaxed) - bytes,
+        elapsed,
+    )
+}
+
+pub fn main() -> Result<(), lexopt::Error> {
+    let files = parse_args()?;
+    let _ = env_logger::builder().try_init();
+    let tmp = tempfile::TempDir::new().unwrap();
+    for i in 0..files {
+        fs::write(tmp.path().join(format!("{i}.txt")), "foo").unwrap();
+    }
+    for (mode, capacity) in [("default", None), ("hint", Some(files + 1))] {
+        let (reallocs, bytes, elapsed) = crawl(tmp.path(), capacity);
+        println!("{mode}: {reallocs} reallocations moving {bytes} bytes, {elapsed:?}");
+    }
+    Ok(())
+}
@@ -1,15 +1,8 @@
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
-use filesentry::{EventType, Filter, Watcher};
-use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use log::error;
-
-struct Ignore {
-    hidden: bool,
-    ignores: Vec<Gitignore>,
-}
+use filesentry::{EventType, GitignoreFilter, Watcher};
 
 const HELP: &str = r#"
 Watch the files in a directory (recursively) for changes.
@@ -37,7 +30,7 @@ Options:
           '.gitignore', '.ignore', '.fdignore', or the global ignore file, The flag can be
           overridden with --ignore.
 "#;
-fn parse_args() -> Result<(PathBuf, Ignore), lexopt::Error> {
+fn parse_args() -> Result<(PathBuf, GitignoreFilter), lexopt::Error> {
     use lexopt::prelude::*;
 
     let _ = env_logger::builder().try_init();
@@ -66,86 +59,24 @@ fn parse_args() -> Result<(PathBuf, Ignore), lexopt::Error> {
         .map(Ok)
         .unwrap_or_else(std::env::current_dir)
         .map_err(|err| lexopt::Error::Custom(Box::new(err)))?;
-    let mut ignores = Vec::new();
-    if !no_ignore {
-        let (global, errors) = Gitignore::global();
-        if let Some(errs) = errors {
-            error!("invalid global .gitignore: {errs}");
-        }
-        ignores.push(global);
-        for parent in root.ancestors() {
-            let mut builder = None;
-            for path in [".ignore", ".gitignore"] {
-                let path = parent.join(path);
-                if path.exists() {
-                    builder
-                        .get_or_insert_with(|| GitignoreBuilder::new(parent))
-                        .add(path);
-                }
-            }
-            if let Some(builder) = builder.take() {
-                match builder.build() {
-                    Ok(ig) => ignores.push(ig),
-                    Err(err) => error!("invalid ignores at {parent:?} {err}"),
-                }
-            }
-        }
-    }
-    Ok((root, Ignore { hidden, ignores }))
-}
-
-fn is_hidden(path: &Path) -> bool {
-    path.file_name()
-        .is_some_and(|it| it.as_encoded_bytes().first() == Some(&b'.'))
-}
-
-impl Filter for Ignore {
-    fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool {
-        match is_dir {
-            Some(is_dir) => {
-                for ignore in &self.ignores {
-                    match ignore.matched(path, is_dir) {
-                        ignore::Match::None => continue,
-                        ignore::Match::Ignore(_) => return true,
-                        ignore::Match::Whitelist(_) => return false,
-                    }
-                }
-            }
-            None => {
-                // if we don't know wether this is a directory (on windows)
-                // then we are conservative and allow the dirs
-                for ignore in &self.ignores {
-                    match ignore.matched(path, true) {
-                        ignore::Match::None => continue,
-                        ignore::Match::Ignore(glob) => {
-                            if glob.is_only_dir() {
-                                match ignore.matched(path, false) {
-                                    ignore::Match::None => (),
-                                    ignore::Match::Ignore(_) => return true,
-                                    ignore::Match::Whitelist(_) => return false,
-                                }
-                            } else {
-                                return true;
-                            }
-                        }
-                        ignore::Match::Whitelist(_) => return false,
-                    }
-                }
-            }
-        }
-        !self.hidden && is_hidden(path)
-    }
+    let filter = GitignoreFilter::builder(&root)
+        .hidden(!hidden)
+        .ignore_files(!no_ignore)
+        .global(!no_ignore)
+        .build()
+        .map_err(|err| lexopt::Error::Custom(Box::new(err)))?;
+    Ok((root, filter))
 }
 
 pub fn main() -> Result<(), lexopt::Error> {
-    let (root, ignore) = parse_args()?;
+    let (root, filter) = parse_args()?;
     let _ = env_logger::builder().try_init();
     let watcher = Watcher::new().unwrap();
     watcher
         .add_root(&root, true, |_| ())
         .map_err(|err| lexopt::Error::Custom(Box::new(err)))?;
 
-    watcher.set_filter(Arc::new(ignore), false);
+    watcher.set_filter(Arc::new(filter), false);
     watcher.add_handler(|events| {
         for event in &*events {
             match event.ty {
@@ -153,11 +84,17 @@ pub fn main() -> Result<(), lexopt::Error> {
                 EventType::Delete => println!("{:?} delete", event.path),
                 EventType::Modified => println!("{:?} modify", event.path),
                 EventType::Tempfile => println!("{:?} tempfile", event.path),
+                EventType::TypeChanged => println!("{:?} type changed", event.path),
+                EventType::Rename => println!(
+                    "{:?} rename from {:?}",
+                    event.path,
+                    event.renamed_from.as_ref().unwrap()
+                ),
             }
         }
         true
     });
-    watcher.start();
+    watcher.start().unwrap();
     std::thread::sleep(Duration::from_secs(60 * 60));
     Ok(())
 }
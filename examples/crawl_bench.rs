@@ -0,0 +1,110 @@
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use filesentry::Watcher;
+
+const HELP: &str = r#"
+Compare the time the initial crawl takes when crawling serially and in parallel.
+
+Usage: crawl_bench [OPTIONS] [dir]
+
+Arguments:
+  [dir]
+      The directory to crawl, by default a synthetic tree is generated in a
+      temporary directory.
+
+Options:
+  -d, --dirs <n>
+          Number of directories of the synthetic tree (default: 1000).
+
+  -f, --files <n>
+          Number of files in each directory of the synthetic tree (default: 100).
+
+  -r, --runs <n>
+          Number of crawls for each mode, the fastest one is reported (default: 3).
+"#;
+
+struct Args {
+    root: Option<std::path::PathBuf>,
+    dirs: usize,
+    files: usize,
+    runs: usize,
+}
+
+fn parse_args() -> Result<Args, lexopt::Error> {
+    use lexopt::prelude::*;
+
+    let mut args = Args {
+        root: None,
+        dirs: 1000,
+        files: 100,
+        runs: 3,
+    };
+    let mut parser = lexopt::Parser::from_env();
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('d') | Long("dirs") => args.dirs = parser.value()?.parse()?,
+            Short('f') | Long("files") => args.files = parser.value()?.parse()?,
+            Short('r') | Long("runs") => args.runs = parser.value()?.parse()?,
+            Long("help") => {
+                println!("{HELP}");
+                std::process::exit(0);
+            }
+            Value(root) if args.root.is_none() => args.root = Some(root.into()),
+            _ => return Err(arg.unexpected()),
+        }
+    }
+    Ok(args)
+}
+
+/// creates `dirs` directories (nested up to 3 levels deep) with `files` files each
+fn generate_tree(root: &Path, dirs: usize, files: usize) {
+    for i in 0..dirs {
+        let dir = root.join(format!("{}/{}/{i}", i % 10, i % 100));
+        fs::create_dir_all(&dir).unwrap();
+        for j in 0..files {
+            fs::write(dir.join(format!("{j}.txt")), "foo").unwrap();
+        }
+    }
+}
+
+fn crawl(root: &Path, parallel: bool) -> Duration {
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.set_parallel_crawl(parallel);
+    let (tx, rx) = mpsc::channel();
+    let start = Instant::now();
+    watcher
+        .add_root(root, true, move |success| {
+            let _ = tx.send(success);
+        })
+        .unwrap();
+    watcher.start().unwrap();
+    assert!(rx.recv().unwrap(), "failed to crawl {root:?}");
+    start.elapsed()
+}
+
+pub fn main() -> Result<(), lexopt::Error> {
+    let args = parse_args()?;
+    let _ = env_logger::builder().try_init();
+    let tmp;
+    let root = match &args.root {
+        Some(root) => root.as_path(),
+        None => {
+            tmp = tempfile::TempDir::new().unwrap();
+            generate_tree(tmp.path(), args.dirs, args.files);
+            tmp.path()
+        }
+    };
+    for parallel in [false, true] {
+        let fastest = (0..args.runs.max(1))
+            .map(|_| crawl(root, parallel))
+            .min()
+            .unwrap();
+        let mode = if parallel { "parallel" } else { "serial" };
+        println!("{mode}: {fastest:?}");
+    }
+    Ok(())
+}
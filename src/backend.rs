@@ -0,0 +1,66 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::path::{CannonicalPath, CanonicalPathBuf};
+use crate::pending::PendingChangesLock;
+use crate::poll::PollWatcher;
+use crate::{OsWatcher, WatchMask};
+
+/// The backend selected with [`Backend`](crate::Backend). The polling
+/// backend doesn't create the native watcher at all, so it also works
+/// where the native backend is unavailable (or out of resources).
+#[derive(Debug)]
+pub(crate) enum Notify {
+    Native(Arc<OsWatcher>),
+    Poll(PollWatcher),
+}
+
+/// forwards methods to the watcher of the selected backend, every backend
+/// implements the same set of methods
+macro_rules! forward {
+    ($(fn $name:ident(&self $(, $arg:ident: $ty:ty)*) $(-> $ret:ty)?;)*) => {
+        $(
+            pub fn $name(&self $(, $arg: $ty)*) $(-> $ret)? {
+                match self {
+                    Notify::Native(watcher) => watcher.$name($($arg),*),
+                    Notify::Poll(watcher) => watcher.$name($($arg),*),
+                }
+            }
+        )*
+    };
+}
+
+impl Notify {
+    forward! {
+        fn shutdown(&self);
+        fn is_shutdown(&self) -> bool;
+        fn set_follow_symlinks(&self, follow_symlinks: bool);
+        fn set_delete_only(&self, delete_only: bool);
+        fn delete_only(&self) -> bool;
+        fn set_only_on_close(&self, only_on_close: bool);
+        fn set_watch_mask(&self, mask: WatchMask);
+        fn watch_dir(&self, path: CanonicalPathBuf) -> io::Result<()>;
+        fn unwatch_dir(&self, path: &CannonicalPath) -> io::Result<()>;
+        fn unwatch_tree(&self, root: &CannonicalPath);
+        fn rename_watches(&self, from: &CannonicalPath, to: &CanonicalPathBuf);
+        fn refresh_config(&self);
+        fn watch_count(&self) -> usize;
+        fn queue_overflows(&self) -> usize;
+        fn retry_watches(&self) -> usize;
+        fn pending_watch_retries(&self) -> usize;
+        fn watch_root_parent(&self, root: &CannonicalPath) -> io::Result<()>;
+        fn unwatch_root_parent(&self, root: &CannonicalPath);
+    }
+
+    pub fn changes(&self) -> &PendingChangesLock {
+        match self {
+            Notify::Native(watcher) => &watcher.changes,
+            Notify::Poll(watcher) => &watcher.changes,
+        }
+    }
+
+    /// the polling backend finds changes by recrawling all roots periodically
+    pub fn is_polling(&self) -> bool {
+        matches!(self, Notify::Poll(_))
+    }
+}
@@ -1,27 +1,157 @@
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
-use crate::events::Events;
+use bitflags::bitflags;
+
+use crate::events::{CoalescePolicy, Events};
+use crate::metadata::Metadata;
+use crate::path::CanonicalPathBuf;
+use crate::pending::RecrawlCause;
 
 pub type Handler = Box<dyn FnMut(Events) -> bool + Send>;
+/// called with the path of a symlink and the ancestor it loops back to
+pub type SymlinkLoopHandler = Box<dyn FnMut(&Path, &Path) + Send>;
+pub type ErrorHandler = Box<dyn FnMut(WatchError) + Send>;
+/// called with the total number of queue overflows so far
+pub type OverflowHandler = Box<dyn FnMut(usize) + Send>;
+pub type RecrawlHandler = Box<dyn FnMut(RecrawlCause) + Send>;
+/// called with the path of a root that was deleted or moved away
+pub type RootRemovedHandler = Box<dyn FnMut(&Path) + Send>;
+
+/// An error encountered while crawling a directory, for example because a
+/// subdirectory can't be read. The crawl skips the entry and continues.
+#[derive(Debug)]
+pub struct WatchError {
+    pub path: PathBuf,
+    pub error: walkdir::Error,
+}
+
+impl std::fmt::Display for WatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to crawl {:?}: {}", self.path, self.error)
+    }
+}
+
+impl std::error::Error for WatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+bitflags! {
+    /// The kinds of file changes the backend subscribes to, see
+    /// [`Watcher::set_watch_mask`](crate::Watcher::set_watch_mask).
+    ///
+    /// Creations, deletions and renames are always subscribed to since they
+    /// are required to discover new subtrees, so they can't be masked out.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct WatchMask: u8 {
+        /// metadata changes like permissions, ownership or timestamps
+        const ATTRIB = 1;
+        /// writes to the contents of a file
+        const MODIFY = 2;
+        /// a file that was opened for writing was closed, only supported by
+        /// inotify and implied by
+        /// [`Watcher::set_only_on_close`](crate::Watcher::set_only_on_close)
+        const CLOSE_WRITE = 4;
+    }
+}
+
+impl Default for WatchMask {
+    fn default() -> Self {
+        Self::ATTRIB | Self::MODIFY
+    }
+}
+
+/// Turns a user provided path into an absolute path without symlinks or `.`/`..`
+/// components, see [`Watcher::set_canonicalize`](crate::Watcher::set_canonicalize).
+pub type Canonicalize = Arc<dyn Fn(&Path) -> io::Result<PathBuf> + Send + Sync>;
+
+/// The function set with `Watcher::set_canonicalize`, wrapped so that it
+/// can be swapped atomically.
+pub(crate) struct CanonicalizeHook(pub(crate) Canonicalize);
+
+impl std::fmt::Debug for CanonicalizeHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CanonicalizeHook").finish_non_exhaustive()
+    }
+}
 
 pub struct Config {
-    pub(crate) filter: Arc<dyn Filter>,
     pub(crate) settle_time: Duration,
+    pub(crate) max_settle_time: Option<Duration>,
+    pub(crate) settle_threshold: usize,
+    pub(crate) min_recrawl_interval: Duration,
+    pub(crate) max_recrawl_interval: Duration,
+    pub(crate) defer_empty_creates: Option<Duration>,
+    pub(crate) coalesce_deletes: Option<Duration>,
+    pub(crate) min_modified_interval: Option<Duration>,
+    pub(crate) max_root_entries: Option<usize>,
+    pub(crate) max_watches: Option<usize>,
+    pub(crate) max_batch: Option<usize>,
+    pub(crate) max_pending_changes: Option<usize>,
+    pub(crate) hash_contents: Option<usize>,
+    pub(crate) report_metadata_changes: bool,
+    pub(crate) report_directories: bool,
+    pub(crate) preserve_order: bool,
+    pub(crate) coalesce_policy: Arc<dyn CoalescePolicy>,
+    pub(crate) track_modifications: bool,
+    pub(crate) track_hardlinks: bool,
+    pub(crate) case_insensitive: bool,
+    pub(crate) capacity_hint: usize,
+    #[cfg(feature = "parallel")]
+    pub(crate) parallel_crawl: bool,
+    pub(crate) display_root: Option<CanonicalPathBuf>,
+    pub(crate) report_tempfiles: bool,
+    pub(crate) report_vanished_files: bool,
+    pub(crate) poll_interval: Duration,
+    pub(crate) read_buffer_size: Option<usize>,
     pub(crate) handlers: Vec<Handler>,
+    pub(crate) symlink_loop_handler: Option<SymlinkLoopHandler>,
+    pub(crate) error_handler: Option<ErrorHandler>,
+    pub(crate) overflow_handler: Option<OverflowHandler>,
+    pub(crate) recrawl_handler: Option<RecrawlHandler>,
+    pub(crate) root_removed_handler: Option<RootRemovedHandler>,
 }
 
 impl std::fmt::Debug for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Config")
             .field("settle_time", &self.settle_time)
+            .field("max_settle_time", &self.max_settle_time)
+            .field("settle_threshold", &self.settle_threshold)
+            .field("min_recrawl_interval", &self.min_recrawl_interval)
+            .field("max_recrawl_interval", &self.max_recrawl_interval)
+            .field("defer_empty_creates", &self.defer_empty_creates)
+            .field("coalesce_deletes", &self.coalesce_deletes)
+            .field("min_modified_interval", &self.min_modified_interval)
+            .field("max_root_entries", &self.max_root_entries)
+            .field("max_watches", &self.max_watches)
+            .field("max_batch", &self.max_batch)
+            .field("max_pending_changes", &self.max_pending_changes)
+            .field("hash_contents", &self.hash_contents)
+            .field("report_metadata_changes", &self.report_metadata_changes)
+            .field("report_directories", &self.report_directories)
+            .field("preserve_order", &self.preserve_order)
+            .field("track_modifications", &self.track_modifications)
+            .field("track_hardlinks", &self.track_hardlinks)
+            .field("case_insensitive", &self.case_insensitive)
+            .field("capacity_hint", &self.capacity_hint)
+            .field("display_root", &self.display_root)
+            .field("report_tempfiles", &self.report_tempfiles)
+            .field("report_vanished_files", &self.report_vanished_files)
+            .field("poll_interval", &self.poll_interval)
+            .field("read_buffer_size", &self.read_buffer_size)
             .finish_non_exhaustive()
     }
 }
 
 pub trait Filter: 'static + Send + Sync {
-    fn ignore_path_rec(&self, mut path: &Path, is_dir: Option<bool>) -> bool {
+    /// Checks `path` and all of its ancestors with `ignore_path`. The
+    /// ancestors are always directories so they are passed `Some(true)`.
+    fn ignore_path_rec(&self, mut path: &Path, mut is_dir: Option<bool>) -> bool {
         loop {
             if self.ignore_path(path, is_dir) {
                 return true;
@@ -30,14 +160,201 @@ pub trait Filter: 'static + Send + Sync {
                 break;
             };
             path = parent;
+            is_dir = Some(true);
+        }
+        false
+    }
+    /// Like `ignore_path_rec` but stops at `root`, ancestors of `root` are
+    /// not checked. If `path` isn't inside `root` only `path` is checked.
+    fn ignore_path_rec_until(
+        &self,
+        mut path: &Path,
+        root: &Path,
+        mut is_dir: Option<bool>,
+    ) -> bool {
+        loop {
+            if self.ignore_path(path, is_dir) {
+                return true;
+            }
+            if path == root {
+                break;
+            }
+            match path.parent() {
+                Some(parent) if parent.starts_with(root) => path = parent,
+                _ => break,
+            }
+            is_dir = Some(true);
         }
         false
     }
     fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool;
+    /// Called after a path that passed `ignore_path` was stat-ed, allows
+    /// filtering by size or mtime. A path that is ignored this way is
+    /// treated as if it didn't exist (so a file that starts to match is
+    /// reported as deleted). Ignored directories are not crawled.
+    fn ignore_metadata(&self, _path: &Path, _meta: &Metadata) -> bool {
+        false
+    }
 }
 
+/// Ignores nothing.
 impl Filter for () {
-    fn ignore_path(&self, path: &Path, _is_dir: Option<bool>) -> bool {
-        path.ends_with(".git")
+    fn ignore_path(&self, _path: &Path, _is_dir: Option<bool>) -> bool {
+        false
+    }
+}
+
+/// The filter a [`Watcher`](crate::Watcher) uses unless another filter is
+/// set. Ignores nothing by default.
+#[derive(Debug, Clone, Default)]
+pub struct DefaultFilter {
+    ignore_git_dirs: bool,
+}
+
+impl DefaultFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether directories named `.git` (and everything inside them) are
+    /// ignored. Files named `.git` (as used by git worktrees and submodules)
+    /// are still watched. Disabled by default.
+    pub fn ignore_git_dirs(mut self, ignore: bool) -> Self {
+        self.ignore_git_dirs = ignore;
+        self
+    }
+}
+
+impl Filter for DefaultFilter {
+    fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool {
+        self.ignore_git_dirs && is_dir != Some(false) && path.ends_with(".git")
+    }
+}
+
+/// Ignores a path if any of the inner filters ignores it, an empty
+/// `AnyFilter` ignores nothing.
+#[derive(Clone, Default)]
+pub struct AnyFilter(pub Vec<Arc<dyn Filter>>);
+
+impl Filter for AnyFilter {
+    fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool {
+        self.0.iter().any(|filter| filter.ignore_path(path, is_dir))
+    }
+
+    fn ignore_metadata(&self, path: &Path, meta: &Metadata) -> bool {
+        self.0
+            .iter()
+            .any(|filter| filter.ignore_metadata(path, meta))
+    }
+}
+
+/// Ignores a path only if all inner filters ignore it, an empty
+/// `AllFilter` ignores everything.
+#[derive(Clone, Default)]
+pub struct AllFilter(pub Vec<Arc<dyn Filter>>);
+
+impl Filter for AllFilter {
+    fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool {
+        self.0.iter().all(|filter| filter.ignore_path(path, is_dir))
+    }
+
+    fn ignore_metadata(&self, path: &Path, meta: &Metadata) -> bool {
+        self.0
+            .iter()
+            .all(|filter| filter.ignore_metadata(path, meta))
+    }
+}
+
+/// Uses the filter of the innermost root that contains a path, paths
+/// outside of these roots use the default filter.
+pub(crate) struct RootFilter {
+    /// the filter set with `Watcher::set_filter`
+    default: Arc<dyn Filter>,
+    /// the filters set with `RootOptions::filter` sorted by path
+    roots: Vec<(CanonicalPathBuf, Arc<dyn Filter>)>,
+}
+
+impl std::fmt::Debug for RootFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RootFilter").finish_non_exhaustive()
     }
 }
+
+impl RootFilter {
+    pub(crate) fn new(default: Arc<dyn Filter>) -> Self {
+        RootFilter {
+            default,
+            roots: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_default(&self, default: Arc<dyn Filter>) -> Self {
+        RootFilter {
+            default,
+            roots: self.roots.clone(),
+        }
+    }
+
+    /// `roots` must be sorted by path
+    pub(crate) fn with_roots(&self, roots: Vec<(CanonicalPathBuf, Arc<dyn Filter>)>) -> Self {
+        RootFilter {
+            default: self.default.clone(),
+            roots,
+        }
+    }
+
+    pub(crate) fn has_roots(&self) -> bool {
+        !self.roots.is_empty()
+    }
+
+    fn filter_for(&self, path: &Path) -> &dyn Filter {
+        // ancestors always sort before their children
+        let end = self
+            .roots
+            .partition_point(|(root, _)| root.as_std_path() <= path);
+        self.roots[..end]
+            .iter()
+            .rev()
+            .find(|(root, _)| path.starts_with(root.as_std_path()))
+            .map_or(&*self.default, |(_, filter)| &**filter)
+    }
+}
+
+impl Filter for RootFilter {
+    fn ignore_path_rec(&self, path: &Path, is_dir: Option<bool>) -> bool {
+        self.filter_for(path).ignore_path_rec(path, is_dir)
+    }
+
+    fn ignore_path_rec_until(&self, path: &Path, root: &Path, is_dir: Option<bool>) -> bool {
+        self.filter_for(path)
+            .ignore_path_rec_until(path, root, is_dir)
+    }
+
+    fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool {
+        self.filter_for(path).ignore_path(path, is_dir)
+    }
+
+    fn ignore_metadata(&self, path: &Path, meta: &Metadata) -> bool {
+        self.filter_for(path).ignore_metadata(path, meta)
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+    impl<F: super::Filter> Sealed for F {}
+}
+
+/// Combinators for [`Filter`]s.
+pub trait FilterExt: Filter + sealed::Sealed + Sized {
+    /// Ignores a path if either `self` or `other` ignores it.
+    fn or(self, other: impl Filter) -> AnyFilter {
+        AnyFilter(vec![Arc::new(self), Arc::new(other)])
+    }
+
+    /// Ignores a path only if both `self` and `other` ignore it.
+    fn and(self, other: impl Filter) -> AllFilter {
+        AllFilter(vec![Arc::new(self), Arc::new(other)])
+    }
+}
+
+impl<F: Filter> FilterExt for F {}
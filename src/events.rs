@@ -1,13 +1,20 @@
+use std::ffi::OsStr;
 use std::hash::BuildHasher;
-use std::mem::replace;
+use std::mem::{replace, take};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use ecow::EcoVec;
-use hashbrown::{hash_table, DefaultHashBuilder, HashTable};
+use hashbrown::{hash_table, DefaultHashBuilder, HashMap, HashTable};
 
-use crate::path::CanonicalPathBuf;
+use crate::metadata::Metadata;
+use crate::path::{CannonicalPath, CanonicalPathBuf};
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EventType {
     Create,
     Delete,
@@ -15,19 +22,205 @@ pub enum EventType {
     /// a file that was added and removed again immedietly
     /// (within the settle period) can usually be ignored
     Tempfile,
+    /// a file was moved to `path` from `Event::renamed_from` (within the
+    /// watched roots), replaces the `Delete` and `Create` events
+    Rename,
+    /// a file was replaced by a directory or a directory by a file within
+    /// the settle period. The contents of a new directory are reported as
+    /// `Create` events, the contents of a replaced directory as `Delete`
+    /// events. Only reported if
+    /// [`Watcher::set_report_directories`](crate::Watcher::set_report_directories)
+    /// is enabled, otherwise only the file is reported (as `Create` or
+    /// `Delete`).
+    TypeChanged,
+}
+
+/// The metadata of a file as it was last observed by the watcher.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileMeta {
+    pub mtime: SystemTime,
+    pub size: usize,
+    pub is_dir: bool,
+}
+
+impl FileMeta {
+    /// passed as the previous metadata of deleted directories, whose
+    /// metadata isn't tracked
+    pub(crate) const DIR: FileMeta = FileMeta {
+        mtime: SystemTime::UNIX_EPOCH,
+        size: 0,
+        is_dir: true,
+    };
+}
+
+impl From<&Metadata> for FileMeta {
+    fn from(meta: &Metadata) -> Self {
+        FileMeta {
+            mtime: meta.mtime,
+            size: meta.size,
+            is_dir: meta.is_dir,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Event {
     pub path: CanonicalPathBuf,
     pub ty: EventType,
+    /// For `Modified` events the metadata of the file before the first change
+    /// that was coalesced into this event. `None` for all other events and if
+    /// the file was replaced (deleted and recreated) within the settle period.
+    pub prev: Option<FileMeta>,
+    /// The tag of the closest root (added with `add_root_with`) containing
+    /// this path, `None` if that root has no tag.
+    pub tag: Option<u64>,
+    /// The previous path of a `Rename` event.
+    pub renamed_from: Option<CanonicalPathBuf>,
+    /// When the (latest) change was observed. For changes found while
+    /// crawling this is the time of the crawl.
+    pub time: SystemTime,
+    /// see `Event::metadata`
+    pub(crate) meta: Option<FileMeta>,
+    /// whether a `Modified` change was merged into this event, see
+    /// `Event::modified_before`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) modified: bool,
+    /// for `Delete` events whether a directory was deleted, a `Create` of a
+    /// different kind turns the event into `TypeChanged`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) deleted_dir: bool,
+    /// length of the display root prefix (including the separator) or 0
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) display_offset: usize,
+}
+
+impl Event {
+    /// The path relative to the display root set with
+    /// [`Watcher::set_display_root`](crate::Watcher::set_display_root).
+    /// Paths outside the display root (or if none is set) are absolute.
+    pub fn display_path(&self) -> &Path {
+        let bytes = &self.path.as_bytes()[self.display_offset..];
+        // safety: the offset always points after a path separator which is ascii
+        Path::new(unsafe { OsStr::from_encoded_bytes_unchecked(bytes) })
+    }
+
+    /// The metadata the watcher observed when it detected the (latest)
+    /// change, saves handlers from racing further changes by stat-ing the
+    /// file again. `None` for `Delete` and `Tempfile` events.
+    pub fn metadata(&self) -> Option<FileMeta> {
+        self.meta
+    }
+
+    /// Whether modifications were merged into this event within the settle
+    /// period, for example for a file that was modified and then deleted.
+    /// Always `false` for `Modified` events and unless enabled
+    /// with [`Watcher::set_track_modifications`](crate::Watcher::set_track_modifications).
+    pub fn modified_before(&self) -> bool {
+        self.modified && self.ty != EventType::Modified
+    }
+
+    /// Copies the path and type into std types. Allocates, prefer accessing
+    /// `path` and `ty` directly.
+    pub fn to_std(&self) -> (PathBuf, EventType) {
+        (self.path.as_std_path().to_owned(), self.ty)
+    }
+}
+
+/// How a new change to a path is merged with the event that is already
+/// pending for it, see [`CoalescePolicy`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MergeAction {
+    /// keep the pending event as is
+    Keep,
+    /// change the type of the pending event
+    Replace(EventType),
+    /// drop the pending event, nothing is reported for the path
+    Remove,
+}
+
+/// Decides how changes to the same path within the settle period are
+/// coalesced into a single event, see
+/// [`Watcher::set_coalesce_policy`](crate::Watcher::set_coalesce_policy).
+/// Every path occurs at most once per batch.
+pub trait CoalescePolicy: 'static + Send + Sync {
+    /// Merges the change `new` into the pending event of type `old`. `new`
+    /// is never `Rename`, renames are paired after all changes were merged.
+    /// A `Create` for a path with a pending `Delete` of a different kind of
+    /// file (a directory instead of a file or vice versa) is passed as
+    /// `TypeChanged`.
+    fn merge(&self, old: EventType, new: EventType) -> MergeAction;
+}
+
+/// The policy a [`Watcher`](crate::Watcher) uses unless another policy is
+/// set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultCoalescePolicy;
+
+impl CoalescePolicy for DefaultCoalescePolicy {
+    fn merge(&self, old: EventType, new: EventType) -> MergeAction {
+        match (old, new) {
+            // temporary file that was created and immidiately removed
+            (EventType::Create, EventType::Delete) => MergeAction::Replace(EventType::Tempfile),
+            (_, EventType::Delete) => MergeAction::Replace(EventType::Delete),
+            (EventType::Delete, EventType::Create) => MergeAction::Replace(EventType::Modified),
+            // reported again after the file vanished
+            (EventType::Create, EventType::Tempfile) => MergeAction::Replace(EventType::Tempfile),
+            (EventType::Delete | EventType::Tempfile, EventType::Tempfile) => MergeAction::Keep,
+            (EventType::Tempfile, EventType::Create) => MergeAction::Replace(EventType::Create),
+            (EventType::Delete, EventType::TypeChanged) => {
+                MergeAction::Replace(EventType::TypeChanged)
+            }
+            // the path still didn't exist before the batch
+            (EventType::Create, EventType::TypeChanged)
+            | (EventType::TypeChanged, EventType::Modified | EventType::TypeChanged) => {
+                MergeAction::Keep
+            }
+            (EventType::Modified, EventType::TypeChanged) => {
+                MergeAction::Replace(EventType::TypeChanged)
+            }
+            (EventType::Create, EventType::Modified)
+            | (EventType::Modified, EventType::Modified) => MergeAction::Keep,
+            (old, new) => {
+                log::error!("cannot merge {old:?}->{new:?}, this should be impossible!");
+                MergeAction::Keep
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
 pub(crate) struct EventDebouncer {
     table: HashTable<u32>,
     hasher: DefaultHashBuilder,
     events: EcoVec<Event>,
+    /// `Create` events for empty files and `Delete` events that are held
+    /// back (index into events and deadline) until the path changes again or
+    /// the deadline passes
+    held: Vec<(u32, Instant)>,
+    /// indices of events that were dropped by the coalesce policy or paired
+    /// into a rename, they are removed from `events` by the next `take`
+    removed: Vec<usize>,
+    /// grace period for which `Create` events of empty files are held back
+    pub defer_empty_creates: Option<Duration>,
+    /// grace period for which `Delete` events are held back so that a
+    /// recreate turns them into `Modified`
+    pub coalesce_deletes: Option<Duration>,
+    /// minimum time between two `Modified` events for the same path
+    pub min_modified_interval: Option<Duration>,
+    /// deliver events in the order they were first recorded instead of
+    /// sorted by path
+    pub preserve_order: bool,
+    pub coalesce_policy: Arc<dyn CoalescePolicy>,
+    /// remember `Modified` changes that were merged into another event,
+    /// see `Event::modified_before`
+    pub track_modifications: bool,
+    /// when the last `Modified` event was released for paths that are
+    /// currently rate limited
+    last_modified: HashMap<CanonicalPathBuf, Instant>,
+    /// renames (from, to) reported by the backend which are turned into
+    /// `Rename` events if the matching `Delete` and `Create` are found
+    renames: Vec<(CanonicalPathBuf, CanonicalPathBuf)>,
 }
 
 impl EventDebouncer {
@@ -36,10 +229,29 @@ impl EventDebouncer {
             table: HashTable::with_capacity(128),
             hasher: DefaultHashBuilder::default(),
             events: EcoVec::with_capacity(8),
+            held: Vec::new(),
+            removed: Vec::new(),
+            defer_empty_creates: None,
+            coalesce_deletes: None,
+            min_modified_interval: None,
+            preserve_order: false,
+            coalesce_policy: Arc::new(DefaultCoalescePolicy),
+            track_modifications: false,
+            last_modified: HashMap::new(),
+            renames: Vec::new(),
         }
     }
 
-    pub fn add(&mut self, path: CanonicalPathBuf, ty: EventType) {
+    /// Records a change. For `Delete` events `prev` is [`FileMeta::DIR`] if a
+    /// directory was deleted.
+    pub fn add(
+        &mut self,
+        path: CanonicalPathBuf,
+        ty: EventType,
+        meta: Option<&Metadata>,
+        prev: Option<FileMeta>,
+        time: SystemTime,
+    ) {
         let entry = self.table.entry(
             self.hasher.hash_one(&path),
             |&i| self.events[i as usize].path == path,
@@ -48,49 +260,278 @@ impl EventDebouncer {
         match entry {
             hash_table::Entry::Occupied(entry) => {
                 let i = *entry.get() as usize;
+                // any further change releases a held back event
+                if let Some(pos) = self.held.iter().position(|&(held, _)| held as usize == i) {
+                    self.held.swap_remove(pos);
+                }
                 let event = &mut self.events.make_mut()[i];
-                match (event.ty, ty) {
-                    // temporary file that was created and immidiately removed
-                    (EventType::Create, EventType::Delete) => event.ty = EventType::Tempfile,
-                    (_, EventType::Delete) => {
-                        event.ty = EventType::Delete;
-                    }
-                    (EventType::Delete, EventType::Create) => {
-                        event.ty = EventType::Modified;
+                event.time = event.time.max(time);
+                event.meta = meta.map(FileMeta::from);
+                event.modified |= self.track_modifications && ty == EventType::Modified;
+                let is_dir = meta.is_some_and(|meta| meta.is_dir);
+                let ty = if event.ty == EventType::Delete
+                    && ty == EventType::Create
+                    && event.deleted_dir != is_dir
+                {
+                    EventType::TypeChanged
+                } else {
+                    ty
+                };
+                event.deleted_dir = ty == EventType::Delete && prev.is_some_and(|prev| prev.is_dir);
+                match self.coalesce_policy.merge(event.ty, ty) {
+                    MergeAction::Keep => (),
+                    MergeAction::Replace(ty) => {
+                        event.ty = ty;
+                        // only `Modified` events carry the previous metadata
+                        if ty != EventType::Modified {
+                            event.prev = None;
+                        }
                     }
-                    (EventType::Create, EventType::Modified)
-                    | (EventType::Modified, EventType::Modified) => (),
-                    (old, new) => {
-                        log::error!(
-                            "cannot merge {old:?}->{new:?} for {path}, this should be impossible!",
-                        )
+                    MergeAction::Remove => {
+                        // removing the event right away would shift all later
+                        // indices, only drop it from the lookup table for now
+                        entry.remove();
+                        self.removed.push(i);
                     }
                 }
             }
             hash_table::Entry::Vacant(entry) => {
-                entry.insert(self.events.len() as u32);
-                self.events.push(Event { path, ty });
+                let i = self.events.len() as u32;
+                entry.insert(i);
+                self.events.push(Event {
+                    path,
+                    ty,
+                    prev: prev.filter(|_| ty == EventType::Modified),
+                    tag: None,
+                    renamed_from: None,
+                    time,
+                    meta: meta.map(FileMeta::from),
+                    modified: self.track_modifications && ty == EventType::Modified,
+                    deleted_dir: ty == EventType::Delete && prev.is_some_and(|prev| prev.is_dir),
+                    display_offset: 0,
+                });
+                if let Some(grace_period) = self.defer_empty_creates {
+                    if ty == EventType::Create
+                        && meta.is_some_and(|meta| !meta.is_dir && meta.size == 0)
+                    {
+                        self.held.push((i, Instant::now() + grace_period));
+                    }
+                }
+                if let Some(grace_period) = self.coalesce_deletes {
+                    if ty == EventType::Delete {
+                        self.held.push((i, Instant::now() + grace_period));
+                    }
+                }
             }
         }
     }
 
+    pub fn add_renames(&mut self, renames: Vec<(CanonicalPathBuf, CanonicalPathBuf)>) {
+        self.renames.extend(renames);
+    }
+
+    fn find(&self, path: &CanonicalPathBuf) -> Option<usize> {
+        self.table
+            .find(self.hasher.hash_one(path), |&i| {
+                self.events[i as usize].path == *path
+            })
+            .map(|&i| i as usize)
+    }
+
+    /// merges the `Delete` and `Create` events of renamed files into a
+    /// single `Rename` event
+    fn pair_renames(&mut self) {
+        for (from, to) in take(&mut self.renames) {
+            let (Some(i), Some(j)) = (self.find(&from), self.find(&to)) else {
+                continue;
+            };
+            let events = self.events.make_mut();
+            if events[i].ty != EventType::Delete
+                || !matches!(events[j].ty, EventType::Create | EventType::Modified)
+            {
+                continue;
+            }
+            events[j].ty = EventType::Rename;
+            events[j].renamed_from = Some(from);
+            events[j].prev = None;
+            events[j].time = events[j].time.max(events[i].time);
+            self.held.retain(|&(held, _)| held as usize != j);
+            self.removed.push(i);
+        }
+    }
+
+    /// drops the events in `removed` from `events`, the lookup table is not
+    /// updated since `take` rebuilds it anyway
+    fn remove_events(&mut self) {
+        let mut removed = take(&mut self.removed);
+        removed.sort_unstable();
+        // a rename may pair an earlier delete than the previous one
+        removed.dedup();
+        let mut i = 0;
+        self.events.retain(|_| {
+            i += 1;
+            removed.binary_search(&(i - 1)).is_err()
+        });
+        self.held
+            .retain(|&(held, _)| removed.binary_search(&(held as usize)).is_err());
+        for (held, _) in &mut self.held {
+            *held -= removed.partition_point(|&i| i < *held as usize) as u32;
+        }
+    }
+
     pub fn take(&mut self) -> Events {
+        let mut events = self.take_unsorted();
+        if !events.is_empty() && !self.preserve_order {
+            events
+                .events
+                .make_mut()
+                .sort_unstable_by(|event1, event2| event1.path.cmp(&event2.path));
+        }
+        events
+    }
+
+    fn take_unsorted(&mut self) -> Events {
+        if !self.renames.is_empty() {
+            self.pair_renames();
+        }
+        if !self.removed.is_empty() {
+            self.remove_events();
+        }
         self.table.clear();
-        Events {
-            events: replace(&mut self.events, EcoVec::with_capacity(8)),
+        let events = replace(&mut self.events, EcoVec::with_capacity(8));
+        if self.held.is_empty() && self.min_modified_interval.is_none() {
+            return Events { events };
+        }
+        // hold back events until their grace period expired
+        let now = Instant::now();
+        let mut held = take(&mut self.held);
+        held.retain(|&(_, deadline)| deadline > now);
+        if let Some(interval) = self.min_modified_interval {
+            self.last_modified
+                .retain(|_, &mut last| now.duration_since(last) < interval);
+        } else {
+            self.last_modified.clear();
         }
+        let mut released = EcoVec::with_capacity(events.len());
+        for (i, event) in events.into_iter().enumerate() {
+            let deadline = held
+                .iter()
+                .find(|&&(held, _)| held as usize == i)
+                .map(|&(_, deadline)| deadline);
+            // modifications are rate limited, but creates and deletes are not
+            let throttled =
+                event.ty == EventType::Modified && self.last_modified.contains_key(&event.path);
+            if deadline.is_none() && !throttled {
+                if event.ty == EventType::Modified && self.min_modified_interval.is_some() {
+                    self.last_modified.insert(event.path.clone(), now);
+                }
+                released.push(event);
+                continue;
+            }
+            let i = self.events.len() as u32;
+            self.table
+                .insert_unique(self.hasher.hash_one(&event.path), i, |&i| {
+                    self.hasher.hash_one(&self.events[i as usize].path)
+                });
+            self.events.push(event);
+            if let Some(deadline) = deadline {
+                self.held.push((i, deadline));
+            }
+        }
+        Events { events: released }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.events.is_empty()
+        self.events.len() == self.removed.len()
+    }
+
+    /// releases all held back (and rate limited) events with the next `take`
+    pub fn release_held(&mut self) {
+        self.held.clear();
+        self.last_modified.clear();
     }
 }
 
+/// A batch of events. Every path occurs at most once and the events are
+/// sorted by path, a directory always sorts before its children. With
+/// [`Watcher::set_preserve_order`](crate::Watcher::set_preserve_order) the
+/// events are in the order they occurred instead.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Events {
     events: EcoVec<Event>,
 }
 
+impl Events {
+    /// The paths of all events of type `ty`.
+    pub fn by_type(&self, ty: EventType) -> impl Iterator<Item = &CannonicalPath> + '_ {
+        self.events
+            .iter()
+            .filter(move |event| event.ty == ty)
+            .map(|event| &*event.path)
+    }
+
+    /// The paths of all `Create` events.
+    pub fn created(&self) -> impl Iterator<Item = &CannonicalPath> + '_ {
+        self.by_type(EventType::Create)
+    }
+
+    /// The paths of all `Delete` events.
+    pub fn deleted(&self) -> impl Iterator<Item = &CannonicalPath> + '_ {
+        self.by_type(EventType::Delete)
+    }
+
+    /// The paths of all `Modified` events.
+    pub fn modified(&self) -> impl Iterator<Item = &CannonicalPath> + '_ {
+        self.by_type(EventType::Modified)
+    }
+
+    /// Copies the path and type of all events into std types, see
+    /// [`Event::to_std`]. Allocates, prefer iterating the events directly.
+    pub fn to_std_vec(&self) -> Vec<(PathBuf, EventType)> {
+        self.events.iter().map(Event::to_std).collect()
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> slice::IterMut<'_, Event> {
+        self.events.make_mut().iter_mut()
+    }
+
+    pub(crate) fn retain(&mut self, f: impl FnMut(&mut Event) -> bool) {
+        self.events.retain(f)
+    }
+
+    /// splits the events into batches of at most `max` events
+    pub(crate) fn into_batches(self, max: usize) -> Vec<Events> {
+        if self.events.len() <= max {
+            return vec![self];
+        }
+        self.events
+            .chunks(max.max(1))
+            .map(|chunk| Events {
+                events: chunk.into(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Events {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Events {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // events are serialized sorted by path unless the watcher preserved
+        // the order they occurred in, either way that order is kept
+        let events = Vec::<Event>::deserialize(deserializer)?;
+        Ok(Events {
+            events: events.into(),
+        })
+    }
+}
+
 impl Deref for Events {
     type Target = [Event];
 
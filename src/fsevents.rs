@@ -0,0 +1,379 @@
+use std::ffi::{c_char, c_void, CStr, OsStr};
+use std::mem::{take, ManuallyDrop};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+use std::{io, ptr, slice};
+
+use fsevent_sys::core_foundation as cf;
+use fsevent_sys::{self as fs, FSEventStreamEventFlags, FSEventStreamRef};
+use hashbrown::{DefaultHashBuilder, HashMap, HashSet};
+
+use crate::path::{CannonicalPath, CanonicalPathBuf};
+use crate::pending::{self, PendingChangesLock, RecrawlCause};
+use crate::{Filter, WatchMask, WatcherState};
+
+/// how long (in seconds) FSEvents coalesces events before delivering them
+const LATENCY: f64 = 0.05;
+
+type DispatchQueue = *mut c_void;
+
+extern "C" {
+    fn dispatch_queue_create(label: *const c_char, attr: *mut c_void) -> DispatchQueue;
+    fn dispatch_release(object: *mut c_void);
+}
+
+#[link(name = "CoreServices", kind = "framework")]
+extern "C" {
+    fn FSEventStreamSetDispatchQueue(stream: FSEventStreamRef, queue: DispatchQueue);
+}
+
+#[derive(Debug)]
+struct Queue(DispatchQueue);
+
+// safety: dispatch queues are thread safe
+unsafe impl Send for Queue {}
+unsafe impl Sync for Queue {}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        unsafe { dispatch_release(self.0) }
+    }
+}
+
+#[derive(Debug)]
+struct Stream(FSEventStreamRef);
+
+// safety: the stream is only accessed by its owner, events are
+// delivered on the dispatch queue
+unsafe impl Send for Stream {}
+
+impl Stream {
+    fn new(
+        path: &CannonicalPath,
+        watcher: Weak<FsEventsWatcher>,
+        queue: &Queue,
+    ) -> io::Result<Stream> {
+        unsafe {
+            let cf_path = cf::CFStringCreateWithCString(
+                cf::kCFAllocatorDefault,
+                path.as_c_str().as_ptr(),
+                cf::kCFStringEncodingUTF8,
+            );
+            if cf_path.is_null() {
+                return Err(io::Error::other(format!(
+                    "can't watch {path:?}: not valid UTF-8"
+                )));
+            }
+            let paths =
+                cf::CFArrayCreateMutable(cf::kCFAllocatorDefault, 1, &cf::kCFTypeArrayCallBacks);
+            cf::CFArrayAppendValue(paths, cf_path);
+            cf::CFRelease(cf_path);
+            let info = Weak::into_raw(watcher);
+            let context = fs::FSEventStreamContext {
+                version: 0,
+                info: info.cast_mut().cast(),
+                retain: None,
+                release: Some(release_watcher),
+                copy_description: None,
+            };
+            let stream = fs::FSEventStreamCreate(
+                cf::kCFAllocatorDefault,
+                handle_events,
+                &context,
+                paths,
+                fs::kFSEventStreamEventIdSinceNow,
+                LATENCY,
+                fs::kFSEventStreamCreateFlagNoDefer | fs::kFSEventStreamCreateFlagWatchRoot,
+            );
+            cf::CFRelease(paths);
+            if stream.is_null() {
+                drop(Weak::from_raw(info));
+                return Err(io::Error::other(format!(
+                    "failed to create FSEvents stream for {path:?}"
+                )));
+            }
+            FSEventStreamSetDispatchQueue(stream, queue.0);
+            // the stream is released (and invalidated) by drop
+            let stream = Stream(stream);
+            if fs::FSEventStreamStart(stream.0) == 0 {
+                return Err(io::Error::other(format!(
+                    "failed to start FSEvents stream for {path:?}"
+                )));
+            }
+            Ok(stream)
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe {
+            fs::FSEventStreamStop(self.0);
+            fs::FSEventStreamInvalidate(self.0);
+            fs::FSEventStreamRelease(self.0);
+        }
+    }
+}
+
+extern "C" fn release_watcher(info: *const c_void) {
+    drop(unsafe { Weak::from_raw(info.cast::<FsEventsWatcher>()) });
+}
+
+extern "C" fn handle_events(
+    _stream: FSEventStreamRef,
+    info: *mut c_void,
+    num_events: usize,
+    paths: *mut c_void,
+    flags: *const FSEventStreamEventFlags,
+    _ids: *const fs::FSEventStreamEventId,
+) {
+    // the weak reference is owned by the stream
+    let watcher = ManuallyDrop::new(unsafe { Weak::from_raw(info.cast::<FsEventsWatcher>()) });
+    let Some(watcher) = watcher.upgrade() else {
+        return;
+    };
+    let timestamp = SystemTime::now();
+    let paths = unsafe { slice::from_raw_parts(paths.cast::<*const c_char>(), num_events) };
+    let flags = unsafe { slice::from_raw_parts(flags, num_events) };
+    let filter = watcher.state.filter.load();
+    for (&path, &flags) in paths.iter().zip(flags) {
+        let path = unsafe { CStr::from_ptr(path) };
+        watcher.handle_event(
+            OsStr::from_bytes(path.to_bytes()),
+            flags,
+            &**filter,
+            timestamp,
+        );
+    }
+    watcher.changes.notify();
+}
+
+pub(crate) struct FsEventsWatcher {
+    this: Weak<FsEventsWatcher>,
+    state: Arc<WatcherState>,
+    shutdown: AtomicBool,
+    follow_symlinks: AtomicBool,
+    delete_only: AtomicBool,
+    /// all directories the worker asked us to watch, FSEvents streams are
+    /// recursive so events for any other directories are ignored
+    dirs: Mutex<HashSet<CanonicalPathBuf, DefaultHashBuilder>>,
+    /// streams are only created for directories that are not already
+    /// covered by the stream of a parent directory
+    streams: Mutex<HashMap<CanonicalPathBuf, Stream, DefaultHashBuilder>>,
+    /// must be dropped after all streams
+    queue: Queue,
+    /// number of times events were dropped, see `Watcher::stats`
+    queue_overflows: AtomicUsize,
+    pub changes: PendingChangesLock,
+}
+
+impl std::fmt::Debug for FsEventsWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FsEventsWatcher")
+            .field("shutdown", &self.shutdown)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .field("delete_only", &self.delete_only)
+            .field("dirs", &self.dirs)
+            .field("streams", &self.streams)
+            .field("queue", &self.queue)
+            .field("queue_overflows", &self.queue_overflows)
+            .field("changes", &self.changes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FsEventsWatcher {
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, atomic::Ordering::Relaxed);
+        // drop the streams outside of the lock
+        let streams = take(&mut *self.streams.lock().unwrap());
+        drop(streams);
+        self.changes.notify();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn new(#[cfg(test)] _slow: bool, state: Arc<WatcherState>) -> io::Result<Arc<Self>> {
+        let queue = unsafe { dispatch_queue_create(c"filesentry".as_ptr(), ptr::null_mut()) };
+        if queue.is_null() {
+            return Err(io::Error::other("failed to create dispatch queue"));
+        }
+        Ok(Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            state,
+            shutdown: AtomicBool::new(false),
+            follow_symlinks: AtomicBool::new(false),
+            delete_only: AtomicBool::new(false),
+            dirs: Mutex::new(HashSet::with_capacity_and_hasher(
+                1024,
+                DefaultHashBuilder::default(),
+            )),
+            streams: Mutex::new(HashMap::with_hasher(DefaultHashBuilder::default())),
+            queue: Queue(queue),
+            queue_overflows: AtomicUsize::new(0),
+            changes: PendingChangesLock::default(),
+        }))
+    }
+
+    pub fn set_follow_symlinks(&self, follow_symlinks: bool) {
+        self.follow_symlinks
+            .store(follow_symlinks, atomic::Ordering::Relaxed);
+    }
+
+    pub fn set_delete_only(&self, delete_only: bool) {
+        self.delete_only
+            .store(delete_only, atomic::Ordering::Relaxed);
+    }
+
+    pub fn delete_only(&self) -> bool {
+        self.delete_only.load(atomic::Ordering::Relaxed)
+    }
+
+    /// FSEvents streams can't be restricted to certain kinds of changes
+    /// only inotify reports when a file is closed
+    pub fn set_only_on_close(&self, _only_on_close: bool) {}
+
+    pub fn set_watch_mask(&self, _mask: WatchMask) {}
+
+    pub fn watch_dir(&self, path: CanonicalPathBuf) -> io::Result<()> {
+        if self.is_shutdown() {
+            return Ok(());
+        }
+        self.dirs.lock().unwrap().insert(path.clone());
+        let mut streams = self.streams.lock().unwrap();
+        if streams
+            .keys()
+            .any(|dir| *dir == path || dir.is_parent_of(&path))
+        {
+            return Ok(());
+        }
+        let stream = Stream::new(&path, self.this.clone(), &self.queue)?;
+        // the new stream covers all streams below it
+        streams.retain(|dir, _| !path.is_parent_of(dir));
+        streams.insert(path, stream);
+        Ok(())
+    }
+
+    pub fn unwatch_dir(&self, path: &CannonicalPath) -> io::Result<()> {
+        let mut uncovered: Vec<_> = {
+            let mut dirs = self.dirs.lock().unwrap();
+            dirs.remove(path);
+            dirs.iter()
+                .filter(|dir| path.is_parent_of(dir))
+                .cloned()
+                .collect()
+        };
+        let mut streams = self.streams.lock().unwrap();
+        if streams.remove(path).is_none() {
+            return Ok(());
+        }
+        // directories below the removed stream need streams of their own,
+        // sorted so that parents are watched before their children
+        uncovered.sort();
+        for dir in uncovered {
+            if streams.keys().any(|it| it.is_parent_of(&dir)) {
+                continue;
+            }
+            let stream = Stream::new(&dir, self.this.clone(), &self.queue)?;
+            streams.insert(dir, stream);
+        }
+        Ok(())
+    }
+
+    /// removes the watches of `root` and all directories below it
+    pub fn unwatch_tree(&self, root: &CannonicalPath) {
+        self.dirs
+            .lock()
+            .unwrap()
+            .retain(|dir| **dir != *root && !root.is_parent_of(dir));
+        self.streams
+            .lock()
+            .unwrap()
+            .retain(|dir, _| **dir != *root && !root.is_parent_of(dir));
+    }
+
+    /// the filter is loaded for every batch of events, there is nothing to
+    /// refresh
+    pub fn refresh_config(&self) {}
+
+    pub fn watch_count(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub fn queue_overflows(&self) -> usize {
+        self.queue_overflows.load(atomic::Ordering::Relaxed)
+    }
+
+    /// renames are only paired by inotify and windows
+    pub fn rename_watches(&self, _from: &CannonicalPath, _to: &CanonicalPathBuf) {}
+
+    /// only inotify has a limit on the number of watches that is worth retrying
+    pub fn retry_watches(&self) -> usize {
+        0
+    }
+
+    pub fn pending_watch_retries(&self) -> usize {
+        0
+    }
+
+    /// the removal of a root is noticed through the watch of the root itself
+    pub fn watch_root_parent(&self, _root: &CannonicalPath) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn unwatch_root_parent(&self, _root: &CannonicalPath) {}
+
+    fn handle_event(
+        &self,
+        path: &OsStr,
+        flags: FSEventStreamEventFlags,
+        filter: &dyn Filter,
+        timestamp: SystemTime,
+    ) {
+        if flags
+            & (fs::kFSEventStreamEventFlagUserDropped | fs::kFSEventStreamEventFlagKernelDropped)
+            != 0
+        {
+            self.queue_overflows.fetch_add(1, atomic::Ordering::Relaxed);
+            self.changes.lock().recrawl(RecrawlCause::QueueOverflow);
+            return;
+        }
+        // without file events FSEvents only reports directories (with a trailing slash)
+        let path = path
+            .as_bytes()
+            .strip_suffix(b"/")
+            .filter(|path| !path.is_empty())
+            .map_or(path, OsStr::from_bytes);
+        let path = CanonicalPathBuf::assert_canonicalized(Path::new(path));
+        let must_scan_subdirs = flags
+            & (fs::kFSEventStreamEventFlagMustScanSubDirs | fs::kFSEventStreamEventFlagRootChanged)
+            != 0;
+        {
+            let dirs = self.dirs.lock().unwrap();
+            if !dirs.contains(&path) {
+                // events were coalesced into a directory we don't watch
+                if must_scan_subdirs && dirs.iter().any(|dir| path.is_parent_of(dir)) {
+                    drop(dirs);
+                    self.changes.lock().recrawl(RecrawlCause::BackendRescan);
+                }
+                return;
+            }
+        }
+        if filter.ignore_path(path.as_std_path(), Some(true)) {
+            return;
+        }
+        // FSEvents only tells us that something changed in the directory,
+        // the worker has to stat its children to find out what
+        let flags = if must_scan_subdirs {
+            pending::Flags::NEEDS_RECURSIVE_CRAWL
+        } else {
+            pending::Flags::NEEDS_NON_RECURSIVE_CRAWL
+        };
+        self.changes.lock().add_watcher(path, timestamp, flags);
+    }
+}
@@ -0,0 +1,149 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+use crate::Filter;
+
+/// ignore files loaded from every ancestor of the root, files that come
+/// later take precedence
+const IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", ".fdignore"];
+
+/// A [`Filter`] that ignores paths matched by the `.gitignore`, `.ignore` and
+/// `.fdignore` files in the root (and its ancestors), the global gitignore
+/// and hidden files.
+///
+/// Ignore files in directories below the root are not loaded.
+#[derive(Debug, Clone)]
+pub struct GitignoreFilter {
+    root: PathBuf,
+    hidden: bool,
+    /// ordered by precedence, the closest ignore files come first
+    ignores: Vec<Gitignore>,
+}
+
+impl GitignoreFilter {
+    /// Creates a filter for `root` with the default settings, see
+    /// [`GitignoreFilterBuilder`].
+    pub fn for_root(root: &Path) -> io::Result<Self> {
+        Self::builder(root).build()
+    }
+
+    pub fn builder(root: &Path) -> GitignoreFilterBuilder {
+        GitignoreFilterBuilder {
+            root: root.to_owned(),
+            hidden: true,
+            ignore_files: true,
+            global: true,
+        }
+    }
+
+    fn is_hidden(&self, path: &Path) -> bool {
+        // the root itself (or its ancestors) being hidden is fine
+        path.strip_prefix(&self.root)
+            .is_ok_and(|rel| !rel.as_os_str().is_empty())
+            && path
+                .file_name()
+                .is_some_and(|name| name.as_encoded_bytes().first() == Some(&b'.'))
+    }
+}
+
+impl Filter for GitignoreFilter {
+    fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool {
+        for ignore in &self.ignores {
+            let matched = match is_dir {
+                Some(is_dir) => ignore.matched(path, is_dir),
+                // if we don't know whether this is a directory then we are
+                // conservative and only apply directory patterns if they
+                // match the path as a file too
+                None => match ignore.matched(path, true) {
+                    Match::Ignore(glob) if glob.is_only_dir() => ignore.matched(path, false),
+                    matched => matched,
+                },
+            };
+            match matched {
+                Match::None => continue,
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        self.hidden && self.is_hidden(path)
+    }
+}
+
+/// Configures a [`GitignoreFilter`], created with [`GitignoreFilter::builder`].
+#[derive(Debug, Clone)]
+pub struct GitignoreFilterBuilder {
+    root: PathBuf,
+    hidden: bool,
+    ignore_files: bool,
+    global: bool,
+}
+
+impl GitignoreFilterBuilder {
+    /// Whether hidden files and directories (whose name starts with a `.`)
+    /// below the root are ignored. Enabled by default.
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Whether the `.gitignore`, `.ignore` and `.fdignore` files in the root
+    /// and its ancestors are respected. Enabled by default.
+    pub fn ignore_files(mut self, ignore_files: bool) -> Self {
+        self.ignore_files = ignore_files;
+        self
+    }
+
+    /// Whether the global gitignore (`core.excludesFile`) is respected.
+    /// Enabled by default.
+    pub fn global(mut self, global: bool) -> Self {
+        self.global = global;
+        self
+    }
+
+    /// Loads the ignore files. Fails if an ignore file can't be read, invalid
+    /// patterns are skipped (like git does) and only logged.
+    pub fn build(self) -> io::Result<GitignoreFilter> {
+        // event paths are canonicalized
+        let root = self.root.canonicalize()?;
+        let mut ignores = Vec::new();
+        if self.ignore_files {
+            for dir in root.ancestors() {
+                let mut builder = None;
+                for file in IGNORE_FILES {
+                    let path = dir.join(file);
+                    if !path.is_file() {
+                        continue;
+                    }
+                    let builder = builder.get_or_insert_with(|| GitignoreBuilder::new(dir));
+                    if let Some(err) = builder.add(&path) {
+                        if err.is_io() {
+                            return Err(err.into_io_error().unwrap());
+                        }
+                        log::warn!("invalid patterns in {path:?}: {err}");
+                    }
+                }
+                if let Some(builder) = builder {
+                    let ignore = builder
+                        .build()
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    ignores.push(ignore);
+                }
+            }
+        }
+        if self.global {
+            let (global, err) = Gitignore::global();
+            if let Some(err) = err {
+                log::warn!("invalid global gitignore: {err}");
+            }
+            ignores.push(global);
+        }
+        Ok(GitignoreFilter {
+            root,
+            hidden: self.hidden,
+            ignores,
+        })
+    }
+}
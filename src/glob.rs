@@ -0,0 +1,116 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::Filter;
+
+/// A [`Filter`] built from an ordered list of include and exclude globs.
+///
+/// Globs are matched against the path relative to the root, paths outside
+/// the root are never ignored. If multiple globs match a path the last one
+/// wins, a path that matches no glob is not ignored. Globs with a trailing
+/// `/` only match directories.
+#[derive(Debug, Clone)]
+pub struct GlobFilter {
+    root: PathBuf,
+    globs: GlobSet,
+    /// one entry for every glob in `globs`
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pattern {
+    exclude: bool,
+    dir_only: bool,
+}
+
+impl GlobFilter {
+    pub fn builder(root: &Path) -> GlobFilterBuilder {
+        GlobFilterBuilder {
+            root: root.to_owned(),
+            globs: Vec::new(),
+        }
+    }
+}
+
+impl Filter for GlobFilter {
+    fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        if rel.as_os_str().is_empty() {
+            return false;
+        }
+        let matches = self.globs.matches(rel);
+        for &i in matches.iter().rev() {
+            let pattern = self.patterns[i];
+            if pattern.dir_only {
+                match is_dir {
+                    Some(true) => (),
+                    Some(false) => continue,
+                    // if we don't know whether this is a directory then we
+                    // are conservative and rather don't ignore the path
+                    None if pattern.exclude => continue,
+                    None => (),
+                }
+            }
+            return pattern.exclude;
+        }
+        false
+    }
+}
+
+/// Configures a [`GlobFilter`], created with [`GlobFilter::builder`].
+#[derive(Debug, Clone)]
+pub struct GlobFilterBuilder {
+    root: PathBuf,
+    globs: Vec<(String, bool)>,
+}
+
+impl GlobFilterBuilder {
+    /// Don't ignore paths matching `glob`, even if an earlier glob excluded
+    /// them.
+    pub fn include(mut self, glob: &str) -> Self {
+        self.globs.push((glob.to_owned(), false));
+        self
+    }
+
+    /// Ignore paths matching `glob`, unless a later glob includes them.
+    pub fn exclude(mut self, glob: &str) -> Self {
+        self.globs.push((glob.to_owned(), true));
+        self
+    }
+
+    /// Compiles the globs. Fails if the root can't be canonicalized or a glob
+    /// is invalid.
+    pub fn build(self) -> io::Result<GlobFilter> {
+        // event paths are canonicalized
+        let root = self.root.canonicalize()?;
+        let mut globs = GlobSetBuilder::new();
+        let mut patterns = Vec::with_capacity(self.globs.len());
+        for (glob, exclude) in &self.globs {
+            let (glob, dir_only) = match glob.strip_suffix('/') {
+                Some(glob) => (glob, true),
+                None => (&**glob, false),
+            };
+            let glob = GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            globs.add(glob);
+            patterns.push(Pattern {
+                exclude: *exclude,
+                dir_only,
+            });
+        }
+        let globs = globs
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(GlobFilter {
+            root,
+            globs,
+            patterns,
+        })
+    }
+}
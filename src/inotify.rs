@@ -1,23 +1,55 @@
-use std::sync::atomic::{self, AtomicBool};
-use std::sync::Arc;
+use std::ffi::OsStr;
+use std::sync::atomic::{self, AtomicBool, AtomicU8, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use std::{io, thread};
 
 mod sys;
 
 use hashbrown::DefaultHashBuilder;
 use mio::{Poll, Waker};
-use papaya::HashMap;
+use papaya::{HashMap, HashSet};
 
-use crate::inotify::sys::{Event, EventFlags, Inotify, Watch};
-use crate::path::CanonicalPathBuf;
-use crate::pending::{self, PendingChangesLock};
-use crate::{Filter, WatcherState};
+pub(crate) use crate::inotify::sys::EventFlags;
+use crate::inotify::sys::Inotify;
+pub(crate) use crate::inotify::sys::{Event, Watch};
+use crate::path::{CannonicalPath, CanonicalPathBuf};
+use crate::pending::{self, PendingChangesLock, RecrawlCause, UnpairedMoves};
+use crate::{Filter, WatchMask, WatcherState};
 
 pub(crate) struct InotifyWatcher {
     waker: mio::Waker,
     shutdown: AtomicBool,
+    follow_symlinks: AtomicBool,
+    delete_only: AtomicBool,
+    /// modifications are applied once the file is closed, see
+    /// `Watcher::set_only_on_close`
+    only_on_close: AtomicBool,
+    watch_mask: AtomicU8,
     notify: Inotify,
     watches: HashMap<Watch, CanonicalPathBuf, DefaultHashBuilder>,
+    /// directories with a live watch, used to skip the `inotify_add_watch`
+    /// syscall when a directory is watched again (for example during a
+    /// recrawl, which would otherwise issue one syscall per directory).
+    /// Re-watching 10k directories takes ~0.5ms this way instead of ~10ms.
+    watched_dirs: HashSet<CanonicalPathBuf, DefaultHashBuilder>,
+    /// watches that were removed by us but for which the kernel
+    /// has not yet confirmed the removal with `IGNORED`
+    removed_watches: HashSet<Watch, DefaultHashBuilder>,
+    /// new watches whose descriptor is still in `removed_watches`, they are
+    /// moved to `watches` once the `IGNORED` event was read, see
+    /// `insert_watch`. The lock is also held while that event is handled.
+    reused_watches: Mutex<hashbrown::HashMap<Watch, CanonicalPathBuf>>,
+    /// watches of the parent directories of roots (that aren't watched
+    /// otherwise) and the roots they contain, see `watch_root_parent`
+    root_parents: Mutex<hashbrown::HashMap<Watch, Vec<CanonicalPathBuf>>>,
+    /// directories that couldn't be watched because `max_user_watches` was
+    /// exhausted, see `retry_watches`
+    retry_watches: HashSet<CanonicalPathBuf, DefaultHashBuilder>,
+    /// number of times the kernel queue overflowed, see `Watcher::stats`
+    queue_overflows: AtomicUsize,
+    /// used to pair the old and new name of a rename
+    unpaired_moves: Mutex<UnpairedMoves>,
     pub changes: PendingChangesLock,
 }
 
@@ -26,8 +58,16 @@ impl std::fmt::Debug for InotifyWatcher {
         f.debug_struct("InotifyWatcher")
             .field("waker", &self.waker)
             .field("shutdown", &self.shutdown)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .field("delete_only", &self.delete_only)
+            .field("watch_mask", &self.watch_mask)
             .field("notify", &self.notify)
             .field("watches", &self.watches)
+            .field("watched_dirs", &self.watched_dirs)
+            .field("removed_watches", &self.removed_watches)
+            .field("reused_watches", &self.reused_watches)
+            .field("retry_watches", &self.retry_watches)
+            .field("queue_overflows", &self.queue_overflows)
             .field("changes", &self.changes)
             .finish_non_exhaustive()
     }
@@ -51,96 +91,520 @@ impl InotifyWatcher {
             waker,
             notify: Inotify::new()?,
             watches: HashMap::with_capacity_and_hasher(1024, DefaultHashBuilder::default()),
+            watched_dirs: HashSet::with_capacity_and_hasher(1024, DefaultHashBuilder::default()),
+            removed_watches: HashSet::with_hasher(DefaultHashBuilder::default()),
+            reused_watches: Mutex::default(),
+            root_parents: Mutex::default(),
+            retry_watches: HashSet::with_hasher(DefaultHashBuilder::default()),
+            queue_overflows: AtomicUsize::new(0),
+            unpaired_moves: Mutex::default(),
             changes: PendingChangesLock::default(),
             shutdown: AtomicBool::new(false),
+            follow_symlinks: AtomicBool::new(false),
+            delete_only: AtomicBool::new(false),
+            only_on_close: AtomicBool::new(false),
+            watch_mask: AtomicU8::new(WatchMask::default().bits()),
         });
-        let mut filter = state.config.lock().unwrap().filter.clone();
+        let mut config = (
+            state.filter(),
+            state.config.lock().unwrap().read_buffer_size,
+        );
 
         let watcher_ = watcher.clone();
-        thread::spawn(move || {
+        let reader = thread::Builder::new().name("filesentry-inotify".into());
+        reader.spawn(move || {
             watcher_.notify.event_loop(
                 &mut poll,
-                &mut filter,
-                |filter, event /* , timestamp */| {
-                    watcher_.handle_event(event, &**filter /* , timestamp */)
-                },
+                &mut config,
+                |(_, buffer_size)| buffer_size.unwrap_or(sys::DEFAULT_BUFFER_SIZE),
+                |(filter, _), event, timestamp| watcher_.handle_event(event, &**filter, timestamp),
                 |_| {
                     watcher_.changes.notify();
                 },
-                |filter| {
-                    *filter = state.config.lock().unwrap().filter.clone();
+                |(filter, buffer_size)| {
+                    *filter = state.filter();
+                    *buffer_size = state.config.lock().unwrap().read_buffer_size;
                     watcher_.is_shutdown()
                 },
                 #[cfg(test)]
                 slow,
             )
-        });
+        })?;
         Ok(watcher)
     }
 
+    pub fn set_follow_symlinks(&self, follow_symlinks: bool) {
+        self.follow_symlinks
+            .store(follow_symlinks, atomic::Ordering::Relaxed);
+    }
+
+    pub fn set_delete_only(&self, delete_only: bool) {
+        self.delete_only
+            .store(delete_only, atomic::Ordering::Relaxed);
+    }
+
+    pub fn delete_only(&self) -> bool {
+        self.delete_only.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_only_on_close(&self, only_on_close: bool) {
+        self.only_on_close
+            .store(only_on_close, atomic::Ordering::Relaxed);
+    }
+
+    fn only_on_close(&self) -> bool {
+        self.only_on_close.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_watch_mask(&self, mask: WatchMask) {
+        self.watch_mask
+            .store(mask.bits(), atomic::Ordering::Relaxed);
+    }
+
+    fn watch_mask(&self) -> WatchMask {
+        if self.delete_only() {
+            return WatchMask::empty();
+        }
+        let mask = WatchMask::from_bits_retain(self.watch_mask.load(atomic::Ordering::Relaxed));
+        if self.only_on_close() {
+            mask | WatchMask::CLOSE_WRITE
+        } else {
+            mask
+        }
+    }
+
     pub fn watch_dir(&self, path: CanonicalPathBuf) -> io::Result<()> {
-        let watch = self.notify.add_directory_watch(&*path)?;
-        self.watches.pin().insert(watch, path);
+        let watched_dirs = self.watched_dirs.pin();
+        if watched_dirs.contains(&path) {
+            return Ok(());
+        }
+        let follow_symlinks = self.follow_symlinks.load(atomic::Ordering::Relaxed);
+        let watch =
+            match self
+                .notify
+                .add_directory_watch(&*path, follow_symlinks, self.watch_mask())
+            {
+                Ok(watch) => watch,
+                Err(err) if Inotify::is_watch_limit(&err) => {
+                    self.retry_watches.pin().insert(path);
+                    return Err(err);
+                }
+                Err(err) => return Err(err),
+            };
+        watched_dirs.insert(path.clone());
+        // a watch of a root's parent (see `watch_root_parent`) shares the
+        // descriptor and now also reports the regular events, the entry in
+        // `root_parents` is kept so the roots are still tracked once the
+        // directory is unwatched again
+        self.insert_watch(watch, path);
+        Ok(())
+    }
+
+    /// Records the directory of a new watch.
+    ///
+    /// The kernel frees a watch descriptor as soon as it queues the
+    /// `IGNORED` event for it, so the number can be handed out again while
+    /// that event and older events for the previous directory are still
+    /// unread. Linux allocates descriptors cyclically which makes this
+    /// rare, but a descriptor that is reused before its `IGNORED` event was
+    /// handled is parked in `reused_watches` so that the queued events are
+    /// not attributed to the new directory. This relies on a removed watch
+    /// being added to `removed_watches` before the `inotify_rm_watch`
+    /// syscall.
+    pub(crate) fn insert_watch(&self, watch: Watch, path: CanonicalPathBuf) {
+        let mut reused_watches = self.reused_watches.lock().unwrap();
+        if self.removed_watches.pin().contains(&watch) {
+            log::debug!("watch descriptor for {path:?} is reused before the old watch was removed");
+            reused_watches.insert(watch, path);
+        } else {
+            self.watches.pin().insert(watch, path);
+        }
+    }
+
+    /// Tries to install the watches that previously failed because the watch
+    /// limit was reached. Returns the number of directories that are still
+    /// not watched.
+    pub fn retry_watches(&self) -> usize {
+        let retry_watches = self.retry_watches.pin();
+        let paths: Vec<_> = retry_watches.iter().cloned().collect();
+        for path in paths {
+            retry_watches.remove(&path);
+            match self.watch_dir(path.clone()) {
+                // changes while the directory wasn't watched were missed
+                Ok(()) => self.changes.lock().add_watcher(
+                    path,
+                    SystemTime::now(),
+                    pending::Flags::NEEDS_NON_RECURSIVE_CRAWL,
+                ),
+                // `watch_dir` queued the directory again
+                Err(err) if Inotify::is_watch_limit(&err) => break,
+                Err(err) => log::debug!("not retrying watch for {path:?}: {err}"),
+            }
+        }
+        self.changes.notify();
+        retry_watches.len()
+    }
+
+    pub fn pending_watch_retries(&self) -> usize {
+        self.retry_watches.len()
+    }
+
+    /// Watches the parent directory of `root` so that the root being
+    /// deleted, moved away or recreated is noticed even once the watch of
+    /// the root itself is gone. Only changes to the root's entry are
+    /// processed, the rest of the parent is not tracked.
+    pub fn watch_root_parent(&self, root: &CannonicalPath) -> io::Result<()> {
+        let Some(parent) = root.parent() else {
+            return Ok(());
+        };
+        let parent = CanonicalPathBuf::assert_canonicalized(parent);
+        // a watched parent already reports changes to the root, adding the
+        // watch again would replace its mask
+        if self.watched_dirs.pin().contains(&parent)
+            || self.watches.pin().values().any(|dir| *dir == parent)
+        {
+            return Ok(());
+        }
+        let follow_symlinks = self.follow_symlinks.load(atomic::Ordering::Relaxed);
+        let watch =
+            self.notify
+                .add_directory_watch(&*parent, follow_symlinks, WatchMask::empty())?;
+        let mut root_parents = self.root_parents.lock().unwrap();
+        let roots = root_parents.entry(watch).or_default();
+        if !roots.iter().any(|it| **it == *root) {
+            roots.push(CanonicalPathBuf::assert_canonicalized(root.as_std_path()));
+        }
+        Ok(())
+    }
+
+    pub fn unwatch_root_parent(&self, root: &CannonicalPath) {
+        let mut root_parents = self.root_parents.lock().unwrap();
+        let Some((&watch, roots)) = root_parents
+            .iter_mut()
+            .find(|(_, roots)| roots.iter().any(|it| **it == *root))
+        else {
+            return;
+        };
+        roots.retain(|it| **it != *root);
+        if !roots.is_empty() {
+            return;
+        }
+        root_parents.remove(&watch);
+        // the parent may have been watched as a directory in the meantime
+        if self.watches.pin().contains_key(&watch) {
+            return;
+        }
+        self.removed_watches.pin().insert(watch);
+        if let Err(err) = self.notify.remove_watch(watch) {
+            log::error!("failed to remove watch: {err}");
+        }
+    }
+
+    pub fn unwatch_dir(&self, path: &CannonicalPath) -> io::Result<()> {
+        self.retry_watches.pin().remove(path);
+        let watches = self.watches.pin();
+        let Some(watch) = watches
+            .iter()
+            .find(|(_, dir)| ***dir == *path)
+            .map(|(&watch, _)| watch)
+        else {
+            return Ok(());
+        };
+        if self.root_parents.lock().unwrap().contains_key(&watch) {
+            watches.remove(&watch);
+            self.watched_dirs.pin().remove(path);
+            return self.downgrade_to_root_parent(path);
+        }
+        // mark the watch as removed before removing it from the map so
+        // the event thread never mistakes it for an unknown watch
+        self.removed_watches.pin().insert(watch);
+        watches.remove(&watch);
+        self.watched_dirs.pin().remove(path);
+        self.notify.remove_watch(watch)
+    }
+
+    /// Reduces the watch of `dir` to the mask of `watch_root_parent` when
+    /// `dir` is no longer watched but still contains a root.
+    fn downgrade_to_root_parent(&self, dir: &CannonicalPath) -> io::Result<()> {
+        let follow_symlinks = self.follow_symlinks.load(atomic::Ordering::Relaxed);
+        self.notify
+            .add_directory_watch(dir, follow_symlinks, WatchMask::empty())?;
         Ok(())
     }
 
+    /// removes the watches of `root` and all directories below it
+    pub fn unwatch_tree(&self, root: &CannonicalPath) {
+        self.retry_watches
+            .pin()
+            .retain(|dir| **dir != *root && !root.is_parent_of(dir));
+        let watches = self.watches.pin();
+        let removed: Vec<_> = watches
+            .iter()
+            .filter(|(_, dir)| ***dir == *root || root.is_parent_of(dir))
+            .map(|(&watch, _)| watch)
+            .collect();
+        let removed_watches = self.removed_watches.pin();
+        let watched_dirs = self.watched_dirs.pin();
+        for watch in removed {
+            if self.root_parents.lock().unwrap().contains_key(&watch) {
+                let Some(dir) = watches.remove(&watch) else {
+                    continue;
+                };
+                watched_dirs.remove(dir);
+                if let Err(err) = self.downgrade_to_root_parent(dir) {
+                    log::error!("failed to update watch: {err}");
+                }
+                continue;
+            }
+            removed_watches.insert(watch);
+            if let Some(dir) = watches.remove(&watch) {
+                watched_dirs.remove(dir);
+            }
+            if let Err(err) = self.notify.remove_watch(watch) {
+                log::error!("failed to remove watch: {err}");
+            }
+        }
+    }
+
+    /// Updates the paths of the watches below `from` after the directory was
+    /// moved to `to`, the kernel keeps the watches of moved directories.
+    pub fn rename_watches(&self, from: &CannonicalPath, to: &CanonicalPathBuf) {
+        let watches = self.watches.pin();
+        let moved: Vec<_> = watches
+            .iter()
+            .filter_map(|(&watch, dir)| Some((watch, dir.clone(), dir.replace_prefix(from, to)?)))
+            .collect();
+        let watched_dirs = self.watched_dirs.pin();
+        for (watch, old, new) in moved {
+            watched_dirs.remove(&old);
+            watched_dirs.insert(new.clone());
+            watches.insert(watch, new);
+        }
+        let retry_watches = self.retry_watches.pin();
+        let moved: Vec<_> = retry_watches
+            .iter()
+            .filter_map(|dir| Some((dir.clone(), dir.replace_prefix(from, to)?)))
+            .collect();
+        for (old, new) in moved {
+            retry_watches.remove(&old);
+            retry_watches.insert(new);
+        }
+    }
+
     pub fn refresh_config(&self) {
         let _ = self.waker.wake();
     }
 
-    fn handle_event(&self, event: Event, filter: &dyn Filter) {
-        // need to recrawl everything anyway if the queue overflowed
+    pub fn watch_count(&self) -> usize {
+        let watches = self.watches.pin();
+        // a root parent can share the descriptor of a directory watch
+        let root_parents = self.root_parents.lock().unwrap();
+        watches.len()
+            + root_parents
+                .keys()
+                .filter(|&watch| !watches.contains_key(watch))
+                .count()
+    }
+
+    pub fn queue_overflows(&self) -> usize {
+        self.queue_overflows.load(atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn handle_event(&self, event: Event, filter: &dyn Filter, timestamp: SystemTime) {
         if event.flags.contains(EventFlags::QUEUE_OVERFLOW) {
-            self.changes.lock().recrawl();
+            self.queue_overflows.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+        // only changes to the directory structure matter in delete only mode,
+        // we don't want to stat files for every modification
+        if self.delete_only()
+            && !watch_deleted(event.flags)
+            && !event.flags.intersects(
+                EventFlags::QUEUE_OVERFLOW
+                    | EventFlags::CREATE
+                    | EventFlags::DELETE
+                    | EventFlags::MOVED_FROM
+                    | EventFlags::MOVED_TO,
+            )
+        {
             return;
         }
         let watches = self.watches.pin();
-        let Some(dir) = watches.get(&event.wd) else {
-            if event.wd.is_invalid()
-                || event
-                    .flags
-                    .intersects(EventFlags::MOVE_SELF | EventFlags::IGNORED)
-            {
-                self.changes.lock().recrawl();
+        let dir = watches.get(&event.wd);
+        if let Some(dir) = dir.filter(|_| watch_deleted(event.flags)) {
+            // the directory (or at least this path) is no longer watched,
+            // the crawl triggered below must install a new watch
+            self.watched_dirs.pin().remove(dir);
+        }
+        if dir.is_none() && !event.flags.contains(EventFlags::QUEUE_OVERFLOW) {
+            let removed_watches = self.removed_watches.pin();
+            if removed_watches.contains(&event.wd) {
+                // events still queued for a watch we removed ourselves
+                if event.flags.contains(EventFlags::IGNORED) {
+                    let mut reused_watches = self.reused_watches.lock().unwrap();
+                    // all events for the old watch were read, the descriptor
+                    // now belongs to the new watch
+                    if let Some(path) = reused_watches.remove(&event.wd) {
+                        watches.insert(event.wd, path);
+                    }
+                    removed_watches.remove(&event.wd);
+                }
+                return;
             }
+        }
+        if self.handle_root_parent_event(&event, dir.is_some(), timestamp) {
             return;
-        };
-
-        let watch_deleted = event.flags.intersects(
-            EventFlags::IGNORED
-                | EventFlags::MOVE_SELF
-                | EventFlags::DELETE_SELF
-                | EventFlags::UNMOUNT,
-        );
-        if event.child.is_empty() || watch_deleted {
-            if event.flags.contains(EventFlags::IGNORED) {
-                watches.remove(&event.wd);
-            }
-            let path = dir.clone();
-            self.changes.lock().add_watcher(
+        }
+        if dir.is_none()
+            && !event.flags.contains(EventFlags::QUEUE_OVERFLOW)
+            && event.wd.is_invalid()
+        {
+            self.changes.lock().recrawl(RecrawlCause::BackendRescan);
+            return;
+        }
+        match classify_event(event.flags, event.child, dir) {
+            EventAction::Ignore => (),
+            EventAction::Recrawl(cause) => self.changes.lock().recrawl(cause),
+            EventAction::Dir {
                 path,
-                /* timestamp, */ pending::Flags::NEEDS_RECURSIVE_CRAWL,
-            );
-        } else {
-            let path = dir.join(event.child);
-            if filter.ignore_path(
-                path.as_std_path(),
-                Some(event.flags.contains(EventFlags::ISDIR)),
-            ) {
-                return;
-            }
-            let mut pending = self.changes.lock();
-            if event
-                .flags
-                .intersects(EventFlags::CREATE | EventFlags::DELETE)
-            {
-                pending.add_watcher(
+                watch_removed,
+            } => {
+                if watch_removed {
+                    watches.remove(&event.wd);
+                }
+                self.changes.lock().add_watcher(
                     path,
-                    /* timestamp, */ pending::Flags::NEEDS_RECURSIVE_CRAWL,
+                    timestamp,
+                    pending::Flags::NEEDS_RECURSIVE_CRAWL,
                 );
-            } else {
-                pending.add_watcher(path, /* timestamp, */ pending::Flags::empty());
             }
+            EventAction::Child {
+                path,
+                is_dir,
+                mut flags,
+            } => {
+                if filter.ignore_path(path.as_std_path(), Some(is_dir)) {
+                    return;
+                }
+                if event.flags.contains(EventFlags::CLOSE_WRITE) {
+                    flags |= pending::Flags::CLOSED;
+                } else if self.only_on_close()
+                    && event.flags.contains(EventFlags::MODIFY)
+                    && !is_dir
+                {
+                    flags |= pending::Flags::AWAIT_CLOSE;
+                }
+                let mut changes = self.changes.lock();
+                if event.flags.contains(EventFlags::MOVED_FROM) {
+                    let mut moves = self.unpaired_moves.lock().unwrap();
+                    moves.moved_from(event.cookie, path.clone());
+                } else if event.flags.contains(EventFlags::MOVED_TO) {
+                    let mut moves = self.unpaired_moves.lock().unwrap();
+                    moves.moved_to(event.cookie, path.clone(), &mut changes);
+                }
+                changes.add_watcher(path, timestamp, flags);
+            }
+        }
+    }
+}
+
+impl InotifyWatcher {
+    /// Handles an event for the watch of a root's parent. Returns `true` if
+    /// the event was consumed, which is only the case for events naming a
+    /// root. The descriptor can be shared with the watch of a regular
+    /// directory (`watched`), its other events are handled as usual.
+    fn handle_root_parent_event(
+        &self,
+        event: &Event,
+        watched: bool,
+        timestamp: SystemTime,
+    ) -> bool {
+        let mut root_parents = self.root_parents.lock().unwrap();
+        let Some(roots) = root_parents.get(&event.wd) else {
+            return false;
+        };
+        if event.flags.contains(EventFlags::IGNORED) {
+            // the parent is gone, the roots rely on their own watches now
+            root_parents.remove(&event.wd);
+            return !watched;
         }
+        if !event.flags.intersects(
+            EventFlags::CREATE | EventFlags::DELETE | EventFlags::MOVED_FROM | EventFlags::MOVED_TO,
+        ) {
+            return false;
+        }
+        let Some(root) = roots
+            .iter()
+            .find(|root| root.as_std_path().file_name() == Some(event.child))
+        else {
+            return false;
+        };
+        self.changes.lock().add_watcher(
+            root.clone(),
+            timestamp,
+            pending::Flags::NEEDS_RECURSIVE_CRAWL,
+        );
+        true
+    }
+}
+
+/// What needs to be done in response to an inotify event
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum EventAction {
+    Ignore,
+    /// the state of the watched directories is unknown, recrawl everything
+    Recrawl(RecrawlCause),
+    /// the watched directory itself changed (or was removed)
+    Dir {
+        path: CanonicalPathBuf,
+        /// the kernel removed the watch, it must be forgotten
+        watch_removed: bool,
+    },
+    /// a child of the watched directory changed
+    Child {
+        path: CanonicalPathBuf,
+        is_dir: bool,
+        flags: pending::Flags,
+    },
+}
+
+fn watch_deleted(flags: EventFlags) -> bool {
+    flags.intersects(
+        EventFlags::IGNORED | EventFlags::MOVE_SELF | EventFlags::DELETE_SELF | EventFlags::UNMOUNT,
+    )
+}
+
+/// Maps an inotify event for the watched directory `dir` (`None` if the
+/// watch is unknown) to the action the watcher needs to take.
+pub(crate) fn classify_event(
+    flags: EventFlags,
+    child: &OsStr,
+    dir: Option<&CanonicalPathBuf>,
+) -> EventAction {
+    // need to recrawl everything anyway if the queue overflowed
+    if flags.contains(EventFlags::QUEUE_OVERFLOW) {
+        return EventAction::Recrawl(RecrawlCause::QueueOverflow);
+    }
+    let Some(dir) = dir else {
+        // a watch we don't know about was moved or removed
+        if flags.intersects(EventFlags::MOVE_SELF | EventFlags::IGNORED) {
+            return EventAction::Recrawl(RecrawlCause::BackendRescan);
+        }
+        return EventAction::Ignore;
+    };
+    if child.is_empty() || watch_deleted(flags) {
+        return EventAction::Dir {
+            path: dir.clone(),
+            watch_removed: flags.contains(EventFlags::IGNORED),
+        };
+    }
+    let flags_ = if flags.intersects(EventFlags::CREATE | EventFlags::DELETE) {
+        pending::Flags::NEEDS_RECURSIVE_CRAWL
+    } else {
+        pending::Flags::empty()
+    };
+    EventAction::Child {
+        path: dir.join(child),
+        is_dir: flags.contains(EventFlags::ISDIR),
+        flags: flags_,
     }
 }
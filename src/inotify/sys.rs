@@ -1,6 +1,7 @@
 use std::ffi::{c_int, OsStr};
-use std::mem::{align_of, size_of, MaybeUninit};
+use std::mem::{align_of, size_of, size_of_val, MaybeUninit};
 use std::os::fd::AsRawFd;
+use std::time::SystemTime;
 use std::{io, slice};
 
 use mio::unix::SourceFd;
@@ -10,14 +11,21 @@ pub use rustix::fs::inotify::ReadFlags as EventFlags;
 use rustix::fs::inotify::{self, CreateFlags, WatchFlags};
 use rustix::io::Errno;
 
+use crate::WatchMask;
+
 const INOTIFY: mio::Token = mio::Token(0);
 pub const MESSAGE: mio::Token = mio::Token(1);
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[repr(transparent)]
-pub(super) struct Watch(c_int);
+pub(crate) struct Watch(c_int);
 
 impl Watch {
+    #[cfg(test)]
+    pub fn from_raw(wd: c_int) -> Watch {
+        Watch(wd)
+    }
+
     pub fn is_invalid(&self) -> bool {
         self.0 < 0
     }
@@ -27,6 +35,8 @@ pub struct Event<'a> {
     pub wd: Watch,
     pub child: &'a OsStr,
     pub flags: EventFlags,
+    /// links the `MOVED_FROM` and `MOVED_TO` events of a rename
+    pub cookie: u32,
 }
 
 #[derive(Debug)]
@@ -34,30 +44,54 @@ pub(super) struct Inotify {
     fd: OwnedFd,
 }
 
+#[derive(Debug)]
+struct WatchLimitReached;
+
+impl std::fmt::Display for WatchLimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("exhaused inotify max_user_watches, try increasing the setting or adding stricter glob filter")
+    }
+}
+
+impl std::error::Error for WatchLimitReached {}
+
 impl Inotify {
     pub(super) fn new() -> io::Result<Inotify> {
         let fd = inotify::init(CreateFlags::CLOEXEC | CreateFlags::NONBLOCK)?;
         Ok(Inotify { fd })
     }
 
-    pub(super) fn add_directory_watch(&self, path: impl rustix::path::Arg) -> io::Result<Watch> {
-        let watch = inotify::add_watch(
-            self.as_fd(),
-            path,
-            WatchFlags::ATTRIB
-                | WatchFlags::CREATE
-                | WatchFlags::DELETE
-                | WatchFlags::DELETE_SELF
-                | WatchFlags::MODIFY
-                | WatchFlags::MOVE_SELF
-                | WatchFlags::MOVE
-                | WatchFlags::DONT_FOLLOW
-                | WatchFlags::EXCL_UNLINK
-                | WatchFlags::ONLYDIR,
-        )
-        .map_err(|err| {
+    pub(super) fn add_directory_watch(
+        &self,
+        path: impl rustix::path::Arg,
+        follow_symlinks: bool,
+        mask: WatchMask,
+    ) -> io::Result<Watch> {
+        // CREATE is always required (even in delete only mode), otherwise
+        // files created after the initial crawl are never tracked (and their
+        // deletion is missed)
+        let mut flags = WatchFlags::CREATE
+            | WatchFlags::DELETE
+            | WatchFlags::DELETE_SELF
+            | WatchFlags::MOVE_SELF
+            | WatchFlags::MOVE
+            | WatchFlags::EXCL_UNLINK
+            | WatchFlags::ONLYDIR;
+        if mask.contains(WatchMask::ATTRIB) {
+            flags |= WatchFlags::ATTRIB;
+        }
+        if mask.contains(WatchMask::MODIFY) {
+            flags |= WatchFlags::MODIFY;
+        }
+        if mask.contains(WatchMask::CLOSE_WRITE) {
+            flags |= WatchFlags::CLOSE_WRITE;
+        }
+        if !follow_symlinks {
+            flags |= WatchFlags::DONT_FOLLOW;
+        }
+        let watch = inotify::add_watch(self.as_fd(), path, flags).map_err(|err| {
             if err == Errno::NOSPC {
-                io::Error::other("exhaused inotify max_user_watches, try increasing the setting or adding stricter glob filter")
+                io::Error::other(WatchLimitReached)
             } else {
                 err.into()
             }
@@ -65,28 +99,34 @@ impl Inotify {
         Ok(Watch(watch))
     }
 
-    // pub(super) fn remove_watch(&self, watch: Watch) -> io::Result<()> {
-    //     inotify::remove_watch(self.as_fd(), watch.0)?;
-    //     Ok(())
-    // }
+    /// whether `err` was returned by `add_directory_watch` because
+    /// `max_user_watches` was exhausted
+    pub(super) fn is_watch_limit(err: &io::Error) -> bool {
+        err.get_ref()
+            .is_some_and(|err| err.is::<WatchLimitReached>())
+    }
+
+    pub(super) fn remove_watch(&self, watch: Watch) -> io::Result<()> {
+        inotify::remove_watch(self.as_fd(), watch.0)?;
+        Ok(())
+    }
 
+    /// `buffer_size` returns the size (in bytes) of the buffer events are
+    /// read into, it's checked again after every message.
+    #[cfg_attr(test, expect(clippy::too_many_arguments))]
     pub(super) fn event_loop<T>(
         &self,
         poll: &mut Poll,
         state: &mut T,
-        mut handle_event: impl FnMut(&mut T, Event<'_> /* , SystemTime */),
+        buffer_size: impl Fn(&T) -> usize,
+        mut handle_event: impl FnMut(&mut T, Event<'_>, SystemTime),
         mut event_stream_done: impl FnMut(&mut T),
         mut handle_message: impl FnMut(&mut T) -> bool,
         #[cfg(test)] slow: bool,
     ) -> io::Result<()> {
-        let mut buf = vec![0u32; BUFFERSIZE].into_boxed_slice();
-        let buf_bytes = unsafe {
-            slice::from_raw_parts_mut(
-                buf.as_mut_ptr().cast::<MaybeUninit<u8>>(),
-                buf.len() * size_of::<u32>(),
-            )
-        };
-        let mut reader = inotify::Reader::new(&self.fd, buf_bytes);
+        let mut buf = alloc_buffer(buffer_size(state));
+        let mut buf_len = buf.len();
+        let mut reader = inotify::Reader::new(&self.fd, as_bytes(&mut buf));
         let raw_fd = self.fd.as_raw_fd();
         let mut fd = SourceFd(&raw_fd);
         poll.registry()
@@ -103,7 +143,7 @@ impl Inotify {
                 Ok(()) => {}
             }
 
-            // let time = SystemTime::now();
+            let time = SystemTime::now();
             let mut message = false;
             let mut inotify = false;
             for event in &events {
@@ -114,8 +154,17 @@ impl Inotify {
                 }
             }
             events.clear();
-            if message && handle_message(state) {
-                break;
+            if message {
+                if handle_message(state) {
+                    break;
+                }
+                // the buffer is always drained completely so it can be replaced
+                let size = buffer_size(state);
+                if buffer_len(size) != buf_len {
+                    buf = alloc_buffer(size);
+                    buf_len = buf.len();
+                    reader = inotify::Reader::new(&self.fd, as_bytes(&mut buf));
+                }
             }
             if inotify {
                 // to reliably reproduce queue overflow we need to read events slowly
@@ -141,7 +190,9 @@ impl Inotify {
                                 OsStr::from_encoded_bytes_unchecked(src.to_bytes())
                             }),
                             flags: event.events(),
-                        }, /* , time */
+                            cookie: event.cookie(),
+                        },
+                        time,
                     );
                 }
                 event_stream_done(state)
@@ -158,4 +209,23 @@ impl AsFd for Inotify {
 }
 
 const ALIGNED_MAX_EVENT_SIZE: usize = (4 * size_of::<u32>() + 256) / align_of::<u32>();
-const BUFFERSIZE: usize = 16 * 1024 * ALIGNED_MAX_EVENT_SIZE;
+/// the default size of the read buffer in bytes, room for 16k events with
+/// the longest possible name
+pub(super) const DEFAULT_BUFFER_SIZE: usize = 16 * 1024 * ALIGNED_MAX_EVENT_SIZE * size_of::<u32>();
+
+/// the number of `u32`s needed for a buffer of `size` bytes, the buffer must
+/// fit atleast one event
+fn buffer_len(size: usize) -> usize {
+    size.div_ceil(size_of::<u32>()).max(ALIGNED_MAX_EVENT_SIZE)
+}
+
+fn alloc_buffer(size: usize) -> Box<[u32]> {
+    vec![0u32; buffer_len(size)].into_boxed_slice()
+}
+
+fn as_bytes(buf: &mut [u32]) -> &mut [MaybeUninit<u8>] {
+    // safety: u32 has no padding and a stricter alignment than u8
+    unsafe {
+        slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<u8>>(), size_of_val(buf))
+    }
+}
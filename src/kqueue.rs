@@ -0,0 +1,447 @@
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{self, AtomicBool, AtomicU8};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use std::{fs, io, thread};
+
+use hashbrown::{DefaultHashBuilder, HashMap};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Waker};
+use rustix::buffer::spare_capacity;
+use rustix::event::kqueue::{self, Event, EventFilter, EventFlags, VnodeEvents};
+use rustix::fs::{Mode, OFlags};
+use rustix::io::Errno;
+
+use crate::path::{CannonicalPath, CanonicalPathBuf};
+use crate::pending::{self, PendingChangesLock, RecrawlCause};
+use crate::{Filter, WatchMask, WatcherState};
+
+const KQUEUE: mio::Token = mio::Token(0);
+const MESSAGE: mio::Token = mio::Token(1);
+const BUFFERSIZE: usize = 1024;
+
+#[derive(Debug)]
+enum WatchKind {
+    /// the descriptors of the watched files in the directory
+    Dir(Vec<RawFd>),
+    /// the descriptor of the parent directory
+    File(RawFd),
+}
+
+#[derive(Debug)]
+struct Watch {
+    /// kqueue reports events for file descriptors so every watched
+    /// directory and file must be kept open
+    _fd: OwnedFd,
+    path: CanonicalPathBuf,
+    kind: WatchKind,
+}
+
+#[derive(Debug, Default)]
+struct Watches {
+    by_fd: HashMap<RawFd, Watch, DefaultHashBuilder>,
+    by_path: HashMap<CanonicalPathBuf, RawFd, DefaultHashBuilder>,
+}
+
+impl Watches {
+    fn insert(&mut self, fd: OwnedFd, path: CanonicalPathBuf, kind: WatchKind) {
+        let raw_fd = fd.as_raw_fd();
+        if let WatchKind::File(dir) = kind {
+            if let Some(Watch {
+                kind: WatchKind::Dir(files),
+                ..
+            }) = self.by_fd.get_mut(&dir)
+            {
+                files.push(raw_fd);
+            }
+        }
+        self.by_path.insert(path.clone(), raw_fd);
+        self.by_fd.insert(
+            raw_fd,
+            Watch {
+                _fd: fd,
+                path,
+                kind,
+            },
+        );
+    }
+
+    /// removes the watch of `fd`, for directories the watches of the files
+    /// inside are removed too
+    fn remove(&mut self, fd: RawFd) -> Option<CanonicalPathBuf> {
+        // closing the descriptor also removes it from the kqueue
+        let watch = self.by_fd.remove(&fd)?;
+        self.by_path.remove(&watch.path);
+        match watch.kind {
+            WatchKind::Dir(files) => {
+                for file in files {
+                    if let Some(file) = self.by_fd.remove(&file) {
+                        self.by_path.remove(&file.path);
+                    }
+                }
+            }
+            WatchKind::File(dir) => {
+                // the descriptor may be reused once it is closed
+                if let Some(Watch {
+                    kind: WatchKind::Dir(files),
+                    ..
+                }) = self.by_fd.get_mut(&dir)
+                {
+                    files.retain(|&file| file != fd);
+                }
+            }
+        }
+        Some(watch.path)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct KqueueWatcher {
+    waker: mio::Waker,
+    shutdown: AtomicBool,
+    follow_symlinks: AtomicBool,
+    delete_only: AtomicBool,
+    watch_mask: AtomicU8,
+    /// set once the descriptor budget was exhausted so that we only
+    /// recrawl once instead of every time the crawl tries to add a watch
+    exceeded_budget: AtomicBool,
+    kqueue: OwnedFd,
+    watches: Mutex<Watches>,
+    state: Arc<WatcherState>,
+    pub changes: PendingChangesLock,
+}
+
+impl KqueueWatcher {
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, atomic::Ordering::Relaxed);
+        let _ = self.waker.wake();
+        self.changes.notify();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn new(#[cfg(test)] _slow: bool, state: Arc<WatcherState>) -> io::Result<Arc<Self>> {
+        let mut poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), MESSAGE)?;
+        let watcher = Arc::new(Self {
+            waker,
+            shutdown: AtomicBool::new(false),
+            follow_symlinks: AtomicBool::new(false),
+            delete_only: AtomicBool::new(false),
+            watch_mask: AtomicU8::new(WatchMask::default().bits()),
+            exceeded_budget: AtomicBool::new(false),
+            kqueue: kqueue::kqueue()?,
+            watches: Mutex::new(Watches::default()),
+            state,
+            changes: PendingChangesLock::default(),
+        });
+        let watcher_ = watcher.clone();
+        let reader = thread::Builder::new().name("filesentry-kqueue".into());
+        reader.spawn(move || {
+            if let Err(err) = watcher_.event_loop(&mut poll) {
+                log::error!("kqueue event loop failed: {err}");
+            }
+        })?;
+        Ok(watcher)
+    }
+
+    pub fn set_follow_symlinks(&self, follow_symlinks: bool) {
+        self.follow_symlinks
+            .store(follow_symlinks, atomic::Ordering::Relaxed);
+    }
+
+    pub fn set_delete_only(&self, delete_only: bool) {
+        self.delete_only
+            .store(delete_only, atomic::Ordering::Relaxed);
+    }
+
+    pub fn delete_only(&self) -> bool {
+        self.delete_only.load(atomic::Ordering::Relaxed)
+    }
+
+    /// only inotify reports when a file is closed
+    pub fn set_only_on_close(&self, _only_on_close: bool) {}
+
+    pub fn set_watch_mask(&self, mask: WatchMask) {
+        self.watch_mask
+            .store(mask.bits(), atomic::Ordering::Relaxed);
+    }
+
+    fn watch_mask(&self) -> WatchMask {
+        if self.delete_only() {
+            return WatchMask::empty();
+        }
+        WatchMask::from_bits_retain(self.watch_mask.load(atomic::Ordering::Relaxed))
+    }
+
+    /// the watched directory can't be watched because we ran out of
+    /// descriptors, fall back to a recrawl
+    fn exceeded_budget(&self, path: &CannonicalPath) {
+        if self.exceeded_budget.swap(true, atomic::Ordering::Relaxed) {
+            return;
+        }
+        log::warn!("exhausted the descriptor budget while watching {path:?}, changes in some directories will be missed. Try increasing the budget or adding stricter glob filters");
+        self.changes.lock().recrawl(RecrawlCause::WatchLimit);
+        self.changes.notify();
+    }
+
+    pub fn watch_dir(&self, path: CanonicalPathBuf) -> io::Result<()> {
+        let max_watches = self.state.config.lock().unwrap().max_watches;
+        let mut watches = self.watches.lock().unwrap();
+        if watches.by_path.contains_key(&path) {
+            return Ok(());
+        }
+        let mut flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC;
+        if !self.follow_symlinks.load(atomic::Ordering::Relaxed) {
+            flags |= OFlags::NOFOLLOW;
+        }
+        let mut events = VnodeEvents::DELETE
+            | VnodeEvents::RENAME
+            | VnodeEvents::REVOKE
+            // entries were added or removed
+            | VnodeEvents::WRITE;
+        if self.watch_mask().contains(WatchMask::ATTRIB) {
+            events |= VnodeEvents::ATTRIBUTES;
+        }
+        let Some(fd) = self.add_watch(&mut watches, &path, flags, events, max_watches)? else {
+            return Ok(());
+        };
+        watches.insert(fd, path.clone(), WatchKind::Dir(Vec::new()));
+        self.watch_files(&mut watches, &path, max_watches);
+        Ok(())
+    }
+
+    /// Opens `path` and registers it with the kqueue, returns `None` if the
+    /// descriptor budget is exhausted.
+    fn add_watch(
+        &self,
+        watches: &mut Watches,
+        path: &CannonicalPath,
+        flags: OFlags,
+        events: VnodeEvents,
+        max_watches: Option<usize>,
+    ) -> io::Result<Option<OwnedFd>> {
+        if max_watches.is_some_and(|max| watches.by_fd.len() >= max) {
+            self.exceeded_budget(path);
+            return Ok(None);
+        }
+        let fd = match rustix::fs::open(path, flags, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(Errno::MFILE | Errno::NFILE) => {
+                self.exceeded_budget(path);
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let event = Event::new(
+            EventFilter::Vnode {
+                vnode: fd.as_raw_fd(),
+                flags: events,
+            },
+            EventFlags::ADD | EventFlags::CLEAR,
+            std::ptr::null_mut(),
+        );
+        // safety: the descriptor is kept open until the watch is removed
+        unsafe { kqueue::kevent(&self.kqueue, &[event], &mut [] as &mut [Event], None)? };
+        Ok(Some(fd))
+    }
+
+    /// Writes to a file don't change its directory, so unlike inotify
+    /// kqueue needs a descriptor for every file to report modifications.
+    /// Files that are already watched are skipped so this is also used to
+    /// pick up new files when the directory changes.
+    fn watch_files(&self, watches: &mut Watches, dir: &CannonicalPath, max_watches: Option<usize>) {
+        let mask = self.watch_mask();
+        let mut events = VnodeEvents::empty();
+        if mask.intersects(WatchMask::MODIFY | WatchMask::CLOSE_WRITE) {
+            events |= VnodeEvents::WRITE | VnodeEvents::EXTEND;
+        }
+        if mask.contains(WatchMask::ATTRIB) {
+            events |= VnodeEvents::ATTRIBUTES;
+        }
+        // deletions and renames are already reported by the directory
+        if events.is_empty() {
+            return;
+        }
+        events |= VnodeEvents::DELETE | VnodeEvents::RENAME | VnodeEvents::REVOKE;
+        let Some(&dir_fd) = watches.by_path.get(dir) else {
+            return;
+        };
+        let Ok(entries) = fs::read_dir(dir.as_std_path()) else {
+            return;
+        };
+        let filter = self.state.filter();
+        // NONBLOCK so that a file replaced by a fifo can't block the open
+        let flags = OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::NONBLOCK | OFlags::CLOEXEC;
+        for entry in entries.flatten() {
+            if !entry.file_type().is_ok_and(|ty| ty.is_file()) {
+                continue;
+            }
+            let path = dir.join(&entry.file_name());
+            if watches.by_path.contains_key(&path)
+                || filter.ignore_path(path.as_std_path(), Some(false))
+            {
+                continue;
+            }
+            match self.add_watch(watches, &path, flags, events, max_watches) {
+                Ok(Some(fd)) => watches.insert(fd, path, WatchKind::File(dir_fd)),
+                Ok(None) => return,
+                // the file may have been removed since it was listed
+                Err(err) => log::debug!("failed to watch {path:?}: {err}"),
+            }
+        }
+    }
+
+    pub fn unwatch_dir(&self, path: &CannonicalPath) -> io::Result<()> {
+        let mut watches = self.watches.lock().unwrap();
+        if let Some(fd) = watches.by_path.get(path).copied() {
+            watches.remove(fd);
+            self.exceeded_budget.store(false, atomic::Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// removes the watches of `root` and all directories below it
+    pub fn unwatch_tree(&self, root: &CannonicalPath) {
+        let mut watches = self.watches.lock().unwrap();
+        let removed: Vec<_> = watches
+            .by_path
+            .iter()
+            .filter(|(dir, _)| ***dir == *root || root.is_parent_of(dir))
+            .map(|(_, &fd)| fd)
+            .collect();
+        for fd in removed {
+            watches.remove(fd);
+        }
+        self.exceeded_budget.store(false, atomic::Ordering::Relaxed);
+    }
+
+    pub fn refresh_config(&self) {
+        let _ = self.waker.wake();
+    }
+
+    /// the number of open descriptors, including watched files
+    pub fn watch_count(&self) -> usize {
+        self.watches.lock().unwrap().by_fd.len()
+    }
+
+    /// kqueue has no event queue that could overflow
+    pub fn queue_overflows(&self) -> usize {
+        0
+    }
+
+    /// renames are only paired by inotify and windows
+    pub fn rename_watches(&self, _from: &CannonicalPath, _to: &CanonicalPathBuf) {}
+
+    /// only inotify has a limit on the number of watches that is worth retrying
+    pub fn retry_watches(&self) -> usize {
+        0
+    }
+
+    pub fn pending_watch_retries(&self) -> usize {
+        0
+    }
+
+    /// the removal of a root is noticed through the watch of the root itself
+    pub fn watch_root_parent(&self, _root: &CannonicalPath) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn unwatch_root_parent(&self, _root: &CannonicalPath) {}
+
+    fn event_loop(&self, poll: &mut Poll) -> io::Result<()> {
+        let mut filter = self.state.filter();
+        let raw_fd = self.kqueue.as_raw_fd();
+        poll.registry()
+            .register(&mut SourceFd(&raw_fd), KQUEUE, Interest::READABLE)?;
+        let mut events = Events::with_capacity(16);
+        let mut buf = Vec::with_capacity(BUFFERSIZE);
+        loop {
+            match poll.poll(&mut events, None) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+                Ok(()) => {}
+            }
+            let mut message = false;
+            let mut kqueue = false;
+            for event in &events {
+                match event.token() {
+                    KQUEUE => kqueue = true,
+                    MESSAGE => message = true,
+                    _ => unreachable!(),
+                }
+            }
+            events.clear();
+            if message {
+                filter = self.state.filter();
+                if self.is_shutdown() {
+                    break;
+                }
+            }
+            if kqueue {
+                let timestamp = SystemTime::now();
+                loop {
+                    buf.clear();
+                    // safety: we only register descriptors we keep open
+                    let read = unsafe {
+                        kqueue::kevent(
+                            &self.kqueue,
+                            &[],
+                            spare_capacity(&mut buf),
+                            Some(std::time::Duration::ZERO),
+                        )?
+                    };
+                    for event in &buf {
+                        self.handle_event(event, &*filter, timestamp);
+                    }
+                    if read < BUFFERSIZE {
+                        break;
+                    }
+                }
+                self.changes.notify();
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_event(&self, event: &Event, filter: &dyn Filter, timestamp: SystemTime) {
+        let EventFilter::Vnode { vnode, flags } = event.filter() else {
+            return;
+        };
+        let mut watches = self.watches.lock().unwrap();
+        let Some(watch) = watches.by_fd.get(&vnode) else {
+            // the watch was already removed
+            return;
+        };
+        let path = watch.path.clone();
+        let is_dir = matches!(watch.kind, WatchKind::Dir(_));
+        if filter.ignore_path(path.as_std_path(), Some(is_dir)) {
+            return;
+        }
+        // kqueue only reports changes for the directory itself, the worker
+        // has to stat its children to find out what changed
+        let flags =
+            if flags.intersects(VnodeEvents::DELETE | VnodeEvents::RENAME | VnodeEvents::REVOKE) {
+                watches.remove(vnode);
+                self.exceeded_budget.store(false, atomic::Ordering::Relaxed);
+                if is_dir {
+                    pending::Flags::NEEDS_RECURSIVE_CRAWL
+                } else {
+                    pending::Flags::empty()
+                }
+            } else if is_dir && flags.contains(VnodeEvents::WRITE) {
+                // the config lock must not be acquired while holding the watches
+                drop(watches);
+                let max_watches = self.state.config.lock().unwrap().max_watches;
+                watches = self.watches.lock().unwrap();
+                self.watch_files(&mut watches, &path, max_watches);
+                pending::Flags::NEEDS_NON_RECURSIVE_CRAWL
+            } else {
+                pending::Flags::empty()
+            };
+        drop(watches);
+        self.changes.lock().add_watcher(path, timestamp, flags);
+    }
+}
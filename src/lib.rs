@@ -1,40 +1,253 @@
 use std::io;
-use std::path::Path;
-#[cfg(test)]
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::{self, AtomicBool};
-use std::sync::{Arc, Mutex, Weak};
+use std::mem::{replace, take};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::sync::{mpsc, Arc, Mutex, OnceLock, Weak};
+use std::thread::{self, JoinHandle, ThreadId};
 use std::time::Duration;
 
-use crate::config::Config;
+use arc_swap::ArcSwap;
+
+use crate::backend::Notify;
+use crate::config::{CanonicalizeHook, Config, RootFilter};
 use crate::events::EventDebouncer;
-pub use crate::events::{Event, EventType, Events};
-use crate::inotify::InotifyWatcher;
+pub use crate::events::{
+    CoalescePolicy, DefaultCoalescePolicy, Event, EventType, Events, FileMeta, MergeAction,
+};
+#[cfg(target_os = "macos")]
+use crate::fsevents::FsEventsWatcher as OsWatcher;
+#[cfg(feature = "gitignore")]
+pub use crate::gitignore::{GitignoreFilter, GitignoreFilterBuilder};
+#[cfg(feature = "glob")]
+pub use crate::glob::{GlobFilter, GlobFilterBuilder};
+#[cfg(target_os = "linux")]
+use crate::inotify::InotifyWatcher as OsWatcher;
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+use crate::kqueue::KqueueWatcher as OsWatcher;
+pub use crate::metadata::Metadata;
 pub use crate::path::{CannonicalPath, CanonicalPathBuf};
-use crate::worker::Worker;
-pub use config::Filter;
+pub use crate::pending::{PendingKind, RecrawlCause};
+use crate::poll::PollWatcher;
+pub use crate::tree::estimate_watches;
+#[cfg(windows)]
+use crate::windows::WindowsWatcher as OsWatcher;
+use crate::worker::{RootInfo, Worker};
+pub use config::{
+    AllFilter, AnyFilter, Canonicalize, DefaultFilter, Filter, FilterExt, WatchError, WatchMask,
+};
 
+mod backend;
 mod config;
 mod events;
+#[cfg(target_os = "macos")]
+mod fsevents;
+#[cfg(feature = "gitignore")]
+mod gitignore;
+#[cfg(feature = "glob")]
+mod glob;
+#[cfg(target_os = "linux")]
 mod inotify;
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+mod kqueue;
 mod metadata;
 mod path;
 mod pending;
+mod poll;
 #[cfg(test)]
 mod tests;
 mod tree;
+#[cfg(windows)]
+mod windows;
 mod worker;
 
 struct AddRoot {
-    path: CanonicalPathBuf,
-    recursive: bool,
+    info: RootInfo,
     notify: Box<dyn FnOnce(bool) + Send>,
 }
 
+/// The mechanism used to detect changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// The native backend of the platform: inotify on Linux, FSEvents on
+    /// macOS, `ReadDirectoryChangesW` on Windows and kqueue on the BSDs.
+    /// Note that kqueue needs an open descriptor for every watched file and
+    /// directory, see [`Watcher::set_max_watches`].
+    #[default]
+    Native,
+    /// Periodically crawls all roots and compares the results. Useful for
+    /// filesystems where inotify doesn't work (like NFS, SMB or FUSE) but
+    /// much more expensive.
+    Poll { interval: Duration },
+}
+
+/// How a root passed to [`Watcher::add_root`] relates to the roots that are
+/// already watched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootStatus {
+    /// the root is watched in addition to the existing roots
+    Added,
+    /// the root is recursive and replaces existing roots nested inside it
+    Merged,
+    /// the root is nested inside an existing recursive root (or was already
+    /// added) and doesn't need to be watched separately
+    AlreadyCovered,
+    /// the root matches the ignore filter and is not watched
+    Ignored,
+}
+
+/// Options for a root added with [`Watcher::add_root_with`].
+#[derive(Clone, Default)]
+pub struct RootOptions {
+    recursive: bool,
+    tag: Option<u64>,
+    cross_filesystem: bool,
+    follow_symlinks: bool,
+    follow_root_link: bool,
+    max_depth: Option<usize>,
+    initial_events: bool,
+    filter: Option<Arc<dyn Filter>>,
+}
+
+impl std::fmt::Debug for RootOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RootOptions")
+            .field("recursive", &self.recursive)
+            .field("tag", &self.tag)
+            .field("cross_filesystem", &self.cross_filesystem)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .field("follow_root_link", &self.follow_root_link)
+            .field("max_depth", &self.max_depth)
+            .field("initial_events", &self.initial_events)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RootOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the whole directory tree below the root is watched or only
+    /// its direct children. Disabled by default.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// A user defined tag that is attached to all events below this root,
+    /// see [`Event::tag`].
+    pub fn tag(mut self, tag: u64) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Whether the files that already exist when the root is added are
+    /// reported as [`EventType::Create`] events (like any other event they
+    /// are debounced and delivered to the handlers). For large trees this
+    /// produces a correspondingly large batch. Roots nested in an existing
+    /// recursive root report nothing. Disabled by default.
+    pub fn initial_events(mut self, initial_events: bool) -> Self {
+        self.initial_events = initial_events;
+        self
+    }
+
+    /// Whether crawls descend into directories on other filesystems (like
+    /// bind mounts or mounted subdirectories). Disabled by default.
+    pub fn cross_filesystem(mut self, cross_filesystem: bool) -> Self {
+        self.cross_filesystem = cross_filesystem;
+        self
+    }
+
+    /// Whether crawls follow symlinks to directories (and files) below the
+    /// root. Events for paths reached through a symlink are reported with the
+    /// path of the symlink. Directories that were already visited through
+    /// another symlink are skipped to avoid loops. Disabled by default.
+    ///
+    /// Combine this with [`Watcher::set_follow_symlinks`] so that the backend
+    /// also watches the symlink targets.
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Whether the root itself is followed if it is a symlink, without
+    /// following any symlinks below it. Only relevant with a custom
+    /// [`Watcher::set_canonicalize`] that doesn't resolve symlinks, otherwise
+    /// the root is never a symlink. Disabled by default.
+    ///
+    /// Combine this with [`Watcher::set_follow_symlinks`] so that the backend
+    /// watches the symlink target.
+    pub fn follow_root_link(mut self, follow_root_link: bool) -> Self {
+        self.follow_root_link = follow_root_link;
+        self
+    }
+
+    /// Limits how deep a recursive root is crawled, the direct children of the
+    /// root have a depth of 1. Directories at the maximum depth are tracked
+    /// but neither watched nor crawled. Unlimited (`None`) by default.
+    pub fn max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// A filter used for the paths below this root instead of the one set
+    /// with [`Watcher::set_filter`]. For nested roots the filter of the
+    /// innermost root applies.
+    pub fn filter(mut self, filter: Arc<dyn Filter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+}
+
+struct WatchOnce {
+    path: CanonicalPathBuf,
+    event_types: Vec<EventType>,
+    notify: Box<dyn FnOnce(Event) + Send>,
+}
+
+type Query = Box<dyn FnOnce(&mut Worker) + Send>;
+
 #[derive(Default)]
 struct Notifications {
     /// new roots to be added to the watcher
     roots: Vec<AddRoot>,
+    /// new one-shot watches to be installed
+    oneshots: Vec<WatchOnce>,
+    /// requests that need to be answered by the worker thread
+    queries: Vec<Query>,
+}
+
+impl Notifications {
+    /// Queues a root. A root that is queued twice is only crawled once with
+    /// the recursive flag set if either request was recursive, both
+    /// callbacks are invoked.
+    fn push_root(&mut self, root: AddRoot) {
+        let Some(queued) = self
+            .roots
+            .iter_mut()
+            .find(|queued| queued.info.path == root.info.path)
+        else {
+            self.roots.push(root);
+            return;
+        };
+        queued.info.options.recursive |= root.info.options.recursive;
+        let first = replace(&mut queued.notify, Box::new(|_| ()));
+        let second = root.notify;
+        queued.notify = Box::new(move |success| {
+            first(success);
+            second(success);
+        });
+    }
 }
 
 impl std::fmt::Debug for Notifications {
@@ -46,14 +259,98 @@ impl std::fmt::Debug for Notifications {
 #[derive(Debug)]
 struct WatcherState {
     config: Mutex<Config>,
+    /// kept outside of `config` so that the backend and the worker can read
+    /// it without locking, see `Watcher::set_filter`
+    filter: ArcSwap<RootFilter>,
+    /// kept outside of `config` so that resolving the paths passed to the
+    /// watcher doesn't contend with the worker, see `Watcher::set_canonicalize`
+    canonicalize: ArcSwap<CanonicalizeHook>,
     notifications: Mutex<Notifications>,
     has_notifications: AtomicBool,
-    #[cfg(test)]
+    /// the roots of the worker, see `Watcher::watched_roots`
+    roots: Mutex<Vec<RootInfo>>,
     recrawls: AtomicUsize,
+    /// see `Watcher::pause`
+    paused: AtomicBool,
+    /// set once the worker was spawned, see `Watcher::start`
+    started: AtomicBool,
+    /// the worker thread, see `Watcher::shutdown_and_join`
+    worker: Mutex<Option<JoinHandle<()>>>,
+    /// the thread running the worker (and the handlers), see `Watcher::query`
+    worker_thread: OnceLock<ThreadId>,
+}
+
+impl WatcherState {
+    pub(crate) fn filter(&self) -> Arc<dyn Filter> {
+        self.filter.load_full()
+    }
+}
+
+/// Statistics for monitoring a watcher, see [`Watcher::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WatcherStats {
+    /// number of directories watched by the backend (inotify watches,
+    /// FSEvents streams or `ReadDirectoryChangesW` handles), kqueue also
+    /// counts the descriptors of watched files
+    pub watches: usize,
+    /// number of files and directories tracked in memory
+    pub nodes: usize,
+    /// number of deleted nodes and unused slots in memory that are dropped
+    /// by [`Watcher::compact`], unlike [`Watcher::tombstone_count`] this
+    /// includes the slots of nodes that were already released
+    pub reclaimable: usize,
+    /// number of changes reported by the backend that were not processed yet
+    pub pending_changes: usize,
+    /// how often the backend dropped events because its queue overflowed
+    pub queue_overflows: usize,
+    /// number of full recrawls performed so far
+    pub recrawls: usize,
+    /// number of directories that couldn't be watched because the watch
+    /// limit (inotify `max_user_watches`) was reached, these are retried
+    /// periodically
+    pub pending_watch_retries: usize,
+}
+
+/// Returned by [`Watcher::start`] and [`Watcher::run_on_current_thread`] if
+/// the worker couldn't be started.
+#[derive(Debug)]
+pub enum StartError {
+    /// The worker was already started (possibly through a clone of the
+    /// watcher).
+    AlreadyStarted,
+    /// The worker thread couldn't be spawned.
+    Spawn(io::Error),
+}
+
+impl std::fmt::Display for StartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartError::AlreadyStarted => f.write_str("the watcher was already started"),
+            StartError::Spawn(err) => write!(f, "failed to spawn the worker thread: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StartError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StartError::AlreadyStarted => None,
+            StartError::Spawn(err) => Some(err),
+        }
+    }
+}
+
+impl From<StartError> for io::Error {
+    fn from(err: StartError) -> Self {
+        match err {
+            StartError::Spawn(err) => err,
+            err => io::Error::other(err),
+        }
+    }
 }
 
 pub struct ShutdownOnDrop {
-    watcher: Weak<InotifyWatcher>,
+    watcher: Weak<Notify>,
 }
 
 impl ShutdownOnDrop {
@@ -73,74 +370,712 @@ impl Drop for ShutdownOnDrop {
 #[derive(Debug, Clone)]
 pub struct Watcher {
     state: Arc<WatcherState>,
-    notify: Arc<InotifyWatcher>,
+    notify: Arc<Notify>,
 }
 
 impl Watcher {
-    #[cfg(test)]
-    pub fn recrawls(&self) -> usize {
-        self.state.recrawls.load(atomic::Ordering::Relaxed)
+    /// Returns statistics about the resources used by the watcher.
+    ///
+    /// Blocks until the worker answers. `nodes` and `reclaimable` are 0 if
+    /// the worker can't answer (see [`start`](Self::start)), the remaining
+    /// fields are always filled in.
+    pub fn stats(&self) -> WatcherStats {
+        let (nodes, reclaimable) = self
+            .query(|worker| (worker.node_count(), worker.reclaimable_count()))
+            .unwrap_or_default();
+        WatcherStats {
+            watches: self.notify.watch_count(),
+            nodes,
+            reclaimable,
+            pending_changes: self.notify.changes().lock().len(),
+            queue_overflows: self.notify.queue_overflows(),
+            recrawls: self.state.recrawls.load(atomic::Ordering::Relaxed),
+            pending_watch_retries: self.notify.pending_watch_retries(),
+        }
     }
 
     pub fn shutdown(&self) {
         self.notify.shutdown();
     }
 
+    /// Like [`shutdown`](Self::shutdown) but also waits for the worker thread
+    /// to exit. Returns the panic payload if the worker panicked. Returns
+    /// immediately if the watcher was never started (or was already joined).
+    ///
+    /// Must not be called from a handler.
+    pub fn shutdown_and_join(self) -> thread::Result<()> {
+        self.shutdown();
+        let worker = self.state.worker.lock().unwrap().take();
+        match worker {
+            Some(worker) => worker.join(),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops delivering events, for example during bulk operations like a
+    /// large `git checkout`. Changes observed while paused are not processed
+    /// individually, instead a single recrawl is performed by [`resume`](Self::resume).
+    pub fn pause(&self) {
+        self.state.paused.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Resumes a watcher paused with [`pause`](Self::pause). The roots are
+    /// recrawled and all changes made in the meantime are reported at once.
+    pub fn resume(&self) {
+        if !self.state.paused.swap(false, atomic::Ordering::Relaxed) {
+            return;
+        }
+        self.notify.changes().lock().recrawl(RecrawlCause::Resumed);
+        self.notify.changes().notify();
+    }
+
     pub fn shutdown_guard(&self) -> ShutdownOnDrop {
         ShutdownOnDrop {
             watcher: Arc::downgrade(&self.notify),
         }
     }
 
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let canonicalize = self.state.canonicalize.load_full();
+        (canonicalize.0)(path)
+    }
+
+    /// Starts watching `root`, `root_crawled` is invoked once the initial
+    /// crawl finished. Roots nested inside an existing recursive root are
+    /// not watched separately, see [`RootStatus`].
+    ///
+    /// `root` may also be a single file, in that case its parent directory
+    /// is watched so that the file being deleted and recreated is reported.
     pub fn add_root(
         &self,
         root: &Path,
         recursive: bool,
         root_crawled: impl FnOnce(bool) + 'static + Send,
-    ) -> io::Result<()> {
-        let root = root.canonicalize()?;
-        if self
-            .state
-            .config
-            .lock()
-            .unwrap()
+    ) -> io::Result<RootStatus> {
+        self.add_root_with(root, RootOptions::new().recursive(recursive), root_crawled)
+    }
+
+    pub fn add_root_with(
+        &self,
+        root: &Path,
+        options: RootOptions,
+        root_crawled: impl FnOnce(bool) + 'static + Send,
+    ) -> io::Result<RootStatus> {
+        let Some(info) = self.root_info(root, options)? else {
+            return Ok(RootStatus::Ignored);
+        };
+        let mut notifications = self.state.notifications.lock().unwrap();
+        // the worker performs the same check once it adds the root, here
+        // roots that are still queued are considered too
+        let status = {
+            let roots = self.state.roots.lock().unwrap();
+            let mut known = roots
+                .iter()
+                .chain(notifications.roots.iter().map(|root| &root.info));
+            if known.clone().any(|root| root.covers(&info)) {
+                RootStatus::AlreadyCovered
+            } else if known.any(|root| info.covers(root)) {
+                RootStatus::Merged
+            } else {
+                RootStatus::Added
+            }
+        };
+        notifications.push_root(AddRoot {
+            info,
+            notify: Box::new(root_crawled),
+        });
+        drop(notifications);
+        self.state
+            .has_notifications
+            .store(true, atomic::Ordering::Relaxed);
+        self.notify.changes().notify();
+        Ok(status)
+    }
+
+    /// Adds many roots at once, `done` is invoked with the result for each
+    /// root (in order) once all initial crawls finished. Unlike calling
+    /// [`add_root`](Self::add_root) repeatedly the worker is only woken up
+    /// once. Ignored roots are reported as `Ok`.
+    pub fn add_roots(
+        &self,
+        roots: impl IntoIterator<Item = (PathBuf, bool)>,
+        done: impl FnOnce(Vec<io::Result<()>>) + 'static + Send,
+    ) {
+        let mut results = Vec::new();
+        let mut queued = Vec::new();
+        for (i, (root, recursive)) in roots.into_iter().enumerate() {
+            match self.root_info(&root, RootOptions::new().recursive(recursive)) {
+                Ok(Some(info)) => {
+                    results.push(Ok(()));
+                    queued.push((i, info));
+                }
+                Ok(None) => results.push(Ok(())),
+                Err(err) => results.push(Err(err)),
+            }
+        }
+        if queued.is_empty() {
+            done(results);
+            return;
+        }
+        // the results, the number of crawls that are still running and the
+        // callback that is invoked once the last one finished
+        let remaining = queued.len();
+        let bulk = Arc::new(Mutex::new((results, remaining, Some(done))));
+        let mut notifications = self.state.notifications.lock().unwrap();
+        for (i, info) in queued {
+            let path = info.path.clone();
+            let bulk = bulk.clone();
+            let notify = move |success: bool| {
+                let mut bulk = bulk.lock().unwrap();
+                let (results, remaining, done) = &mut *bulk;
+                if !success {
+                    results[i] = Err(io::Error::other(format!("failed to watch {path:?}")));
+                }
+                *remaining -= 1;
+                if *remaining == 0 {
+                    let done = done.take().unwrap();
+                    done(take(results));
+                }
+            };
+            notifications.push_root(AddRoot {
+                info,
+                notify: Box::new(notify),
+            });
+        }
+        drop(notifications);
+        self.state
+            .has_notifications
+            .store(true, atomic::Ordering::Relaxed);
+        self.notify.changes().notify();
+    }
+
+    /// Canonicalizes `root` and checks that it may be watched, returns
+    /// `None` if the root is ignored by the filter.
+    fn root_info(&self, root: &Path, options: RootOptions) -> io::Result<Option<RootInfo>> {
+        let root = self.canonicalize(root)?;
+        let filter = options
             .filter
-            .ignore_path_rec(&root, None)
-        {
+            .clone()
+            .unwrap_or_else(|| self.state.filter());
+        let max_entries = self.state.config.lock().unwrap().max_root_entries;
+        // the directories above the root are not watched, only the root
+        // itself may be ignored
+        if filter.ignore_path_rec_until(&root, &root, None) {
             log::warn!("ignoring root {root:?} as it matches the ignore pattern");
-            return Ok(());
+            return Ok(None);
+        }
+        if options.recursive {
+            if root.components().count() <= 2 {
+                log::warn!(
+                    "recursively watching {root:?}, this will likely watch a huge number of files"
+                );
+            }
+            if let Some(max_entries) = max_entries {
+                if tree::exceeds_entries(&root, &*filter, max_entries, &options) {
+                    return Err(io::Error::other(format!(
+                        "refusing to watch {root:?}: contains more than {max_entries} entries"
+                    )));
+                }
+            }
+        }
+        Ok(Some(RootInfo {
+            path: CanonicalPathBuf::assert_canonicalized(&root),
+            options,
+        }))
+    }
+
+    /// Like [`add_root`](Self::add_root) but blocks until the initial crawl
+    /// completed and returns whether it succeeded. The worker must have been
+    /// started, if the crawl doesn't finish within `timeout` an error of kind
+    /// [`io::ErrorKind::TimedOut`] is returned.
+    pub fn add_root_blocking(
+        &self,
+        root: &Path,
+        recursive: bool,
+        timeout: Duration,
+    ) -> io::Result<bool> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.add_root(root, recursive, move |success| {
+            let _ = tx.send(success);
+        })?;
+        match rx.recv_timeout(timeout) {
+            Ok(success) => Ok(success),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out waiting for the crawl of {root:?}"),
+            )),
+            // ignored roots are never crawled
+            Err(mpsc::RecvTimeoutError::Disconnected) => Ok(false),
         }
-        let root = CanonicalPathBuf::assert_canonicalized(&root);
+    }
+
+    /// Stops watching a root previously added with `add_root`. Returns `false`
+    /// if `root` isn't a root of this watcher. Roots nested in the removed
+    /// root keep being watched.
+    ///
+    /// Blocks until the worker is done, see [`start`](Self::start).
+    pub fn remove_root(&self, root: &Path) -> io::Result<bool> {
+        let root = CanonicalPathBuf::assert_canonicalized(&self.canonicalize(root)?);
+        Ok(self
+            .query(move |worker| worker.remove_root(root))
+            .unwrap_or(false))
+    }
+
+    /// Watches `path` until the first event with one of the given `event_types`
+    /// is observed for it. `notify` is invoked with that event and afterwards the
+    /// watch (and any state that was only kept for it) is removed again.
+    ///
+    /// The path doesn't need to exist yet, only its parent directory must exist.
+    pub fn watch_once(
+        &self,
+        path: &Path,
+        event_types: &[EventType],
+        notify: impl FnOnce(Event) + 'static + Send,
+    ) -> io::Result<()> {
+        let path = match self.canonicalize(path) {
+            Ok(path) => path,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                    return Err(err);
+                };
+                let parent = if parent.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    parent
+                };
+                self.canonicalize(parent)?.join(name)
+            }
+            Err(err) => return Err(err),
+        };
+        let path = CanonicalPathBuf::assert_canonicalized(&path);
         self.state
             .notifications
             .lock()
             .unwrap()
-            .roots
-            .push(AddRoot {
-                path: root,
-                recursive,
-                notify: Box::new(root_crawled),
+            .oneshots
+            .push(WatchOnce {
+                path,
+                event_types: event_types.to_vec(),
+                notify: Box::new(notify),
             });
         self.state
             .has_notifications
             .store(true, atomic::Ordering::Relaxed);
-        self.notify.changes.notify();
+        self.notify.changes().notify();
         Ok(())
     }
 
+    /// Runs `query` on the worker thread and blocks until it has been answered.
+    /// Returns `None` if the watcher was shut down before the query was answered.
+    /// Also returns `None` right away if the worker was never started or if
+    /// called from a handler, the worker could never answer and we would
+    /// block forever.
+    fn query<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut Worker) -> T + Send + 'static,
+    ) -> Option<T> {
+        if !self.state.started.load(atomic::Ordering::Acquire) {
+            log::warn!("the watcher was queried before it was started");
+            return None;
+        }
+        if self.state.worker_thread.get() == Some(&thread::current().id()) {
+            log::error!("the watcher was queried from a handler, this would deadlock");
+            return None;
+        }
+        let (tx, rx) = mpsc::sync_channel(1);
+        {
+            let mut notifications = self.state.notifications.lock().unwrap();
+            if self.notify.is_shutdown() {
+                return None;
+            }
+            notifications.queries.push(Box::new(move |worker| {
+                let _ = tx.send(query(worker));
+            }));
+        }
+        self.state
+            .has_notifications
+            .store(true, atomic::Ordering::Relaxed);
+        self.notify.changes().notify();
+        rx.recv().ok()
+    }
+
+    /// Returns the roots that are currently watched (sorted by path) and
+    /// whether they are recursive. Roots nested inside a recursive root are
+    /// covered by their parent and therefore not included.
+    pub fn watched_roots(&self) -> Vec<(CanonicalPathBuf, bool)> {
+        self.state
+            .roots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|root| (root.path.clone(), root.options.recursive))
+            .collect()
+    }
+
+    /// Returns the number of deleted files and directories that are still
+    /// tracked in memory. Deleted nodes are usually released right away,
+    /// only removed roots and deleted directories that still contain
+    /// (recreated) files are kept until [`compact`](Self::compact).
+    ///
+    /// Blocks until the worker answers, returns 0 if it can't (see
+    /// [`start`](Self::start)).
+    pub fn tombstone_count(&self) -> usize {
+        self.query(|worker| worker.tombstone_count()).unwrap_or(0)
+    }
+
+    /// Whether `path` is currently tracked by the watcher, i.e. it exists
+    /// inside a watched root and is not ignored by the filter.
+    ///
+    /// Blocks until the worker answers, returns `false` if it can't (see
+    /// [`start`](Self::start)).
+    pub fn is_watching(&self, path: &Path) -> bool {
+        let Ok(path) = self.canonicalize(path) else {
+            return false;
+        };
+        let path = CanonicalPathBuf::assert_canonicalized(&path);
+        self.query(move |worker| worker.is_watching(&path))
+            .unwrap_or(false)
+    }
+
+    /// Returns all files and directories that are currently tracked (sorted
+    /// by path), including the roots.
+    ///
+    /// Blocks until the worker answers. The snapshot is empty if called
+    /// before the watcher is started or from a handler.
+    pub fn snapshot(&self) -> Vec<CanonicalPathBuf> {
+        self.query(|worker| worker.snapshot()).unwrap_or_default()
+    }
+
+    /// Processes all changes the backend reported so far and delivers the
+    /// resulting events to the handlers right away instead of waiting for
+    /// them to settle. Events that are held back (see
+    /// [`set_defer_empty_creates`](Self::set_defer_empty_creates)) are
+    /// delivered too. Useful in tests and before shutting down the watcher.
+    ///
+    /// Blocks until the worker is done, does nothing if called before the
+    /// watcher is started or from a handler.
+    pub fn flush(&self) {
+        self.query(|worker| worker.flush());
+    }
+
+    /// Rebuilds the in-memory file tree dropping any deleted nodes and returns
+    /// the number of reclaimed nodes. The slots of deleted files are reused for
+    /// new files, this is useful to return the memory after a large number of
+    /// files was removed.
+    ///
+    /// Blocks until the worker is done, returns 0 without compacting if it
+    /// can't (see [`start`](Self::start)).
+    pub fn compact(&self) -> usize {
+        self.query(|worker| worker.compact()).unwrap_or(0)
+    }
+
+    /// Replaces the filter, by default a [`DefaultFilter`] is used which
+    /// ignores nothing. If `recrawl` is set all roots are recrawled so that
+    /// paths that are no longer ignored are picked up.
+    ///
+    /// The filter is swapped atomically without taking any locks, so
+    /// frequent updates don't block the backend or the worker.
     pub fn set_filter(&self, filter: Arc<dyn Filter>, recrawl: bool) {
-        self.state.config.lock().unwrap().filter = filter;
+        self.state
+            .filter
+            .rcu(|current| current.with_default(filter.clone()));
         self.notify.refresh_config();
         if recrawl {
-            self.notify.changes.lock().recrawl();
-            self.notify.changes.notify();
+            self.notify
+                .changes()
+                .lock()
+                .recrawl(RecrawlCause::FilterChanged);
+            self.notify.changes().notify();
         }
     }
 
+    /// Returns a snapshot of the changes the backend observed but that the worker
+    /// hasn't applied to its file tree yet. Once applied, changes are turned into
+    /// events which are delivered after the settle time.
+    pub fn pending_changes(&self) -> Vec<(CanonicalPathBuf, PendingKind)> {
+        self.notify.changes().lock().snapshot()
+    }
+
+    /// Sets whether watches are installed on the target of a symlink (instead
+    /// of the symlink itself) when a watched directory is a symlink. Disabled
+    /// by default.
+    ///
+    /// Note that roots are always canonicalized by `add_root` so a symlinked
+    /// root is resolved and its target is watched regardless of this setting.
+    /// This only affects symlinks encountered below a root.
+    pub fn set_follow_symlinks(&self, follow_symlinks: bool) {
+        self.notify.set_follow_symlinks(follow_symlinks);
+    }
+
+    /// Enables a mode in which only `Delete` events (and `TypeChanged` events
+    /// for paths that were replaced by a different kind of file) are
+    /// reported. The backend then doesn't subscribe to file modifications at
+    /// all which avoids the overhead of processing them. Deletions of whole
+    /// directory trees are still reported for every contained file.
+    ///
+    /// The subscription is chosen when a directory is first watched and
+    /// isn't updated later, so set this before the watcher is started.
+    pub fn set_delete_only(&self, delete_only: bool) {
+        self.notify.set_delete_only(delete_only);
+    }
+
+    /// Restricts the kinds of file changes the backend subscribes to, by
+    /// default `ATTRIB` and `MODIFY`. For example masking out `ATTRIB`
+    /// avoids wakeups for changes that only touch the metadata of a file.
+    /// Ignored by the FSEvents backend and in delete only mode (see
+    /// `set_delete_only`), which also explains when the mask is applied.
+    pub fn set_watch_mask(&self, mask: WatchMask) {
+        self.notify.set_watch_mask(mask);
+    }
+
+    /// Applies modifications only once the modified file is closed instead of
+    /// on every write, so consumers don't see (or hash) partially written
+    /// files. Files that stay open are still picked up once they weren't
+    /// written to for the settle time, for example because the writer
+    /// crashed. Only supported by inotify, other backends ignore this.
+    ///
+    /// Writes are held back right away, but close events are only received
+    /// for directories watched after this was enabled (it adds
+    /// [`WatchMask::CLOSE_WRITE`] to the watch mask).
+    pub fn set_only_on_close(&self, only_on_close: bool) {
+        self.notify.set_only_on_close(only_on_close);
+    }
+
+    /// Sets the function used to canonicalize the paths passed to `add_root`
+    /// and `watch_once`, by default [`Path::canonicalize`] is used.
+    ///
+    /// The returned paths must be absolute and must not contain `.` or `..`
+    /// components. Any symlinks that are not resolved must be followed by the
+    /// backend (see `set_follow_symlinks`) since the paths of all events are
+    /// derived from the canonicalized root.
+    pub fn set_canonicalize(
+        &self,
+        canonicalize: impl Fn(&Path) -> io::Result<PathBuf> + Send + Sync + 'static,
+    ) {
+        let hook = CanonicalizeHook(Arc::new(canonicalize));
+        self.state.canonicalize.store(Arc::new(hook));
+    }
+
+    /// Limits the number of entries a recursive root may contain. Roots
+    /// exceeding the limit are rejected by `add_root` with an error before
+    /// anything is watched. This guards against accidentally watching huge
+    /// trees (like `/`) which would exhaust the inotify watch limit. Disabled
+    /// (`None`) by default.
+    ///
+    /// Note that this requires an additional traversal of the root.
+    pub fn set_max_root_entries(&self, max_entries: Option<usize>) {
+        self.state.config.lock().unwrap().max_root_entries = max_entries;
+    }
+
+    /// Limits the number of directories and files that are watched at once.
+    /// This is only enforced by the kqueue backend (used on the BSDs) which
+    /// needs to keep a file descriptor open for every watched directory and
+    /// file. Once the budget is exhausted a recrawl is triggered and any
+    /// further paths are not watched. Unlimited (`None`) by default.
+    pub fn set_max_watches(&self, max_watches: Option<usize>) {
+        self.state.config.lock().unwrap().max_watches = max_watches;
+    }
+
+    /// Limits the number of events passed to a handler at once, larger
+    /// batches (for example after a recrawl) are split into multiple calls.
+    /// Every path still occurs at most once per batch. Unlimited (`None`) by
+    /// default.
+    pub fn set_max_batch(&self, max_batch: Option<usize>) {
+        self.state.config.lock().unwrap().max_batch = max_batch;
+    }
+
+    /// Limits the number of changes that may be queued for the worker. During
+    /// a large burst of changes the queue is discarded once it reaches the
+    /// limit and all roots are recrawled instead, the same way a queue
+    /// overflow of the OS is handled. Unlimited (`None`) by default.
+    pub fn set_max_pending_changes(&self, max_pending_changes: Option<usize>) {
+        self.state.config.lock().unwrap().max_pending_changes = max_pending_changes;
+        self.notify.changes().set_max_len(max_pending_changes);
+    }
+
+    /// Sets the directory that [`Event::display_path`] is relative to.
+    pub fn set_display_root(&self, display_root: Option<&Path>) -> io::Result<()> {
+        let display_root = display_root
+            .map(|root| self.canonicalize(root))
+            .transpose()?
+            .map(|root| CanonicalPathBuf::assert_canonicalized(&root));
+        self.state.config.lock().unwrap().display_root = display_root;
+        Ok(())
+    }
+
+    /// Whether files that are created and removed again within the settle
+    /// time are reported as `Tempfile` events. If disabled, such files are
+    /// dropped silently. Enabled by default.
+    pub fn set_report_tempfiles(&self, report_tempfiles: bool) {
+        self.state.config.lock().unwrap().report_tempfiles = report_tempfiles;
+    }
+
+    /// Whether files that are removed before the watcher could stat them are
+    /// reported as `Tempfile` events. By default such files are dropped
+    /// silently since they never existed from the watcher's point of view.
+    /// Has no effect if [`set_report_tempfiles`](Self::set_report_tempfiles)
+    /// is disabled.
+    pub fn set_report_vanished_files(&self, report_vanished_files: bool) {
+        self.state.config.lock().unwrap().report_vanished_files = report_vanished_files;
+    }
+
+    /// Sets how often the roots are crawled with the [`Backend::Poll`] backend.
+    /// Takes effect after the next poll.
+    pub fn set_poll_interval(&self, interval: Duration) {
+        self.state.config.lock().unwrap().poll_interval = interval;
+    }
+
+    /// Sets the size (in bytes) of the buffer the inotify backend reads
+    /// events into, `None` restores the default (room for 16k events, about
+    /// 4.3 MB). A smaller buffer uses less memory but requires more syscalls
+    /// to drain the kernel queue, which also changes how quickly the queue
+    /// overflows under load. Ignored by the other backends.
+    pub fn set_read_buffer_size(&self, size: Option<usize>) {
+        self.state.config.lock().unwrap().read_buffer_size = size;
+        self.notify.refresh_config();
+    }
+
     pub fn set_settle_time(&self, settle_time: Duration) {
         self.state.config.lock().unwrap().settle_time = settle_time;
     }
 
+    /// Lets the settle time grow under load. Whenever more than `threshold`
+    /// changes were observed before the events settled, the settle time is
+    /// doubled (up to `max_settle_time`) so that large bursts (like a
+    /// recrawl or a `git checkout`) are delivered in fewer batches. Otherwise
+    /// it is halved until it reaches the time set with
+    /// [`set_settle_time`](Self::set_settle_time) again. Disabled (`None`)
+    /// by default.
+    pub fn set_adaptive_settle_time(&self, max_settle_time: Option<Duration>, threshold: usize) {
+        let mut config = self.state.config.lock().unwrap();
+        config.max_settle_time = max_settle_time;
+        config.settle_threshold = threshold;
+    }
+
+    /// Configures how recrawls (for example after a queue overflow) are throttled.
+    /// A recrawl is always performed immediately unless the previous recrawl
+    /// finished less than `min` ago. In that case the recrawl is delayed and the
+    /// delay is doubled (up to `max`) for every further recrawl that is requested
+    /// in rapid succession. Requests that arrive before a recrawl starts are
+    /// merged into it. Defaults to 500ms and 30s.
+    pub fn set_recrawl_interval(&self, min: Duration, max: Duration) {
+        let mut config = self.state.config.lock().unwrap();
+        config.min_recrawl_interval = min;
+        config.max_recrawl_interval = max.max(min);
+    }
+
+    /// Many tools create an empty file and only write its content afterwards.
+    /// Depending on timing this is reported as a `Create` for an empty file
+    /// followed by a `Modified` event. When a grace period is set, `Create`
+    /// events for empty files are held back until the file gains content (and
+    /// the event is reported as a single `Create`) or the grace period expires.
+    /// Disabled (`None`) by default.
+    pub fn set_defer_empty_creates(&self, grace_period: Option<Duration>) {
+        self.state.config.lock().unwrap().defer_empty_creates = grace_period;
+    }
+
+    /// Editors often save atomically by replacing a file, which may be
+    /// reported as a `Delete` and a `Create` in separate batches if the two
+    /// are more than the settle time apart. When a grace period is set,
+    /// `Delete` events are held back until it expires so that a recreate
+    /// within the grace period is reported as a single `Modified` event.
+    /// This delays all `Delete` events. Disabled (`None`) by default.
+    pub fn set_coalesce_deletes(&self, grace_period: Option<Duration>) {
+        self.state.config.lock().unwrap().coalesce_deletes = grace_period;
+    }
+
+    /// Reports at most one `Modified` event per path within `interval`, for
+    /// example for log files that are written continuously. Further
+    /// modifications are coalesced and reported once the interval passed.
+    /// `Create` and `Delete` events are never delayed. Disabled (`None`) by
+    /// default.
+    pub fn set_min_modified_interval(&self, interval: Option<Duration>) {
+        self.state.config.lock().unwrap().min_modified_interval = interval;
+    }
+
+    /// Detects modifications of files up to `max_size` bytes by hashing their
+    /// contents instead of comparing mtime and size. This catches changes
+    /// that preserve both and suppresses `Modified` events for files that
+    /// were only touched, at the cost of reading every changed file. Disabled
+    /// (`None`) by default.
+    pub fn set_hash_contents(&self, max_size: Option<usize>) {
+        self.state.config.lock().unwrap().hash_contents = max_size;
+    }
+
+    /// Whether changes to the permissions or ownership of a file are reported
+    /// as `Modified` events. When enabled the ctime of files is compared in
+    /// addition to mtime and size so that these changes are also found by
+    /// crawls and the polling backend. The ctime is only recorded while this
+    /// is enabled, so the first change of a file after enabling it is
+    /// detected by mtime and size alone. Only supported on unix, disabled by
+    /// default.
+    pub fn set_report_metadata_changes(&self, report: bool) {
+        self.state.config.lock().unwrap().report_metadata_changes = report;
+    }
+
+    /// Whether the initial crawl of a recursive root reads directories in
+    /// parallel (on the rayon thread pool). Enabled by default, roots that
+    /// follow symlinks are always crawled serially.
+    #[cfg(feature = "parallel")]
+    pub fn set_parallel_crawl(&self, parallel: bool) {
+        self.state.config.lock().unwrap().parallel_crawl = parallel;
+    }
+
+    /// Whether `Create` and `Delete` events are emitted for directories. By
+    /// default only files are reported, so creating or removing an empty
+    /// directory goes unnoticed.
+    pub fn set_report_directories(&self, report: bool) {
+        self.state.config.lock().unwrap().report_directories = report;
+    }
+
+    /// Whether events are delivered in the order the changes were observed
+    /// instead of sorted by path. A directory is still reported before its
+    /// children. Disabled by default.
+    pub fn set_preserve_order(&self, preserve_order: bool) {
+        self.state.config.lock().unwrap().preserve_order = preserve_order;
+    }
+
+    /// Replaces the rules for merging multiple changes to the same path
+    /// into one event, by default a [`DefaultCoalescePolicy`] is used.
+    pub fn set_coalesce_policy(&self, policy: Arc<dyn CoalescePolicy>) {
+        self.state.config.lock().unwrap().coalesce_policy = policy;
+    }
+
+    /// Whether events remember that the file was modified before the
+    /// change they report, see [`Event::modified_before`]. The event types
+    /// are unaffected, a file that is modified and then deleted is still
+    /// reported as `Delete`. Disabled by default.
+    pub fn set_track_modifications(&self, track_modifications: bool) {
+        self.state.config.lock().unwrap().track_modifications = track_modifications;
+    }
+
+    /// Whether a change to a file is reported for all of its hardlinks.
+    /// Changes are detected per path, so by default modifying a file only
+    /// reports the link it was modified through, and creating a new link
+    /// doesn't report the existing ones. When enabled, every tracked file is
+    /// additionally indexed by its device and inode, which costs roughly 40
+    /// bytes per file. Files that were crawled before this was enabled are
+    /// only indexed once they change. Disabled by default.
+    pub fn set_track_hardlinks(&self, track_hardlinks: bool) {
+        self.state.config.lock().unwrap().track_hardlinks = track_hardlinks;
+    }
+
+    /// Whether paths that only differ in the case of ASCII letters refer to
+    /// the same file, useful for case-insensitive filesystems. Disabled by
+    /// default. Only takes effect if set before the watcher is
+    /// [started](Self::start).
+    pub fn set_case_insensitive(&self, case_insensitive: bool) {
+        self.state.config.lock().unwrap().case_insensitive = case_insensitive;
+    }
+
+    /// The number of files and directories the watcher expects to track,
+    /// the file tree is allocated with room for this many entries so that
+    /// crawling large roots doesn't repeatedly grow it. 1024 by default.
+    /// Only takes effect if set before the watcher is [started](Self::start).
+    pub fn set_capacity_hint(&self, capacity: usize) {
+        self.state.config.lock().unwrap().capacity_hint = capacity;
+    }
+
     pub fn add_handler(&self, handler: impl FnMut(Events) -> bool + Send + 'static) {
         self.state
             .config
@@ -150,38 +1085,210 @@ impl Watcher {
             .push(Box::new(handler));
     }
 
+    /// Returns a receiver that the batches of events are sent to, as an
+    /// alternative to `add_handler`. The channel holds at most `capacity`
+    /// batches. When it is full the worker blocks until the receiver catches
+    /// up, so a slow consumer delays all handlers and can cause the backend to
+    /// drop events (which triggers a recrawl). Dropping the receiver removes
+    /// the channel.
+    ///
+    /// The receiver must keep draining the channel. Methods that wait for the
+    /// worker (like [`is_watching`](Self::is_watching)) never return while
+    /// it is blocked, so the consuming thread must not call them while the
+    /// channel may be full.
+    pub fn events_channel(&self, capacity: usize) -> mpsc::Receiver<Events> {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        self.add_handler(move |events| tx.send(events).is_ok());
+        rx
+    }
+
+    /// Returns a stream of the batches of events, as an alternative to
+    /// `add_handler` for async code. The stream is unbounded so a slow
+    /// consumer never blocks the worker. It ends once the watcher is shut
+    /// down, dropping the stream removes it from the watcher.
+    #[cfg(feature = "async")]
+    pub fn event_stream(&self) -> impl futures_core::Stream<Item = Events> + Send + Unpin {
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        self.add_handler(move |events| tx.unbounded_send(events).is_ok());
+        rx
+    }
+
+    /// Sets a callback that is invoked (once) with the path of a symlink and
+    /// its target whenever a crawl discovers a symlink loop. The crawl skips
+    /// the loop and continues with the rest of the tree.
+    pub fn on_symlink_loop(&self, handler: impl FnMut(&Path, &Path) + Send + 'static) {
+        self.state.config.lock().unwrap().symlink_loop_handler = Some(Box::new(handler));
+    }
+
+    /// Sets a callback that is invoked with any error encountered while
+    /// crawling, like a permission denied error for a subdirectory. Without a
+    /// callback these errors are only logged.
+    pub fn on_error(&self, handler: impl FnMut(WatchError) + Send + 'static) {
+        self.state.config.lock().unwrap().error_handler = Some(Box::new(handler));
+    }
+
+    /// Sets a callback that is invoked when the backend's event queue
+    /// overflowed, which means that events were lost and all roots are
+    /// recrawled. The callback receives the total number of overflows so far
+    /// (see [`WatcherStats::queue_overflows`]), frequent overflows suggest
+    /// raising system limits like `fs.inotify.max_queued_events`.
+    pub fn on_overflow(&self, handler: impl FnMut(usize) + Send + 'static) {
+        self.state.config.lock().unwrap().overflow_handler = Some(Box::new(handler));
+    }
+
+    /// Sets a callback that is invoked with the cause whenever a recrawl of
+    /// all roots is scheduled. Recrawls requested in rapid succession may be
+    /// merged (and delayed), only the first cause is reported in that case.
+    pub fn on_recrawl(&self, handler: impl FnMut(RecrawlCause) + Send + 'static) {
+        self.state.config.lock().unwrap().recrawl_handler = Some(Box::new(handler));
+    }
+
+    /// Sets a callback that is invoked with the path of a root when the root
+    /// itself is deleted, moved away or replaced by a different directory
+    /// (as opposed to only its contents being removed). The root stays
+    /// registered. Roots nested in another recursive root are not reported.
+    ///
+    /// The inotify backend also watches the parent directory of each root,
+    /// so a root that is recreated is picked up again. If the parent can't
+    /// be watched (for example because permission is denied) only the
+    /// events of the root itself are used.
+    pub fn on_root_removed(&self, handler: impl FnMut(&Path) + Send + 'static) {
+        self.state.config.lock().unwrap().root_removed_handler = Some(Box::new(handler));
+    }
+
     pub fn new() -> io::Result<Self> {
         Self::new_impl(false)
     }
 
+    /// Creates a watcher that uses `backend`. [`Backend::Poll`] doesn't use
+    /// any OS facility for watching, so it works even where the native
+    /// backend can't be created.
+    pub fn with_backend(backend: Backend) -> io::Result<Self> {
+        match backend {
+            Backend::Native => Self::new(),
+            Backend::Poll { interval } => {
+                let watcher = Self {
+                    state: Self::new_state(),
+                    notify: Arc::new(Notify::Poll(PollWatcher::default())),
+                };
+                watcher.set_poll_interval(interval);
+                Ok(watcher)
+            }
+        }
+    }
+
     pub fn new_impl(_slow: bool) -> io::Result<Self> {
-        let state = Arc::new(WatcherState {
+        let state = Self::new_state();
+        #[cfg(test)]
+        let watcher = OsWatcher::new(_slow, state.clone())?;
+        #[cfg(not(test))]
+        let watcher = OsWatcher::new(state.clone())?;
+
+        Ok(Self {
+            state,
+            notify: Arc::new(Notify::Native(watcher)),
+        })
+    }
+
+    fn new_state() -> Arc<WatcherState> {
+        Arc::new(WatcherState {
             config: Mutex::new(Config {
-                filter: Arc::new(()),
                 settle_time: Duration::from_millis(200),
+                max_settle_time: None,
+                settle_threshold: 0,
+                min_recrawl_interval: Duration::from_millis(500),
+                max_recrawl_interval: Duration::from_secs(30),
+                defer_empty_creates: None,
+                coalesce_deletes: None,
+                min_modified_interval: None,
+                hash_contents: None,
+                report_metadata_changes: false,
+                report_directories: false,
+                preserve_order: false,
+                coalesce_policy: Arc::new(DefaultCoalescePolicy),
+                track_modifications: false,
+                track_hardlinks: false,
+                case_insensitive: false,
+                capacity_hint: 1024,
+                #[cfg(feature = "parallel")]
+                parallel_crawl: true,
+                max_root_entries: None,
+                max_watches: None,
+                max_batch: None,
+                max_pending_changes: None,
+                display_root: None,
+                report_tempfiles: true,
+                report_vanished_files: false,
+                poll_interval: Duration::from_secs(1),
+                read_buffer_size: None,
                 handlers: Vec::new(),
+                symlink_loop_handler: None,
+                error_handler: None,
+                overflow_handler: None,
+                recrawl_handler: None,
+                root_removed_handler: None,
             }),
+            filter: ArcSwap::from_pointee(RootFilter::new(Arc::new(DefaultFilter::default()))),
+            canonicalize: ArcSwap::from_pointee(CanonicalizeHook(Arc::new(|path| {
+                path.canonicalize()
+            }))),
             notifications: Mutex::new(Notifications::default()),
             has_notifications: AtomicBool::new(false),
-            #[cfg(test)]
+            roots: Mutex::new(Vec::new()),
             recrawls: AtomicUsize::new(0),
-        });
-        #[cfg(test)]
-        let watcher = InotifyWatcher::new(_slow, state.clone())?;
-        #[cfg(not(test))]
-        let watcher = InotifyWatcher::new(state.clone())?;
-
-        Ok(Self {
-            state,
-            notify: watcher,
+            paused: AtomicBool::new(false),
+            started: AtomicBool::new(false),
+            worker: Mutex::new(None),
+            worker_thread: OnceLock::new(),
         })
     }
 
-    pub fn start(&self) {
+    /// Spawns the worker thread (named `filesentry-worker`) that processes
+    /// changes and dispatches events.
+    ///
+    /// A watcher (including all its clones) can only be started once, later
+    /// calls fail with [`StartError::AlreadyStarted`].
+    ///
+    /// Methods that ask the worker for an answer (like [`compact`](Self::compact))
+    /// block until it is answered. Before the watcher is started, or when called
+    /// from a handler, they return right away with a default value instead.
+    pub fn start(&self) -> Result<(), StartError> {
+        self.start_on(thread::Builder::new().name("filesentry-worker".into()))
+    }
+
+    /// Like [`start`](Self::start) but spawns the worker thread with
+    /// `builder`, for example to change its name or stack size.
+    pub fn start_on(&self, builder: thread::Builder) -> Result<(), StartError> {
+        if self.state.started.swap(true, atomic::Ordering::AcqRel) {
+            return Err(StartError::AlreadyStarted);
+        }
         let watcher = self.clone();
-        std::thread::spawn(move || {
+        let worker = builder.spawn(move || {
+            let _ = watcher.state.worker_thread.set(thread::current().id());
             let worker = Worker::new(watcher);
             worker.run();
         });
+        let worker = match worker {
+            Ok(worker) => worker,
+            Err(err) => {
+                self.state.started.store(false, atomic::Ordering::Release);
+                return Err(StartError::Spawn(err));
+            }
+        };
+        *self.state.worker.lock().unwrap() = Some(worker);
+        Ok(())
+    }
+
+    /// Runs the worker on the calling thread instead of spawning one,
+    /// blocks until the watcher is shut down (by a clone of the watcher or a
+    /// [`ShutdownOnDrop`] guard). [`shutdown_and_join`](Self::shutdown_and_join)
+    /// doesn't wait for a worker started this way.
+    pub fn run_on_current_thread(self) -> Result<(), StartError> {
+        if self.state.started.swap(true, atomic::Ordering::AcqRel) {
+            return Err(StartError::AlreadyStarted);
+        }
+        let _ = self.state.worker_thread.set(thread::current().id());
+        Worker::new(self).run();
+        Ok(())
     }
 }
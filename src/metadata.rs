@@ -1,25 +1,35 @@
 use std::time::SystemTime;
 
-#[cfg(unix)]
 use crate::path::CannonicalPath;
 
+/// The result of stat-ing a file, see [`Filter::ignore_metadata`](crate::Filter::ignore_metadata).
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Metadata {
     pub is_dir: bool,
     pub mtime: SystemTime,
+    /// the time the metadata (or contents) last changed, only available on
+    /// unix, the mtime elsewhere
+    pub ctime: SystemTime,
     pub size: usize,
     pub inode: u64,
+    pub dev: u64,
 }
 
 impl Metadata {
+    /// stats `path`, symlinks are only resolved if `follow_symlinks` is set
     #[cfg(unix)]
-    pub fn for_path(path: &CannonicalPath) -> Option<Metadata> {
+    pub(crate) fn for_path(path: &CannonicalPath, follow_symlinks: bool) -> Option<Metadata> {
         use std::time::Duration;
 
-        use rustix::fs::{lstat, FileType};
+        use rustix::fs::{lstat, stat, FileType};
         use rustix::io::Errno;
 
-        let stat = match lstat(path) {
+        let stat = if follow_symlinks {
+            stat(path)
+        } else {
+            lstat(path)
+        };
+        let stat = match stat {
             Ok(stat) => stat,
             Err(Errno::NOTDIR | Errno::NOENT) => {
                 return None;
@@ -31,6 +41,7 @@ impl Metadata {
         };
 
         let mtime = Duration::new(stat.st_mtime as u64, stat.st_mtime_nsec as u32);
+        let ctime = Duration::new(stat.st_ctime as u64, stat.st_ctime_nsec as u32);
         let is_dir = match FileType::from_raw_mode(stat.st_mode) {
             FileType::RegularFile => false,
             FileType::Directory => true,
@@ -39,8 +50,44 @@ impl Metadata {
         Some(Metadata {
             is_dir,
             mtime: SystemTime::UNIX_EPOCH + mtime,
+            ctime: SystemTime::UNIX_EPOCH + ctime,
             size: stat.st_size as usize,
             inode: stat.st_ino,
+            dev: stat.st_dev as u64,
+        })
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn for_path(path: &CannonicalPath, follow_symlinks: bool) -> Option<Metadata> {
+        use std::io;
+
+        let meta = if follow_symlinks {
+            std::fs::metadata(path.as_std_path())
+        } else {
+            std::fs::symlink_metadata(path.as_std_path())
+        };
+        let meta = match meta {
+            Ok(meta) => meta,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                log::error!("failed to stat {path:?}: {err}");
+                return None;
+            }
+        };
+        let file_type = meta.file_type();
+        if !file_type.is_file() && !file_type.is_dir() {
+            return None;
+        }
+        let mtime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        Some(Metadata {
+            is_dir: file_type.is_dir(),
+            mtime,
+            // the change time is not exposed by std
+            ctime: mtime,
+            size: meta.len() as usize,
+            // file ids are not exposed by std
+            inode: 0,
+            dev: 0,
         })
     }
 }
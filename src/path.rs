@@ -1,11 +1,15 @@
+use std::borrow::Borrow;
 use std::cmp::Ordering;
-use std::ffi::{CStr, OsStr};
+#[cfg(unix)]
+use std::ffi::CStr;
+use std::ffi::OsStr;
 use std::fmt::{Debug, Display};
-use std::hash::{Hash, Hasher};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
 use std::mem::transmute;
 use std::ops::Deref;
 use std::path::Path;
-use std::slice;
+use std::{iter, slice};
 
 #[cfg(unix)]
 const PATH_SEPARATOR: u8 = b'/';
@@ -13,7 +17,7 @@ const PATH_SEPARATOR: u8 = b'/';
 const PATH_SEPARATOR: u8 = b'\\';
 
 use ecow::EcoVec;
-use memchr::memrchr;
+use memchr::{memchr, memrchr};
 
 #[repr(transparent)]
 #[derive(PartialEq, Eq)]
@@ -43,6 +47,22 @@ impl CannonicalPath {
         Some(Path::new(path))
     }
 
+    /// Iterates over this path and its ancestors like [`Path::ancestors`],
+    /// but by splitting the bytes at the separators. The last ancestor of an
+    /// absolute path is the empty path. The prefixes aren't null terminated
+    /// so (like [`parent`](Self::parent)) they are returned as [`Path`]s.
+    pub fn ancestors(&self) -> impl Iterator<Item = &Path> + '_ {
+        iter::successors(Some(self.as_std_path()), |path| {
+            let bytes = path.as_os_str().as_encoded_bytes();
+            let i = memrchr(PATH_SEPARATOR, bytes)?;
+            // safety: the path is split at an ascii separator so the prefix
+            // is still a valid OsStr
+            Some(Path::new(unsafe {
+                OsStr::from_encoded_bytes_unchecked(&bytes[..i])
+            }))
+        })
+    }
+
     pub fn join(&self, other: &OsStr) -> CanonicalPathBuf {
         if self.is_empty() {
             let mut res = CanonicalPathBuf::new();
@@ -56,6 +76,30 @@ impl CannonicalPath {
         }
     }
 
+    /// Joins all `components` onto this path, the result is identical to calling
+    /// [`join`](Self::join) for each component but only allocates once.
+    pub fn join_all<I>(&self, components: I) -> CanonicalPathBuf
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+        I::IntoIter: Clone,
+    {
+        let components = components.into_iter();
+        // each component is prefixed with a separator, +1 for the null terminator
+        let capacity = components
+            .clone()
+            .map(|component| component.as_ref().len() + 1)
+            .sum::<usize>()
+            + self.bytes.len()
+            + 1;
+        let mut res = CanonicalPathBuf::with_capacity(capacity);
+        res.buf.extend_from_slice(&self.bytes);
+        for component in components {
+            res.push(component);
+        }
+        res
+    }
+
     fn as_raw_bytes(&self) -> &[u8] {
         if self.bytes.is_empty() {
             EMPTY
@@ -98,11 +142,133 @@ impl CannonicalPath {
         unsafe { CStr::from_bytes_with_nul_unchecked(self.as_raw_bytes()) }
     }
 
+    /// Like `==` but ASCII letters are compared case-insensitively if
+    /// `fold_case` is set, see `hash_path`.
+    pub fn eq_path(&self, other: impl AsRef<OsStr>, fold_case: bool) -> bool {
+        let other = other.as_ref().as_encoded_bytes();
+        if fold_case {
+            self.as_bytes().eq_ignore_ascii_case(other)
+        } else {
+            self.as_bytes() == other
+        }
+    }
+
     pub fn is_parent_of(&self, other: &CannonicalPath) -> bool {
-        other.as_bytes().starts_with(self.as_bytes()) && other.bytes[self.len()] == PATH_SEPARATOR
+        self.is_parent_of_path(other, false)
+    }
+
+    /// Like `is_parent_of` but ASCII letters are compared case-insensitively
+    /// if `fold_case` is set, see `hash_path`.
+    pub fn is_parent_of_path(&self, other: &CannonicalPath, fold_case: bool) -> bool {
+        let (parent, child) = (self.as_bytes(), other.as_bytes());
+        if child.len() <= parent.len() || child[parent.len()] != PATH_SEPARATOR {
+            return false;
+        }
+        if fold_case {
+            child[..parent.len()].eq_ignore_ascii_case(parent)
+        } else {
+            child.starts_with(parent)
+        }
+    }
+
+    /// Like `cmp` but ASCII letters are compared case-insensitively if
+    /// `fold_case` is set, see `hash_path`. Consistent with
+    /// `is_parent_of_path`, a path sorts directly before its children.
+    pub fn cmp_path(&self, other: &CannonicalPath, fold_case: bool) -> Ordering {
+        cmp(&self.bytes, &other.bytes, fold_case)
+    }
+
+    /// Iterates over the names of all components of this path, like the
+    /// `Normal` components of [`Path::components`]. Doesn't allocate.
+    pub fn components(&self) -> impl Iterator<Item = &OsStr> + '_ {
+        let mut bytes = if self.is_empty() {
+            &[][..]
+        } else {
+            self.as_bytes()
+        };
+        iter::from_fn(move || loop {
+            if bytes.is_empty() {
+                return None;
+            }
+            let end = memchr(PATH_SEPARATOR, bytes).unwrap_or(bytes.len());
+            let component = &bytes[..end];
+            bytes = &bytes[(end + 1).min(bytes.len())..];
+            if !component.is_empty() {
+                // safety: the path is split at an ascii separator so each
+                // component is still a valid OsStr
+                return Some(unsafe { OsStr::from_encoded_bytes_unchecked(component) });
+            }
+        })
+    }
+
+    /// Returns the path relative to `base` (without a leading separator) or
+    /// `None` if `base` isn't a parent of this path. Relative to itself a
+    /// path is empty.
+    pub fn strip_prefix(&self, base: &CannonicalPath) -> Option<&CannonicalPath> {
+        let start = if base.is_parent_of(self) {
+            base.len() + 1
+        } else if self == base {
+            // an empty path has no null terminator
+            self.bytes.len()
+        } else {
+            return None;
+        };
+        // safety: repr(transparent), the suffix starts after a separator
+        // (or is empty) so it's still a valid path
+        Some(unsafe { transmute::<&[u8], &CannonicalPath>(&self.bytes[start..]) })
+    }
+
+    /// Replaces `from` with `to` if `from` is this path or one of its
+    /// parents, used to update paths below a moved directory.
+    pub fn replace_prefix(
+        &self,
+        from: &CannonicalPath,
+        to: &CanonicalPathBuf,
+    ) -> Option<CanonicalPathBuf> {
+        self.replace_prefix_path(from, to, false)
+    }
+
+    /// Like `replace_prefix` but ASCII letters are compared
+    /// case-insensitively if `fold_case` is set, see `hash_path`.
+    pub fn replace_prefix_path(
+        &self,
+        from: &CannonicalPath,
+        to: &CanonicalPathBuf,
+        fold_case: bool,
+    ) -> Option<CanonicalPathBuf> {
+        if self.eq_path(from.as_os_str(), fold_case) {
+            return Some(to.clone());
+        }
+        if !from.is_parent_of_path(self, fold_case) {
+            return None;
+        }
+        let rel = &self.as_bytes()[from.len() + 1..];
+        // safety: the suffix starts after an ascii separator so it's still
+        // a valid OsStr
+        Some(to.join(unsafe { OsStr::from_encoded_bytes_unchecked(rel) }))
     }
 }
 
+/// Hashes `path` consistently with the `Hash` impls of the path types. If
+/// `fold_case` is set ASCII letters are lowercased first so that paths that
+/// only differ in case (see [`CannonicalPath::eq_path`]) hash the same.
+pub fn hash_path(hasher: &impl BuildHasher, path: &OsStr, fold_case: bool) -> u64 {
+    if !fold_case {
+        return hasher.hash_one(path);
+    }
+    let bytes = path.as_encoded_bytes();
+    let mut state = hasher.build_hasher();
+    state.write_usize(bytes.len());
+    let mut buf = [0; 64];
+    for chunk in bytes.chunks(buf.len()) {
+        let buf = &mut buf[..chunk.len()];
+        buf.copy_from_slice(chunk);
+        buf.make_ascii_lowercase();
+        state.write(buf);
+    }
+    state.finish()
+}
+
 /// A custom PathBuf type that has some desirable properties:
 ///
 /// * only 2 words size reducing memory pressure
@@ -122,11 +288,11 @@ impl PartialOrd for CanonicalPathBuf {
 }
 impl Ord for CanonicalPathBuf {
     fn cmp(&self, other: &Self) -> Ordering {
-        cmp(&self.buf, &other.buf)
+        cmp(&self.buf, &other.buf, false)
     }
 }
 
-fn cmp(lhs: &[u8], rhs: &[u8]) -> Ordering {
+fn cmp(lhs: &[u8], rhs: &[u8], fold_case: bool) -> Ordering {
     // Since the length of a slice is always less than or equal to
     // isize::MAX, this never underflows.
     let diff = lhs.len() as isize - rhs.len() as isize;
@@ -145,10 +311,21 @@ fn cmp(lhs: &[u8], rhs: &[u8]) -> Ordering {
     // we use some unsafe
     let lhs_ = unsafe { slice::from_raw_parts(lhs.as_ptr(), prefix_len) };
     let rhs_ = unsafe { slice::from_raw_parts(rhs.as_ptr(), prefix_len) };
-    lhs_.cmp(rhs_).then_with(|| match diff.cmp(&0) {
-        Ordering::Less => PATH_SEPARATOR.cmp(unsafe { rhs.get_unchecked(prefix_len) }),
+    let prefix = if fold_case {
+        let lhs_ = lhs_.iter().map(u8::to_ascii_lowercase);
+        lhs_.cmp(rhs_.iter().map(u8::to_ascii_lowercase))
+    } else {
+        lhs_.cmp(rhs_)
+    };
+    // a path always sorts before its children
+    prefix.then_with(|| match diff.cmp(&0) {
+        Ordering::Less => PATH_SEPARATOR
+            .cmp(unsafe { rhs.get_unchecked(prefix_len) })
+            .then(Ordering::Less),
         Ordering::Equal => Ordering::Equal,
-        Ordering::Greater => unsafe { lhs.get_unchecked(prefix_len) }.cmp(&PATH_SEPARATOR),
+        Ordering::Greater => unsafe { lhs.get_unchecked(prefix_len) }
+            .cmp(&PATH_SEPARATOR)
+            .then(Ordering::Greater),
     })
 }
 
@@ -164,12 +341,14 @@ impl CanonicalPathBuf {
         res
     }
 
-    // pub fn from_std_path(path: &Path) -> io::Result<CanonicalPathBuf> {
-    //     let canonicalized = path.canonicalize()?.into_os_string();
-    //     let mut res = Self::with_capacity(canonicalized.len() + 1);
-    //     res.push(canonicalized.as_os_str());
-    //     Ok(res)
-    // }
+    /// Canonicalizes `path` with [`Path::canonicalize`], which fails if the
+    /// path doesn't exist.
+    pub fn from_std_path(path: &Path) -> io::Result<CanonicalPathBuf> {
+        let canonicalized = path.canonicalize()?.into_os_string();
+        let mut res = Self::with_capacity(canonicalized.len() + 2);
+        res.push(canonicalized.as_os_str());
+        Ok(res)
+    }
 
     fn with_capacity(cap: usize) -> CanonicalPathBuf {
         Self {
@@ -237,6 +416,12 @@ impl Deref for CanonicalPathBuf {
     }
 }
 
+impl Borrow<CannonicalPath> for CanonicalPathBuf {
+    fn borrow(&self) -> &CannonicalPath {
+        self
+    }
+}
+
 impl Debug for CanonicalPathBuf {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.as_std_path().fmt(f)
@@ -319,3 +504,67 @@ impl<T: AsRef<OsStr>> PartialEq<T> for CanonicalPathBuf {
         self.as_os_str() == other.as_ref()
     }
 }
+
+/// Human readable formats store the path as a (lossy) UTF-8 string, binary
+/// formats store the raw bytes (without the null terminator).
+#[cfg(feature = "serde")]
+impl serde::Serialize for CanonicalPathBuf {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.as_std_path().to_string_lossy())
+        } else {
+            serializer.serialize_bytes(self.as_bytes())
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for CanonicalPathBuf {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = CanonicalPathBuf;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a canonicalized path")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, path: &str) -> Result<Self::Value, E> {
+                self.visit_bytes(path.as_bytes())
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, path: &[u8]) -> Result<Self::Value, E> {
+                if path.contains(&0) {
+                    return Err(E::custom("path contains a null byte"));
+                }
+                if path.is_empty() {
+                    return Ok(CanonicalPathBuf::new());
+                }
+                #[cfg(unix)]
+                let path = <OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(path);
+                // the encoding of an OsStr is not specified on windows
+                #[cfg(windows)]
+                let path: &OsStr = std::str::from_utf8(path).map_err(E::custom)?.as_ref();
+                Ok(CanonicalPathBuf::assert_canonicalized(Path::new(path)))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut path = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    path.push(byte);
+                }
+                self.visit_bytes(&path)
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(Visitor)
+        } else {
+            deserializer.deserialize_bytes(Visitor)
+        }
+    }
+}
@@ -1,7 +1,9 @@
+#[cfg(any(target_os = "linux", windows))]
+use std::collections::VecDeque;
 use std::hash::BuildHasher;
 use std::mem::{swap, take};
 use std::sync::{Condvar, Mutex, MutexGuard};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use bitflags::bitflags;
 use hashbrown::hash_table::Entry;
@@ -9,8 +11,15 @@ use hashbrown::{DefaultHashBuilder, HashTable};
 
 use crate::path::CanonicalPathBuf;
 
+/// The kernel queues `MOVED_FROM` and `MOVED_TO` back to back, so a rename
+/// within the watched tree is paired right away (even with several renames
+/// in flight). Only moves out of the tree are never paired, this bounds the
+/// memory they hold on to.
+#[cfg(any(target_os = "linux", windows))]
+const MAX_UNPAIRED_MOVES: usize = 64;
+
 bitflags! {
-    #[derive(Clone, Copy, Debug)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct Flags: u32 {
         /// for directories: do a recursive crawl
         const NEEDS_RECURSIVE_CRAWL = 1;
@@ -22,9 +31,50 @@ bitflags! {
         const MARK_RECURSIVE = 4;
         /// change originated from a watcher
         const ORIGIN_WATCHER = 8;
+        /// a write to a file that may still be open, held back until the file
+        /// is closed, see `Watcher::set_only_on_close`
+        const AWAIT_CLOSE = 16;
+        /// a file was closed after writing, only reported as modified if its
+        /// metadata changed since the writes may have been applied already
+        const CLOSED = 32;
+        /// flags that are only kept if all consolidated changes have them
+        const EXCLUSIVE = Self::AWAIT_CLOSE.bits() | Self::CLOSED.bits();
     }
 }
 
+/// The work the watcher has scheduled for a path it observed a change for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PendingKind {
+    /// the path will be stat-ed and compared to the file tree
+    Stat,
+    /// the path will be stat-ed and (if it's a directory) crawled
+    Crawl,
+    /// the path will be stat-ed and (if it's a directory) crawled recursively
+    RecursiveCrawl,
+    /// all roots will be recrawled, this is reported with an empty path
+    Recrawl,
+}
+
+/// Why all roots are recrawled, see [`Watcher::on_recrawl`](crate::Watcher::on_recrawl).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecrawlCause {
+    /// the event queue of the backend overflowed and events were lost
+    QueueOverflow,
+    /// the backend couldn't tell which paths changed, for example because
+    /// a watch it didn't know about was removed
+    BackendRescan,
+    /// the backend couldn't watch all directories, see
+    /// [`Watcher::set_max_watches`](crate::Watcher::set_max_watches)
+    WatchLimit,
+    /// more changes were queued than allowed by
+    /// [`Watcher::set_max_pending_changes`](crate::Watcher::set_max_pending_changes)
+    MaxPendingChanges,
+    /// the filter was replaced with [`Watcher::set_filter`](crate::Watcher::set_filter)
+    FilterChanged,
+    /// the watcher was resumed with [`Watcher::resume`](crate::Watcher::resume)
+    Resumed,
+}
+
 #[derive(Debug, Default)]
 pub struct PendingChangesLock {
     inner: Mutex<PendingChanges>,
@@ -47,7 +97,7 @@ impl PendingChangesLock {
         if res.timed_out() {
             return true;
         }
-        swap(&mut *guard, dst);
+        guard.swap(dst);
         false
     }
 
@@ -57,29 +107,78 @@ impl PendingChangesLock {
             .condvar
             .wait_while(guard, |changes| changes.is_empty() && !exit())
             .unwrap();
-        swap(&mut *guard, dst);
+        guard.swap(dst);
+    }
+
+    /// like `take` but doesn't wait for changes
+    pub fn take_now(&self, dst: &mut PendingChanges) {
+        self.lock().swap(dst);
     }
 
     pub fn lock(&self) -> MutexGuard<'_, PendingChanges> {
         self.inner.lock().unwrap()
     }
 
+    /// Wakes up the worker. Callers update the state checked by `exit`
+    /// (like the shutdown flag) without holding the lock, acquiring it here
+    /// ensures the worker either sees the update or is already waiting.
     pub fn notify(&self) {
+        drop(self.lock());
         self.condvar.notify_all();
     }
+
+    pub fn set_max_len(&self, max_len: Option<usize>) {
+        self.lock().max_len = max_len;
+    }
+}
+
+/// `MOVED_FROM` events still waiting for the matching `MOVED_TO`. Kept by
+/// the backend instead of `PendingChanges` so that a rename is still paired
+/// if the worker takes the changes in between.
+#[cfg(any(target_os = "linux", windows))]
+#[derive(Debug, Default)]
+pub struct UnpairedMoves {
+    moves: VecDeque<(u32, CanonicalPathBuf)>,
+}
+
+#[cfg(any(target_os = "linux", windows))]
+impl UnpairedMoves {
+    /// Remembers the source of a rename until the matching `MOVED_TO`
+    /// (with the same `cookie`) arrives.
+    pub fn moved_from(&mut self, cookie: u32, path: CanonicalPathBuf) {
+        // files moved out of the watched tree never receive a `MOVED_TO`
+        if self.moves.len() >= MAX_UNPAIRED_MOVES {
+            self.moves.pop_front();
+        }
+        self.moves.push_back((cookie, path));
+    }
+
+    pub fn moved_to(&mut self, cookie: u32, path: CanonicalPathBuf, changes: &mut PendingChanges) {
+        if let Some(i) = self.moves.iter().position(|&(it, _)| it == cookie) {
+            let (_, from) = self.moves.remove(i).unwrap();
+            changes.add_rename(from, path);
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct PendingChange {
     pub path: CanonicalPathBuf,
     pub flags: Flags,
+    /// when the change was observed
+    pub timestamp: SystemTime,
 }
 
 impl PendingChange {
-    fn consolidate(&mut self, mut new: Flags) {
-        // TODO: is this really  needed
-        new.remove(Flags::ORIGIN_WATCHER);
+    /// Merges another change to the same path. The crawl flags and
+    /// `ORIGIN_WATCHER` are combined so that the strongest request wins
+    /// regardless of the order the changes arrived in, the `EXCLUSIVE`
+    /// flags only survive if both changes have them.
+    fn consolidate(&mut self, mut new: Flags, timestamp: SystemTime) {
+        self.flags.remove(Flags::EXCLUSIVE.difference(new));
+        new.remove(Flags::EXCLUSIVE);
         self.flags.insert(new);
+        self.timestamp = self.timestamp.max(timestamp);
     }
 }
 
@@ -88,21 +187,31 @@ pub struct PendingChanges {
     path_set: HashTable<u32>,
     state: DefaultHashBuilder,
     changes: Vec<PendingChange>,
-    recrawl: bool,
+    /// paired renames (from, to)
+    renames: Vec<(CanonicalPathBuf, CanonicalPathBuf)>,
+    /// the cause of the first recrawl requested since the last `take_recrawl`
+    recrawl: Option<RecrawlCause>,
+    /// once more changes accumulate a recrawl is scheduled instead
+    max_len: Option<usize>,
 }
 
 impl std::fmt::Debug for PendingChanges {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("PendingChanges")
             .field("changes", &self.changes)
+            .field("renames", &self.renames)
             .field("recrawl", &self.recrawl)
             .finish()
     }
 }
 
 impl PendingChanges {
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.changes.is_empty() & !self.recrawl
+        self.changes.is_empty() & self.recrawl.is_none()
     }
 
     // pub fn remove(&mut self, path: impl AsRef<OsStr>) -> bool {
@@ -120,14 +229,28 @@ impl PendingChanges {
     //     }
     // }
 
-    pub fn recrawl(&mut self) {
+    /// discards all changes (and a pending recrawl)
+    pub fn clear(&mut self) {
         self.path_set.clear();
         self.changes.clear();
-        self.recrawl = true;
+        self.renames.clear();
+        self.recrawl = None;
+    }
+
+    pub fn recrawl(&mut self, cause: RecrawlCause) {
+        let cause = self.recrawl.unwrap_or(cause);
+        self.clear();
+        self.recrawl = Some(cause);
+    }
+
+    /// exchanges the changes with `dst`, the cap stays with `self`
+    fn swap(&mut self, dst: &mut PendingChanges) {
+        swap(self, dst);
+        swap(&mut self.max_len, &mut dst.max_len);
     }
 
     fn add(&mut self, change: PendingChange) {
-        if self.recrawl {
+        if self.recrawl.is_some() {
             return;
         }
         let hash = self.state.hash_one(&change.path);
@@ -138,7 +261,14 @@ impl PendingChanges {
         );
         match ent {
             Entry::Occupied(entry) => {
-                self.changes[*entry.get() as usize].consolidate(change.flags);
+                self.changes[*entry.get() as usize].consolidate(change.flags, change.timestamp);
+            }
+            Entry::Vacant(_) if self.max_len.is_some_and(|max| self.changes.len() >= max) => {
+                log::warn!(
+                    "more than {} pending changes, falling back to a recrawl",
+                    self.changes.len()
+                );
+                self.recrawl(RecrawlCause::MaxPendingChanges);
             }
             Entry::Vacant(entry) => {
                 entry.insert(self.changes.len() as u32);
@@ -147,26 +277,130 @@ impl PendingChanges {
         }
     }
 
-    pub fn add_watcher(
-        &mut self,
-        path: CanonicalPathBuf,
-        /* timestamp: SystemTime, */ flags: Flags,
-    ) {
+    pub fn add_watcher(&mut self, path: CanonicalPathBuf, timestamp: SystemTime, flags: Flags) {
         self.add(PendingChange {
             path,
-            // timestamp,
+            timestamp,
             flags: flags | Flags::ORIGIN_WATCHER,
         });
     }
 
-    pub fn take_recrawl(&mut self) -> bool {
-        take(&mut self.recrawl)
+    pub fn add_rename(&mut self, from: CanonicalPathBuf, to: CanonicalPathBuf) {
+        self.renames.push((from, to));
     }
 
-    pub fn drain(&mut self) -> impl Iterator<Item = PendingChange> + '_ {
-        self.path_set.clear();
+    pub fn take_renames(&mut self) -> Vec<(CanonicalPathBuf, CanonicalPathBuf)> {
+        take(&mut self.renames)
+    }
+
+    pub fn snapshot(&self) -> Vec<(CanonicalPathBuf, PendingKind)> {
+        if self.recrawl.is_some() {
+            return vec![(CanonicalPathBuf::new(), PendingKind::Recrawl)];
+        }
         self.changes
-            .sort_unstable_by(|change1, change2| change1.path.cmp(&change2.path));
+            .iter()
+            .map(|change| {
+                let kind = if change.flags.contains(Flags::NEEDS_RECURSIVE_CRAWL) {
+                    PendingKind::RecursiveCrawl
+                } else if change.flags.contains(Flags::NEEDS_NON_RECURSIVE_CRAWL) {
+                    PendingKind::Crawl
+                } else {
+                    PendingKind::Stat
+                };
+                (change.path.clone(), kind)
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, path: &CanonicalPathBuf) -> bool {
+        self.path_set
+            .find(self.state.hash_one(path), |&i| {
+                self.changes[i as usize].path == *path
+            })
+            .is_some()
+    }
+
+    /// removes and returns the writes to files that weren't closed yet
+    pub fn take_awaiting_close(&mut self) -> Vec<PendingChange> {
+        if !self
+            .changes
+            .iter()
+            .any(|change| change.flags.contains(Flags::AWAIT_CLOSE))
+        {
+            return Vec::new();
+        }
+        let (awaiting, changes) = take(&mut self.changes)
+            .into_iter()
+            .partition(|change| change.flags.contains(Flags::AWAIT_CLOSE));
+        self.changes = changes;
+        self.path_set.clear();
+        for (i, change) in self.changes.iter().enumerate() {
+            self.path_set
+                .insert_unique(self.state.hash_one(&change.path), i as u32, |&i| {
+                    self.state.hash_one(&self.changes[i as usize].path)
+                });
+        }
+        awaiting
+    }
+
+    pub fn take_recrawl(&mut self) -> Option<RecrawlCause> {
+        self.recrawl.take()
+    }
+
+    /// Drains the changes sorted by path. With `preserve_order` the changes
+    /// are drained in the order they were first observed instead, except
+    /// that a directory is moved before the first of its descendants.
+    /// `fold_case` must match the tree so that differently cased changes
+    /// within a directory still sort after it.
+    pub fn drain(
+        &mut self,
+        preserve_order: bool,
+        fold_case: bool,
+    ) -> impl Iterator<Item = PendingChange> + '_ {
+        self.path_set.clear();
+        if preserve_order {
+            self.sort_by_observation(fold_case);
+        } else {
+            self.changes.sort_unstable_by(|change1, change2| {
+                change1.path.cmp_path(&change2.path, fold_case)
+            });
+        }
         self.changes.drain(..)
     }
+
+    /// the index of a change is its sequence number since changes are only
+    /// ever appended
+    fn sort_by_observation(&mut self, fold_case: bool) {
+        let mut order: Vec<u32> = (0..self.changes.len() as u32).collect();
+        order.sort_unstable_by(|&i, &j| {
+            self.changes[i as usize]
+                .path
+                .cmp_path(&self.changes[j as usize].path, fold_case)
+        });
+        // the earliest sequence number within the subtree of each change,
+        // sorting by path places the descendants of a directory right after it
+        let mut keys: Vec<u32> = (0..self.changes.len() as u32).collect();
+        let mut ancestors: Vec<u32> = Vec::new();
+        for &i in &order {
+            let path = &self.changes[i as usize].path;
+            while ancestors.last().is_some_and(|&dir| {
+                !self.changes[dir as usize]
+                    .path
+                    .is_parent_of_path(path, fold_case)
+            }) {
+                ancestors.pop();
+            }
+            for &dir in &ancestors {
+                keys[dir as usize] = keys[dir as usize].min(i);
+            }
+            ancestors.push(i);
+        }
+        // stable, a directory stays before the descendants that share its key
+        order.sort_by_key(|&i| keys[i as usize]);
+        let mut changes: Vec<_> = take(&mut self.changes).into_iter().map(Some).collect();
+        self.changes = order
+            .into_iter()
+            .map(|i| changes[i as usize].take().unwrap())
+            .collect();
+    }
 }
@@ -0,0 +1,79 @@
+use std::io;
+use std::sync::atomic::{self, AtomicBool};
+
+use crate::path::{CannonicalPath, CanonicalPathBuf};
+use crate::pending::PendingChangesLock;
+use crate::WatchMask;
+
+/// A backend that doesn't use any OS facility to watch for changes, the
+/// worker finds them by recrawling all roots every `Config::poll_interval`.
+#[derive(Debug, Default)]
+pub(crate) struct PollWatcher {
+    shutdown: AtomicBool,
+    delete_only: AtomicBool,
+    pub changes: PendingChangesLock,
+}
+
+impl PollWatcher {
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, atomic::Ordering::Relaxed);
+        self.changes.notify();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(atomic::Ordering::Relaxed)
+    }
+
+    /// symlinks are followed by the crawl, see `RootOptions::follow_symlinks`
+    pub fn set_follow_symlinks(&self, _follow_symlinks: bool) {}
+
+    pub fn set_delete_only(&self, delete_only: bool) {
+        self.delete_only
+            .store(delete_only, atomic::Ordering::Relaxed);
+    }
+
+    pub fn delete_only(&self) -> bool {
+        self.delete_only.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_only_on_close(&self, _only_on_close: bool) {}
+
+    pub fn set_watch_mask(&self, _mask: WatchMask) {}
+
+    pub fn watch_dir(&self, _path: CanonicalPathBuf) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn unwatch_dir(&self, _path: &CannonicalPath) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn unwatch_tree(&self, _root: &CannonicalPath) {}
+
+    pub fn rename_watches(&self, _from: &CannonicalPath, _to: &CanonicalPathBuf) {}
+
+    pub fn refresh_config(&self) {}
+
+    pub fn watch_count(&self) -> usize {
+        0
+    }
+
+    pub fn queue_overflows(&self) -> usize {
+        0
+    }
+
+    pub fn retry_watches(&self) -> usize {
+        0
+    }
+
+    pub fn pending_watch_retries(&self) -> usize {
+        0
+    }
+
+    /// a removed (or recreated) root is found by the next poll
+    pub fn watch_root_parent(&self, _root: &CannonicalPath) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn unwatch_root_parent(&self, _root: &CannonicalPath) {}
+}
@@ -1,13 +1,21 @@
 // use pretty_assertions::assert_eq;
+use std::ffi::OsStr;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, LazyLock, Mutex};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use tempfile::TempDir;
 
-use crate::events::EventType;
-use crate::Watcher;
+use crate::events::{Event, EventType};
+use crate::pending::{self, PendingChanges};
+use crate::{
+    Backend, CanonicalPathBuf, DefaultFilter, Filter, Metadata, PendingKind, RecrawlCause,
+    RootOptions, RootStatus, StartError, Watcher,
+};
 
 static TIMEOUT: LazyLock<Duration> =
     LazyLock::new(|| match std::env::var("FILESENTRY_TEST_TIMEOUT") {
@@ -18,6 +26,7 @@ static TIMEOUT: LazyLock<Duration> =
         _ => Duration::from_secs(20),
     });
 
+#[cfg(target_os = "linux")]
 pub static READ_DELAY: LazyLock<Duration> =
     LazyLock::new(|| match std::env::var("FILESENTRY_READ_DELAY") {
         Ok(res) if !res.trim().is_empty() => Duration::from_millis(
@@ -59,11 +68,7 @@ impl Assertion {
                 return false;
             }
             let mut state = state.lock().unwrap();
-            state.extend(
-                events
-                    .iter()
-                    .map(|event| (event.path.as_std_path().to_owned(), event.ty)),
-            );
+            state.extend(events.iter().map(Event::to_std));
             if state.len() >= len {
                 let _ = tx.send(());
                 false
@@ -110,6 +115,7 @@ fn init_watcher() -> (TempDir, Watcher) {
     init_watcher_imp(false)
 }
 
+#[cfg(target_os = "linux")]
 fn init_watcher_slow() -> (TempDir, Watcher) {
     init_watcher_imp(true)
 }
@@ -124,7 +130,7 @@ fn init_watcher_imp(slow: bool) -> (TempDir, Watcher) {
             let _ = tx.send(success);
         })
         .unwrap();
-    watcher.start();
+    watcher.start().unwrap();
     rx.recv_timeout(*TIMEOUT).expect("failed to start watcher");
     (dir, watcher)
 }
@@ -135,6 +141,7 @@ fn with_watcher(f: impl FnOnce(&Path, &Watcher)) {
     drop(shutdown_guard)
 }
 
+#[cfg(target_os = "linux")]
 fn with_watcher_slow(f: impl FnOnce(&Path, &Watcher)) {
     let (dir, watcher) = init_watcher_slow();
     let shutdown_guard = watcher.shutdown_guard();
@@ -216,9 +223,43 @@ fn modify() {
     });
 }
 
+// kqueue only reports writes to a file on the file itself
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+#[test]
+fn kqueue_file_watches() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("foo/baz", EventType::Create)]);
+        mk_write(dir, "foo/baz", "content1");
+        assertion.check();
+        // the root and foo are watched as well
+        assert_eq!(watcher.stats().watches, 3);
+        let assertion = Assertion::new(watcher, dir, [("foo/baz", EventType::Modified)]);
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("foo/baz"))
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"content2").unwrap();
+        assertion.check();
+        let assertion = Assertion::new(watcher, dir, [("foo/baz", EventType::Delete)]);
+        rm_file(dir, "foo/baz");
+        assertion.check();
+        assert_eq!(watcher.stats().watches, 2);
+    });
+}
+
+// reading events slowly to overflow the queue is specific to inotify
+#[cfg(target_os = "linux")]
 #[test]
 fn queue_overflow() {
     with_watcher_slow(|dir, watcher| {
+        let overflows = Arc::new(AtomicUsize::new(0));
+        let overflows_ = overflows.clone();
+        watcher.on_overflow(move |total| overflows_.store(total, Ordering::Relaxed));
         let files: Vec<_> = (0..20_0000)
             .map(|i| format!("foo{}/bar{i}", i % 200))
             .collect();
@@ -240,10 +281,3100 @@ fn queue_overflow() {
             write(dir, file, "content2");
         }
         assertion.check();
-        let recrawls = watcher.recrawls();
+        let recrawls = watcher.stats().recrawls;
         assert!(
             recrawls >= 2,
             "expected atleast 2 recrawls but found {recrawls}"
-        )
+        );
+        let overflows = overflows.load(Ordering::Relaxed);
+        assert!(overflows > 0 && overflows <= watcher.stats().queue_overflows);
+    });
+}
+
+/// Unlike `queue_overflow` this doesn't depend on the kernel actually
+/// dropping events, the overflow is injected into the backend directly.
+#[cfg(target_os = "linux")]
+#[test]
+fn on_overflow() {
+    use crate::backend::Notify;
+    use crate::inotify::{Event, EventFlags, Watch};
+
+    with_watcher(|_dir, watcher| {
+        let (tx, rx) = mpsc::channel();
+        watcher.on_overflow(move |total| tx.send(total).unwrap());
+        let Notify::Native(notify) = &*watcher.notify else {
+            unreachable!()
+        };
+        for total in 1..=2 {
+            // the kernel reports overflows without a watch
+            let event = Event {
+                wd: Watch::from_raw(-1),
+                child: OsStr::new(""),
+                flags: EventFlags::QUEUE_OVERFLOW,
+                cookie: 0,
+            };
+            notify.handle_event(event, &(), SystemTime::now());
+            // done by the event thread after every batch of events
+            notify.changes.notify();
+            let reported = rx.recv_timeout(*TIMEOUT).expect("overflow not reported");
+            assert_eq!(reported, total);
+        }
+        assert_eq!(watcher.stats().queue_overflows, 2);
+    });
+}
+
+#[test]
+fn small_read_buffer() {
+    with_watcher(|dir, watcher| {
+        // clamped to the size of a single event
+        watcher.set_read_buffer_size(Some(1));
+        let files: Vec<_> = (0..100).map(|i| format!("foo{i}")).collect();
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            files.iter().map(|file| (&**file, EventType::Create)),
+        );
+        for file in &files {
+            write(dir, file, "foo");
+        }
+        assertion.check();
+    });
+}
+
+/// waits until the worker has processed all previously queued notifications
+/// the canonical path of `dir`, used as the root of a `FileTree`
+fn tree_root(dir: &TempDir) -> CanonicalPathBuf {
+    CanonicalPathBuf::assert_canonicalized(&dir.path().canonicalize().unwrap())
+}
+
+/// applies a single change reported for `path` and returns the events
+fn apply_change(
+    tree: &mut crate::tree::FileTree,
+    path: CanonicalPathBuf,
+    flags: pending::Flags,
+) -> Vec<(CanonicalPathBuf, EventType)> {
+    let mut changes = PendingChanges::default();
+    changes.add_watcher(path, SystemTime::now(), flags);
+    let mut events = Vec::new();
+    tree.apply_transaction(
+        &mut changes,
+        &(),
+        |path, ty, _, _, _| events.push((path, ty)),
+        &mut Vec::new(),
+        |_| (),
+        |_| (),
+    );
+    events
+}
+
+fn sync(watcher: &Watcher) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    assert!(watcher
+        .add_root_blocking(dir.path(), false, *TIMEOUT)
+        .expect("watcher didn't respond"));
+    dir
+}
+
+#[test]
+fn add_root_blocking() {
+    let dir = TempDir::new().unwrap();
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    // nobody crawls the root before the worker is started
+    let err = watcher
+        .add_root_blocking(dir.path(), true, Duration::from_millis(50))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    watcher.start().unwrap();
+    let other = TempDir::new().unwrap();
+    assert!(watcher
+        .add_root_blocking(other.path(), true, *TIMEOUT)
+        .unwrap());
+}
+
+#[test]
+fn add_roots() {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+    fs::create_dir(dir.join("a")).unwrap();
+    fs::create_dir(dir.join("b")).unwrap();
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.start().unwrap();
+    let (tx, rx) = mpsc::sync_channel(1);
+    let roots = ["a", "b", "missing"].map(|path| (dir.join(path), true));
+    watcher.add_roots(roots, move |results| {
+        let _ = tx.send(results);
+    });
+    let results = rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond");
+    assert!(results[0].is_ok() && results[1].is_ok());
+    assert_eq!(
+        results[2].as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    let assertion = Assertion::new(
+        &watcher,
+        dir,
+        [("a/foo", EventType::Create), ("b/foo", EventType::Create)],
+    );
+    write(dir, "a/foo", "foo");
+    write(dir, "b/foo", "foo");
+    assertion.check();
+}
+
+#[test]
+fn queued_root_twice() {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+    fs::create_dir(dir.join("inner")).unwrap();
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let (tx, rx) = mpsc::channel();
+    for recursive in [false, true] {
+        let tx = tx.clone();
+        watcher
+            .add_root(dir, recursive, move |success| {
+                let _ = tx.send(success);
+            })
+            .unwrap();
+    }
+    assert_eq!(watcher.state.notifications.lock().unwrap().roots.len(), 1);
+    watcher.start().unwrap();
+    for _ in 0..2 {
+        assert!(rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"));
+    }
+    // the recursive request wins
+    let assertion = Assertion::new(&watcher, dir, [("inner/foo", EventType::Create)]);
+    write(dir, "inner/foo", "foo");
+    assertion.check();
+}
+
+#[test]
+fn non_recursive_root() {
+    let dir = TempDir::new().unwrap();
+    mk_write(dir.path(), "sub/inner", "foo");
+    mk_write(dir.path(), "existing", "foo");
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.set_report_directories(true);
+    watcher.start().unwrap();
+    assert!(watcher
+        .add_root_blocking(dir.path(), false, *TIMEOUT)
+        .unwrap());
+    let dir = dir.path();
+    let assertion = Assertion::new(
+        &watcher,
+        dir,
+        [
+            ("dir", EventType::Create),
+            ("existing", EventType::Modified),
+            ("foo", EventType::Create),
+        ],
+    );
+    write(dir, "existing", "bar");
+    write(dir, "foo", "foo");
+    fs::create_dir(dir.join("dir")).unwrap();
+    assertion.check();
+    // only the direct children are watched
+    let assertion = Assertion::new(&watcher, dir, [("foo", EventType::Modified)]);
+    write(dir, "sub/inner", "bar");
+    write(dir, "dir/inner", "bar");
+    write(dir, "foo", "bar");
+    assertion.check();
+    let assertion = Assertion::new(
+        &watcher,
+        dir,
+        [("foo", EventType::Delete), ("sub", EventType::Delete)],
+    );
+    rm_file(dir, "foo");
+    fs::remove_dir_all(dir.join("sub")).unwrap();
+    assertion.check();
+}
+
+#[test]
+fn watch_once() {
+    with_watcher(|dir, watcher| {
+        let other = TempDir::new().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let tx_ = tx.clone();
+        watcher
+            .watch_once(
+                &other.path().join("sentinel"),
+                &[EventType::Create],
+                move |event| {
+                    let _ = tx_.send(event.to_std());
+                },
+            )
+            .unwrap();
+        watcher
+            .watch_once(&dir.join("lock"), &[EventType::Delete], move |event| {
+                let _ = tx.send(event.to_std());
+            })
+            .unwrap();
+        let _sync = sync(watcher);
+        // events outside of the roots are only reported to the one-shot watch
+        let assertion = Assertion::new(watcher, dir, [("lock", EventType::Create)]);
+        write(other.path(), "sentinel", "done");
+        write(other.path(), "unrelated", "foo");
+        write(dir, "lock", "");
+        assertion.check();
+        let assertion = Assertion::new(watcher, dir, [("lock", EventType::Delete)]);
+        rm_file(dir, "lock");
+        assertion.check();
+        let mut fired = vec![
+            rx.recv_timeout(*TIMEOUT).unwrap(),
+            rx.recv_timeout(*TIMEOUT).unwrap(),
+        ];
+        let mut expected = vec![
+            (dir.join("lock"), EventType::Delete),
+            (other.path().join("sentinel"), EventType::Create),
+        ];
+        fired.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(fired, expected);
+        // the watch fires only once
+        let assertion = Assertion::new(watcher, dir, [("lock", EventType::Create)]);
+        write(other.path(), "sentinel2", "foo");
+        rm_file(other.path(), "sentinel");
+        write(dir, "lock", "");
+        assertion.check();
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    });
+}
+
+#[test]
+fn join_all() {
+    let root = CanonicalPathBuf::assert_canonicalized(Path::new("/foo"));
+    let components = ["bar", "baz/qux", "/quux"];
+    let mut expected = root.clone();
+    for component in components {
+        expected = expected.join(OsStr::new(component));
+    }
+    let joined = root.join_all(components);
+    assert_eq!(joined, expected);
+    assert_eq!(joined.as_std_path(), Path::new("/foo/bar/baz/qux/quux"));
+    assert_eq!(
+        CanonicalPathBuf::new().join_all(["foo", "bar"]),
+        CanonicalPathBuf::new()
+            .join(OsStr::new("foo"))
+            .join(OsStr::new("bar"))
+    );
+}
+
+#[test]
+fn components() {
+    let path = CanonicalPathBuf::assert_canonicalized(Path::new("/foo/bar.rs/baz"));
+    assert!(path.components().eq(["foo", "bar.rs", "baz"]));
+    let expected = path
+        .as_std_path()
+        .components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name),
+            _ => None,
+        });
+    assert!(path.components().eq(expected));
+    let root = CanonicalPathBuf::assert_canonicalized(Path::new("/foo"));
+    assert!(path
+        .strip_prefix(&root)
+        .unwrap()
+        .components()
+        .eq(["bar.rs", "baz"]));
+    assert_eq!(CanonicalPathBuf::new().components().count(), 0);
+}
+
+#[test]
+fn ancestors() {
+    let path = CanonicalPathBuf::assert_canonicalized(Path::new("/foo/bar.rs/baz"));
+    assert!(path
+        .ancestors()
+        .eq(["/foo/bar.rs/baz", "/foo/bar.rs", "/foo", ""].map(Path::new)));
+    assert!(CanonicalPathBuf::new().ancestors().eq([Path::new(".")]));
+}
+
+#[test]
+fn strip_prefix() {
+    let root = CanonicalPathBuf::assert_canonicalized(Path::new("/foo"));
+    let path = root.join_all(["bar", "baz"]);
+    let rel = path.strip_prefix(&root).unwrap();
+    assert_eq!(rel.as_std_path(), Path::new("bar/baz"));
+    assert_eq!(rel.len(), "bar/baz".len());
+    assert!(path
+        .strip_prefix(&CanonicalPathBuf::assert_canonicalized(Path::new("/fo")))
+        .is_none());
+    assert!(root.strip_prefix(&path).is_none());
+    assert!(root.strip_prefix(&root).unwrap().is_empty());
+}
+
+#[test]
+fn path_order() {
+    // a path sorts directly before its children, as if it ended with a separator
+    let sorted = ["/a-b", "/a", "/a/b", "/a/b/c", "/a/c", "/a0", "/ab", "/b"]
+        .map(|path| CanonicalPathBuf::assert_canonicalized(Path::new(path)));
+    for (i, lhs) in sorted.iter().enumerate() {
+        for (j, rhs) in sorted.iter().enumerate() {
+            assert_eq!(lhs.cmp(rhs), i.cmp(&j), "{lhs:?} vs {rhs:?}");
+        }
+    }
+    let mut shuffled = sorted.clone();
+    shuffled.reverse();
+    shuffled.swap(1, 5);
+    shuffled.sort();
+    assert_eq!(shuffled, sorted);
+}
+
+#[test]
+fn path_order_fold_case() {
+    let path = |path: &str| CanonicalPathBuf::assert_canonicalized(Path::new(path));
+    let (dir, child) = (path("/Dir"), path("/dir/file"));
+    assert!(!dir.is_parent_of(&child));
+    assert!(dir.is_parent_of_path(&child, true));
+    assert!(!path("/Di").is_parent_of_path(&child, true));
+    assert_eq!(
+        child.replace_prefix_path(&dir, &path("/new"), true),
+        Some(path("/new/file"))
+    );
+    assert_eq!(child.replace_prefix_path(&dir, &path("/new"), false), None);
+    // a directory still sorts directly before its differently cased children
+    let sorted = ["/Dir", "/dir/a", "/DIR/b", "/dir0"].map(path);
+    for (i, lhs) in sorted.iter().enumerate() {
+        for (j, rhs) in sorted.iter().enumerate() {
+            assert_eq!(lhs.cmp_path(rhs, true), i.cmp(&j), "{lhs:?} vs {rhs:?}");
+        }
+    }
+    let mut changes = PendingChanges::default();
+    for path in sorted.iter().rev() {
+        changes.add_watcher(path.clone(), SystemTime::now(), pending::Flags::empty());
+    }
+    let drained: Vec<_> = changes
+        .drain(false, true)
+        .map(|change| change.path)
+        .collect();
+    assert_eq!(drained, sorted);
+}
+
+#[test]
+fn from_std_path() {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    mk_write(&dir, "foo/bar", "baz");
+    let path = CanonicalPathBuf::from_std_path(&dir.join("foo/../foo/./bar")).unwrap();
+    assert_eq!(path.as_std_path(), dir.join("foo/bar"));
+    assert_eq!(
+        path,
+        CanonicalPathBuf::assert_canonicalized(&dir.join("foo/bar"))
+    );
+
+    let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+    let path = CanonicalPathBuf::from_std_path(Path::new("src")).unwrap();
+    assert_eq!(path.as_std_path(), cwd.join("src"));
+
+    assert!(CanonicalPathBuf::from_std_path(&dir.join("missing")).is_err());
+}
+
+#[test]
+fn pending_snapshot() {
+    let mut changes = PendingChanges::default();
+    let foo = CanonicalPathBuf::assert_canonicalized(Path::new("/foo"));
+    let bar = CanonicalPathBuf::assert_canonicalized(Path::new("/bar"));
+    changes.add_watcher(foo.clone(), SystemTime::now(), pending::Flags::empty());
+    changes.add_watcher(
+        bar.clone(),
+        SystemTime::now(),
+        pending::Flags::NEEDS_RECURSIVE_CRAWL,
+    );
+    changes.add_watcher(
+        foo.clone(),
+        SystemTime::now(),
+        pending::Flags::NEEDS_NON_RECURSIVE_CRAWL,
+    );
+    assert_eq!(
+        changes.snapshot(),
+        [
+            (foo, PendingKind::Crawl),
+            (bar, PendingKind::RecursiveCrawl)
+        ]
+    );
+    changes.recrawl(RecrawlCause::QueueOverflow);
+    assert_eq!(
+        changes.snapshot(),
+        [(CanonicalPathBuf::new(), PendingKind::Recrawl)]
+    );
+}
+
+#[test]
+fn consolidate_flags() {
+    use pending::Flags;
+
+    let path = CanonicalPathBuf::assert_canonicalized(Path::new("/foo"));
+    let merged = |first: Flags, second: Flags| {
+        let mut changes = PendingChanges::default();
+        changes.add_watcher(path.clone(), SystemTime::now(), first);
+        changes.add_watcher(path.clone(), SystemTime::now(), second);
+        let changes: Vec<_> = changes.drain(false, false).collect();
+        assert_eq!(changes.len(), 1);
+        changes[0].flags
+    };
+    let all = [
+        Flags::empty(),
+        Flags::NEEDS_RECURSIVE_CRAWL,
+        Flags::NEEDS_NON_RECURSIVE_CRAWL,
+        Flags::NEEDS_RECURSIVE_CRAWL | Flags::MARK_RECURSIVE,
+        Flags::AWAIT_CLOSE,
+        Flags::CLOSED,
+    ];
+    for first in all {
+        for second in all {
+            let flags = merged(first, second);
+            // the order the changes arrive in doesn't matter
+            assert_eq!(flags, merged(second, first), "{first:?} {second:?}");
+            assert!(flags.contains(Flags::ORIGIN_WATCHER));
+            let kept = (first | second).difference(Flags::EXCLUSIVE);
+            assert!(flags.contains(kept), "{first:?} {second:?} lost {kept:?}");
+            assert_eq!(
+                flags & Flags::EXCLUSIVE,
+                first & second & Flags::EXCLUSIVE,
+                "{first:?} {second:?}"
+            );
+        }
+    }
+    assert_eq!(
+        merged(Flags::empty(), Flags::NEEDS_RECURSIVE_CRAWL),
+        Flags::NEEDS_RECURSIVE_CRAWL | Flags::ORIGIN_WATCHER
+    );
+    assert_eq!(
+        merged(Flags::AWAIT_CLOSE, Flags::CLOSED),
+        Flags::ORIGIN_WATCHER
+    );
+}
+
+#[test]
+fn drain_preserve_order() {
+    let path = |path: &str| CanonicalPathBuf::assert_canonicalized(Path::new(path));
+    let mut changes = PendingChanges::default();
+    for p in ["/b", "/a/c", "/d", "/a", "/a/b"] {
+        changes.add_watcher(path(p), SystemTime::now(), pending::Flags::empty());
+    }
+    let drained = |changes: &PendingChanges, preserve_order| {
+        changes
+            .clone()
+            .drain(preserve_order, false)
+            .map(|change| change.path)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        drained(&changes, false),
+        ["/a", "/a/b", "/a/c", "/b", "/d"].map(path)
+    );
+    // `/a` moves before its first child
+    assert_eq!(
+        drained(&changes, true),
+        ["/b", "/a", "/a/c", "/d", "/a/b"].map(path)
+    );
+}
+
+#[test]
+fn preserve_order() {
+    with_watcher(|dir, watcher| {
+        watcher.set_preserve_order(true);
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler(move |events| tx.send(events).is_ok());
+        write(dir, "b", "foo");
+        write(dir, "a", "foo");
+        write(dir, "c", "foo");
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            events.extend(rx.recv_timeout(*TIMEOUT).unwrap().iter().cloned());
+        }
+        let paths: Vec<_> = events
+            .iter()
+            .map(|event| event.path.as_std_path().to_owned())
+            .collect();
+        assert_eq!(paths, ["b", "a", "c"].map(|file| dir.join(file)));
+        // the order survives a round trip
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&events).unwrap();
+            let deserialized: crate::Events = serde_json::from_str(&json).unwrap();
+            assert_eq!(*deserialized, events);
+        }
+    });
+}
+
+#[test]
+fn recrawl_cause() {
+    with_watcher(|_dir, watcher| {
+        let (tx, rx) = mpsc::channel();
+        watcher.on_recrawl(move |cause| {
+            let _ = tx.send(cause);
+        });
+        watcher.pause();
+        watcher.resume();
+        assert_eq!(rx.recv_timeout(*TIMEOUT).unwrap(), RecrawlCause::Resumed);
+        watcher.set_filter(Arc::new(()), true);
+        assert_eq!(
+            rx.recv_timeout(*TIMEOUT).unwrap(),
+            RecrawlCause::FilterChanged
+        );
+    });
+}
+
+#[test]
+fn max_pending_changes() {
+    let lock = pending::PendingChangesLock::default();
+    lock.set_max_len(Some(2));
+    let path = |path: &str| CanonicalPathBuf::assert_canonicalized(Path::new(path));
+    let add = |path| {
+        lock.lock()
+            .add_watcher(path, SystemTime::now(), pending::Flags::empty())
+    };
+    add(path("/foo"));
+    add(path("/bar"));
+    // changes to paths that are already queued don't count
+    add(path("/foo"));
+    let mut changes = PendingChanges::default();
+    lock.take(&mut changes, || false);
+    assert_eq!(changes.len(), 2);
+    // the limit stays with the shared queue
+    add(path("/foo"));
+    add(path("/bar"));
+    add(path("/baz"));
+    assert_eq!(
+        lock.lock().snapshot(),
+        [(CanonicalPathBuf::new(), PendingKind::Recrawl)]
+    );
+}
+
+#[test]
+fn await_close() {
+    let path = |path: &str| CanonicalPathBuf::assert_canonicalized(Path::new(path));
+    let now = SystemTime::now();
+    let mut changes = PendingChanges::default();
+    changes.add_watcher(path("/foo"), now, pending::Flags::AWAIT_CLOSE);
+    changes.add_watcher(path("/bar"), now, pending::Flags::AWAIT_CLOSE);
+    // the file was closed
+    changes.add_watcher(path("/bar"), now, pending::Flags::empty());
+    changes.add_watcher(path("/baz"), now, pending::Flags::empty());
+    let awaiting: Vec<_> = changes
+        .take_awaiting_close()
+        .into_iter()
+        .map(|change| change.path)
+        .collect();
+    assert_eq!(awaiting, [path("/foo")]);
+    assert!(!changes.contains(&path("/foo")));
+    assert!(changes.contains(&path("/bar")));
+    assert_eq!(changes.len(), 2);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn only_on_close() {
+    use std::io::Write;
+
+    let dir = TempDir::new().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    let path = root.join("foo");
+    fs::write(&path, "foo").unwrap();
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.set_only_on_close(true);
+    watcher.set_settle_time(Duration::from_millis(100));
+    let (tx, rx) = mpsc::channel();
+    watcher.add_handler(move |events| {
+        for event in events.iter() {
+            let size = event.metadata().map(|meta| meta.size);
+            let _ = tx.send((event.path.as_std_path().to_owned(), event.ty, size));
+        }
+        true
+    });
+    watcher.start().unwrap();
+    assert!(watcher.add_root_blocking(&root, true, *TIMEOUT).unwrap());
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    for _ in 0..3 {
+        file.write_all(b"foo").unwrap();
+        thread::sleep(Duration::from_millis(30));
+    }
+    drop(file);
+    let event = rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond");
+    assert_eq!(event, (path.clone(), EventType::Modified, Some(12)));
+    // a file that is never closed is still picked up after the settle time
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(b"foo").unwrap();
+    let event = rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond");
+    assert_eq!(event, (path.clone(), EventType::Modified, Some(15)));
+    drop(file);
+    assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+}
+
+#[test]
+fn compact() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [
+                ("foo/bar/baz", EventType::Create),
+                ("foo/baz", EventType::Create),
+                ("baz", EventType::Create),
+            ],
+        );
+        mk_write(dir, "foo/bar/baz", "foo");
+        mk_write(dir, "foo/baz", "foo");
+        mk_write(dir, "baz", "foo");
+        assertion.check();
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [
+                ("foo/bar/baz", EventType::Delete),
+                ("foo/baz", EventType::Delete),
+            ],
+        );
+        fs::remove_dir_all(dir.join("foo")).unwrap();
+        assertion.check();
+        assert_eq!(watcher.stats().reclaimable, 4);
+        // deleted nodes are released right away, compacting only drops
+        // their unused slots
+        assert_eq!(watcher.tombstone_count(), 0);
+        assert_eq!(watcher.compact(), 4);
+        assert_eq!(watcher.tombstone_count(), 0);
+        assert_eq!(watcher.stats().reclaimable, 0);
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [
+                ("foo/bar/baz", EventType::Create),
+                ("baz", EventType::Modified),
+            ],
+        );
+        mk_write(dir, "foo/bar/baz", "foo");
+        write(dir, "baz", "bar");
+        assertion.check();
+    });
+}
+
+#[test]
+fn reuse_deleted_nodes() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let dir = root.as_std_path();
+    mk_write(dir, "a/b/file", "foo");
+    let mut tree = FileTree::new();
+    let mut node = tree
+        .add_root(root.clone(), &RootOptions::new().recursive(true))
+        .unwrap();
+    tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    assert_eq!(tree.node_count(), 4);
+    let update = |tree: &mut FileTree, path: &str| {
+        apply_change(tree, root.join(path.as_ref()), pending::Flags::empty());
+    };
+
+    fs::remove_dir_all(dir.join("a")).unwrap();
+    update(&mut tree, "a");
+    assert_eq!(tree.node_count(), 1);
+    assert_eq!(tree.tombstone_count(), 0);
+    assert!(tree.find(&root.join("a/b/file".as_ref())).is_none());
+    mk_write(dir, "c/d/e/file", "foo");
+    update(&mut tree, "c");
+    assert_eq!(tree.node_count(), 5);
+    assert!(tree.find(&root.join("c/d/e/file".as_ref())).is_some());
+    // the freed slots were reused, only one new node was allocated
+    assert_eq!(tree.compact(&mut [&mut node]), 0);
+}
+
+#[test]
+fn vanished_files() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let mut tree = FileTree::new();
+    let node = tree
+        .add_root(root.clone(), &RootOptions::new().recursive(true))
+        .unwrap();
+    tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    // the file was created and unlinked before the change was applied
+    write(root.as_std_path(), "foo", "bar");
+    fs::remove_file(root.as_std_path().join("foo")).unwrap();
+    let apply = |tree: &mut FileTree| {
+        let path = root.join("foo".as_ref());
+        apply_change(tree, path, pending::Flags::NEEDS_RECURSIVE_CRAWL)
+    };
+    assert_eq!(apply(&mut tree), []);
+    tree.report_vanished_files = true;
+    assert_eq!(
+        apply(&mut tree),
+        [(root.join("foo".as_ref()), EventType::Tempfile)]
+    );
+}
+
+#[test]
+fn missing_parent() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let mut tree = FileTree::new();
+    tree.report_directories = true;
+    let node = tree
+        .add_root(root.clone(), &RootOptions::new().recursive(true))
+        .unwrap();
+    tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    mk_write(root.as_std_path(), "a/b/c", "foo");
+    // only the event for the file arrived so far
+    let mut changes = PendingChanges::default();
+    changes.add_watcher(
+        root.join("a/b/c".as_ref()),
+        SystemTime::now(),
+        pending::Flags::empty(),
+    );
+    let mut events = Vec::new();
+    let mut watched = Vec::new();
+    tree.apply_transaction(
+        &mut changes,
+        &(),
+        |path, ty, _, _, _| events.push((path, ty)),
+        &mut Vec::new(),
+        |path| watched.push(path),
+        |_| (),
+    );
+    events.sort_by(|(path1, _), (path2, _)| path1.cmp(path2));
+    let path = |path: &str| root.join(path.as_ref());
+    assert_eq!(
+        events,
+        [
+            (path("a"), EventType::Create),
+            (path("a/b"), EventType::Create),
+            (path("a/b/c"), EventType::Create),
+        ]
+    );
+    assert_eq!(watched, [path("a"), path("a/b")]);
+}
+
+#[test]
+fn defer_empty_creates() {
+    with_watcher(|dir, watcher| {
+        watcher.set_defer_empty_creates(Some(Duration::from_secs(2)));
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [
+                ("foo", EventType::Create),
+                ("bar", EventType::Create),
+                ("baz", EventType::Create),
+            ],
+        );
+        write(dir, "foo", "");
+        write(dir, "bar", "");
+        // wait longer than the settle time so the content is
+        // not simply merged by the debouncer
+        std::thread::sleep(Duration::from_millis(500));
+        write(dir, "foo", "content");
+        write(dir, "baz", "content");
+        assertion.check();
+    });
+}
+
+#[test]
+fn coalesce_deletes() {
+    with_watcher(|dir, watcher| {
+        watcher.set_coalesce_deletes(Some(Duration::from_secs(2)));
+        let assertion = Assertion::new(watcher, dir, [("foo", EventType::Create)]);
+        write(dir, "foo", "bar");
+        assertion.check();
+        let assertion = Assertion::new(watcher, dir, [("foo", EventType::Modified)]);
+        rm_file(dir, "foo");
+        // wait longer than the settle time so the events are
+        // not simply merged by the debouncer
+        std::thread::sleep(Duration::from_millis(500));
+        write(dir, "foo", "baz");
+        assertion.check();
+        let assertion = Assertion::new(watcher, dir, [("foo", EventType::Delete)]);
+        rm_file(dir, "foo");
+        assertion.check();
+    });
+}
+
+#[test]
+fn min_modified_interval() {
+    with_watcher(|dir, watcher| {
+        watcher.set_min_modified_interval(Some(Duration::from_secs(3)));
+        let events = watcher.events_channel(16);
+        let next = || {
+            let events = events.recv_timeout(*TIMEOUT).unwrap();
+            let events: Vec<_> = events.iter().map(|event| event.ty).collect();
+            events
+        };
+        write(dir, "foo", "bar");
+        assert_eq!(next(), [EventType::Create]);
+        write(dir, "foo", "baz");
+        assert_eq!(next(), [EventType::Modified]);
+        let start = Instant::now();
+        write(dir, "foo", "bar");
+        assert_eq!(next(), [EventType::Modified]);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        // deletes are not delayed
+        write(dir, "foo", "baz");
+        let start = Instant::now();
+        rm_file(dir, "foo");
+        assert_eq!(next(), [EventType::Delete]);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    });
+}
+
+#[test]
+fn adaptive_settle_time() {
+    with_watcher(|dir, watcher| {
+        watcher.set_settle_time(Duration::from_millis(50));
+        watcher.set_adaptive_settle_time(Some(Duration::from_secs(2)), 10);
+        let events = watcher.events_channel(16);
+        for i in 0..50 {
+            write(dir, &format!("file{i}"), "foo");
+        }
+        let mut received = 0;
+        while received < 50 {
+            received += events.recv_timeout(*TIMEOUT).unwrap().len();
+        }
+        // the burst doubled the settle time
+        let start = Instant::now();
+        write(dir, "foo", "bar");
+        events.recv_timeout(*TIMEOUT).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    });
+}
+
+#[test]
+fn flush() {
+    with_watcher(|dir, watcher| {
+        watcher.set_settle_time(Duration::from_secs(60));
+        watcher.set_defer_empty_creates(Some(Duration::from_secs(60)));
+        let events = watcher.events_channel(16);
+        write(dir, "foo", "bar");
+        write(dir, "empty", "");
+        // give the backend time to pick up the changes
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(events.try_recv().is_err());
+        watcher.flush();
+        let events = events.recv_timeout(Duration::from_secs(1)).unwrap();
+        let events = events.to_std_vec();
+        assert_eq!(
+            events,
+            [
+                (dir.join("empty"), EventType::Create),
+                (dir.join("foo"), EventType::Create)
+            ]
+        );
+    });
+}
+
+#[test]
+fn shutdown_and_join() {
+    let (_dir, watcher) = init_watcher();
+    let handle = watcher.clone();
+    watcher.shutdown_and_join().unwrap();
+    // the worker is gone, queries are no longer answered
+    assert_eq!(handle.stats().nodes, 0);
+    handle.shutdown_and_join().unwrap();
+}
+
+#[test]
+fn shutdown_wakes_worker() {
+    for _ in 0..50 {
+        let (_dir, watcher) = init_watcher();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(watcher.shutdown_and_join().is_ok());
+        });
+        assert_eq!(rx.recv_timeout(*TIMEOUT), Ok(true));
+    }
+}
+
+#[test]
+fn root_removed() {
+    let _ = env_logger::builder().try_init();
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path().canonicalize().unwrap();
+    let root = dir.join("root");
+    mk_write(&root, "file", "foo");
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let (tx, rx) = mpsc::channel();
+    watcher.on_root_removed(move |root| {
+        let _ = tx.send(root.to_owned());
+    });
+    watcher.start().unwrap();
+    assert!(watcher.add_root_blocking(&root, true, *TIMEOUT).unwrap());
+    // emptying the root doesn't remove it
+    let assertion = Assertion::new(&watcher, &root, [("file", EventType::Delete)]);
+    rm_file(&root, "file");
+    assertion.check();
+    assert!(rx.try_recv().is_err());
+    fs::remove_dir(&root).unwrap();
+    assert_eq!(rx.recv_timeout(*TIMEOUT), Ok(root.clone()));
+    if !cfg!(target_os = "linux") {
+        return;
+    }
+    // the watch of the parent picks up the recreated root
+    let assertion = Assertion::new(&watcher, &root, [("file", EventType::Create)]);
+    mk_write(&root, "file", "bar");
+    assertion.check();
+    let assertion = Assertion::new(&watcher, &root, [("file", EventType::Modified)]);
+    write(&root, "file", "baz");
+    assertion.check();
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn root_parent_watched_as_dir() {
+    let _ = env_logger::builder().try_init();
+    let tmp = TempDir::new().unwrap();
+    let dir = tmp.path().canonicalize().unwrap();
+    let parent = dir.join("parent");
+    let root = parent.join("root");
+    mk_write(&root, "file", "foo");
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let (tx, rx) = mpsc::channel();
+    watcher.on_root_removed(move |root| {
+        let _ = tx.send(root.to_owned());
+    });
+    watcher.start().unwrap();
+    assert!(watcher.add_root_blocking(&root, true, *TIMEOUT).unwrap());
+    // the parent of the first root is watched as a regular directory now
+    assert!(watcher.add_root_blocking(&parent, true, *TIMEOUT).unwrap());
+    let assertion = Assertion::new(&watcher, &parent, [("other", EventType::Create)]);
+    write(&parent, "other", "foo");
+    assertion.check();
+    let assertion = Assertion::new(&watcher, &parent, [("other", EventType::Modified)]);
+    write(&parent, "other", "bar");
+    assertion.check();
+    // the parent root is gone but its directory still contains a root
+    assert!(watcher.remove_root(&parent).unwrap());
+    let assertion = Assertion::new(&watcher, &root, [("file", EventType::Delete)]);
+    fs::remove_dir_all(&root).unwrap();
+    assertion.check();
+    assert_eq!(rx.recv_timeout(*TIMEOUT), Ok(root.clone()));
+    if !cfg!(target_os = "linux") {
+        return;
+    }
+    let assertion = Assertion::new(&watcher, &root, [("file", EventType::Create)]);
+    mk_write(&root, "file", "bar");
+    assertion.check();
+}
+
+fn is_already_started(res: Result<(), StartError>) -> bool {
+    matches!(res, Err(StartError::AlreadyStarted))
+}
+
+#[test]
+fn start_twice() {
+    with_watcher(|_dir, watcher| {
+        assert!(is_already_started(watcher.start()));
+        assert!(is_already_started(watcher.clone().start()));
+    })
+}
+
+#[test]
+fn start_on() {
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let (tx, rx) = mpsc::channel();
+    watcher.add_handler(move |_| {
+        let _ = tx.send(thread::current().name().map(str::to_owned));
+        false
+    });
+    let builder = thread::Builder::new().name("custom-worker".into());
+    watcher.start_on(builder).unwrap();
+    assert!(is_already_started(watcher.start_on(thread::Builder::new())));
+    let dir = sync(&watcher);
+    write(dir.path(), "foo", "bar");
+    assert_eq!(
+        rx.recv_timeout(*TIMEOUT).unwrap().as_deref(),
+        Some("custom-worker")
+    );
+}
+
+#[test]
+fn query_without_worker() {
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    // nothing could answer these, they must not block
+    assert_eq!(watcher.compact(), 0);
+    assert!(watcher.snapshot().is_empty());
+    let (tx, rx) = mpsc::channel();
+    watcher.add_handler({
+        let watcher = watcher.clone();
+        move |_| {
+            // the worker is busy running this handler
+            let _ = tx.send(watcher.tombstone_count());
+            false
+        }
+    });
+    watcher.start().unwrap();
+    let dir = sync(&watcher);
+    write(dir.path(), "foo", "bar");
+    assert_eq!(rx.recv_timeout(*TIMEOUT), Ok(0));
+    assert_eq!(watcher.snapshot().len(), 2);
+}
+
+#[test]
+fn query_from_handler() {
+    with_watcher(|dir, watcher| {
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler({
+            let watcher = watcher.clone();
+            let dir = dir.to_owned();
+            move |_| {
+                // must neither block on the config nor on the busy worker
+                let _ = tx.send((watcher.is_watching(&dir), watcher.snapshot().len()));
+                watcher.set_settle_time(Duration::from_millis(10));
+                watcher.add_handler(|_| false);
+                false
+            }
+        });
+        write(dir, "foo", "bar");
+        assert_eq!(rx.recv_timeout(*TIMEOUT), Ok((false, 0)));
+        assert!(watcher.is_watching(dir));
+    });
+}
+
+#[test]
+fn run_on_current_thread() {
+    let watcher = Watcher::new().unwrap();
+    let worker = thread::spawn({
+        let watcher = watcher.clone();
+        move || watcher.run_on_current_thread()
+    });
+    let dir = sync(&watcher);
+    drop(dir);
+    assert!(is_already_started(watcher.start()));
+    watcher.shutdown();
+    assert!(worker.join().unwrap().is_ok());
+    assert!(is_already_started(watcher.run_on_current_thread()));
+}
+
+#[test]
+fn custom_canonicalize() {
+    with_watcher(|_dir, watcher| {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_ = calls.clone();
+        watcher.set_canonicalize(move |path| {
+            calls_.fetch_add(1, Ordering::Relaxed);
+            path.canonicalize()
+        });
+        let _dir = sync(watcher);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        watcher.set_canonicalize(|_| Err(io::Error::other("rejected")));
+        let err = watcher.add_root(Path::new("."), false, |_| ()).unwrap_err();
+        assert_eq!(err.to_string(), "rejected");
+
+        // resolving paths doesn't need the config, which the worker may hold
+        let _config = watcher.state.config.lock().unwrap();
+        assert!(watcher.canonicalize(Path::new(".")).is_err());
+    })
+}
+
+#[test]
+fn modified_prev_meta() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("foo", EventType::Create)]);
+        write(dir, "foo", "a");
+        assertion.check();
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler(move |events| {
+            let modified = events.iter().find(|event| event.ty == EventType::Modified);
+            if let Some(event) = modified {
+                let _ = tx.send(event.prev);
+                return false;
+            }
+            true
+        });
+        write(dir, "foo", "ab");
+        write(dir, "foo", "abc");
+        let prev = rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond");
+        assert_eq!(prev.map(|prev| prev.size), Some(1));
+    })
+}
+
+#[test]
+fn event_metadata() {
+    with_watcher(|dir, watcher| {
+        let events = watcher.events_channel(16);
+        let next = || {
+            let events = events.recv_timeout(*TIMEOUT).unwrap();
+            assert_eq!(events.len(), 1);
+            let meta = events[0].metadata();
+            (events[0].ty, meta.map(|meta| (meta.size, meta.is_dir)))
+        };
+        write(dir, "foo", "abc");
+        assert_eq!(next(), (EventType::Create, Some((3, false))));
+        write(dir, "foo", "abcdef");
+        assert_eq!(next(), (EventType::Modified, Some((6, false))));
+        rm_file(dir, "foo");
+        assert_eq!(next(), (EventType::Delete, None));
+    })
+}
+
+#[test]
+fn delete_only() {
+    let dir = TempDir::new().unwrap();
+    mk_write(dir.path(), "existing", "foo");
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.set_delete_only(true);
+    let (tx, rx) = mpsc::sync_channel(1);
+    watcher
+        .add_root(dir.path(), true, move |success| {
+            let _ = tx.send(success);
+        })
+        .unwrap();
+    watcher.start().unwrap();
+    rx.recv_timeout(*TIMEOUT).expect("failed to start watcher");
+
+    let dir = dir.path();
+    let assertion = Assertion::new(
+        &watcher,
+        dir,
+        [
+            ("existing", EventType::Delete),
+            ("foo/bar/baz", EventType::Delete),
+        ],
+    );
+    write(dir, "existing", "bar");
+    mk_write(dir, "foo/bar/baz", "foo");
+    // let the new files settle so their removal isn't merged into a tempfile
+    std::thread::sleep(Duration::from_millis(500));
+    rm_file(dir, "existing");
+    fs::remove_dir_all(dir.join("foo")).unwrap();
+    assertion.check();
+}
+
+#[test]
+fn initial_events() {
+    let dir = TempDir::new().unwrap();
+    write(dir.path(), "foo", "foo");
+    mk_write(dir.path(), "inner/bar", "bar");
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let (tx, rx) = mpsc::channel();
+    watcher.add_handler(move |events| {
+        for event in events.iter() {
+            let _ = tx.send(event.to_std());
+        }
+        true
+    });
+    watcher.start().unwrap();
+    let options = RootOptions::new().recursive(true).initial_events(true);
+    watcher.add_root_with(dir.path(), options, |_| ()).unwrap();
+    let mut events: Vec<_> = (0..2)
+        .map(|_| rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"))
+        .collect();
+    events.sort_unstable();
+    let dir = dir.path().canonicalize().unwrap();
+    assert_eq!(
+        events,
+        [
+            (dir.join("foo"), EventType::Create),
+            (dir.join("inner/bar"), EventType::Create)
+        ]
+    );
+}
+
+#[test]
+fn root_tags() {
+    let dir = TempDir::new().unwrap();
+    fs::create_dir(dir.path().join("inner")).unwrap();
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.start().unwrap();
+    for (path, tag) in [(dir.path().to_owned(), 1), (dir.path().join("inner"), 2)] {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let options = RootOptions::new().recursive(true).tag(tag);
+        watcher
+            .add_root_with(&path, options, move |success| {
+                let _ = tx.send(success);
+            })
+            .unwrap();
+        assert!(rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"));
+    }
+    let (tx, rx) = mpsc::channel();
+    watcher.add_handler(move |events| {
+        for event in events.iter() {
+            let _ = tx.send((event.path.as_std_path().to_owned(), event.tag));
+        }
+        true
+    });
+    let dir = dir.path();
+    write(dir, "foo", "foo");
+    write(dir, "inner/bar", "bar");
+    let mut tags: Vec<_> = (0..2)
+        .map(|_| rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"))
+        .collect();
+    tags.sort_unstable();
+    assert_eq!(
+        tags,
+        [(dir.join("foo"), Some(1)), (dir.join("inner/bar"), Some(2))]
+    );
+}
+
+#[test]
+fn root_filter() {
+    struct Ext(&'static str);
+    impl Filter for Ext {
+        fn ignore_path(&self, path: &Path, _is_dir: Option<bool>) -> bool {
+            path.extension().is_some_and(|ext| ext == self.0)
+        }
+    }
+    let dir = TempDir::new().unwrap();
+    fs::create_dir(dir.path().join("build")).unwrap();
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.set_filter(Arc::new(Ext("log")), false);
+    watcher.start().unwrap();
+    for (path, filter) in [
+        (dir.path().to_owned(), None),
+        (dir.path().join("build"), Some(Arc::new(Ext("tmp")))),
+    ] {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let mut options = RootOptions::new().recursive(true);
+        if let Some(filter) = filter {
+            options = options.filter(filter);
+        }
+        watcher
+            .add_root_with(&path, options, move |success| {
+                let _ = tx.send(success);
+            })
+            .unwrap();
+        assert!(rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"));
+    }
+    let dir = dir.path();
+    let assertion = Assertion::new(
+        &watcher,
+        dir,
+        [
+            ("foo.tmp", EventType::Create),
+            ("build/bar.log", EventType::Create),
+        ],
+    );
+    for path in ["foo.log", "foo.tmp", "build/bar.log", "build/bar.tmp"] {
+        write(dir, path, "foo");
+    }
+    assertion.check();
+    assert!(!watcher.is_watching(&dir.join("foo.log")));
+    assert!(watcher.is_watching(&dir.join("build/bar.log")));
+}
+
+#[test]
+fn max_root_entries() {
+    let dir = TempDir::new().unwrap();
+    mk_write(dir.path(), "foo/bar", "foo");
+    mk_write(dir.path(), "baz", "foo");
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.set_max_root_entries(Some(2));
+    assert!(watcher.add_root(dir.path(), true, |_| ()).is_err());
+    // non-recursive roots are never rejected
+    assert!(watcher.add_root(dir.path(), false, |_| ()).is_ok());
+    watcher.set_max_root_entries(Some(3));
+    assert!(watcher.add_root(dir.path(), true, |_| ()).is_ok());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn classify_event() {
+    use crate::inotify::{classify_event, EventAction, EventFlags};
+
+    let dir = CanonicalPathBuf::assert_canonicalized(Path::new("/foo"));
+    let child = OsStr::new("bar");
+    let classify = |flags, child| classify_event(flags, child, Some(&dir));
+    let change = |is_dir, flags| EventAction::Child {
+        path: dir.join(child),
+        is_dir,
+        flags,
+    };
+    let dir_change = |watch_removed| EventAction::Dir {
+        path: dir.clone(),
+        watch_removed,
+    };
+
+    // overflow always requires a recrawl, even without a known watch
+    for dir in [None, Some(&dir)] {
+        assert_eq!(
+            classify_event(EventFlags::QUEUE_OVERFLOW, OsStr::new(""), dir),
+            EventAction::Recrawl(RecrawlCause::QueueOverflow)
+        );
+    }
+    // unknown watches
+    for flags in [EventFlags::MOVE_SELF, EventFlags::IGNORED] {
+        assert_eq!(
+            classify_event(flags, child, None),
+            EventAction::Recrawl(RecrawlCause::BackendRescan)
+        );
+    }
+    for flags in [
+        EventFlags::CREATE,
+        EventFlags::MODIFY,
+        EventFlags::DELETE_SELF,
+    ] {
+        assert_eq!(classify_event(flags, child, None), EventAction::Ignore);
+    }
+
+    // children
+    let recursive = pending::Flags::NEEDS_RECURSIVE_CRAWL;
+    assert_eq!(
+        classify(EventFlags::CREATE, child),
+        change(false, recursive)
+    );
+    assert_eq!(
+        classify(EventFlags::DELETE, child),
+        change(false, recursive)
+    );
+    assert_eq!(
+        classify(EventFlags::CREATE | EventFlags::ISDIR, child),
+        change(true, recursive)
+    );
+    assert_eq!(
+        classify(EventFlags::DELETE | EventFlags::ISDIR, child),
+        change(true, recursive)
+    );
+    for flags in [
+        EventFlags::MODIFY,
+        EventFlags::ATTRIB,
+        EventFlags::MOVED_FROM,
+        EventFlags::MOVED_TO,
+    ] {
+        assert_eq!(
+            classify(flags, child),
+            change(false, pending::Flags::empty())
+        );
+    }
+    assert_eq!(
+        classify(EventFlags::MOVED_TO | EventFlags::ISDIR, child),
+        change(true, pending::Flags::empty())
+    );
+
+    // the watched directory itself
+    assert_eq!(
+        classify(EventFlags::ATTRIB, OsStr::new("")),
+        dir_change(false)
+    );
+    for flags in [
+        EventFlags::MOVE_SELF,
+        EventFlags::DELETE_SELF,
+        EventFlags::UNMOUNT,
+    ] {
+        assert_eq!(classify(flags, OsStr::new("")), dir_change(false));
+        // events for the directory itself take precedence over the child
+        assert_eq!(classify(flags, child), dir_change(false));
+    }
+    assert_eq!(
+        classify(EventFlags::IGNORED, OsStr::new("")),
+        dir_change(true)
+    );
+    assert_eq!(classify(EventFlags::IGNORED, child), dir_change(true));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn inotify_watch_reuse() {
+    use crate::backend::Notify;
+    use crate::inotify::{Event, EventFlags, Watch};
+
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let Notify::Native(notify) = &*watcher.notify else {
+        unreachable!()
+    };
+    let old = CanonicalPathBuf::assert_canonicalized(Path::new("/old"));
+    let new = CanonicalPathBuf::assert_canonicalized(Path::new("/new"));
+    // far above the descriptors the kernel hands out
+    let wd = Watch::from_raw(1 << 24);
+    let handle = |flags| {
+        let event = Event {
+            wd,
+            child: OsStr::new("foo"),
+            flags,
+            cookie: 0,
+        };
+        notify.handle_event(event, &(), SystemTime::now())
+    };
+    notify.insert_watch(wd, old.clone());
+    // fails since the kernel doesn't know the watch
+    let _ = notify.unwatch_dir(&old);
+    // the kernel hands out the descriptor again before `IGNORED` was read
+    notify.insert_watch(wd, new.clone());
+    handle(EventFlags::MODIFY);
+    handle(EventFlags::IGNORED);
+    handle(EventFlags::MODIFY);
+    let changes = notify.changes.lock();
+    assert!(!changes.contains(&old.join("foo".as_ref())));
+    assert!(changes.contains(&new.join("foo".as_ref())));
+    assert_eq!(changes.len(), 1);
+    assert_eq!(notify.watch_count(), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn inotify_follow_symlinks() {
+    use crate::backend::Notify;
+
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    fs::create_dir(dir.join("data")).unwrap();
+    std::os::unix::fs::symlink(dir.join("data"), dir.join("link")).unwrap();
+    let link = CanonicalPathBuf::assert_canonicalized(&dir.join("link"));
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let Notify::Native(notify) = &*watcher.notify else {
+        unreachable!()
+    };
+    // the symlink itself is not a directory
+    assert!(notify.watch_dir(link.clone()).is_err());
+    watcher.set_follow_symlinks(true);
+    notify.watch_dir(link).unwrap();
+    assert_eq!(notify.watch_count(), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn inotify_rename_across_drain() {
+    use crate::backend::Notify;
+    use crate::inotify::{Event, EventFlags, Watch};
+
+    let watcher = Watcher::new_impl(false).unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let Notify::Native(notify) = &*watcher.notify else {
+        unreachable!()
+    };
+    let dir = CanonicalPathBuf::assert_canonicalized(Path::new("/dir"));
+    let wd = Watch::from_raw(1 << 24);
+    notify.insert_watch(wd, dir.clone());
+    let handle = |flags, child| {
+        let event = Event {
+            wd,
+            child: OsStr::new(child),
+            flags,
+            cookie: 42,
+        };
+        notify.handle_event(event, &(), SystemTime::now())
+    };
+    handle(EventFlags::MOVED_FROM, "foo");
+    // the worker takes the changes between both halves of the rename
+    let mut taken = PendingChanges::default();
+    notify.changes.take_now(&mut taken);
+    assert!(taken.take_renames().is_empty());
+    handle(EventFlags::MOVED_TO, "bar");
+    let renames = notify.changes.lock().take_renames();
+    let (from, to) = (dir.join("foo".as_ref()), dir.join("bar".as_ref()));
+    assert_eq!(renames, [(from, to)]);
+}
+
+#[cfg(windows)]
+#[test]
+fn windows_notify_records() {
+    use hashbrown::{DefaultHashBuilder, HashSet};
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_ACTION_ADDED, FILE_ACTION_RENAMED_NEW_NAME, FILE_ACTION_RENAMED_OLD_NAME,
+    };
+
+    use crate::windows::{handle_events, Renames};
+
+    let dir = TempDir::new().unwrap();
+    let dir = tree_root(&dir);
+    let records = [
+        (FILE_ACTION_RENAMED_OLD_NAME, "foo"),
+        (FILE_ACTION_RENAMED_NEW_NAME, "bar"),
+        (FILE_ACTION_ADDED, "baz"),
+        // the watch is recursive but `sub` itself isn't watched
+        (FILE_ACTION_ADDED, "sub\\file"),
+    ];
+    // serialized like `ReadDirectoryChangesW` does, each record is DWORD aligned
+    let mut bytes = Vec::new();
+    for (i, (action, name)) in records.iter().enumerate() {
+        let name: Vec<u8> = name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let len = (12 + name.len()).next_multiple_of(4);
+        let next = if i + 1 == records.len() { 0 } else { len };
+        bytes.extend((next as u32).to_le_bytes());
+        bytes.extend(action.to_le_bytes());
+        bytes.extend((name.len() as u32).to_le_bytes());
+        bytes.extend(name);
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+    }
+    let words: Vec<u32> = bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+    let buf = unsafe { std::slice::from_raw_parts(words.as_ptr().cast::<u8>(), bytes.len()) };
+    let mut watched_dirs = HashSet::<_, DefaultHashBuilder>::default();
+    watched_dirs.insert(dir.clone());
+    let mut changes = PendingChanges::default();
+    let mut renames = Renames::default();
+    handle_events(
+        buf,
+        &dir,
+        &watched_dirs,
+        &mut changes,
+        &(),
+        &mut renames,
+        SystemTime::now(),
+    );
+    let path = |name: &str| dir.join(name.as_ref());
+    assert_eq!(changes.take_renames(), [(path("foo"), path("bar"))]);
+    let mut pending = changes.snapshot();
+    pending.sort_by(|(path1, _), (path2, _)| path1.cmp(path2));
+    assert_eq!(
+        pending,
+        [
+            (path("bar"), PendingKind::Stat),
+            (path("baz"), PendingKind::RecursiveCrawl),
+            (path("foo"), PendingKind::Stat),
+        ]
+    );
+}
+
+#[test]
+fn display_root() {
+    with_watcher(|dir, watcher| {
+        fs::create_dir(dir.join("foo")).unwrap();
+        watcher.set_display_root(Some(&dir.join("foo"))).unwrap();
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler(move |events| {
+            for event in events.iter() {
+                let _ = tx.send(event.display_path().to_owned());
+            }
+            true
+        });
+        write(dir, "foo/bar", "foo");
+        write(dir, "baz", "foo");
+        // the display root is a prefix of the string but not of the path
+        write(dir, "foobar", "foo");
+        let mut paths: Vec<_> = (0..3)
+            .map(|_| rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"))
+            .collect();
+        paths.sort_unstable();
+        let mut expected = [PathBuf::from("bar"), dir.join("baz"), dir.join("foobar")];
+        expected.sort_unstable();
+        assert_eq!(paths, expected);
+    })
+}
+
+#[test]
+fn overlapping_roots() {
+    with_watcher(|dir, watcher| {
+        let other = TempDir::new().unwrap();
+        let other = other.path();
+        fs::create_dir(other.join("inner")).unwrap();
+        let add_root = |root: &Path, recursive: bool| {
+            let (tx, rx) = mpsc::sync_channel(1);
+            let status = watcher
+                .add_root(root, recursive, move |success| {
+                    let _ = tx.send(success);
+                })
+                .unwrap();
+            assert!(rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"));
+            status
+        };
+        assert_eq!(add_root(&dir.join("."), false), RootStatus::AlreadyCovered);
+        assert_eq!(add_root(&other.join("inner"), false), RootStatus::Added);
+        assert_eq!(
+            add_root(&other.join("inner"), false),
+            RootStatus::AlreadyCovered
+        );
+        assert_eq!(add_root(other, true), RootStatus::Merged);
+        assert_eq!(
+            add_root(&other.join("inner"), true),
+            RootStatus::AlreadyCovered
+        );
+        let mut roots = vec![(dir.to_owned(), true), (other.to_owned(), true)];
+        roots.sort_unstable();
+        let watched_roots: Vec<_> = watcher
+            .watched_roots()
+            .into_iter()
+            .map(|(root, recursive)| (root.as_std_path().to_owned(), recursive))
+            .collect();
+        assert_eq!(watched_roots, roots);
+    })
+}
+
+#[test]
+fn watched_roots() {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    fs::create_dir_all(dir.join("b/nested")).unwrap();
+    fs::create_dir(dir.join("a")).unwrap();
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.start().unwrap();
+    assert!(watcher.watched_roots().is_empty());
+    for (root, recursive) in [("b", false), ("b/nested", true), ("a", true)] {
+        let crawled = watcher.add_root_blocking(&dir.join(root), recursive, *TIMEOUT);
+        assert!(crawled.unwrap());
+    }
+    let watched_roots: Vec<_> = watcher
+        .watched_roots()
+        .into_iter()
+        .map(|(root, recursive)| (root.as_std_path().to_owned(), recursive))
+        .collect();
+    // sorted by path, a non-recursive root doesn't cover nested roots
+    let expected = [
+        (dir.join("a"), true),
+        (dir.join("b"), false),
+        (dir.join("b/nested"), true),
+    ];
+    assert_eq!(watched_roots, expected);
+}
+
+#[test]
+fn file_root() {
+    let dir = TempDir::new().unwrap();
+    let dir_path = dir.path().canonicalize().unwrap();
+    let dir = &*dir_path;
+    mk_write(dir, "config.toml", "foo");
+    mk_write(dir, "other", "foo");
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.start().unwrap();
+    assert!(watcher
+        .add_root_blocking(&dir.join("config.toml"), false, *TIMEOUT)
+        .unwrap());
+    let assertion = Assertion::new(&watcher, dir, [("config.toml", EventType::Modified)]);
+    // changes to the rest of the directory are ignored
+    write(dir, "other", "bar");
+    mk_write(dir, "new", "bar");
+    write(dir, "config.toml", "bar");
+    assertion.check();
+    let assertion = Assertion::new(&watcher, dir, [("config.toml", EventType::Delete)]);
+    rm_file(dir, "config.toml");
+    assertion.check();
+    let assertion = Assertion::new(&watcher, dir, [("config.toml", EventType::Create)]);
+    write(dir, "config.toml", "baz");
+    assertion.check();
+    // the parent directory isn't needed anymore
+    assert!(watcher.remove_root(&dir.join("config.toml")).unwrap());
+    assert_eq!(watcher.stats().watches, 0);
+}
+
+#[test]
+fn track_hardlinks() {
+    let dir = TempDir::new().unwrap();
+    let dir_path = dir.path().canonicalize().unwrap();
+    let dir = &*dir_path;
+    mk_write(dir, "a", "foo");
+    mk_write(dir, "sub/keep", "foo");
+    fs::hard_link(dir.join("a"), dir.join("sub/b")).unwrap();
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.set_track_hardlinks(true);
+    watcher.start().unwrap();
+    assert!(watcher.add_root_blocking(dir, true, *TIMEOUT).unwrap());
+    let assertion = Assertion::new(
+        &watcher,
+        dir,
+        [("a", EventType::Modified), ("sub/b", EventType::Modified)],
+    );
+    write(dir, "a", "bar");
+    assertion.check();
+    // a new link changes the link count of the existing ones
+    let assertion = Assertion::new(
+        &watcher,
+        dir,
+        [
+            ("a", EventType::Modified),
+            ("c", EventType::Create),
+            ("sub/b", EventType::Modified),
+        ],
+    );
+    fs::hard_link(dir.join("a"), dir.join("c")).unwrap();
+    assertion.check();
+}
+
+#[test]
+fn estimate_watches() {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+    mk_write(dir, "foo/bar/file", "foo");
+    mk_write(dir, "foo/baz/file", "foo");
+    mk_write(dir, "ignored/sub/file", "foo");
+    struct Ignore;
+    impl Filter for Ignore {
+        fn ignore_path(&self, path: &Path, _is_dir: Option<bool>) -> bool {
+            path.ends_with("ignored")
+        }
+    }
+    assert_eq!(crate::estimate_watches(dir, &()).unwrap(), 6);
+    assert_eq!(crate::estimate_watches(dir, &Ignore).unwrap(), 4);
+    let err = crate::estimate_watches(&dir.join("missing"), &()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn default_filter() {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+    mk_write(dir, "repo/.git/HEAD", "foo");
+    mk_write(dir, "worktree/.git", "foo");
+    let filter = DefaultFilter::new();
+    assert!(!filter.ignore_path(&dir.join("repo/.git"), Some(true)));
+    assert_eq!(crate::estimate_watches(dir, &filter).unwrap(), 4);
+    let filter = DefaultFilter::new().ignore_git_dirs(true);
+    assert!(filter.ignore_path(&dir.join("repo/.git"), Some(true)));
+    assert!(!filter.ignore_path(&dir.join("worktree/.git"), Some(false)));
+    assert_eq!(crate::estimate_watches(dir, &filter).unwrap(), 3);
+}
+
+#[test]
+fn ignore_ancestors_as_dirs() {
+    #[derive(Default)]
+    struct Record(Mutex<Vec<(PathBuf, Option<bool>)>>);
+    impl Filter for Record {
+        fn ignore_path(&self, path: &Path, is_dir: Option<bool>) -> bool {
+            self.0.lock().unwrap().push((path.to_owned(), is_dir));
+            false
+        }
+    }
+    let filter = Record::default();
+    assert!(!filter.ignore_path_rec(Path::new("/foo/bar/baz"), None));
+    let expected = [
+        ("/foo/bar/baz", None),
+        ("/foo/bar", Some(true)),
+        ("/foo", Some(true)),
+        ("/", Some(true)),
+    ]
+    .map(|(path, is_dir)| (PathBuf::from(path), is_dir));
+    assert_eq!(*filter.0.lock().unwrap(), expected);
+    filter.0.lock().unwrap().clear();
+    assert!(!filter.ignore_path_rec_until(
+        Path::new("/foo/bar/baz"),
+        Path::new("/foo"),
+        Some(false)
+    ));
+    assert_eq!(
+        filter.0.lock().unwrap()[..],
+        [
+            (PathBuf::from("/foo/bar/baz"), Some(false)),
+            (PathBuf::from("/foo/bar"), Some(true)),
+            (PathBuf::from("/foo"), Some(true)),
+        ]
+    );
+}
+
+#[test]
+fn root_below_ignored_dir() {
+    struct Ignore;
+    impl Filter for Ignore {
+        fn ignore_path(&self, path: &Path, _is_dir: Option<bool>) -> bool {
+            path.ends_with("ignored")
+        }
+    }
+    let dir = TempDir::new().unwrap();
+    let dir_path = dir.path().canonicalize().unwrap();
+    let dir = &*dir_path;
+    mk_write(dir, "ignored/root/file", "foo");
+    let root = dir.join("ignored/root");
+    assert!(Ignore.ignore_path_rec(&root.join("file"), Some(false)));
+    assert!(!Ignore.ignore_path_rec_until(&root.join("file"), &root, Some(false)));
+    assert!(Ignore.ignore_path_rec_until(&root.join("ignored/file"), &root, Some(false)));
+
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    watcher.set_filter(Arc::new(Ignore), false);
+    watcher.start().unwrap();
+    let (tx, rx) = mpsc::sync_channel(1);
+    let status = watcher
+        .add_root(&root, true, move |success| {
+            let _ = tx.send(success);
+        })
+        .unwrap();
+    assert_eq!(status, RootStatus::Added);
+    assert!(rx.recv_timeout(*TIMEOUT).unwrap());
+    assert!(watcher.is_watching(&root.join("file")));
+    assert_eq!(
+        watcher
+            .add_root(&dir.join("ignored"), true, |_| ())
+            .unwrap(),
+        RootStatus::Ignored
+    );
+}
+
+#[test]
+fn remove_root() {
+    with_watcher(|dir, watcher| {
+        let other = TempDir::new().unwrap();
+        let other = other.path();
+        fs::create_dir(other.join("inner")).unwrap();
+        for root in [other.join("inner"), other.to_owned()] {
+            let (tx, rx) = mpsc::sync_channel(1);
+            watcher
+                .add_root(&root, true, move |success| {
+                    let _ = tx.send(success);
+                })
+                .unwrap();
+            assert!(rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"));
+        }
+        let mut roots = vec![(dir.to_owned(), true), (other.to_owned(), true)];
+        roots.sort_unstable();
+        let watched_roots = || -> Vec<_> {
+            watcher
+                .watched_roots()
+                .into_iter()
+                .map(|(root, recursive)| (root.as_std_path().to_owned(), recursive))
+                .collect()
+        };
+        assert_eq!(watched_roots(), roots);
+        assert!(!watcher.remove_root(dir.parent().unwrap()).unwrap());
+        assert!(watcher.remove_root(other).unwrap());
+        roots.retain(|(root, _)| root != other);
+        roots.push((other.join("inner"), true));
+        roots.sort_unstable();
+        assert_eq!(watched_roots(), roots);
+        assert!(!watcher.remove_root(other).unwrap());
+
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler(move |events| {
+            for event in events.iter() {
+                let _ = tx.send(event.path.as_std_path().to_owned());
+            }
+            true
+        });
+        write(other, "foo", "foo");
+        write(other, "inner/bar", "bar");
+        write(dir, "baz", "baz");
+        let mut paths = Vec::new();
+        while paths.len() < 2 {
+            paths.push(rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"));
+        }
+        paths.sort_unstable();
+        let mut expected = [other.join("inner/bar"), dir.join("baz")];
+        expected.sort_unstable();
+        assert_eq!(paths, expected);
+    })
+}
+
+#[test]
+fn rename() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("foo", EventType::Create)]);
+        write(dir, "foo", "foo");
+        assertion.check();
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler(move |events| {
+            for event in events.iter() {
+                let from = event.renamed_from.as_ref();
+                let from = from.map(|from| from.as_std_path().to_owned());
+                let _ = tx.send((event.path.as_std_path().to_owned(), event.ty, from));
+            }
+            false
+        });
+        fs::rename(dir.join("foo"), dir.join("bar")).unwrap();
+        let event = rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond");
+        assert_eq!(
+            event,
+            (dir.join("bar"), EventType::Rename, Some(dir.join("foo")))
+        );
+        assert!(rx.try_recv().is_err());
+    })
+}
+
+#[test]
+fn rename_multiple() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [("a", EventType::Create), ("b", EventType::Create)],
+        );
+        write(dir, "a", "foo");
+        write(dir, "b", "foo");
+        assertion.check();
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler(move |events| {
+            for event in events.iter() {
+                let from = event.renamed_from.as_ref();
+                let from = from.map(|from| from.as_std_path().to_owned());
+                let _ = tx.send((event.path.as_std_path().to_owned(), event.ty, from));
+            }
+            true
+        });
+        fs::rename(dir.join("b"), dir.join("c")).unwrap();
+        fs::rename(dir.join("a"), dir.join("d")).unwrap();
+        let mut events: Vec<_> = (0..2)
+            .map(|_| rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"))
+            .collect();
+        events.sort();
+        let rename = |from: &str, to: &str| (dir.join(to), EventType::Rename, Some(dir.join(from)));
+        assert_eq!(events, [rename("b", "c"), rename("a", "d")]);
+        assert!(rx.try_recv().is_err());
+    })
+}
+
+#[test]
+fn rename_dir() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [("a/x", EventType::Create), ("a/b/y", EventType::Create)],
+        );
+        mk_write(dir, "a/x", "foo");
+        mk_write(dir, "a/b/y", "foo");
+        assertion.check();
+        let watches = watcher.stats().watches;
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler(move |events| {
+            for event in events.iter() {
+                let from = event.renamed_from.as_ref();
+                let from = from.map(|from| from.as_std_path().to_owned());
+                let _ = tx.send((event.path.as_std_path().to_owned(), event.ty, from));
+            }
+            true
+        });
+        fs::rename(dir.join("a"), dir.join("c")).unwrap();
+        let mut events: Vec<_> = (0..2)
+            .map(|_| rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond"))
+            .collect();
+        events.sort();
+        let rename = |from: &str, to: &str| (dir.join(to), EventType::Rename, Some(dir.join(from)));
+        assert_eq!(events, [rename("a/b/y", "c/b/y"), rename("a/x", "c/x")]);
+        assert_eq!(watcher.stats().watches, watches);
+        // the watch of the moved directory reports the new path
+        write(dir, "c/b/y", "bar");
+        let event = rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond");
+        assert_eq!(event, (dir.join("c/b/y"), EventType::Modified, None));
+        assert!(rx.try_recv().is_err());
+    })
+}
+
+#[test]
+fn tempfile() {
+    with_watcher(|dir, watcher| {
+        // the file must still exist when the worker picks up the change but
+        // be removed before the settle time has passed
+        let create_tempfile = |path| {
+            write(dir, path, "foo");
+            std::thread::sleep(Duration::from_millis(50));
+            rm_file(dir, path);
+        };
+        let assertion = Assertion::new(watcher, dir, [("foo", EventType::Tempfile)]);
+        create_tempfile("foo");
+        assertion.check();
+
+        watcher.set_report_tempfiles(false);
+        let assertion = Assertion::new(watcher, dir, [("baz", EventType::Create)]);
+        create_tempfile("bar");
+        write(dir, "baz", "foo");
+        assertion.check();
+    })
+}
+
+#[test]
+fn type_changed() {
+    with_watcher(|dir, watcher| {
+        watcher.set_report_directories(true);
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [
+                ("dir", EventType::Create),
+                ("dir/inner", EventType::Create),
+                ("file", EventType::Create),
+            ],
+        );
+        mk_write(dir, "dir/inner", "foo");
+        write(dir, "file", "foo");
+        assertion.check();
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [
+                ("dir", EventType::TypeChanged),
+                ("dir/inner", EventType::Delete),
+                ("file", EventType::TypeChanged),
+                ("file/inner", EventType::Create),
+            ],
+        );
+        fs::remove_dir_all(dir.join("dir")).unwrap();
+        write(dir, "dir", "foo");
+        rm_file(dir, "file");
+        mk_write(dir, "file/inner", "foo");
+        assertion.check();
+    })
+}
+
+#[test]
+fn coalesce_policy() {
+    use crate::events::EventDebouncer;
+    use crate::{CoalescePolicy, DefaultCoalescePolicy, MergeAction};
+
+    /// drops files that were created and removed again
+    struct DropTempfiles;
+    impl CoalescePolicy for DropTempfiles {
+        fn merge(&self, old: EventType, new: EventType) -> MergeAction {
+            match (old, new) {
+                (EventType::Create, EventType::Delete) => MergeAction::Remove,
+                _ => DefaultCoalescePolicy.merge(old, new),
+            }
+        }
+    }
+
+    let mut debouncer = EventDebouncer::new();
+    debouncer.coalesce_policy = Arc::new(DropTempfiles);
+    let path = |name: &str| CanonicalPathBuf::assert_canonicalized(Path::new(name));
+    let now = SystemTime::now();
+    for name in ["/a", "/b", "/c"] {
+        debouncer.add(path(name), EventType::Create, None, None, now);
+    }
+    debouncer.add(path("/b"), EventType::Delete, None, None, now);
+    debouncer.add(path("/c"), EventType::Modified, None, None, now);
+    debouncer.add(path("/d"), EventType::Create, None, None, now);
+    // the removed event must not be merged with the recreated file
+    debouncer.add(path("/b"), EventType::Create, None, None, now);
+    debouncer.add(path("/d"), EventType::Delete, None, None, now);
+    let events: Vec<_> = debouncer
+        .take()
+        .iter()
+        .map(|event| (event.path.clone(), event.ty))
+        .collect();
+    assert_eq!(
+        events,
+        [
+            (path("/a"), EventType::Create),
+            (path("/b"), EventType::Create),
+            (path("/c"), EventType::Create)
+        ]
+    );
+    debouncer.add(path("/e"), EventType::Create, None, None, now);
+    debouncer.add(path("/e"), EventType::Delete, None, None, now);
+    assert!(debouncer.is_empty());
+    assert!(debouncer.take().is_empty());
+
+    with_watcher(|dir, watcher| {
+        watcher.set_coalesce_policy(Arc::new(DropTempfiles));
+        let assertion = Assertion::new(watcher, dir, [("bar", EventType::Create)]);
+        write(dir, "foo", "foo");
+        std::thread::sleep(Duration::from_millis(50));
+        rm_file(dir, "foo");
+        write(dir, "bar", "foo");
+        assertion.check();
+    })
+}
+
+#[test]
+fn track_modifications() {
+    use crate::events::EventDebouncer;
+
+    let path = |name: &str| CanonicalPathBuf::assert_canonicalized(Path::new(name));
+    let now = SystemTime::now();
+    for track in [false, true] {
+        let mut debouncer = EventDebouncer::new();
+        debouncer.track_modifications = track;
+        debouncer.add(path("/a"), EventType::Modified, None, None, now);
+        debouncer.add(path("/a"), EventType::Delete, None, None, now);
+        debouncer.add(path("/b"), EventType::Delete, None, None, now);
+        debouncer.add(path("/c"), EventType::Modified, None, None, now);
+        debouncer.add(path("/c"), EventType::Modified, None, None, now);
+        let events: Vec<_> = debouncer
+            .take()
+            .iter()
+            .map(|event| (event.path.clone(), event.ty, event.modified_before()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (path("/a"), EventType::Delete, track),
+                (path("/b"), EventType::Delete, false),
+                (path("/c"), EventType::Modified, false),
+            ]
+        );
+    }
+}
+
+#[test]
+fn event_time() {
+    with_watcher(|dir, watcher| {
+        let (tx, rx) = mpsc::channel();
+        watcher.add_handler(move |events| {
+            for event in events.iter() {
+                let _ = tx.send(event.time);
+            }
+            true
+        });
+        let start = SystemTime::now();
+        write(dir, "foo", "foo");
+        let time = rx.recv_timeout(*TIMEOUT).expect("watcher didn't respond");
+        assert!(start <= time && time <= SystemTime::now());
+        // events are reported after the settle time
+        assert!(SystemTime::now().duration_since(time).unwrap() >= Duration::from_millis(200));
+    })
+}
+
+#[test]
+fn poll_backend() {
+    let dir = TempDir::new().unwrap();
+    let watcher = Watcher::with_backend(Backend::Poll {
+        interval: Duration::from_millis(100),
+    })
+    .unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    struct Ignore;
+    impl Filter for Ignore {
+        fn ignore_path(&self, path: &Path, _is_dir: Option<bool>) -> bool {
+            path.ends_with("ignored")
+        }
+    }
+    watcher.set_filter(Arc::new(Ignore), false);
+    let (tx, rx) = mpsc::sync_channel(1);
+    watcher
+        .add_root(dir.path(), true, move |success| {
+            let _ = tx.send(success);
+        })
+        .unwrap();
+    watcher.start().unwrap();
+    rx.recv_timeout(*TIMEOUT).expect("failed to start watcher");
+    // no native watcher is created
+    assert!(watcher.notify.is_polling());
+    assert_eq!(watcher.stats().watches, 0);
+
+    let dir = dir.path();
+    let assertion = Assertion::new(
+        &watcher,
+        dir,
+        [("foo", EventType::Create), ("bar/baz", EventType::Create)],
+    );
+    write(dir, "ignored", "foo");
+    write(dir, "foo", "foo");
+    mk_write(dir, "bar/baz", "foo");
+    assertion.check();
+    let assertion = Assertion::new(&watcher, dir, [("foo", EventType::Modified)]);
+    write(dir, "foo", "foobar");
+    assertion.check();
+    let assertion = Assertion::new(&watcher, dir, [("bar/baz", EventType::Delete)]);
+    fs::remove_dir_all(dir.join("bar")).unwrap();
+    assertion.check();
+}
+
+#[test]
+fn ignore_metadata() {
+    struct MaxSize;
+    impl Filter for MaxSize {
+        fn ignore_path(&self, _path: &Path, _is_dir: Option<bool>) -> bool {
+            false
+        }
+        fn ignore_metadata(&self, _path: &Path, meta: &Metadata) -> bool {
+            !meta.is_dir && meta.size > 4
+        }
+    }
+    with_watcher(|dir, watcher| {
+        watcher.set_filter(Arc::new(MaxSize), false);
+        let assertion = Assertion::new(watcher, dir, [("small", EventType::Create)]);
+        write(dir, "large", "foobar");
+        write(dir, "small", "foo");
+        assertion.check();
+        // files that grow too large are no longer tracked
+        let assertion = Assertion::new(watcher, dir, [("small", EventType::Delete)]);
+        write(dir, "small", "foobar");
+        assertion.check();
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn case_insensitive_tree() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let dir = root.as_std_path();
+    write(dir, "Foo", "foo");
+    // emulate a case-insensitive filesystem where both names refer to the same file
+    fs::hard_link(dir.join("Foo"), dir.join("FOO")).unwrap();
+    for fold_case in [false, true] {
+        let mut tree = FileTree::with_case_folding(fold_case);
+        let node = tree.add_root(root.clone(), &RootOptions::new()).unwrap();
+        tree.crawl_root(node, false, &(), |_, _, _, _, _| (), |_| (), |_| ());
+        let foo = tree.find(&root.join("Foo".as_ref()));
+        assert!(foo.is_some());
+        assert_eq!(tree.find(&root.join("foo".as_ref())) == foo, fold_case);
+        // the root and either one or two files
+        let node_count = if fold_case { 2 } else { 3 };
+        assert_eq!(tree.node_count(), node_count);
+
+        let events: Vec<_> = ["FOO", "Foo"]
+            .into_iter()
+            .flat_map(|name| {
+                apply_change(&mut tree, root.join(name.as_ref()), pending::Flags::empty())
+            })
+            .map(|(_, ty)| ty)
+            .collect();
+        // changes reported by the backend are always modifications, but no
+        // file is created for the other spelling
+        assert_eq!(events, [EventType::Modified, EventType::Modified]);
+        assert_eq!(tree.node_count(), node_count);
+    }
+}
+
+#[test]
+fn shallow_crawl() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let dir = root.as_std_path();
+    mk_write(dir, "a/b/file", "foo");
+    write(dir, "removed", "foo");
+    let mut tree = FileTree::new();
+    let node = tree
+        .add_root(root.clone(), &RootOptions::new().recursive(true))
+        .unwrap();
+    tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+
+    write(dir, "new", "foo");
+    mk_write(dir, "new_dir/file", "foo");
+    rm_file(dir, "removed");
+    // not a direct child of the root, must not be picked up
+    write(dir, "a/b/file", "foobar");
+    let mut events = apply_change(
+        &mut tree,
+        root.clone(),
+        pending::Flags::NEEDS_NON_RECURSIVE_CRAWL,
+    );
+    events.sort_by(|(path1, _), (path2, _)| path1.cmp(path2));
+    assert_eq!(
+        events,
+        [
+            (root.join("new".as_ref()), EventType::Create),
+            (root.join("new_dir/file".as_ref()), EventType::Create),
+            (root.join("removed".as_ref()), EventType::Delete),
+        ]
+    );
+}
+
+#[cfg(feature = "gitignore")]
+#[test]
+fn gitignore_filter() {
+    use crate::GitignoreFilter;
+
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    write(&dir, ".gitignore", "target/\n*.log\n");
+    write(&dir, ".ignore", "!keep.log\n");
+    mk_write(&dir, "sub/.hidden", "");
+    let filter = GitignoreFilter::builder(&dir.join("sub"))
+        .global(false)
+        .build()
+        .unwrap();
+    let sub = dir.join("sub");
+    assert!(filter.ignore_path(&sub.join("foo.log"), Some(false)));
+    assert!(!filter.ignore_path(&sub.join("keep.log"), Some(false)));
+    assert!(!filter.ignore_path(&sub.join("foo.rs"), Some(false)));
+    assert!(filter.ignore_path(&sub.join("target"), Some(true)));
+    assert!(!filter.ignore_path(&sub.join("target"), Some(false)));
+    // directory patterns only apply if we know that the path is a directory
+    assert!(!filter.ignore_path(&sub.join("target"), None));
+    assert!(filter.ignore_path_rec(&sub.join("target/debug/foo"), Some(false)));
+    assert!(filter.ignore_path(&sub.join(".hidden"), Some(false)));
+    // the root is not hidden even though its parent is
+    assert!(!filter.ignore_path_rec(&sub, Some(true)));
+
+    let filter = GitignoreFilter::builder(&sub)
+        .hidden(false)
+        .ignore_files(false)
+        .global(false)
+        .build()
+        .unwrap();
+    assert!(!filter.ignore_path(&sub.join("foo.log"), Some(false)));
+    assert!(!filter.ignore_path(&sub.join(".hidden"), Some(false)));
+}
+
+#[cfg(feature = "glob")]
+#[test]
+fn glob_filter() {
+    use crate::GlobFilter;
+
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    let filter = GlobFilter::builder(&dir)
+        .exclude("*.log")
+        .include("keep.log")
+        .exclude("**/target/")
+        .build()
+        .unwrap();
+    assert!(filter.ignore_path(&dir.join("foo.log"), Some(false)));
+    assert!(!filter.ignore_path(&dir.join("keep.log"), Some(false)));
+    // `*` doesn't match path separators
+    assert!(!filter.ignore_path(&dir.join("sub/foo.log"), Some(false)));
+    assert!(filter.ignore_path(&dir.join("sub/target"), Some(true)));
+    assert!(!filter.ignore_path(&dir.join("sub/target"), Some(false)));
+    // directory patterns only exclude if we know that the path is a directory
+    assert!(!filter.ignore_path(&dir.join("sub/target"), None));
+    assert!(filter.ignore_path_rec(&dir.join("target/debug/foo"), Some(false)));
+    assert!(!filter.ignore_path(Path::new("/foo.log"), Some(false)));
+    assert!(GlobFilter::builder(&dir).exclude("a[").build().is_err());
+}
+
+#[test]
+fn filter_combinators() {
+    use crate::{AllFilter, FilterExt};
+
+    struct Ext(&'static str);
+    impl Filter for Ext {
+        fn ignore_path(&self, path: &Path, _is_dir: Option<bool>) -> bool {
+            path.extension().is_some_and(|ext| ext == self.0)
+        }
+    }
+    struct Dir;
+    impl Filter for Dir {
+        fn ignore_path(&self, _path: &Path, is_dir: Option<bool>) -> bool {
+            is_dir == Some(true)
+        }
+    }
+
+    let filter = Ext("log").or(Ext("tmp"));
+    assert!(filter.ignore_path(Path::new("/a.log"), Some(false)));
+    assert!(filter.ignore_path(Path::new("/a.tmp"), Some(false)));
+    assert!(!filter.ignore_path(Path::new("/a.rs"), Some(false)));
+    // combinators nest and forward `is_dir`
+    let filter = filter.and(Dir);
+    assert!(filter.ignore_path(Path::new("/a.log"), Some(true)));
+    assert!(!filter.ignore_path(Path::new("/a.log"), None));
+    assert!(!filter.ignore_path(Path::new("/a.rs"), Some(true)));
+    assert!(AllFilter::default().ignore_path(Path::new("/a.rs"), None));
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    mk_write(&dir, "data/file", "foo");
+    fs::create_dir(dir.join("root")).unwrap();
+    symlink(dir.join("data"), dir.join("root/link")).unwrap();
+    symlink(dir.join("data"), dir.join("root/link2")).unwrap();
+    symlink(dir.join("root"), dir.join("data/loop")).unwrap();
+    let root = CanonicalPathBuf::assert_canonicalized(&dir.join("root"));
+    let mut tree = FileTree::new();
+    let node = tree
+        .add_root(
+            root.clone(),
+            &RootOptions::new().recursive(true).follow_symlinks(true),
+        )
+        .unwrap();
+    tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    let found = |path: &str| tree.find(&root.join(path.as_ref())).is_some();
+    assert!(found("link/file"));
+    // already visited through `link`
+    assert!(!found("link2/file"));
+    assert!(!found("link/loop/link"));
+
+    let mut tree = FileTree::new();
+    let node = tree
+        .add_root(root.clone(), &RootOptions::new().recursive(true))
+        .unwrap();
+    tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    assert!(tree.find(&root.join("link/file".as_ref())).is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_loop() {
+    use std::os::unix::fs::symlink;
+
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    mk_write(&dir, "a/file", "foo");
+    symlink(&dir, dir.join("a/loop")).unwrap();
+    let watcher = Watcher::new().unwrap();
+    let _shutdown_guard = watcher.shutdown_guard();
+    let (tx, rx) = mpsc::channel();
+    watcher.on_symlink_loop(move |link, target| {
+        let _ = tx.send((link.to_owned(), target.to_owned()));
+    });
+    let (err_tx, err_rx) = mpsc::channel();
+    watcher.on_error(move |err| {
+        let _ = err_tx.send(err.path);
+    });
+    watcher.start().unwrap();
+    let (crawled_tx, crawled_rx) = mpsc::sync_channel(1);
+    let options = RootOptions::new().recursive(true).follow_symlinks(true);
+    watcher
+        .add_root_with(&dir, options, move |success| {
+            let _ = crawled_tx.send(success);
+        })
+        .unwrap();
+    assert!(crawled_rx.recv_timeout(*TIMEOUT).unwrap());
+    let event = rx.recv_timeout(*TIMEOUT).expect("loop wasn't reported");
+    assert_eq!(event, (dir.join("a/loop"), dir.clone()));
+    // a loop isn't an error and is only reported once, even if crawled again
+    let recrawls = watcher.stats().recrawls;
+    watcher.pause();
+    watcher.resume();
+    let start = Instant::now();
+    while watcher.stats().recrawls == recrawls {
+        assert!(start.elapsed() < *TIMEOUT, "watcher didn't recrawl");
+        thread::sleep(Duration::from_millis(10));
+    }
+    watcher.flush();
+    assert!(rx.try_recv().is_err());
+    assert!(err_rx.try_recv().is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn cross_filesystem() {
+    use std::os::unix::fs::MetadataExt;
+
+    use crate::tree::FileTree;
+
+    // /dev/shm is a tmpfs mounted below devtmpfs on most systems
+    let dev = |path| fs::metadata(path).map(|meta| meta.dev());
+    let (Ok(outer), Ok(inner)) = (dev("/dev"), dev("/dev/shm")) else {
+        return;
+    };
+    if outer == inner {
+        return;
+    }
+    let Ok(file) = tempfile::NamedTempFile::new_in("/dev/shm") else {
+        return;
+    };
+    let root = CanonicalPathBuf::assert_canonicalized(Path::new("/dev"));
+    let file = CanonicalPathBuf::assert_canonicalized(file.path());
+    for cross_filesystem in [false, true] {
+        let mut tree = FileTree::new();
+        let options = RootOptions::new()
+            .recursive(true)
+            .cross_filesystem(cross_filesystem);
+        let node = tree.add_root(root.clone(), &options).unwrap();
+        tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+        // the mount point itself is always tracked, only its contents are skipped
+        assert!(tree.find(&root.join("shm".as_ref())).is_some());
+        assert_eq!(tree.find(&file).is_some(), cross_filesystem);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_root_link() {
+    use std::os::unix::fs::symlink;
+
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path().canonicalize().unwrap();
+    mk_write(&dir, "data/file", "foo");
+    mk_write(&dir, "other/file", "foo");
+    symlink(dir.join("other"), dir.join("data/link")).unwrap();
+    symlink(dir.join("data"), dir.join("root")).unwrap();
+    let root = CanonicalPathBuf::assert_canonicalized(&dir.join("root"));
+    let mut tree = FileTree::new();
+    let node = tree
+        .add_root(
+            root.clone(),
+            &RootOptions::new().recursive(true).follow_root_link(true),
+        )
+        .unwrap();
+    tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    let found = |path: &str| tree.find(&root.join(path.as_ref())).is_some();
+    assert!(found("file"));
+    // symlinks below the root are not followed
+    assert!(!found("link/file"));
+
+    // symlinks aren't tracked, the root can't be added without following it
+    let mut tree = FileTree::new();
+    assert!(tree
+        .add_root(root, &RootOptions::new().recursive(true))
+        .is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn crawl_errors() {
+    use std::os::unix::fs::symlink;
+
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let dir = root.as_std_path();
+    write(dir, "file", "foo");
+    // following a dangling symlink fails
+    symlink(dir.join("missing"), dir.join("dangling")).unwrap();
+    let mut tree = FileTree::new();
+    let node = tree
+        .add_root(
+            root.clone(),
+            &RootOptions::new().recursive(true).follow_symlinks(true),
+        )
+        .unwrap();
+    let mut errors = Vec::new();
+    tree.crawl_root(
+        node,
+        true,
+        &(),
+        |_, _, _, _, _| (),
+        |_| (),
+        |err| errors.push(err),
+    );
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, dir.join("dangling"));
+    assert!(tree.find(&root.join("file".as_ref())).is_some());
+}
+
+#[test]
+fn events_channel() {
+    with_watcher(|dir, watcher| {
+        let events = watcher.events_channel(1);
+        write(dir, "foo", "bar");
+        let events = events.recv_timeout(*TIMEOUT).unwrap();
+        let event = events.iter().next().unwrap();
+        assert_eq!(event.path.as_std_path(), dir.join("foo"));
+        assert_eq!(event.ty, EventType::Create);
+    });
+}
+
+#[test]
+fn events_channel_full() {
+    with_watcher(|dir, watcher| {
+        let events = watcher.events_channel(1);
+        let (tx, delivered) = mpsc::channel();
+        // runs after the batch was sent to the channel
+        watcher.add_handler(move |_| tx.send(()).is_ok());
+        write(dir, "foo", "bar");
+        delivered.recv_timeout(*TIMEOUT).unwrap();
+        // the worker blocks sending this batch until the first is received
+        write(dir, "bar", "baz");
+        assert!(delivered.recv_timeout(Duration::from_millis(500)).is_err());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn({
+            let watcher = watcher.clone();
+            move || {
+                watcher.set_settle_time(Duration::from_millis(10));
+                watcher.add_handler(|_| false);
+                let _ = tx.send(());
+            }
+        });
+        rx.recv_timeout(*TIMEOUT)
+            .expect("configuring the watcher blocked on the full channel");
+        let mut paths = Vec::new();
+        while paths.len() < 2 {
+            let batch = events.recv_timeout(*TIMEOUT).unwrap();
+            paths.extend(
+                batch
+                    .iter()
+                    .map(|event| event.path.as_std_path().to_owned()),
+            );
+        }
+        assert_eq!(paths, ["foo", "bar"].map(|file| dir.join(file)));
+    });
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn event_stream() {
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use futures_core::Stream;
+
+    struct Unpark(Thread);
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match Pin::new(&mut *stream).poll_next(&mut cx) {
+                Poll::Ready(item) => return item,
+                Poll::Pending => thread::park_timeout(*TIMEOUT),
+            }
+        }
+    }
+
+    let (dir, watcher) = init_watcher();
+    let mut stream = watcher.event_stream();
+    write(dir.path(), "foo", "bar");
+    let events = next(&mut stream).unwrap();
+    assert_eq!(events.iter().next().unwrap().ty, EventType::Create);
+    watcher.shutdown();
+    assert!(next(&mut stream).is_none());
+}
+
+#[test]
+fn stats() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("foo/bar", EventType::Create)]);
+        mk_write(dir, "foo/bar", "baz");
+        assertion.check();
+        let stats = watcher.stats();
+        // FSEvents and ReadDirectoryChangesW watch recursively
+        assert!(stats.watches >= 1);
+        assert_eq!(stats.nodes, 3);
+        assert_eq!(stats.reclaimable, 0);
+        assert_eq!(stats.queue_overflows, 0);
+    });
+}
+
+#[test]
+fn recrawl_backoff() {
+    use crate::worker::Worker;
+
+    let watcher = Watcher::new().unwrap();
+    let ms = Duration::from_millis;
+    watcher.set_recrawl_interval(ms(100), ms(400));
+    let mut worker = Worker::new(watcher);
+    let delays: Vec<_> = (0..5).map(|_| worker.recrawl_now()).collect();
+    assert_eq!(delays, [0, 100, 200, 400, 400].map(ms));
+    // the backoff is reset once recrawls stop arriving in rapid succession
+    thread::sleep(ms(450));
+    assert_eq!(worker.recrawl_now(), Duration::ZERO);
+    assert_eq!(worker.recrawl_now(), ms(100));
+}
+
+#[test]
+fn pause() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("foo", EventType::Create)]);
+        write(dir, "foo", "bar");
+        assertion.check();
+        let recrawls = watcher.stats().recrawls;
+        watcher.pause();
+        let events = watcher.events_channel(16);
+        write(dir, "bar", "baz");
+        rm_file(dir, "foo");
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(events.try_recv().is_err());
+        watcher.resume();
+        let mut events = events.recv_timeout(*TIMEOUT).unwrap().to_std_vec();
+        events.sort();
+        assert_eq!(
+            events,
+            [
+                (dir.join("bar"), EventType::Create),
+                (dir.join("foo"), EventType::Delete)
+            ]
+        );
+        assert_eq!(watcher.stats().recrawls, recrawls + 1);
+    });
+}
+
+#[test]
+fn max_depth() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let dir = root.as_std_path();
+    mk_write(dir, "a/b/c/file", "foo");
+    let mut tree = FileTree::new();
+    let options = RootOptions::new().recursive(true).max_depth(Some(2));
+    let node = tree.add_root(root.clone(), &options).unwrap();
+    let mut watched = Vec::new();
+    tree.crawl_root(
+        node,
+        true,
+        &(),
+        |_, _, _, _, _| (),
+        |path| watched.push(path),
+        |_| (),
+    );
+    let found = |tree: &FileTree, path: &str| tree.find(&root.join(path.as_ref())).is_some();
+    assert!(found(&tree, "a/b"));
+    assert!(!found(&tree, "a/b/c"));
+    assert_eq!(watched, [root.join("a".as_ref())]);
+
+    // new directories at the maximum depth are leaves too
+    mk_write(dir, "a/new/file", "foo");
+    let mut changes = PendingChanges::default();
+    changes.add_watcher(
+        root.join("a".as_ref()),
+        SystemTime::now(),
+        pending::Flags::NEEDS_NON_RECURSIVE_CRAWL,
+    );
+    let mut watched = Vec::new();
+    tree.apply_transaction(
+        &mut changes,
+        &(),
+        |_, _, _, _, _| (),
+        &mut Vec::new(),
+        |path| watched.push(path),
+        |_| (),
+    );
+    assert!(found(&tree, "a/new"));
+    assert!(!found(&tree, "a/new/file"));
+    assert_eq!(watched, [root.join("a".as_ref())]);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_crawl() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let dir = root.as_std_path();
+    for i in 0..20 {
+        mk_write(dir, &format!("{}/{i}/file", i % 4), "foo");
+        mk_write(dir, &format!("{}/{i}/sub/file", i % 4), "foo");
+    }
+    // a nested root that was crawled before is skipped with its children
+    for (max_depth, nested) in [(None, false), (Some(2), false), (None, true)] {
+        let crawl = |parallel| {
+            let mut tree = FileTree::new();
+            tree.parallel_crawl = parallel;
+            let options = RootOptions::new().recursive(true).max_depth(max_depth);
+            if nested {
+                let nested = root.join("0".as_ref());
+                let node = tree.add_root(nested, &options).unwrap();
+                tree.crawl_root(node, true, &(), |_, _, _, _, _| (), |_| (), |_| ());
+            }
+            let node = tree.add_root(root.clone(), &options).unwrap();
+            let mut watched = Vec::new();
+            tree.crawl_root(
+                node,
+                true,
+                &(),
+                |_, _, _, _, _| (),
+                |path| watched.push(path),
+                |_| (),
+            );
+            let mut paths: Vec<_> = tree.iter().cloned().collect();
+            paths.sort();
+            watched.sort();
+            (paths, watched)
+        };
+        assert_eq!(crawl(false), crawl(true));
+    }
+}
+
+#[test]
+fn hash_contents() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let dir = root.as_std_path();
+    write(dir, "file", "foo");
+    let mut tree = FileTree::new();
+    tree.hash_contents = Some(1024);
+    let node = tree.add_root(root.clone(), &RootOptions::new()).unwrap();
+    tree.crawl_root(node, false, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    let check = |tree: &mut FileTree| {
+        let events = apply_change(tree, root.join("file".as_ref()), pending::Flags::empty());
+        events.into_iter().map(|(_, ty)| ty).collect::<Vec<_>>()
+    };
+
+    // only touched
+    let mtime = fs::metadata(dir.join("file")).unwrap().modified().unwrap();
+    let file = fs::File::options()
+        .write(true)
+        .open(dir.join("file"))
+        .unwrap();
+    file.set_modified(mtime + Duration::from_secs(10)).unwrap();
+    assert_eq!(check(&mut tree), []);
+    // same size and mtime but different contents
+    write(dir, "file", "bar");
+    file.set_modified(mtime + Duration::from_secs(10)).unwrap();
+    assert_eq!(check(&mut tree), [EventType::Modified]);
+}
+
+#[test]
+fn node_memory() {
+    use crate::tree::FileTree;
+
+    // `FsNode` used 7 words before its metadata was packed
+    const BASELINE: usize = 7 * 8;
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    for i in 0..1000 {
+        write(root.as_std_path(), &format!("file{i}"), "foo");
+    }
+    let mut tree = FileTree::with_capacity(0, false);
+    let node = tree.add_root(root.clone(), &RootOptions::new()).unwrap();
+    tree.crawl_root(node, false, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    assert_eq!(tree.node_count(), 1001);
+    // the nodes vec grows by doubling
+    assert!(tree.node_memory() <= 1024 * 6 * 8);
+    assert!(tree.node_memory() < 1024 * BASELINE);
+
+    // hashes are kept outside of the nodes and only when enabled
+    let mut tree = FileTree::with_capacity(0, false);
+    tree.hash_contents = Some(1024);
+    let node = tree.add_root(root.clone(), &RootOptions::new()).unwrap();
+    tree.crawl_root(node, false, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    assert!(tree.node_memory() > 1024 * 6 * 8);
+}
+
+// sparse files are cheap to create on unix
+#[cfg(unix)]
+#[test]
+fn large_file_size() {
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let path = root.join("file".as_ref());
+    let file = fs::File::create(path.as_std_path()).unwrap();
+    file.set_len(5 << 30).unwrap();
+    let mtime = fs::metadata(path.as_std_path())
+        .unwrap()
+        .modified()
+        .unwrap();
+    let mut tree = FileTree::new();
+    let node = tree.add_root(root.clone(), &RootOptions::new()).unwrap();
+    tree.crawl_root(node, false, &(), |_, _, _, _, _| (), |_| (), |_| ());
+
+    // the lower 32 bits of the size stay the same
+    file.set_len(9 << 30).unwrap();
+    file.set_modified(mtime).unwrap();
+    let mut changes = PendingChanges::default();
+    changes.add_watcher(path.clone(), SystemTime::now(), pending::Flags::empty());
+    let mut events = Vec::new();
+    tree.apply_transaction(
+        &mut changes,
+        &(),
+        |_, ty, _, prev, _| events.push((ty, prev.map(|prev| prev.size))),
+        &mut Vec::new(),
+        |_| (),
+        |_| (),
+    );
+    assert_eq!(events, [(EventType::Modified, Some(5 << 30))]);
+}
+
+#[cfg(unix)]
+#[test]
+fn report_metadata_changes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::tree::FileTree;
+
+    let dir = TempDir::new().unwrap();
+    let root = tree_root(&dir);
+    let dir = root.as_std_path();
+    write(dir, "file", "foo");
+    let mut tree = FileTree::new();
+    let node = tree.add_root(root.clone(), &RootOptions::new()).unwrap();
+    tree.crawl_root(node, false, &(), |_, _, _, _, _| (), |_| (), |_| ());
+    let check = |tree: &mut FileTree| {
+        // changes found by crawling are only reported if the metadata changed
+        let flags = pending::Flags::NEEDS_NON_RECURSIVE_CRAWL;
+        let events = apply_change(tree, root.clone(), flags);
+        events.into_iter().map(|(_, ty)| ty).collect::<Vec<_>>()
+    };
+    let chmod = |mode| {
+        // make sure the ctime actually changes
+        std::thread::sleep(Duration::from_millis(50));
+        fs::set_permissions(dir.join("file"), fs::Permissions::from_mode(mode)).unwrap();
+    };
+
+    chmod(0o600);
+    assert_eq!(check(&mut tree), []);
+    // the ctime is recorded the next time the file is stat-ed
+    tree.compare_ctime = true;
+    assert_eq!(check(&mut tree), []);
+    chmod(0o644);
+    assert_eq!(check(&mut tree), [EventType::Modified]);
+}
+
+#[test]
+fn report_directories() {
+    with_watcher(|dir, watcher| {
+        watcher.set_report_directories(true);
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [
+                ("foo", EventType::Create),
+                ("foo/bar", EventType::Create),
+                ("foo/bar/baz", EventType::Create),
+            ],
+        );
+        mk_write(dir, "foo/bar/baz", "foo");
+        assertion.check();
+        let assertion = Assertion::new(
+            watcher,
+            dir,
+            [
+                ("foo", EventType::Delete),
+                ("foo/bar", EventType::Delete),
+                ("foo/bar/baz", EventType::Delete),
+            ],
+        );
+        fs::remove_dir_all(dir.join("foo")).unwrap();
+        assertion.check();
+    });
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn watch_mask() {
+    with_watcher(|dir, watcher| {
+        // only affects directories watched afterwards
+        watcher.set_watch_mask(crate::WatchMask::ATTRIB);
+        let assertion = Assertion::new(watcher, dir, [("sub/file", EventType::Create)]);
+        mk_write(dir, "sub/file", "foo");
+        assertion.check();
+        let events = watcher.events_channel(16);
+        write(dir, "sub/file", "foobar");
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(events.try_recv().is_err());
+    });
+}
+
+#[test]
+fn max_batch() {
+    with_watcher(|dir, watcher| {
+        watcher.set_max_batch(Some(2));
+        let events = watcher.events_channel(16);
+        for file in ["a", "b", "c", "d", "e"] {
+            write(dir, file, "foo");
+        }
+        let mut paths = Vec::new();
+        while paths.len() < 5 {
+            let batch = events.recv_timeout(*TIMEOUT).unwrap();
+            assert!(batch.len() <= 2);
+            paths.extend(
+                batch
+                    .iter()
+                    .map(|event| event.path.as_std_path().to_owned()),
+            );
+        }
+        paths.sort();
+        let expected: Vec<_> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|file| dir.join(file))
+            .collect();
+        assert_eq!(paths, expected);
+    });
+}
+
+#[test]
+fn events_by_type() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("foo", EventType::Create)]);
+        write(dir, "foo", "bar");
+        assertion.check();
+        let events = watcher.events_channel(16);
+        write(dir, "bar", "baz");
+        rm_file(dir, "foo");
+        let mut created = Vec::new();
+        let mut deleted = Vec::new();
+        while created.len() + deleted.len() < 2 {
+            let batch = events.recv_timeout(*TIMEOUT).unwrap();
+            assert_eq!(batch.modified().count(), 0);
+            created.extend(batch.created().map(|path| path.as_std_path().to_owned()));
+            deleted.extend(batch.deleted().map(|path| path.as_std_path().to_owned()));
+        }
+        assert_eq!(created, [dir.join("bar")]);
+        assert_eq!(deleted, [dir.join("foo")]);
+    });
+}
+
+#[test]
+fn events_sorted() {
+    with_watcher(|dir, watcher| {
+        let events = watcher.events_channel(16);
+        for file in ["d/b", "c", "d/a", "a", "b/c"] {
+            mk_write(dir, file, "foo");
+        }
+        let mut seen = 0;
+        while seen < 5 {
+            let batch = events.recv_timeout(*TIMEOUT).unwrap();
+            assert!(batch
+                .windows(2)
+                .all(|events| events[0].path < events[1].path));
+            seen += batch.len();
+        }
+    });
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde() {
+    use crate::{Event, Events};
+
+    with_watcher(|dir, watcher| {
+        let events = watcher.events_channel(16);
+        write(dir, "foo", "bar");
+        let events = events.recv_timeout(*TIMEOUT).unwrap();
+        let json = serde_json::to_string(&events).unwrap();
+        assert!(!json.contains("\\u0000"));
+        let deserialized: Events = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, events);
+        let event: Event =
+            serde_json::from_str(&serde_json::to_string(&events[0]).unwrap()).unwrap();
+        assert_eq!(event.path.as_std_path(), dir.join("foo"));
+        assert!(event.path.as_std_path().is_file());
+    });
+}
+
+#[test]
+fn snapshot() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("foo/bar", EventType::Create)]);
+        mk_write(dir, "foo/bar", "baz");
+        assertion.check();
+        let snapshot = |watcher: &Watcher| -> Vec<_> {
+            watcher
+                .snapshot()
+                .iter()
+                .map(|path| path.as_std_path().to_owned())
+                .collect()
+        };
+        assert_eq!(
+            snapshot(watcher),
+            [dir.to_owned(), dir.join("foo"), dir.join("foo/bar")]
+        );
+        let assertion = Assertion::new(watcher, dir, [("foo/bar", EventType::Delete)]);
+        rm_file(dir, "foo/bar");
+        assertion.check();
+        assert_eq!(snapshot(watcher), [dir.to_owned(), dir.join("foo")]);
+    });
+}
+
+#[test]
+fn is_watching() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("foo/bar", EventType::Create)]);
+        mk_write(dir, "foo/bar", "baz");
+        assertion.check();
+        assert!(watcher.is_watching(dir));
+        assert!(watcher.is_watching(&dir.join("foo/bar")));
+        assert!(watcher.is_watching(&dir.join("foo/../foo/bar")));
+        assert!(!watcher.is_watching(&dir.join("missing")));
+        assert!(!watcher.is_watching(dir.parent().unwrap()));
+
+        struct Ignore;
+        impl Filter for Ignore {
+            fn ignore_path(&self, path: &Path, _is_dir: Option<bool>) -> bool {
+                path.ends_with("foo")
+            }
+        }
+        watcher.set_filter(Arc::new(Ignore), false);
+        assert!(!watcher.is_watching(&dir.join("foo/bar")));
+    });
+}
+
+#[test]
+fn recreated_dir_is_watched() {
+    with_watcher(|dir, watcher| {
+        let assertion = Assertion::new(watcher, dir, [("sub/foo", EventType::Create)]);
+        mk_write(dir, "sub/foo", "bar");
+        assertion.check();
+        let assertion = Assertion::new(watcher, dir, [("sub/foo", EventType::Modified)]);
+        fs::remove_dir_all(dir.join("sub")).unwrap();
+        mk_write(dir, "sub/foo", "baz");
+        assertion.check();
+        // the new directory must have been watched again
+        let assertion = Assertion::new(watcher, dir, [("sub/bar", EventType::Create)]);
+        write(dir, "sub/bar", "baz");
+        assertion.check();
     });
 }
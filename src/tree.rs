@@ -1,25 +1,51 @@
-use std::hash::{BuildHasher, Hash};
-use std::mem::replace;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, Read};
+use std::mem::{replace, take};
 use std::ops::{Index, IndexMut};
+use std::path::Path;
 use std::slice;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use bitflags::bitflags;
 use ecow::EcoVec;
 use hashbrown::hash_table::Entry;
-use hashbrown::{DefaultHashBuilder, HashTable};
+use hashbrown::{DefaultHashBuilder, HashMap, HashSet, HashTable};
 use walkdir::WalkDir;
 
-use crate::config::Filter;
-use crate::events::EventType;
+use crate::config::{Filter, WatchError};
+use crate::events::{EventType, FileMeta};
 use crate::metadata::Metadata;
-use crate::path::CanonicalPathBuf;
+use crate::path::{hash_path, CannonicalPath, CanonicalPathBuf};
 use crate::pending::{self, PendingChange, PendingChanges};
+use crate::RootOptions;
+
+#[cfg(feature = "parallel")]
+mod parallel;
+
+/// sizes are stored with 48 bits, larger sizes saturate
+const MAX_SIZE: u64 = (1 << 48) - 1;
+
+/// nanoseconds since the epoch, times before the epoch are clamped to it
+fn to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum NodeMeta {
     Dir,
-    File { mtime: SystemTime, size: usize },
+    /// the fields are packed to keep the tree small, `SystemTime` and `usize`
+    /// would need an extra word
+    File {
+        /// nanoseconds since the epoch
+        mtime: u64,
+        /// the lower 32 bits of the size
+        size: u32,
+        /// the upper 16 bits of the size
+        size_hi: u16,
+    },
     Deleted,
 }
 
@@ -36,36 +62,119 @@ impl NodeMeta {
         if meta.is_dir {
             NodeMeta::Dir
         } else {
+            let size = (meta.size as u64).min(MAX_SIZE);
             NodeMeta::File {
-                mtime: meta.mtime,
-                size: meta.size,
+                mtime: to_nanos(meta.mtime),
+                size: size as u32,
+                size_hi: (size >> 32) as u16,
             }
         }
     }
 
-    fn change_type(&self, new: &Self, skip_check: bool) -> Option<EventType> {
-        // we only care for changes that inolve a file, ingnore everything else
+    /// the metadata reported for a file that was moved without stat-ing it,
+    /// the mtime stands in for the ctime if it isn't tracked
+    fn metadata(&self, inode: u64, extra: Option<&ExtraMeta>) -> Option<Metadata> {
+        let meta = self.file_meta()?;
+        let ctime = extra.map_or(meta.mtime, |extra| {
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(extra.ctime)
+        });
+        Some(Metadata {
+            is_dir: false,
+            mtime: meta.mtime,
+            ctime,
+            size: meta.size,
+            inode,
+            dev: 0,
+        })
+    }
+
+    fn file_meta(&self) -> Option<FileMeta> {
+        match *self {
+            NodeMeta::File {
+                mtime,
+                size,
+                size_hi,
+                ..
+            } => Some(FileMeta {
+                mtime: SystemTime::UNIX_EPOCH + Duration::from_nanos(mtime),
+                size: ((size_hi as u64) << 32 | size as u64) as usize,
+                is_dir: false,
+            }),
+            _ => None,
+        }
+    }
+
+    fn change_type(
+        &self,
+        new: &Self,
+        skip_check: bool,
+        extra: Option<(ExtraMeta, ExtraMeta)>,
+        compare_ctime: bool,
+        report_directories: bool,
+    ) -> Option<EventType> {
+        // we only care for changes that inolve a file (or a directory if
+        // enabled), ingnore everything else
         match (&self, &new) {
             (
-                NodeMeta::File { mtime, size },
+                NodeMeta::File {
+                    mtime,
+                    size,
+                    size_hi,
+                },
                 NodeMeta::File {
                     mtime: nmtime,
                     size: nsize,
+                    size_hi: nsize_hi,
                 },
             ) => {
-                if !skip_check && mtime == nmtime && size == nsize {
-                    None
+                // if both versions were hashed only the contents matter
+                let mut unchanged = if let Some((old, new)) =
+                    extra.filter(|(old, new)| old.hash != 0 && new.hash != 0)
+                {
+                    old.hash == new.hash
                 } else {
-                    Some(EventType::Modified)
+                    !skip_check && mtime == nmtime && size == nsize && size_hi == nsize_hi
+                };
+                // permission and ownership changes only update the ctime
+                if let Some((old, new)) = extra.filter(|_| compare_ctime) {
+                    unchanged &= old.ctime == new.ctime;
                 }
+                (!unchanged).then_some(EventType::Modified)
+            }
+            (NodeMeta::Dir, NodeMeta::File { .. }) | (NodeMeta::File { .. }, NodeMeta::Dir)
+                if report_directories =>
+            {
+                Some(EventType::TypeChanged)
             }
             (NodeMeta::Deleted | NodeMeta::Dir, NodeMeta::File { .. }) => Some(EventType::Create),
             (NodeMeta::File { .. }, NodeMeta::Deleted | NodeMeta::Dir) => Some(EventType::Delete),
+            (NodeMeta::Deleted, NodeMeta::Dir) if report_directories => Some(EventType::Create),
             _ => None,
         }
     }
 }
 
+/// Metadata of files that is only tracked when enabled, see
+/// `FileTree::hash_contents` and `FileTree::compare_ctime`. It's kept out of
+/// `FsNode` in a side table so the tree doesn't grow when it's not used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ExtraMeta {
+    /// hash of the contents or 0 if the file wasn't hashed
+    hash: u64,
+    /// nanoseconds since the epoch
+    ctime: u64,
+}
+
+impl ExtraMeta {
+    fn new(meta: &Metadata, hash: u64) -> ExtraMeta {
+        ExtraMeta {
+            hash,
+            ctime: to_nanos(meta.ctime),
+        }
+    }
+}
+
+/// iterates the paths of all nodes that are not deleted
 #[derive(Debug, Clone)]
 pub struct TreeIter<'a> {
     iter: slice::Iter<'a, FsNode>,
@@ -75,7 +184,9 @@ impl<'a> Iterator for TreeIter<'a> {
     type Item = &'a CanonicalPathBuf;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|it| &it.path)
+        self.iter
+            .find(|it| !matches!(it.meta, NodeMeta::Deleted))
+            .map(|it| &it.path)
     }
 }
 
@@ -128,8 +239,11 @@ impl From<usize> for NodeId {
     }
 }
 
+// the path and the packed metadata take two words each, the inode one and
+// the flags share the last one with the children. Five words would need a
+// thinner path or dropping the inode, which detects replaced directories.
 const _ASSERT: () = {
-    if size_of::<FsNode>() != 7 * 8 {
+    if size_of::<FsNode>() != 6 * 8 {
         panic!("size of FsNode must stay constant")
     }
 };
@@ -146,13 +260,31 @@ bitflags! {
         const WATCH_CHILDREN = 0b10;
         /// wether this node is being watched recursively
         const RECURSIVE = 0b110;
+        /// wether crawls may descend into other filesystems, inherited
+        /// from the root
+        const CROSS_FILESYSTEM = 0b1000;
+        /// wether symlinks are followed, inherited from the root
+        const FOLLOW_SYMLINKS = 0b10000;
+        /// wether the root limits the crawl depth, see `FileTree::remaining_depth`
+        const DEPTH_LIMITED = 0b100000;
+        /// nodes added with `FileTree::add_root`, the worker holds on to
+        /// their ids so their slots are never reused
+        const ROOT = 0b1000000;
+        /// the slot of this node is unused, see `FileTree::reclaim`
+        const FREED = 0b10000000;
+        /// wether a root that is a symlink is followed, not inherited
+        const FOLLOW_ROOT_LINK = 0b100000000;
+        /// flags that are inherited from the parent
+        const INHERITED = Self::CROSS_FILESYSTEM.bits()
+            | Self::FOLLOW_SYMLINKS.bits()
+            | Self::DEPTH_LIMITED.bits();
     }
 }
 
 #[derive(Debug)]
 pub struct FsNode {
     pub path: CanonicalPathBuf, // 2 words
-    meta: NodeMeta,             // 3 words
+    meta: NodeMeta,             // 2 words
     inode: u64,                 // 1 word
     flags: Flags,               // 1 word
     children: DirId,
@@ -170,22 +302,162 @@ impl FsNode {
     pub fn unset_maybe_deleted_flag(&mut self) {
         self.flags.remove(Flags::MAYBE_DELETED)
     }
+
+    /// `None` if the node was deleted
+    pub fn is_dir(&self) -> Option<bool> {
+        match self.meta {
+            NodeMeta::Deleted => None,
+            NodeMeta::Dir => Some(true),
+            NodeMeta::File { .. } => Some(false),
+        }
+    }
+
+    pub fn watches_children(&self) -> bool {
+        !matches!(self.meta, NodeMeta::Deleted) && self.flags.contains(Flags::WATCH_CHILDREN)
+    }
 }
 
 pub struct FileTree {
     path_table: HashTable<NodeId>,
     hasher: DefaultHashBuilder,
+    /// paths that only differ in the case of ASCII letters refer to the
+    /// same node, for case-insensitive filesystems
+    fold_case: bool,
     nodes: Vec<FsNode>,
     dirs: Vec<EcoVec<NodeId>>,
+    /// unused slots in `nodes` and `dirs` that are filled before growing them
+    free_nodes: Vec<NodeId>,
+    free_dirs: Vec<DirId>,
+    /// nodes that were deleted since the last `reclaim`
+    tombstones: Vec<NodeId>,
+    /// symlinks that were found to form a loop, these are only
+    /// reported once and not again on every recrawl
+    symlink_loops: HashSet<CanonicalPathBuf>,
+    new_symlink_loops: Vec<(CanonicalPathBuf, CanonicalPathBuf)>,
+    /// whether any root follows symlinks (or its root link), avoids looking up the flag
+    /// for every change otherwise
+    follows_symlinks: bool,
+    /// the maximum depth of the roots with a depth limit
+    depth_limits: Vec<(CanonicalPathBuf, usize)>,
+    /// roots that are files, their parent directory is watched instead
+    /// (without being part of the tree)
+    file_roots: Vec<CanonicalPathBuf>,
+    /// directories (from, to) that were moved within the tree since the
+    /// last call to `take_moved_dirs`
+    moved_dirs: Vec<(CanonicalPathBuf, CanonicalPathBuf)>,
+    /// roots that were deleted (or replaced by a different directory) since
+    /// the last call to `take_removed_roots`
+    removed_roots: Vec<CanonicalPathBuf>,
+    /// files up to this size are hashed to detect modifications
+    pub hash_contents: Option<usize>,
+    /// the `ExtraMeta` of files, empty unless `tracks_extra_meta`
+    extra_meta: HashMap<NodeId, ExtraMeta>,
+    /// report changes of the ctime as modifications
+    pub compare_ctime: bool,
+    /// emit events for directories, not just files
+    pub report_directories: bool,
+    /// apply changes in the order they were observed instead of by path
+    pub preserve_order: bool,
+    /// report changes to a file for all of its hardlinks, see `links`
+    pub track_hardlinks: bool,
+    /// report files that were removed before they could be stat-ed as
+    /// tempfiles instead of dropping them
+    pub report_vanished_files: bool,
+    /// the files known for each (device, inode) if `track_hardlinks` is
+    /// set, entries may be stale and are validated on use
+    links: HashMap<(u64, u64), Vec<NodeId>>,
+    /// crawl (recursive) roots in parallel
+    #[cfg(feature = "parallel")]
+    pub parallel_crawl: bool,
+}
+
+impl Default for FileTree {
+    fn default() -> Self {
+        Self::with_capacity(0, false)
+    }
 }
 
 impl FileTree {
+    #[cfg(test)]
     pub fn new() -> Self {
+        Self::with_case_folding(false)
+    }
+
+    #[cfg(test)]
+    pub fn with_case_folding(fold_case: bool) -> Self {
+        Self::with_capacity(1024, fold_case)
+    }
+
+    /// A tree with room for `capacity` nodes before it reallocates. Paths
+    /// that only differ in the case of ASCII letters are treated as the same
+    /// path if `fold_case` is set.
+    pub fn with_capacity(capacity: usize, fold_case: bool) -> Self {
         Self {
-            path_table: HashTable::with_capacity(1024),
+            path_table: HashTable::with_capacity(capacity),
             hasher: DefaultHashBuilder::default(),
-            nodes: Vec::with_capacity(1024),
-            dirs: Vec::with_capacity(128),
+            fold_case,
+            nodes: Vec::with_capacity(capacity),
+            dirs: Vec::with_capacity(capacity / 8),
+            free_nodes: Vec::new(),
+            free_dirs: Vec::new(),
+            tombstones: Vec::new(),
+            symlink_loops: HashSet::new(),
+            new_symlink_loops: Vec::new(),
+            follows_symlinks: false,
+            depth_limits: Vec::new(),
+            file_roots: Vec::new(),
+            moved_dirs: Vec::new(),
+            removed_roots: Vec::new(),
+            hash_contents: None,
+            extra_meta: HashMap::new(),
+            compare_ctime: false,
+            report_directories: false,
+            preserve_order: false,
+            track_hardlinks: false,
+            report_vanished_files: false,
+            links: HashMap::new(),
+            #[cfg(feature = "parallel")]
+            parallel_crawl: true,
+        }
+    }
+
+    /// returns the symlink loops (symlink and loop target) discovered
+    /// since the last call
+    pub fn take_symlink_loops(&mut self) -> Vec<(CanonicalPathBuf, CanonicalPathBuf)> {
+        take(&mut self.new_symlink_loops)
+    }
+
+    /// returns the directories (from, to) that were moved within the tree
+    /// since the last call, the backend must update the paths of their watches
+    pub fn take_moved_dirs(&mut self) -> Vec<(CanonicalPathBuf, CanonicalPathBuf)> {
+        take(&mut self.moved_dirs)
+    }
+
+    /// returns the roots that were deleted or replaced since the last call
+    pub fn take_removed_roots(&mut self) -> Vec<CanonicalPathBuf> {
+        take(&mut self.removed_roots)
+    }
+
+    fn crawl_error(
+        &mut self,
+        root: NodeId,
+        err: walkdir::Error,
+        on_error: &mut impl FnMut(WatchError),
+    ) {
+        let (Some(link), Some(target)) = (err.path(), err.loop_ancestor()) else {
+            let path = err
+                .path()
+                .unwrap_or(self[root].path.as_std_path())
+                .to_owned();
+            log::warn!("failed to crawl {path:?}: {err}");
+            on_error(WatchError { path, error: err });
+            return;
+        };
+        let link = CanonicalPathBuf::assert_canonicalized(link);
+        if self.symlink_loops.insert(link.clone()) {
+            log::warn!("symlink {link:?} forms a loop with {target:?}");
+            let target = CanonicalPathBuf::assert_canonicalized(target);
+            self.new_symlink_loops.push((link, target));
         }
     }
 
@@ -193,34 +465,719 @@ impl FileTree {
         &mut self,
         transaction: &mut PendingChanges,
         filter: &dyn Filter,
-        mut emit_event: impl FnMut(CanonicalPathBuf, EventType),
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
         work_stack: &mut Vec<(NodeId, usize)>,
         mut add_watch: impl FnMut(CanonicalPathBuf),
+        mut on_error: impl FnMut(WatchError),
     ) {
-        let mut transaction = transaction.drain().peekable();
-        while let Some(change) = transaction.next() {
-            let (node, recurse) = self.apply_change(&change, work_stack, &mut emit_event);
-            if recurse {
-                if node.is_some()
-                    && self[node].meta.is_dir()
-                    // double check that this path is not ignored before dowing an expensive crawl
-                    && !filter.ignore_path(change.path.as_std_path(), Some(true))
-                {
-                    self.crawl(node, filter, work_stack, &mut emit_event, &mut add_watch);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "apply_transaction",
+            changes = transaction.len(),
+            nodes = self.node_count()
+        )
+        .entered();
+        if !self.tracks_extra_meta() && !self.extra_meta.is_empty() {
+            self.extra_meta = HashMap::new();
+        }
+        let moved_start = self.moved_dirs.len();
+        let renames = transaction.take_renames();
+        if !renames.is_empty() {
+            let time = SystemTime::now();
+            for (from, to) in renames {
+                if self.move_dir(&from, &to, filter, time, &mut emit_event, transaction) {
+                    self.moved_dirs.push((from, to));
+                } else {
+                    transaction.add_rename(from, to);
+                }
+            }
+        }
+        let moved_dirs = take(&mut self.moved_dirs);
+        let mut transaction = transaction
+            .drain(self.preserve_order, self.fold_case)
+            .peekable();
+        while let Some(mut change) = transaction.next() {
+            for (from, to) in &moved_dirs[moved_start..] {
+                if change.path.eq_path(to.as_os_str(), self.fold_case) {
+                    // the subtree was already moved, no need to crawl it
+                    change.flags.remove(
+                        pending::Flags::NEEDS_RECURSIVE_CRAWL
+                            | pending::Flags::NEEDS_NON_RECURSIVE_CRAWL,
+                    );
+                } else if from.is_parent_of_path(&change.path, self.fold_case) {
+                    // observed by a watch that still used the old path
+                    change.path = change
+                        .path
+                        .replace_prefix_path(from, to, self.fold_case)
+                        .unwrap();
                 }
+            }
+            if !self.file_roots.is_empty() && self.find(&change.path).is_none() {
+                let roots = self.file_roots_in(change.path.as_std_path());
+                if !roots.is_empty() {
+                    // some backends only report that the directory changed
+                    for path in roots {
+                        let change = PendingChange {
+                            path,
+                            flags: pending::Flags::empty(),
+                            timestamp: change.timestamp,
+                        };
+                        self.apply_change(&change, filter, work_stack, &mut emit_event);
+                    }
+                    continue;
+                }
+                // another entry of the parent directory of a file root
+                let untracked_sibling = change.path.parent().is_some_and(|parent| {
+                    self.find_path(parent).is_none() && !self.file_roots_in(parent).is_empty()
+                });
+                if untracked_sibling {
+                    continue;
+                }
+            }
+            // the event for a new directory may arrive after the events for
+            // its children, crawl the missing directory instead of dropping
+            // the change
+            if let Some(ancestor) = self.missing_ancestor(&change.path) {
+                log::debug!(
+                    "{:?} is not in the tree yet, crawling {ancestor:?}",
+                    change.path
+                );
+                change.path = ancestor;
+            }
+            let (node, recurse) = self.apply_change(&change, filter, work_stack, &mut emit_event);
+            // the backend only told us that something changed inside this
+            // directory, re-stat its direct children
+            let shallow = !recurse
+                && change
+                    .flags
+                    .contains(pending::Flags::NEEDS_NON_RECURSIVE_CRAWL)
+                && node.is_some()
+                && self[node].flags.contains(Flags::WATCH_CHILDREN);
+            if (recurse || shallow)
+                && node.is_some()
+                && self[node].meta.is_dir()
+                // double check that this path is not ignored before dowing an expensive crawl
+                && !filter.ignore_path(change.path.as_std_path(), Some(true))
+            {
+                self.crawl(
+                    node,
+                    shallow,
+                    filter,
+                    work_stack,
+                    &mut emit_event,
+                    &mut add_watch,
+                    &mut on_error,
+                );
+            }
+            if recurse {
                 // skip any pending changes for child directories
                 while transaction
-                    .next_if(|next_change| change.path.is_parent_of(&next_change.path))
+                    .next_if(|next_change| {
+                        change
+                            .path
+                            .is_parent_of_path(&next_change.path, self.fold_case)
+                    })
                     .is_some()
                 {}
             }
         }
+        drop(transaction);
+        self.moved_dirs = moved_dirs;
+        self.reclaim();
+    }
+
+    /// Moves the directory `from` and all nodes below it to `to` by
+    /// rewriting their paths, which avoids deleting `from` and crawling
+    /// `to`. Emits a `Delete` and `Create` event for every moved file and
+    /// records them as renames in `transaction`. Returns `false` (and leaves
+    /// the tree untouched) if `to` isn't inside a recursively watched
+    /// directory with the same options, in that case the changes are
+    /// applied as usual.
+    fn move_dir(
+        &mut self,
+        from: &CanonicalPathBuf,
+        to: &CanonicalPathBuf,
+        filter: &dyn Filter,
+        time: SystemTime,
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
+        transaction: &mut PendingChanges,
+    ) -> bool {
+        let (Some(id), Some(old_parent), Some(parent)) = (
+            self.find(from),
+            self.find_parent(from),
+            self.find_parent(to),
+        ) else {
+            return false;
+        };
+        let node = &self[id];
+        let movable = node.meta.is_dir()
+            && node.flags.contains(Flags::RECURSIVE)
+            && !node.flags.intersects(Flags::ROOT | Flags::DEPTH_LIMITED)
+            && self[parent].flags.contains(Flags::RECURSIVE)
+            && (self[parent].flags & Flags::INHERITED).bits()
+                == (node.flags & Flags::INHERITED).bits()
+            && self.find(to).is_none();
+        if !movable {
+            return false;
+        }
+        // collect the new paths first so nothing is changed if the filter
+        // ignores a path at the new location or the subtree contains a root
+        let mut moved = vec![(id, to.clone())];
+        let mut i = 0;
+        while let Some((id, path)) = moved.get(i) {
+            let (id, path) = (*id, path.clone());
+            i += 1;
+            let node = &self[id];
+            if (i != 1 && node.flags.contains(Flags::ROOT))
+                || filter.ignore_path(path.as_std_path(), node.is_dir())
+            {
+                return false;
+            }
+            if node.children.is_none() {
+                continue;
+            }
+            for &child in self[node.children].iter() {
+                let name = self[child].path.strip_prefix(&self[id].path).unwrap();
+                moved.push((child, path.join(name.as_os_str())));
+            }
+        }
+        log::debug!("moving {from:?} to {to:?} ({} nodes)", moved.len());
+        let children = self[old_parent].children;
+        self[children].retain(|child| *child != id);
+        self.add_child(parent, id);
+        for (id, path) in moved {
+            self.unlink_path(id);
+            let old_path = replace(&mut self[id].path, path.clone());
+            let hash = hash_path(&self.hasher, path.as_os_str(), self.fold_case);
+            let (nodes, hasher, fold_case) = (&self.nodes, &self.hasher, self.fold_case);
+            self.path_table.insert_unique(hash, id, |id| {
+                hash_path(hasher, nodes[id.idx()].path.as_os_str(), fold_case)
+            });
+            let node = &self[id];
+            let report = match node.meta {
+                NodeMeta::File { .. } => true,
+                NodeMeta::Dir => self.report_directories,
+                NodeMeta::Deleted => false,
+            };
+            if report {
+                let meta = node.meta.metadata(node.inode, self.extra_meta.get(&id));
+                let prev = node.meta.is_dir().then_some(FileMeta::DIR);
+                emit_event(old_path.clone(), EventType::Delete, None, prev, time);
+                emit_event(path.clone(), EventType::Create, meta.as_ref(), None, time);
+                transaction.add_rename(old_path, path);
+            }
+        }
+        true
+    }
+
+    /// whether symlinks at `path` are followed, determined by the node
+    /// itself or its parent if it isn't in the tree yet
+    fn follows_symlinks(&self, path: &CannonicalPath) -> bool {
+        if !self.follows_symlinks {
+            return false;
+        }
+        if let Some(node) = self.find(path) {
+            return self[node]
+                .flags
+                .intersects(Flags::FOLLOW_SYMLINKS | Flags::FOLLOW_ROOT_LINK);
+        }
+        let Some(parent) = path.parent() else {
+            return false;
+        };
+        self.find_path(parent)
+            .is_some_and(|node| self[node].flags.contains(Flags::FOLLOW_SYMLINKS))
+    }
+
+    /// how many levels below `node` may still be crawled, `None` if the
+    /// depth is not limited
+    fn remaining_depth(&self, node: NodeId) -> Option<usize> {
+        if !self[node].flags.contains(Flags::DEPTH_LIMITED) {
+            return None;
+        }
+        let path = &self[node].path;
+        let (root, max_depth) = self
+            .depth_limits
+            .iter()
+            .filter(|(root, _)| {
+                root.eq_path(path.as_os_str(), self.fold_case)
+                    || root.is_parent_of_path(path, self.fold_case)
+            })
+            .max_by_key(|(root, _)| root.as_os_str().len())?;
+        let depth =
+            path.as_std_path().components().count() - root.as_std_path().components().count();
+        Some(max_depth.saturating_sub(depth))
+    }
+
+    /// hashes the contents of the file at `path`, returns 0 if the file is
+    /// not hashed (or can't be read)
+    fn content_hash(&self, path: &CannonicalPath, meta: &Metadata) -> u64 {
+        content_hash(&self.hasher, self.hash_contents, path, meta)
+    }
+
+    /// stats (and hashes) `path`, metadata rejected by the filter is treated
+    /// like a missing file
+    fn stat(&self, path: &CanonicalPathBuf, filter: &dyn Filter) -> (Option<Metadata>, u64) {
+        let fs_meta = Metadata::for_path(path, self.follows_symlinks(path))
+            .filter(|meta| !filter.ignore_metadata(path.as_std_path(), meta));
+        let content_hash = fs_meta
+            .as_ref()
+            .map_or(0, |meta| self.content_hash(path, meta));
+        (fs_meta, content_hash)
+    }
+
+    /// whether `ExtraMeta` is recorded for files
+    fn tracks_extra_meta(&self) -> bool {
+        self.hash_contents.is_some() || self.compare_ctime
+    }
+
+    /// records the `ExtraMeta` of `id` after it was stat-ed
+    fn set_extra_meta(&mut self, id: NodeId, fs_meta: &Metadata, content_hash: u64) {
+        if self.tracks_extra_meta() && !fs_meta.is_dir {
+            self.extra_meta
+                .insert(id, ExtraMeta::new(fs_meta, content_hash));
+        } else if !self.extra_meta.is_empty() {
+            self.extra_meta.remove(&id);
+        }
+    }
+
+    /// whether the backend reported a change for a file we never saw inside
+    /// a watched directory, which means it was created and removed again
+    /// before it could be stat-ed
+    fn vanished(&self, change: &PendingChange) -> bool {
+        change.flags.contains(pending::Flags::ORIGIN_WATCHER)
+            && self
+                .find_parent(&change.path)
+                .is_some_and(|parent| self[parent].watches_children())
+            && fs::symlink_metadata(change.path.as_std_path())
+                .is_err_and(|err| err.kind() == io::ErrorKind::NotFound)
+    }
+
+    pub fn find(&self, path: &CannonicalPath) -> Option<NodeId> {
+        self.find_hashed(path.as_os_str())
+    }
+
+    /// Removes a node and all of its children from the tree. In contrast to
+    /// `delete_rec` no events are emitted, the nodes are simply forgotten.
+    pub fn remove(&mut self, id: NodeId, work_stack: &mut Vec<(NodeId, usize)>) {
+        let parent = self[id]
+            .path
+            .parent()
+            .and_then(|parent| self.find_path(parent));
+        if let Some(parent) = parent {
+            if self[parent].children.is_some() {
+                let children = self[parent].children;
+                self[children].retain(|child| *child != id);
+            }
+        }
+        if self[id].flags.contains(Flags::DEPTH_LIMITED) {
+            let path = self[id].path.clone();
+            self.depth_limits.retain(|(root, _)| *root != path);
+        }
+        if !self.file_roots.is_empty() {
+            let path = self[id].path.clone();
+            self.file_roots.retain(|root| *root != path);
+        }
+        let start_len = work_stack.len();
+        work_stack.push((id, 0));
+        while work_stack.len() > start_len {
+            let (id, child) = work_stack.last_mut().unwrap();
+            let id = *id;
+            let children = self[id].children;
+            if children.is_some() {
+                if let Some(&child_id) = self[children].get(*child) {
+                    *child += 1;
+                    work_stack.push((child_id, 0));
+                    continue;
+                }
+                self[children] = EcoVec::new();
+            }
+            work_stack.pop();
+            if !self[id].flags.contains(Flags::ROOT) {
+                self.release(id);
+                continue;
+            }
+            self.unlink_path(id);
+            if children.is_some() {
+                self.free_dirs.push(children);
+            }
+            self.extra_meta.remove(&id);
+            let node = &mut self[id];
+            node.meta = NodeMeta::Deleted;
+            node.flags = Flags::ROOT;
+            node.children = DirId::NONE;
+        }
+    }
+
+    fn find_path(&self, path: &Path) -> Option<NodeId> {
+        self.find_hashed(path.as_os_str())
+    }
+
+    /// looks up `path` in the path table, honoring `fold_case`
+    fn find_hashed(&self, path: &OsStr) -> Option<NodeId> {
+        let hash = hash_path(&self.hasher, path, self.fold_case);
+        self.path_table
+            .find(hash, |&id| {
+                self.nodes[id.idx()].path.eq_path(path, self.fold_case)
+            })
+            .copied()
+    }
+
+    /// removes `id` from the path table, the node itself is left untouched
+    fn unlink_path(&mut self, id: NodeId) {
+        let hash = hash_path(&self.hasher, self[id].path.as_os_str(), self.fold_case);
+        if let Ok(entry) = self.path_table.find_entry(hash, |&tree_id| tree_id == id) {
+            entry.remove();
+        }
+    }
+
+    fn find_parent(&self, path: &CannonicalPath) -> Option<NodeId> {
+        self.find_path(path.parent()?)
+    }
+
+    /// The topmost ancestor of `path` that is missing from the tree. `None`
+    /// if the parent of `path` is in the tree or if the missing ancestor is
+    /// not inside a directory whose children are watched.
+    fn missing_ancestor(&self, path: &CannonicalPath) -> Option<CanonicalPathBuf> {
+        let mut ancestors = path.ancestors().skip(1);
+        let mut missing = ancestors.next()?;
+        if self.find_path(missing).is_some() {
+            return None;
+        }
+        for parent in ancestors {
+            if let Some(node) = self.find_path(parent) {
+                return self[node]
+                    .watches_children()
+                    .then(|| CanonicalPathBuf::assert_canonicalized(missing));
+            }
+            missing = parent;
+        }
+        None
+    }
+
+    /// Records `id` as a link of the inode in `meta`. If the file `changed`
+    /// all other known links are reported as modified, they share the
+    /// contents (and metadata) of `id`.
+    fn update_links(
+        &mut self,
+        id: NodeId,
+        meta: &Metadata,
+        changed: bool,
+        time: SystemTime,
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
+    ) {
+        let key = (meta.dev, meta.inode);
+        let mut links = self.links.remove(&key).unwrap_or_default();
+        let nodes = &self.nodes;
+        links.retain(|&link| {
+            let node = &nodes[link.idx()];
+            link != id && node.inode == meta.inode && node.meta.is_file()
+        });
+        if changed {
+            let node_meta = self[id].meta.clone();
+            let extra = self.extra_meta.get(&id).copied();
+            for &link in &links {
+                if let Some(extra) = extra {
+                    self.extra_meta.insert(link, extra);
+                }
+                let node = &mut self.nodes[link.idx()];
+                let prev = replace(&mut node.meta, node_meta.clone()).file_meta();
+                emit_event(
+                    node.path.clone(),
+                    EventType::Modified,
+                    Some(meta),
+                    prev,
+                    time,
+                );
+            }
+        }
+        links.push(id);
+        self.links.insert(key, links);
+    }
+
+    /// whether `id` was added as a root even though it's a file
+    pub fn is_file_root(&self, id: NodeId) -> bool {
+        self.file_roots.contains(&self[id].path)
+    }
+
+    /// the file roots directly inside `dir`
+    fn file_roots_in(&self, dir: &Path) -> Vec<CanonicalPathBuf> {
+        self.file_roots
+            .iter()
+            .filter(|root| root.parent() == Some(dir))
+            .cloned()
+            .collect()
+    }
+
+    /// whether the backend needs to watch `dir`, either because its
+    /// children are tracked or because it contains a file root
+    pub fn needs_watch(&self, dir: &CannonicalPath) -> bool {
+        self.find(dir)
+            .is_some_and(|node| self[node].watches_children())
+            || !self.file_roots_in(dir.as_std_path()).is_empty()
+    }
+
+    /// marks `id` as deleted, its slot is reused once it's not needed anymore
+    fn tombstone(&mut self, id: NodeId) {
+        if !matches!(self[id].meta, NodeMeta::Deleted) {
+            self.tombstones.push(id);
+        }
+        self[id].meta = NodeMeta::Deleted;
+        self.extra_meta.remove(&id);
+    }
+
+    /// whether the slot of `id` can be reused, deleted directories are
+    /// only released once all of their children were released
+    fn is_releasable(&self, id: NodeId) -> bool {
+        let node = &self[id];
+        matches!(node.meta, NodeMeta::Deleted)
+            && !node.flags.intersects(Flags::ROOT | Flags::FREED)
+            && (node.children.is_none()
+                || self[node.children]
+                    .iter()
+                    .all(|&child| self[child].flags.contains(Flags::FREED)))
+    }
+
+    /// removes `id` from the tree and puts its slot on the free list, the
+    /// caller must unlink it from its parent
+    fn release(&mut self, id: NodeId) {
+        self.unlink_path(id);
+        let children = self[id].children;
+        if children.is_some() {
+            self[children] = EcoVec::new();
+            self.free_dirs.push(children);
+        }
+        self.extra_meta.remove(&id);
+        self[id] = FsNode {
+            path: CanonicalPathBuf::new(),
+            meta: NodeMeta::Deleted,
+            inode: 0,
+            flags: Flags::FREED,
+            children: DirId::NONE,
+        };
+        self.free_nodes.push(id);
+    }
+
+    /// Releases the slots of nodes deleted since the last call so they can
+    /// be reused. Must not be called while node ids are held (for example
+    /// on the work stack).
+    fn reclaim(&mut self) {
+        let mut tombstones = take(&mut self.tombstones);
+        let mut parents = Vec::new();
+        for &id in &tombstones {
+            let mut id = id;
+            while self.is_releasable(id) {
+                let parent = self.find_parent(&self[id].path);
+                self.release(id);
+                let Some(parent) = parent else {
+                    break;
+                };
+                parents.push(parent);
+                id = parent;
+            }
+        }
+        parents.sort_unstable_by_key(|parent| parent.0);
+        parents.dedup();
+        for parent in parents {
+            let children = self[parent].children;
+            if self[parent].flags.contains(Flags::FREED) || children.is_none() {
+                continue;
+            }
+            let nodes = &self.nodes;
+            self.dirs[children.idx()]
+                .retain(|child| !nodes[child.idx()].flags.contains(Flags::FREED));
+            if self[children].is_empty() {
+                self.free_dirs.push(children);
+                self[parent].children = DirId::NONE;
+            }
+        }
+        tombstones.clear();
+        self.tombstones = tombstones;
+    }
+
+    /// the id of the next node added to the tree, see `insert_node`
+    fn next_node_id(&self) -> NodeId {
+        self.free_nodes
+            .last()
+            .copied()
+            .unwrap_or_else(|| NodeId::from(self.nodes.len()))
+    }
+
+    fn insert_node(&mut self, id: NodeId, node: FsNode) {
+        if id.idx() == self.nodes.len() {
+            self.nodes.push(node);
+        } else {
+            debug_assert_eq!(self.free_nodes.last(), Some(&id));
+            self.free_nodes.pop();
+            self[id] = node;
+        }
+    }
+
+    /// number of nodes that are kept in the tree even though their file was deleted
+    pub fn node_count(&self) -> usize {
+        self.nodes.len() - self.free_nodes.len()
+    }
+
+    pub fn iter(&self) -> TreeIter<'_> {
+        TreeIter {
+            iter: self.nodes.iter(),
+        }
+    }
+
+    /// bytes allocated for the nodes, including the side table of their
+    /// `ExtraMeta`
+    #[cfg(test)]
+    pub fn node_memory(&self) -> usize {
+        self.nodes.capacity() * size_of::<FsNode>()
+            + self.extra_meta.capacity() * size_of::<(NodeId, ExtraMeta)>()
+    }
+
+    /// number of released nodes whose slots were not reused yet
+    pub fn free_slots(&self) -> usize {
+        self.free_nodes.len()
+    }
+
+    pub fn tombstone_count(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| {
+                matches!(node.meta, NodeMeta::Deleted) && !node.flags.contains(Flags::FREED)
+            })
+            .count()
+    }
+
+    /// Rebuilds the tree dropping all deleted nodes that are not needed anymore.
+    /// Only nodes reachable from `roots` are retained and the ids in `roots` are
+    /// updated to point to the new nodes. Returns the number of reclaimed nodes.
+    pub fn compact(&mut self, roots: &mut [&mut NodeId]) -> usize {
+        // post-order traversal so that we only drop nodes for which all children
+        // were dropped too (a deleted directory may still contain live files if
+        // it was recreated before the tree caught up)
+        let mut keep = vec![false; self.nodes.len()];
+        let mut visited = vec![false; self.nodes.len()];
+        for root in roots.iter() {
+            keep[root.idx()] = true;
+        }
+        let mut stack: Vec<(NodeId, usize)> = Vec::with_capacity(16);
+        for root in roots.iter() {
+            let root = **root;
+            if visited[root.idx()] {
+                continue;
+            }
+            visited[root.idx()] = true;
+            stack.push((root, 0));
+            while let Some(&mut (id, ref mut child)) = stack.last_mut() {
+                let children = self[id].children;
+                if children.is_some() {
+                    if let Some(&child_id) = self[children].get(*child) {
+                        *child += 1;
+                        if visited[child_id.idx()] {
+                            keep[id.idx()] |= keep[child_id.idx()];
+                        } else {
+                            visited[child_id.idx()] = true;
+                            stack.push((child_id, 0));
+                        }
+                        continue;
+                    }
+                }
+                stack.pop();
+                keep[id.idx()] |= !matches!(self[id].meta, NodeMeta::Deleted);
+                if keep[id.idx()] {
+                    if let Some(&(parent, _)) = stack.last() {
+                        keep[parent.idx()] = true;
+                    }
+                }
+            }
+        }
+
+        let old_len = self.nodes.len();
+        let mut remap = vec![NodeId::NONE; old_len];
+        let old_nodes = replace(&mut self.nodes, Vec::with_capacity(old_len));
+        let old_dirs = take(&mut self.dirs);
+        for (i, node) in old_nodes.into_iter().enumerate() {
+            if keep[i] {
+                remap[i] = NodeId::from(self.nodes.len());
+                self.nodes.push(node);
+            }
+        }
+        for i in 0..self.nodes.len() {
+            let children = self.nodes[i].children;
+            if children.is_none() {
+                continue;
+            }
+            let children: EcoVec<NodeId> = old_dirs[children.idx()]
+                .iter()
+                .map(|child| remap[child.idx()])
+                .filter(|child| child.is_some())
+                .collect();
+            self.nodes[i].children = if children.is_empty() {
+                DirId::NONE
+            } else {
+                let dir = self.dirs.len().into();
+                self.dirs.push(children);
+                dir
+            };
+        }
+        self.path_table.clear();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let hash = hash_path(&self.hasher, node.path.as_os_str(), self.fold_case);
+            self.path_table.insert_unique(hash, NodeId::from(i), |id| {
+                hash_path(
+                    &self.hasher,
+                    self.nodes[id.idx()].path.as_os_str(),
+                    self.fold_case,
+                )
+            });
+        }
+        for root in roots {
+            **root = remap[root.idx()];
+        }
+        for links in self.links.values_mut() {
+            links.retain_mut(|link| {
+                *link = remap[link.idx()];
+                link.is_some()
+            });
+        }
+        self.links.retain(|_, links| !links.is_empty());
+        self.extra_meta = take(&mut self.extra_meta)
+            .into_iter()
+            .filter_map(|(id, extra)| {
+                let id = remap[id.idx()];
+                id.is_some().then_some((id, extra))
+            })
+            .collect();
+        self.free_nodes.clear();
+        self.free_dirs.clear();
+        self.tombstones.clear();
+        old_len - self.nodes.len()
     }
 
     fn reserve_dir(&mut self, node: NodeId, size: usize) -> DirId {
-        let dir = self.dirs.len().into();
+        let dir = if let Some(dir) = self.free_dirs.pop() {
+            self[dir] = EcoVec::with_capacity(size);
+            dir
+        } else {
+            self.dirs.push(EcoVec::with_capacity(size));
+            DirId::from(self.dirs.len() - 1)
+        };
         self[node].children = dir;
-        self.dirs.push(EcoVec::with_capacity(size));
         dir
     }
 
@@ -237,22 +1194,45 @@ impl FileTree {
     pub fn apply_change(
         &mut self,
         change: &PendingChange,
+        filter: &dyn Filter,
         work_stack: &mut Vec<(NodeId, usize)>,
-        mut emit_event: impl FnMut(CanonicalPathBuf, EventType),
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
     ) -> (NodeId, bool) {
-        let fs_meta = Metadata::for_path(&change.path);
+        let (fs_meta, content_hash) = self.stat(&change.path, filter);
 
-        let hash = self.hasher.hash_one(&change.path);
+        let hash = hash_path(&self.hasher, change.path.as_os_str(), self.fold_case);
+        let next_id = self.next_node_id();
         let entry = self.path_table.entry(
             hash,
-            |&tree_id| self.nodes[tree_id.idx()].path == change.path,
-            |id| self.hasher.hash_one(&self.nodes[id.idx()].path),
+            |&tree_id| {
+                self.nodes[tree_id.idx()]
+                    .path
+                    .eq_path(change.path.as_os_str(), self.fold_case)
+            },
+            |id| {
+                hash_path(
+                    &self.hasher,
+                    self.nodes[id.idx()].path.as_os_str(),
+                    self.fold_case,
+                )
+            },
         );
         let mut recursive = change.flags.contains(pending::Flags::NEEDS_RECURSIVE_CRAWL);
         let mark_recursive = change.flags.contains(pending::Flags::MARK_RECURSIVE);
         match entry {
             Entry::Occupied(entry) => {
                 let id = *entry.get();
+                let extra = self.extra_meta.get(&id).copied().zip(
+                    fs_meta
+                        .as_ref()
+                        .map(|meta| ExtraMeta::new(meta, content_hash)),
+                );
                 let node = &mut self.nodes[id.idx()];
                 if mark_recursive {
                     node.flags |= Flags::RECURSIVE
@@ -265,14 +1245,37 @@ impl FileTree {
                     // have given us the correct hints about this change.  BTRFS is one
                     // example of a filesystem where this has been observed to happen.
                     recursive |= inode_changed;
+                    // the root was deleted and recreated before we noticed
+                    let replaced_root =
+                        inode_changed && node.meta.is_dir() && node.flags.contains(Flags::ROOT);
                     node.inode = fs_meta.inode;
                     let changed = node.meta.change_type(
                         &meta,
-                        inode_changed | change.flags.contains(pending::Flags::ORIGIN_WATCHER),
+                        inode_changed
+                            | (change.flags.contains(pending::Flags::ORIGIN_WATCHER)
+                                && !change.flags.contains(pending::Flags::CLOSED)),
+                        extra,
+                        self.compare_ctime,
+                        self.report_directories,
                     );
+                    // a directory that was replaced by a file
+                    if node.meta.is_dir() && meta.is_file() {
+                        self.delete_children(id, change.timestamp, work_stack, &mut emit_event);
+                    }
+                    if replaced_root {
+                        self.removed_roots.push(change.path.clone());
+                    }
+                    let node = &mut self.nodes[id.idx()];
                     if let Some(changed) = changed {
-                        emit_event(change.path.clone(), changed);
-                        recursive |= changed == EventType::Create;
+                        let meta = (changed != EventType::Delete).then_some(&fs_meta);
+                        emit_event(
+                            change.path.clone(),
+                            changed,
+                            meta,
+                            node.meta.file_meta(),
+                            change.timestamp,
+                        );
+                        recursive |= matches!(changed, EventType::Create | EventType::TypeChanged);
                     }
                     node.meta = meta;
                     let watch_children = node.flags.contains(Flags::WATCH_CHILDREN);
@@ -283,12 +1286,32 @@ impl FileTree {
                     {
                         self.reserve_dir(id, fs_meta.size);
                     }
+                    self.set_extra_meta(id, &fs_meta, content_hash);
+                    if self.track_hardlinks && !fs_meta.is_dir {
+                        let changed = changed.is_some();
+                        self.update_links(id, &fs_meta, changed, change.timestamp, &mut emit_event);
+                    }
                     (id, recursive && watch_children)
                 } else {
                     let old_meta = replace(&mut node.meta, NodeMeta::Deleted);
+                    self.extra_meta.remove(&id);
+                    if old_meta != NodeMeta::Deleted {
+                        if node.flags.contains(Flags::ROOT) {
+                            self.removed_roots.push(change.path.clone());
+                        }
+                        self.tombstones.push(id);
+                    }
                     match old_meta {
-                        NodeMeta::Dir => self.delete_rec(id, work_stack, &mut emit_event),
-                        NodeMeta::File { .. } => emit_event(change.path.clone(), EventType::Delete),
+                        NodeMeta::Dir => {
+                            self.delete_rec(id, change.timestamp, work_stack, &mut emit_event)
+                        }
+                        NodeMeta::File { .. } => emit_event(
+                            change.path.clone(),
+                            EventType::Delete,
+                            None,
+                            None,
+                            change.timestamp,
+                        ),
                         NodeMeta::Deleted => (),
                     }
                     (id, true)
@@ -296,44 +1319,67 @@ impl FileTree {
             }
             Entry::Vacant(entry) => {
                 let Some(fs_meta) = fs_meta else {
+                    if self.report_vanished_files && self.vanished(change) {
+                        emit_event(
+                            change.path.clone(),
+                            EventType::Tempfile,
+                            None,
+                            None,
+                            change.timestamp,
+                        );
+                    }
                     return (NodeId::NONE, true);
                 };
                 let meta = NodeMeta::new(&fs_meta);
-                let id = NodeId::from(self.nodes.len());
+                let id = next_id;
                 entry.insert(id);
-                let parent = change.path.parent().and_then(|parent| {
-                    let hash = self.hasher.hash_one(parent.as_os_str());
-                    self.path_table
-                        .find(hash, |&id| self.nodes[id.idx()].path == parent)
-                        .copied()
-                });
+                let parent = change
+                    .path
+                    .parent()
+                    .and_then(|parent| self.find_path(parent));
                 let Some(parent) = parent else {
                     log::error!("for {change:?} the parent wasn't yet in the tree! Ignoring...");
                     self.path_table
-                        .find_entry(hash, |&tree_id| {
-                            self.nodes[tree_id.idx()].path == change.path
-                        })
+                        .find_entry(hash, |&tree_id| tree_id == id)
                         .unwrap()
                         .remove();
                     return (NodeId::NONE, true);
                 };
                 self.add_child(parent, id);
                 recursive = mark_recursive || self[parent].flags.contains(Flags::RECURSIVE);
-                let flags = if recursive {
-                    Flags::RECURSIVE
-                } else {
-                    Flags::empty()
-                };
-                self.nodes.push(FsNode {
-                    path: change.path.clone(),
-                    meta,
-                    flags,
-                    inode: fs_meta.inode,
-                    children: DirId::NONE,
-                });
-                if !fs_meta.is_dir {
-                    emit_event(change.path.clone(), EventType::Create)
-                } else if recursive && fs_meta.size != 0 {
+                // directories at the maximum depth are leaves
+                if self.remaining_depth(parent).is_some_and(|depth| depth <= 1) {
+                    recursive = false;
+                }
+                let mut flags = self[parent].flags & Flags::INHERITED;
+                if recursive {
+                    flags |= Flags::RECURSIVE
+                }
+                self.insert_node(
+                    id,
+                    FsNode {
+                        path: change.path.clone(),
+                        meta,
+                        flags,
+                        inode: fs_meta.inode,
+                        children: DirId::NONE,
+                    },
+                );
+                self.set_extra_meta(id, &fs_meta, content_hash);
+                if !fs_meta.is_dir || self.report_directories {
+                    emit_event(
+                        change.path.clone(),
+                        EventType::Create,
+                        Some(&fs_meta),
+                        None,
+                        change.timestamp,
+                    )
+                }
+                // a new link changes the link count of the existing ones
+                if self.track_hardlinks && !fs_meta.is_dir {
+                    self.update_links(id, &fs_meta, true, change.timestamp, &mut emit_event);
+                }
+                if fs_meta.is_dir && recursive && fs_meta.size != 0 {
                     self.reserve_dir(id, fs_meta.size);
                 }
                 (id, recursive)
@@ -341,16 +1387,74 @@ impl FileTree {
         }
     }
 
-    pub fn add_root(&mut self, root: CanonicalPathBuf, recursive: bool) -> Option<NodeId> {
-        self.add(root, recursive, true)
+    pub fn add_root(&mut self, root: CanonicalPathBuf, options: &RootOptions) -> Option<NodeId> {
+        let id = self.add(root, options.recursive, options.follow_root_link)?;
+        self[id].flags.insert(Flags::ROOT);
+        if self[id].is_dir() == Some(false) {
+            self[id].flags.remove(Flags::RECURSIVE);
+            self.file_roots.push(self[id].path.clone());
+        }
+        if options.cross_filesystem {
+            self[id].flags.insert(Flags::CROSS_FILESYSTEM);
+        }
+        if options.follow_symlinks {
+            self[id].flags.insert(Flags::FOLLOW_SYMLINKS);
+            self.follows_symlinks = true;
+        }
+        if options.follow_root_link {
+            self[id].flags.insert(Flags::FOLLOW_ROOT_LINK);
+            self.follows_symlinks = true;
+        }
+        if let Some(max_depth) = options.max_depth {
+            self[id].flags.insert(Flags::DEPTH_LIMITED);
+            let path = self[id].path.clone();
+            self.depth_limits.retain(|(root, _)| *root != path);
+            self.depth_limits.push((path, max_depth));
+        }
+        Some(id)
+    }
+
+    /// adds a root, `follow_link` stats its symlink target if it is a symlink
+    fn add(
+        &mut self,
+        path: CanonicalPathBuf,
+        recursive: bool,
+        follow_link: bool,
+    ) -> Option<NodeId> {
+        let follow_symlinks = follow_link || self.follows_symlinks(&path);
+        let fs_meta = Metadata::for_path(&path, follow_symlinks);
+        let content_hash = fs_meta
+            .as_ref()
+            .map_or(0, |meta| self.content_hash(&path, meta));
+        self.add_stated(path, fs_meta, content_hash, recursive, true, |_, _| ())
     }
 
-    fn add(&mut self, path: CanonicalPathBuf, recursive: bool, root: bool) -> Option<NodeId> {
-        let hash = self.hasher.hash_one(&path);
+    /// like `add` but with the metadata (and content hash) of `path` already known
+    fn add_stated(
+        &mut self,
+        path: CanonicalPathBuf,
+        fs_meta: Option<Metadata>,
+        content_hash: u64,
+        recursive: bool,
+        root: bool,
+        mut on_create: impl FnMut(&CanonicalPathBuf, &Metadata),
+    ) -> Option<NodeId> {
+        let hash = hash_path(&self.hasher, path.as_os_str(), self.fold_case);
+        let next_id = self.next_node_id();
         let entry = self.path_table.entry(
             hash,
-            |&tree_id| self.nodes[tree_id.idx()].path == path,
-            |id| self.hasher.hash_one(&self.nodes[id.idx()].path),
+            |&tree_id| {
+                self.nodes[tree_id.idx()]
+                    .path
+                    .eq_path(path.as_os_str(), self.fold_case)
+            },
+            |id| {
+                hash_path(
+                    &self.hasher,
+                    self.nodes[id.idx()].path.as_os_str(),
+                    self.fold_case,
+                )
+            },
         );
         match entry {
             Entry::Occupied(entry) => {
@@ -361,9 +1465,9 @@ impl FileTree {
                     return None;
                 }
                 let id = *entry.get();
-                // also roots can only be dirs, not files
+                // a file is already watched by the root containing it
                 if root && !self[id].meta.is_dir() {
-                    log::error!("invalid root {path:?}: not a directory");
+                    log::debug!("already watching {path:?}");
                     return None;
                 }
                 if self[id].flags.contains(Flags::RECURSIVE) {
@@ -374,42 +1478,47 @@ impl FileTree {
                 Some(id)
             }
             Entry::Vacant(entry) => {
-                let fs_meta = Metadata::for_path(&path)?;
+                let fs_meta = fs_meta?;
                 let meta = NodeMeta::new(&fs_meta);
-                let id = NodeId::from(self.nodes.len());
+                let id = next_id;
                 entry.insert(id);
-                let parent = path.parent().and_then(|parent| {
-                    let hash = self.hasher.hash_one(parent.as_os_str());
-                    self.path_table
-                        .find(hash, |&id| self.nodes[id.idx()].path == parent)
-                        .copied()
-                });
+                let parent = path.parent().and_then(|parent| self.find_path(parent));
+                let mut flags = if recursive {
+                    Flags::RECURSIVE
+                } else if root {
+                    Flags::WATCH_CHILDREN
+                } else {
+                    Flags::empty()
+                };
                 if let Some(parent) = parent {
                     self.add_child(parent, id);
+                    flags |= self[parent].flags & Flags::INHERITED;
                 } else if !root {
                     log::error!("for {path:?} the parent wasn't yet in the tree! Ignoring...");
                     self.path_table
-                        .find_entry(hash, |&tree_id| self.nodes[tree_id.idx()].path == path)
+                        .find_entry(hash, |&tree_id| tree_id == id)
                         .unwrap()
                         .remove();
                     return None;
                 };
-                self.nodes.push(FsNode {
-                    path: path.clone(),
-                    meta,
-                    inode: fs_meta.inode,
-                    children: DirId::NONE,
-                    flags: if recursive {
-                        Flags::RECURSIVE
-                    } else if root {
-                        Flags::WATCH_CHILDREN
-                    } else {
-                        Flags::empty()
+                self.insert_node(
+                    id,
+                    FsNode {
+                        path: path.clone(),
+                        meta,
+                        inode: fs_meta.inode,
+                        children: DirId::NONE,
+                        flags,
                     },
-                });
+                );
+                self.set_extra_meta(id, &fs_meta, content_hash);
                 if fs_meta.is_dir && (recursive || root) && fs_meta.size != 0 {
                     self.reserve_dir(id, fs_meta.size);
                 }
+                if self.track_hardlinks && !fs_meta.is_dir {
+                    self.update_links(id, &fs_meta, false, SystemTime::now(), |_, _, _, _, _| ());
+                }
+                on_create(&path, &fs_meta);
                 Some(id)
             }
         }
@@ -420,53 +1529,157 @@ impl FileTree {
     fn delete_rec(
         &mut self,
         id: NodeId,
+        time: SystemTime,
         work_stack: &mut Vec<(NodeId, usize)>,
-        mut emit_event: impl FnMut(CanonicalPathBuf, EventType),
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
+    ) {
+        if self.report_directories {
+            let path = self[id].path.clone();
+            emit_event(path, EventType::Delete, None, Some(FileMeta::DIR), time);
+        }
+        if self[id].children.is_none() {
+            return;
+        }
+        self.tombstone(id);
+        self.delete_children(id, time, work_stack, emit_event);
+    }
+
+    /// marks all descendants of a directory as deleted
+    fn delete_children(
+        &mut self,
+        id: NodeId,
+        time: SystemTime,
+        work_stack: &mut Vec<(NodeId, usize)>,
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
     ) {
         if self[id].children.is_none() {
             return;
         }
-        self[id].meta = NodeMeta::Deleted;
         let start_len = work_stack.len();
         work_stack.push((id, 0));
         while work_stack.len() > start_len {
             let (id, child) = work_stack.last_mut().unwrap();
             let Some(&child_id) = self[self[*id].children].get(*child) else {
-                self[*id].meta = NodeMeta::Deleted;
                 work_stack.pop();
                 continue;
             };
             *child += 1;
             if self[child_id].meta.is_file() {
-                emit_event(self[child_id].path.clone(), EventType::Delete);
-            } else if self[child_id].meta.is_dir() && self[child_id].children.is_some() {
-                work_stack.push((child_id, 0));
+                emit_event(
+                    self[child_id].path.clone(),
+                    EventType::Delete,
+                    None,
+                    None,
+                    time,
+                );
+            } else if self[child_id].meta.is_dir() {
+                if self.report_directories {
+                    emit_event(
+                        self[child_id].path.clone(),
+                        EventType::Delete,
+                        None,
+                        Some(FileMeta::DIR),
+                        time,
+                    );
+                }
+                if self[child_id].children.is_some() {
+                    work_stack.push((child_id, 0));
+                }
             }
-            self[child_id].meta = NodeMeta::Deleted
+            self.tombstone(child_id);
         }
     }
 
+    /// marks a node that was not found by a crawl as deleted
+    fn delete_missing(
+        &mut self,
+        id: NodeId,
+        time: SystemTime,
+        work_stack: &mut Vec<(NodeId, usize)>,
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
+    ) {
+        match self[id].meta {
+            NodeMeta::File { .. } => {
+                emit_event(self[id].path.clone(), EventType::Delete, None, None, time)
+            }
+            NodeMeta::Dir => self.delete_rec(id, time, work_stack, &mut emit_event),
+            NodeMeta::Deleted => (),
+        }
+        self.tombstone(id);
+    }
+
     // (recursively) crawl a directory to re-synchronize the file tree
-    // and record any changes observed along the way
+    // and record any changes observed along the way. A `shallow` crawl
+    // only descends into child directories that are new (or were replaced)
+    #[expect(clippy::too_many_arguments)]
     pub fn crawl(
         &mut self,
         root: NodeId,
+        shallow: bool,
         filter: &dyn Filter,
         work_stack: &mut Vec<(NodeId, usize)>,
-        mut emit_event: impl FnMut(CanonicalPathBuf, EventType),
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
         mut add_watch: impl FnMut(CanonicalPathBuf),
+        mut on_error: impl FnMut(WatchError),
     ) {
+        if self.is_file_root(root) {
+            let change = PendingChange {
+                path: self[root].path.clone(),
+                flags: pending::Flags::empty(),
+                timestamp: SystemTime::now(),
+            };
+            self.apply_change(&change, filter, work_stack, &mut emit_event);
+            return;
+        }
+        let follow_symlinks = self[root].flags.contains(Flags::FOLLOW_SYMLINKS);
+        let follow_root_link = self[root].flags.contains(Flags::FOLLOW_ROOT_LINK);
         let mut walk_builder = WalkDir::new(self[root].path.as_std_path())
-            .follow_links(false)
-            .follow_root_links(false)
-            .same_file_system(true);
+            .follow_links(follow_symlinks)
+            .follow_root_links(follow_symlinks || follow_root_link)
+            .same_file_system(!self[root].flags.contains(Flags::CROSS_FILESYSTEM));
+        let mut visited = HashSet::new();
+        if follow_symlinks {
+            first_visit(&mut visited, &self[root].path);
+        }
         let recursive = self[root].flags.contains(Flags::RECURSIVE);
-        let flags = if recursive {
+        let remaining_depth = self.remaining_depth(root);
+        if let Some(depth) = remaining_depth.filter(|_| recursive) {
+            walk_builder = walk_builder.max_depth(depth);
+        }
+        let flags = if recursive && shallow {
+            pending::Flags::MARK_RECURSIVE
+        } else if recursive {
             pending::Flags::NEEDS_RECURSIVE_CRAWL | pending::Flags::MARK_RECURSIVE
         } else {
             walk_builder = walk_builder.max_depth(1);
             pending::Flags::NEEDS_RECURSIVE_CRAWL
         };
+        // changes found by crawling have no event time, use the time of the crawl
+        let now = SystemTime::now();
         add_watch(self[root].path.clone());
         if self[root].children.is_some() {
             for &child in &self.dirs[self[root].children.idx()] {
@@ -477,10 +1690,14 @@ impl FileTree {
 
         let mut walk = walk_builder.into_iter();
         while let Some(child) = walk.next() {
-            let Ok(child) = child else {
-                // TODO: why can this fail? permission issue?
-                // how to handle that? just ignore?
-                continue;
+            let child = match child {
+                Ok(child) => child,
+                Err(err) => {
+                    // usually a permission issue, skip the entry but
+                    // continue with the rest of the crawl
+                    self.crawl_error(root, err, &mut on_error);
+                    continue;
+                }
             };
             // the root was already analyzed by the caller don't restart it
             if child.depth() == 0 {
@@ -493,8 +1710,27 @@ impl FileTree {
                 continue;
             }
             let path = CanonicalPathBuf::assert_canonicalized(child.path());
-            let change = PendingChange { path, flags };
-            let (node, _) = self.apply_change(&change, work_stack, &mut emit_event);
+            if follow_symlinks && child.file_type().is_dir() && !first_visit(&mut visited, &path) {
+                walk.skip_current_dir();
+                continue;
+            }
+            let change = PendingChange {
+                path,
+                flags,
+                timestamp: now,
+            };
+            let (node, recurse) = self.apply_change(&change, filter, work_stack, &mut emit_event);
+            // vanished or rejected by the filter
+            if !node.is_some() {
+                if child.file_type().is_dir() {
+                    walk.skip_current_dir();
+                }
+                continue;
+            }
+            let at_depth_limit = remaining_depth.is_some_and(|depth| child.depth() >= depth);
+            if at_depth_limit {
+                self[node].flags.remove(Flags::RECURSIVE);
+            }
 
             self[node].unset_maybe_deleted_flag();
             while work_stack
@@ -504,12 +1740,15 @@ impl FileTree {
                 let (node, _) = work_stack.pop().unwrap();
                 for &child in &self.dirs[self[node].children.idx()].clone() {
                     if self.nodes[child.idx()].maybe_deleted_flag() {
-                        emit_event(self[child].path.clone(), EventType::Delete);
-                        self.delete_rec(child, work_stack, &mut emit_event);
+                        self.delete_missing(child, now, work_stack, &mut emit_event);
                     }
                 }
             }
-            if self[node].meta.is_dir() && recursive {
+            if self[node].meta.is_dir() && recursive && !at_depth_limit {
+                if shallow && child.depth() == 1 && !recurse {
+                    walk.skip_current_dir();
+                    continue;
+                }
                 add_watch(change.path.clone());
                 // track which directories we are entering/exiting so that we can mark any
                 // files that were not visited as removed
@@ -524,33 +1763,72 @@ impl FileTree {
         while let Some((node, _)) = work_stack.pop() {
             for &child in &self.dirs[self[node].children.idx()].clone() {
                 if self.nodes[child.idx()].maybe_deleted_flag() {
-                    emit_event(self[child].path.clone(), EventType::Delete);
-                    self.delete_rec(child, work_stack, &mut emit_event);
+                    self.delete_missing(child, now, work_stack, &mut emit_event);
                 }
             }
         }
+        self.reclaim();
     }
 
+    /// Adds the contents of a new root to the tree. Unlike `crawl` this
+    /// doesn't compare against the previous state, `emit_event` is only
+    /// invoked for the initial `Create` events of the root.
     pub fn crawl_root(
         &mut self,
         root: NodeId,
         recursive: bool,
         filter: &dyn Filter,
+        mut emit_event: impl FnMut(
+            CanonicalPathBuf,
+            EventType,
+            Option<&Metadata>,
+            Option<FileMeta>,
+            SystemTime,
+        ),
         mut add_watch: impl FnMut(CanonicalPathBuf),
+        mut on_error: impl FnMut(WatchError),
     ) {
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("crawl_root", path = ?self[root].path, recursive).entered();
+        let now = SystemTime::now();
+        let report_directories = self.report_directories;
+        let mut on_create = |path: &CanonicalPathBuf, meta: &Metadata| {
+            if !meta.is_dir || report_directories {
+                emit_event(path.clone(), EventType::Create, Some(meta), None, now)
+            }
+        };
+        let follow_symlinks = self[root].flags.contains(Flags::FOLLOW_SYMLINKS);
+        let follow_root_link = self[root].flags.contains(Flags::FOLLOW_ROOT_LINK);
+        // following symlinks requires tracking the visited directories
+        #[cfg(feature = "parallel")]
+        if self.parallel_crawl && recursive && !follow_symlinks {
+            return self.crawl_root_parallel(root, filter, on_create, add_watch, on_error);
+        }
         let mut walk = WalkDir::new(self[root].path.as_std_path())
-            .follow_links(false)
-            .follow_root_links(false)
-            .same_file_system(true);
+            .follow_links(follow_symlinks)
+            .follow_root_links(follow_symlinks || follow_root_link)
+            .same_file_system(!self[root].flags.contains(Flags::CROSS_FILESYSTEM));
+        let mut visited = HashSet::new();
+        if follow_symlinks {
+            first_visit(&mut visited, &self[root].path);
+        }
+        let remaining_depth = self.remaining_depth(root).filter(|_| recursive);
         if !recursive {
             walk = walk.max_depth(1);
+        } else if let Some(depth) = remaining_depth {
+            walk = walk.max_depth(depth);
         }
         let mut walk = walk.into_iter();
         while let Some(child) = walk.next() {
-            let Ok(child) = child else {
-                // TODO: why can this fail? permission issue?
-                // how to handle that? just ignore?
-                continue;
+            let child = match child {
+                Ok(child) => child,
+                Err(err) => {
+                    // usually a permission issue, skip the entry but
+                    // continue with the rest of the crawl
+                    self.crawl_error(root, err, &mut on_error);
+                    continue;
+                }
             };
             if child.depth() == 0 {
                 continue;
@@ -562,17 +1840,156 @@ impl FileTree {
                 continue;
             }
             let path = CanonicalPathBuf::assert_canonicalized(child.path());
-            if let Some(node) = self.add(path.clone(), recursive, false) {
+            if follow_symlinks && child.file_type().is_dir() && !first_visit(&mut visited, &path) {
+                walk.skip_current_dir();
+                continue;
+            }
+            // directories at the maximum depth are leaves
+            if remaining_depth.is_some_and(|depth| child.depth() >= depth) {
+                if self.find(&path).is_none() {
+                    let (meta, content_hash) = self.stat(&path, filter);
+                    self.add_stated(path, meta, content_hash, false, false, &mut on_create);
+                }
+                continue;
+            }
+            let (meta, content_hash) = self.stat(&path, filter);
+            if let Some(node) =
+                self.add_stated(path, meta, content_hash, recursive, false, &mut on_create)
+            {
                 if self[node].meta.is_dir() && recursive {
                     add_watch(self[node].path.clone())
                 }
-            } else {
+            } else if child.file_type().is_dir() {
                 walk.skip_current_dir()
             }
         }
     }
 }
 
+/// hashes the contents of the file at `path` if it's at most `max_size`
+/// bytes, returns 0 if the file wasn't hashed
+fn content_hash(
+    hasher: &DefaultHashBuilder,
+    max_size: Option<usize>,
+    path: &CannonicalPath,
+    meta: &Metadata,
+) -> u64 {
+    if meta.is_dir || max_size.is_none_or(|max| meta.size > max) {
+        return 0;
+    }
+    let mut file = match File::open(path.as_std_path()) {
+        Ok(file) => file,
+        Err(err) => {
+            log::debug!("failed to hash {path:?}: {err}");
+            return 0;
+        }
+    };
+    let mut hasher = hasher.build_hasher();
+    let mut buf = [0; 8192];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.write(&buf[..n]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => {
+                log::debug!("failed to hash {path:?}: {err}");
+                return 0;
+            }
+        }
+    }
+    // 0 is reserved for files that were not hashed
+    hasher.finish().max(1)
+}
+
+/// Records the (device, inode) pair of a directory reached while following
+/// symlinks. Returns `false` if the directory was already visited during this
+/// crawl, in that case it must be skipped to avoid crawling in a loop.
+fn first_visit(visited: &mut HashSet<(u64, u64)>, path: &CannonicalPath) -> bool {
+    let Some(meta) = Metadata::for_path(path, true) else {
+        return true;
+    };
+    // inodes are not available on all platforms
+    if meta.inode == 0 || visited.insert((meta.dev, meta.inode)) {
+        return true;
+    }
+    log::warn!("skipping {path:?}: the directory was already visited through a symlink");
+    false
+}
+
+/// Whether the directory tree below `root` contains more than `max` entries
+/// that are not ignored by `filter`. Stops traversing once the limit is hit.
+pub fn exceeds_entries(
+    root: &Path,
+    filter: &dyn Filter,
+    max: usize,
+    options: &RootOptions,
+) -> bool {
+    let mut walk = WalkDir::new(root)
+        .follow_links(false)
+        .follow_root_links(options.follow_root_link)
+        .same_file_system(!options.cross_filesystem)
+        .min_depth(1);
+    if let Some(max_depth) = options.max_depth {
+        walk = walk.max_depth(max_depth);
+    }
+    let mut walk = walk.into_iter();
+    let mut entries = 0;
+    while let Some(child) = walk.next() {
+        let Ok(child) = child else {
+            continue;
+        };
+        if filter.ignore_path(child.path(), Some(child.file_type().is_dir())) {
+            if child.file_type().is_dir() {
+                walk.skip_current_dir()
+            }
+            continue;
+        }
+        entries += 1;
+        if entries > max {
+            return true;
+        }
+    }
+    false
+}
+
+/// Counts the directories below (and including) `root` that are not ignored
+/// by `filter`, that is the number of watches a recursive root at `root`
+/// would need. No watches are installed.
+pub fn estimate_watches(root: &Path, filter: &dyn Filter) -> io::Result<usize> {
+    let mut walk = WalkDir::new(root)
+        .follow_links(false)
+        .follow_root_links(false)
+        .same_file_system(true)
+        .into_iter();
+    let mut watches = 0;
+    while let Some(child) = walk.next() {
+        let child = match child {
+            Ok(child) => child,
+            Err(err) if err.depth() == 0 => return Err(err.into()),
+            // the crawl skips unreadable entries too
+            Err(_) => continue,
+        };
+        if !child.file_type().is_dir() {
+            continue;
+        }
+        if child.depth() != 0 {
+            if filter.ignore_path(child.path(), Some(true)) {
+                walk.skip_current_dir();
+                continue;
+            }
+            let path = CanonicalPathBuf::assert_canonicalized(child.path());
+            let ignored = Metadata::for_path(&path, false)
+                .is_none_or(|meta| filter.ignore_metadata(child.path(), &meta));
+            if ignored {
+                walk.skip_current_dir();
+                continue;
+            }
+        }
+        watches += 1;
+    }
+    Ok(watches)
+}
+
 impl Index<NodeId> for FileTree {
     type Output = FsNode;
 
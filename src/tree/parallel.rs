@@ -0,0 +1,182 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+use hashbrown::{DefaultHashBuilder, HashTable};
+use walkdir::WalkDir;
+
+use crate::config::{Filter, WatchError};
+use crate::metadata::Metadata;
+use crate::path::{hash_path, CanonicalPathBuf};
+use crate::tree::{content_hash, FileTree, Flags, NodeId};
+
+enum Message {
+    Entry {
+        path: CanonicalPathBuf,
+        meta: Option<Metadata>,
+        content_hash: u64,
+        /// directories at the maximum depth are not crawled
+        leaf: bool,
+    },
+    Error(walkdir::Error),
+}
+
+/// Reads directories and stats their entries on the rayon thread pool. The
+/// results are sent back to the thread that owns the tree.
+struct Walker<'a> {
+    filter: &'a dyn Filter,
+    hasher: DefaultHashBuilder,
+    hash_contents: Option<usize>,
+    max_depth: Option<usize>,
+    /// the device of the root, `None` if the crawl may cross file systems
+    dev: Option<u64>,
+    /// only applies to the root, other directories are never symlinks
+    follow_root_link: bool,
+}
+
+impl Walker<'_> {
+    fn walk<'s>(
+        &'s self,
+        scope: &rayon::Scope<'s>,
+        dir: CanonicalPathBuf,
+        depth: usize,
+        tx: mpsc::Sender<Message>,
+    ) {
+        let walk = WalkDir::new(dir.as_std_path())
+            .follow_links(false)
+            .follow_root_links(self.follow_root_link)
+            .min_depth(1)
+            .max_depth(1);
+        let depth = depth + 1;
+        let leaf = self.max_depth.is_some_and(|max_depth| depth >= max_depth);
+        for child in walk {
+            let child = match child {
+                Ok(child) => child,
+                Err(err) => {
+                    let _ = tx.send(Message::Error(err));
+                    continue;
+                }
+            };
+            let is_dir = child.file_type().is_dir();
+            if self.filter.ignore_path(child.path(), Some(is_dir)) {
+                continue;
+            }
+            let path = CanonicalPathBuf::assert_canonicalized(child.path());
+            let meta = Metadata::for_path(&path, false)
+                .filter(|meta| !self.filter.ignore_metadata(child.path(), meta));
+            let content_hash = meta.as_ref().map_or(0, |meta| {
+                content_hash(&self.hasher, self.hash_contents, &path, meta)
+            });
+            let crawl = !leaf
+                && meta
+                    .as_ref()
+                    .is_some_and(|meta| meta.is_dir && self.dev.is_none_or(|dev| dev == meta.dev));
+            // the parent must be sent before its children
+            let _ = tx.send(Message::Entry {
+                path: path.clone(),
+                meta,
+                content_hash,
+                leaf,
+            });
+            if crawl {
+                let tx = tx.clone();
+                scope.spawn(move |scope| self.walk(scope, path, depth, tx));
+            }
+        }
+    }
+}
+
+impl FileTree {
+    /// A recursive crawl of `root` that reads directories in parallel, only
+    /// used for roots that don't follow symlinks.
+    pub(super) fn crawl_root_parallel(
+        &mut self,
+        root: NodeId,
+        filter: &dyn Filter,
+        mut on_create: impl FnMut(&CanonicalPathBuf, &Metadata),
+        mut add_watch: impl FnMut(CanonicalPathBuf),
+        mut on_error: impl FnMut(WatchError),
+    ) {
+        let root_path = self[root].path.clone();
+        let follow_root_link = self[root].flags.contains(Flags::FOLLOW_ROOT_LINK);
+        let dev = if self[root].flags.contains(Flags::CROSS_FILESYSTEM) {
+            None
+        } else {
+            Metadata::for_path(&root_path, follow_root_link).map(|meta| meta.dev)
+        };
+        // the hasher is only `Copy` on some targets
+        #[allow(clippy::clone_on_copy)]
+        let hasher = self.hasher.clone();
+        let walker = Walker {
+            filter,
+            hasher,
+            hash_contents: self.hash_contents,
+            max_depth: self.remaining_depth(root),
+            dev,
+            follow_root_link,
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| rayon::scope(|scope| walker.walk(scope, root_path, 0, tx)));
+            // directories that were already watched (and their subdirectories),
+            // their children are skipped. A directory is always sent before its
+            // children so only the parent has to be looked up
+            let mut skipped: HashTable<CanonicalPathBuf> = HashTable::new();
+            let hasher = DefaultHashBuilder::default();
+            let hash = |path: &OsStr| hash_path(&hasher, path, false);
+            for message in rx {
+                let (path, meta, content_hash, leaf) = match message {
+                    Message::Entry {
+                        path,
+                        meta,
+                        content_hash,
+                        leaf,
+                    } => (path, meta, content_hash, leaf),
+                    Message::Error(err) => {
+                        self.crawl_error(root, err, &mut on_error);
+                        continue;
+                    }
+                };
+                let parent = path.parent().map_or(OsStr::new(""), Path::as_os_str);
+                let parent_skipped = !skipped.is_empty()
+                    && skipped
+                        .find(hash(parent), |dir| dir.as_os_str() == parent)
+                        .is_some();
+                if parent_skipped {
+                    if !leaf {
+                        skipped.insert_unique(hash(path.as_os_str()), path, |dir| {
+                            hash(dir.as_os_str())
+                        });
+                    }
+                    continue;
+                }
+                if leaf {
+                    if self.find(&path).is_none() {
+                        self.add_stated(path, meta, content_hash, false, false, &mut on_create);
+                    }
+                    continue;
+                }
+                match self.add_stated(
+                    path.clone(),
+                    meta,
+                    content_hash,
+                    true,
+                    false,
+                    &mut on_create,
+                ) {
+                    Some(node) => {
+                        if self[node].meta.is_dir() {
+                            add_watch(self[node].path.clone())
+                        }
+                    }
+                    None => {
+                        skipped.insert_unique(hash(path.as_os_str()), path, |dir| {
+                            hash(dir.as_os_str())
+                        });
+                    }
+                }
+            }
+        });
+    }
+}
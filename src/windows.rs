@@ -0,0 +1,563 @@
+use std::ffi::OsString;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::sync::atomic::{self, AtomicBool, AtomicU8, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use std::{io, iter, ptr, slice, thread};
+
+use hashbrown::{DefaultHashBuilder, HashMap, HashSet};
+use windows_sys::Win32::Foundation::{
+    CloseHandle, GetLastError, ERROR_NOTIFY_ENUM_DIR, HANDLE, INVALID_HANDLE_VALUE,
+};
+use windows_sys::Win32::Storage::FileSystem::{
+    CreateFileW, ReadDirectoryChangesW, FILE_ACTION_ADDED, FILE_ACTION_REMOVED,
+    FILE_ACTION_RENAMED_NEW_NAME, FILE_ACTION_RENAMED_OLD_NAME, FILE_FLAG_BACKUP_SEMANTICS,
+    FILE_FLAG_OVERLAPPED, FILE_LIST_DIRECTORY, FILE_NOTIFY_CHANGE_ATTRIBUTES,
+    FILE_NOTIFY_CHANGE_CREATION, FILE_NOTIFY_CHANGE_DIR_NAME, FILE_NOTIFY_CHANGE_FILE_NAME,
+    FILE_NOTIFY_CHANGE_LAST_WRITE, FILE_NOTIFY_CHANGE_SIZE, FILE_NOTIFY_INFORMATION,
+    FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
+};
+use windows_sys::Win32::System::IO::{
+    CancelIoEx, CreateIoCompletionPort, GetQueuedCompletionStatus, PostQueuedCompletionStatus,
+    OVERLAPPED,
+};
+
+use crate::path::{CannonicalPath, CanonicalPathBuf};
+use crate::pending::{self, PendingChanges, PendingChangesLock, RecrawlCause, UnpairedMoves};
+use crate::{Filter, WatchMask, WatcherState};
+
+/// completion key used to wake the event thread
+const MESSAGE: usize = 0;
+/// completion key of `ReadDirectoryChangesW` calls
+const WATCH: usize = 1;
+/// 64KiB is the maximum supported for network drives
+const BUFFERSIZE: usize = 64 * 1024 / size_of::<u32>();
+
+#[derive(Debug)]
+struct Port(HANDLE);
+
+// safety: completion ports are thread safe
+unsafe impl Send for Port {}
+unsafe impl Sync for Port {}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// A directory handle with an outstanding `ReadDirectoryChangesW` call. The
+/// memory must stay valid until the call completed so watches are only freed
+/// by the event thread.
+#[repr(C)]
+struct Watch {
+    /// must be the first field so that the `OVERLAPPED` returned by the
+    /// completion port can be cast back to the watch
+    overlapped: OVERLAPPED,
+    handle: HANDLE,
+    path: CanonicalPathBuf,
+    mask: WatchMask,
+    /// set (while holding the lock of `Watches`) when the watch is removed,
+    /// the event thread frees it once the pending call completed
+    cancelled: bool,
+    buf: Box<[u32]>,
+}
+
+impl Watch {
+    /// opens `path` and starts watching it
+    fn start(path: CanonicalPathBuf, mask: WatchMask, port: &Port) -> io::Result<WatchPtr> {
+        let wide_path: Vec<u16> = path
+            .as_os_str()
+            .encode_wide()
+            .chain(iter::once(0))
+            .collect();
+        let handle = unsafe {
+            CreateFileW(
+                wide_path.as_ptr(),
+                FILE_LIST_DIRECTORY,
+                FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                ptr::null(),
+                OPEN_EXISTING,
+                FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED,
+                ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { CreateIoCompletionPort(handle, port.0, WATCH, 0) }.is_null() {
+            let err = io::Error::last_os_error();
+            unsafe { CloseHandle(handle) };
+            return Err(err);
+        }
+        let watch = WatchPtr(Box::into_raw(Box::new(Watch {
+            overlapped: unsafe { std::mem::zeroed() },
+            handle,
+            path,
+            mask,
+            cancelled: false,
+            buf: vec![0; BUFFERSIZE].into_boxed_slice(),
+        })));
+        if let Err(err) = unsafe { watch.read_changes() } {
+            unsafe { watch.free() };
+            return Err(err);
+        }
+        Ok(watch)
+    }
+}
+
+struct WatchPtr(*mut Watch);
+
+// safety: watches are only accessed while holding the lock of `Watches`
+unsafe impl Send for WatchPtr {}
+
+impl WatchPtr {
+    /// # Safety
+    ///
+    /// no other call may be pending for this watch
+    unsafe fn read_changes(&self) -> io::Result<()> {
+        let watch = &mut *self.0;
+        let mut filter = FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_DIR_NAME;
+        if watch.mask.contains(WatchMask::ATTRIB) {
+            filter |= FILE_NOTIFY_CHANGE_ATTRIBUTES | FILE_NOTIFY_CHANGE_CREATION;
+        }
+        if watch.mask.contains(WatchMask::MODIFY) {
+            filter |= FILE_NOTIFY_CHANGE_SIZE | FILE_NOTIFY_CHANGE_LAST_WRITE;
+        }
+        watch.overlapped = std::mem::zeroed();
+        let res = ReadDirectoryChangesW(
+            watch.handle,
+            watch.buf.as_mut_ptr().cast(),
+            (watch.buf.len() * size_of::<u32>()) as u32,
+            1,
+            filter,
+            ptr::null_mut(),
+            &mut watch.overlapped,
+            None,
+        );
+        if res == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// must only be called while holding the lock of `Watches`
+    unsafe fn cancel(&self) {
+        let watch = &mut *self.0;
+        watch.cancelled = true;
+        // fails if the call already completed, the event thread
+        // frees the watch either way
+        CancelIoEx(watch.handle, &watch.overlapped);
+    }
+
+    /// # Safety
+    ///
+    /// no call may be pending for this watch
+    unsafe fn free(self) {
+        let watch = Box::from_raw(self.0);
+        CloseHandle(watch.handle);
+    }
+}
+
+#[derive(Default)]
+struct Watches {
+    /// all directories the worker asked us to watch, watches are recursive
+    /// so changes in any other directories are ignored
+    dirs: HashSet<CanonicalPathBuf, DefaultHashBuilder>,
+    /// watches are only created for directories that are not already
+    /// covered by the watch of a parent directory
+    active: HashMap<CanonicalPathBuf, WatchPtr, DefaultHashBuilder>,
+    /// watches that have not been freed by the event thread yet
+    outstanding: usize,
+}
+
+impl Watches {
+    /// # Safety
+    ///
+    /// the lock of `Watches` must be held
+    unsafe fn cancel(&mut self, path: &CannonicalPath) -> bool {
+        let Some(watch) = self.active.remove(path) else {
+            return false;
+        };
+        watch.cancel();
+        true
+    }
+
+    fn is_covered(&self, path: &CannonicalPath) -> bool {
+        self.active
+            .keys()
+            .any(|dir| **dir == *path || dir.is_parent_of(path))
+    }
+}
+
+impl std::fmt::Debug for Watches {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watches")
+            .field("dirs", &self.dirs)
+            .field("active", &self.active.keys())
+            .field("outstanding", &self.outstanding)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct WindowsWatcher {
+    shutdown: AtomicBool,
+    follow_symlinks: AtomicBool,
+    delete_only: AtomicBool,
+    watch_mask: AtomicU8,
+    port: Port,
+    watches: Mutex<Watches>,
+    /// number of times a change buffer overflowed, see `Watcher::stats`
+    queue_overflows: AtomicUsize,
+    state: Arc<WatcherState>,
+    pub changes: PendingChangesLock,
+}
+
+impl WindowsWatcher {
+    pub fn shutdown(&self) {
+        {
+            let mut watches = self.watches.lock().unwrap();
+            self.shutdown.store(true, atomic::Ordering::Relaxed);
+            for (_, watch) in watches.active.drain() {
+                unsafe { watch.cancel() };
+            }
+        }
+        self.wake();
+        self.changes.notify();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn new(#[cfg(test)] _slow: bool, state: Arc<WatcherState>) -> io::Result<Arc<Self>> {
+        let port = unsafe { CreateIoCompletionPort(INVALID_HANDLE_VALUE, ptr::null_mut(), 0, 1) };
+        if port.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let watcher = Arc::new(Self {
+            shutdown: AtomicBool::new(false),
+            follow_symlinks: AtomicBool::new(false),
+            delete_only: AtomicBool::new(false),
+            watch_mask: AtomicU8::new(WatchMask::default().bits()),
+            port: Port(port),
+            watches: Mutex::new(Watches::default()),
+            queue_overflows: AtomicUsize::new(0),
+            state,
+            changes: PendingChangesLock::default(),
+        });
+        let watcher_ = watcher.clone();
+        thread::spawn(move || watcher_.event_loop());
+        Ok(watcher)
+    }
+
+    pub fn set_follow_symlinks(&self, follow_symlinks: bool) {
+        self.follow_symlinks
+            .store(follow_symlinks, atomic::Ordering::Relaxed);
+    }
+
+    pub fn set_delete_only(&self, delete_only: bool) {
+        self.delete_only
+            .store(delete_only, atomic::Ordering::Relaxed);
+    }
+
+    pub fn delete_only(&self) -> bool {
+        self.delete_only.load(atomic::Ordering::Relaxed)
+    }
+
+    /// only inotify reports when a file is closed
+    pub fn set_only_on_close(&self, _only_on_close: bool) {}
+
+    pub fn set_watch_mask(&self, mask: WatchMask) {
+        self.watch_mask
+            .store(mask.bits(), atomic::Ordering::Relaxed);
+    }
+
+    fn watch_mask(&self) -> WatchMask {
+        if self.delete_only() {
+            return WatchMask::empty();
+        }
+        WatchMask::from_bits_retain(self.watch_mask.load(atomic::Ordering::Relaxed))
+    }
+
+    pub fn watch_dir(&self, path: CanonicalPathBuf) -> io::Result<()> {
+        let mut watches = self.watches.lock().unwrap();
+        if self.is_shutdown() {
+            return Ok(());
+        }
+        watches.dirs.insert(path.clone());
+        if watches.is_covered(&path) {
+            return Ok(());
+        }
+        let watch = Watch::start(path.clone(), self.watch_mask(), &self.port)?;
+        watches.outstanding += 1;
+        // the new watch covers all watches below it
+        let covered: Vec<_> = watches
+            .active
+            .keys()
+            .filter(|dir| path.is_parent_of(dir))
+            .cloned()
+            .collect();
+        for dir in covered {
+            unsafe { watches.cancel(&dir) };
+        }
+        watches.active.insert(path, watch);
+        Ok(())
+    }
+
+    pub fn unwatch_dir(&self, path: &CannonicalPath) -> io::Result<()> {
+        let mut watches = self.watches.lock().unwrap();
+        watches.dirs.remove(path);
+        if !unsafe { watches.cancel(path) } {
+            return Ok(());
+        }
+        // directories below the removed watch need watches of their own,
+        // sorted so that parents are watched before their children
+        let mut uncovered: Vec<_> = watches
+            .dirs
+            .iter()
+            .filter(|dir| path.is_parent_of(dir))
+            .cloned()
+            .collect();
+        uncovered.sort();
+        for dir in uncovered {
+            if watches.is_covered(&dir) {
+                continue;
+            }
+            let watch = Watch::start(dir.clone(), self.watch_mask(), &self.port)?;
+            watches.outstanding += 1;
+            watches.active.insert(dir, watch);
+        }
+        Ok(())
+    }
+
+    /// removes the watches of `root` and all directories below it
+    pub fn unwatch_tree(&self, root: &CannonicalPath) {
+        let mut watches = self.watches.lock().unwrap();
+        watches
+            .dirs
+            .retain(|dir| **dir != *root && !root.is_parent_of(dir));
+        let removed: Vec<_> = watches
+            .active
+            .keys()
+            .filter(|dir| ***dir == *root || root.is_parent_of(dir))
+            .cloned()
+            .collect();
+        for dir in removed {
+            unsafe { watches.cancel(&dir) };
+        }
+    }
+
+    /// Updates the paths of the directories below `from` after it was moved
+    /// to `to`, they are covered by the (recursive) watch of a root.
+    pub fn rename_watches(&self, from: &CannonicalPath, to: &CanonicalPathBuf) {
+        let mut watches = self.watches.lock().unwrap();
+        let moved: Vec<_> = watches
+            .dirs
+            .iter()
+            .filter_map(|dir| Some((dir.clone(), dir.replace_prefix(from, to)?)))
+            .collect();
+        for (old, new) in moved {
+            watches.dirs.remove(&old);
+            watches.dirs.insert(new);
+        }
+    }
+
+    pub fn refresh_config(&self) {
+        self.wake();
+    }
+
+    pub fn watch_count(&self) -> usize {
+        self.watches.lock().unwrap().active.len()
+    }
+
+    pub fn queue_overflows(&self) -> usize {
+        self.queue_overflows.load(atomic::Ordering::Relaxed)
+    }
+
+    /// only inotify has a limit on the number of watches that is worth retrying
+    pub fn retry_watches(&self) -> usize {
+        0
+    }
+
+    pub fn pending_watch_retries(&self) -> usize {
+        0
+    }
+
+    /// the removal of a root is noticed through the watch of the root itself
+    pub fn watch_root_parent(&self, _root: &CannonicalPath) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn unwatch_root_parent(&self, _root: &CannonicalPath) {}
+
+    fn wake(&self) {
+        unsafe { PostQueuedCompletionStatus(self.port.0, 0, MESSAGE, ptr::null()) };
+    }
+
+    fn event_loop(&self) {
+        let mut filter = self.state.filter();
+        let mut renames = Renames::default();
+        loop {
+            let mut len = 0;
+            let mut key = 0;
+            let mut overlapped = ptr::null_mut();
+            let ok = unsafe {
+                GetQueuedCompletionStatus(
+                    self.port.0,
+                    &mut len,
+                    &mut key,
+                    &mut overlapped,
+                    u32::MAX,
+                )
+            } != 0;
+            let err = (!ok).then(|| unsafe { GetLastError() });
+            if overlapped.is_null() {
+                if let Some(err) = err {
+                    log::error!(
+                        "failed to wait for changes: {}",
+                        io::Error::from_raw_os_error(err as i32)
+                    );
+                    return;
+                }
+                if key == MESSAGE {
+                    filter = self.state.filter();
+                }
+            } else {
+                let timestamp = SystemTime::now();
+                let watch = WatchPtr(overlapped.cast::<Watch>());
+                let mut watches = self.watches.lock().unwrap();
+                let watch_ = unsafe { &*watch.0 };
+                if watch_.cancelled {
+                    unsafe { watch.free() };
+                    watches.outstanding -= 1;
+                } else {
+                    let mut changes = self.changes.lock();
+                    match err {
+                        // the buffer overflowed
+                        None if len == 0 => {
+                            self.queue_overflows.fetch_add(1, atomic::Ordering::Relaxed);
+                            changes.recrawl(RecrawlCause::QueueOverflow)
+                        }
+                        Some(ERROR_NOTIFY_ENUM_DIR) => {
+                            self.queue_overflows.fetch_add(1, atomic::Ordering::Relaxed);
+                            changes.recrawl(RecrawlCause::QueueOverflow)
+                        }
+                        None => {
+                            let buf = unsafe {
+                                slice::from_raw_parts(
+                                    watch_.buf.as_ptr().cast::<u8>(),
+                                    len as usize,
+                                )
+                            };
+                            handle_events(
+                                buf,
+                                &watch_.path,
+                                &watches.dirs,
+                                &mut changes,
+                                &*filter,
+                                &mut renames,
+                                timestamp,
+                            );
+                        }
+                        Some(_) => (),
+                    }
+                    let res = match err {
+                        None | Some(ERROR_NOTIFY_ENUM_DIR) => unsafe { watch.read_changes() },
+                        Some(err) => Err(io::Error::from_raw_os_error(err as i32)),
+                    };
+                    if let Err(err) = res {
+                        // most likely the watched directory was removed
+                        log::debug!("watch for {:?} failed: {err}", watch_.path);
+                        changes.add_watcher(
+                            watch_.path.clone(),
+                            timestamp,
+                            pending::Flags::NEEDS_RECURSIVE_CRAWL,
+                        );
+                        watches.active.remove(&watch_.path);
+                        unsafe { watch.free() };
+                        watches.outstanding -= 1;
+                    }
+                    drop(changes);
+                    self.changes.notify();
+                }
+            }
+            if self.is_shutdown() && self.watches.lock().unwrap().outstanding == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// used to pair the old and new name of a rename
+#[derive(Default)]
+pub(crate) struct Renames {
+    cookie: u32,
+    unpaired: UnpairedMoves,
+}
+
+/// translates the `FILE_NOTIFY_INFORMATION` records in `buf`
+pub(crate) fn handle_events(
+    buf: &[u8],
+    dir: &CannonicalPath,
+    watched_dirs: &HashSet<CanonicalPathBuf, DefaultHashBuilder>,
+    changes: &mut PendingChanges,
+    filter: &dyn Filter,
+    renames: &mut Renames,
+    timestamp: SystemTime,
+) {
+    let mut offset = 0;
+    while offset + size_of::<FILE_NOTIFY_INFORMATION>() <= buf.len() {
+        // safety: the kernel writes DWORD aligned records into the buffer
+        let info = unsafe { &*buf.as_ptr().add(offset).cast::<FILE_NOTIFY_INFORMATION>() };
+        let name = unsafe {
+            slice::from_raw_parts(
+                info.FileName.as_ptr(),
+                info.FileNameLength as usize / size_of::<u16>(),
+            )
+        };
+        let path = dir.join(&OsString::from_wide(name));
+        // the old and new name of a rename are reported right after each
+        // other, this also needs to happen for unwatched directories so that
+        // an unpaired new name never matches a stale old name
+        let cookie = match info.Action {
+            FILE_ACTION_RENAMED_OLD_NAME => {
+                renames.cookie = renames.cookie.wrapping_add(1);
+                renames.cookie
+            }
+            FILE_ACTION_RENAMED_NEW_NAME => {
+                let cookie = renames.cookie;
+                renames.cookie = renames.cookie.wrapping_add(1);
+                cookie
+            }
+            _ => 0,
+        };
+        // the watch is recursive, only report changes in watched directories
+        let watched = path.parent().is_some_and(|parent| {
+            watched_dirs.contains(&CanonicalPathBuf::assert_canonicalized(parent))
+        });
+        if watched && !filter.ignore_path(path.as_std_path(), None) {
+            let flags = match info.Action {
+                FILE_ACTION_ADDED | FILE_ACTION_REMOVED => pending::Flags::NEEDS_RECURSIVE_CRAWL,
+                FILE_ACTION_RENAMED_OLD_NAME => {
+                    renames.unpaired.moved_from(cookie, path.clone());
+                    pending::Flags::empty()
+                }
+                FILE_ACTION_RENAMED_NEW_NAME => {
+                    renames.unpaired.moved_to(cookie, path.clone(), changes);
+                    pending::Flags::empty()
+                }
+                // FILE_ACTION_MODIFIED
+                _ => pending::Flags::empty(),
+            };
+            changes.add_watcher(path, timestamp, flags);
+        }
+        if info.NextEntryOffset == 0 {
+            break;
+        }
+        offset += info.NextEntryOffset as usize;
+    }
+}
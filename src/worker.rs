@@ -1,9 +1,48 @@
+use std::cmp::min;
 use std::mem::take;
-use std::sync::atomic;
+use std::sync::{atomic, Arc};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::pending::PendingChanges;
+use hashbrown::HashMap;
+
+use crate::config::Config;
+use crate::events::{Event, EventType, Events};
+use crate::path::{CannonicalPath, CanonicalPathBuf};
+use crate::pending::{self, PendingChanges, RecrawlCause};
 use crate::tree::{FileTree, NodeId};
-use crate::{EventDebouncer, Watcher};
+use crate::{EventDebouncer, Filter, RootOptions, WatchError, WatchOnce, Watcher};
+
+/// initial delay before retrying watches that failed because the watch limit
+/// was reached, doubled for every retry that doesn't succeed
+const MIN_WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+struct OneShot {
+    path: CanonicalPathBuf,
+    event_types: Vec<EventType>,
+    notify: Box<dyn FnOnce(Event) + Send>,
+    /// non-recursive root that was only added to the tree for this
+    /// watch, `None` if the path is already covered by a root
+    root: Option<NodeId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RootInfo {
+    pub path: CanonicalPathBuf,
+    pub options: RootOptions,
+}
+
+impl RootInfo {
+    /// whether everything watched by `other` is already watched by this root
+    pub fn covers(&self, other: &RootInfo) -> bool {
+        if self.path == other.path {
+            return self.options.recursive || !other.options.recursive;
+        }
+        self.options.recursive
+            && self.options.max_depth.is_none()
+            && self.path.is_parent_of(&other.path)
+    }
+}
 
 pub struct Worker {
     pending_changes: PendingChanges,
@@ -11,6 +50,33 @@ pub struct Worker {
     work_stack: Vec<(NodeId, usize)>,
     tree: FileTree,
     roots: Vec<(NodeId, bool)>,
+    /// all roots added with `add_root` sorted by path. Unlike `roots` this
+    /// also contains roots nested in a recursive root
+    added_roots: Vec<RootInfo>,
+    oneshots: Vec<OneShot>,
+    /// when the next (deferred) recrawl is due
+    recrawl_due: Option<Instant>,
+    /// when the last recrawl finished
+    last_recrawl: Option<Instant>,
+    /// minimum time between the last recrawl and the next, doubled
+    /// whenever recrawls are requested in rapid succession
+    recrawl_backoff: Duration,
+    /// errors encountered while crawling that weren't reported yet
+    crawl_errors: Vec<WatchError>,
+    /// the current settle time if it adapts to the load, see
+    /// `Watcher::set_adaptive_settle_time`
+    settle_time: Duration,
+    /// number of changes observed since the events last settled
+    settle_changes: usize,
+    /// the number of queue overflows the overflow handler was invoked for
+    reported_overflows: usize,
+    /// when the watches that failed because the watch limit was reached are
+    /// retried next
+    watch_retry_due: Option<Instant>,
+    watch_retry_backoff: Duration,
+    /// files that were written to but not closed yet with the time of the
+    /// last write, see `Watcher::set_only_on_close`
+    unclosed_writes: HashMap<CanonicalPathBuf, (Instant, SystemTime)>,
     watcher: Watcher,
 }
 
@@ -18,36 +84,274 @@ impl Watcher {
     fn should_wakeup(&self) -> bool {
         self.state.has_notifications.load(atomic::Ordering::Relaxed) || self.notify.is_shutdown()
     }
+
+    /// Invokes the callback in `slot` without holding the config lock, the
+    /// callback may call into the watcher. If a new callback was set in the
+    /// meantime it replaces this one.
+    fn with_callback<H>(&self, slot: fn(&mut Config) -> &mut Option<H>, f: impl FnOnce(&mut H)) {
+        let Some(mut callback) = slot(&mut self.state.config.lock().unwrap()).take() else {
+            return;
+        };
+        f(&mut callback);
+        slot(&mut self.state.config.lock().unwrap()).get_or_insert(callback);
+    }
 }
 
 impl Worker {
     pub fn new(watcher: Watcher) -> Self {
+        let (capacity, case_insensitive) = {
+            let config = watcher.state.config.lock().unwrap();
+            (config.capacity_hint, config.case_insensitive)
+        };
         Worker {
             pending_changes: PendingChanges::default(),
             events: EventDebouncer::new(),
             work_stack: Vec::with_capacity(16),
-            tree: FileTree::new(),
+            tree: FileTree::with_capacity(capacity, case_insensitive),
             roots: Vec::with_capacity(16),
+            added_roots: Vec::new(),
+            oneshots: Vec::new(),
+            recrawl_due: None,
+            last_recrawl: None,
+            recrawl_backoff: Duration::ZERO,
+            crawl_errors: Vec::new(),
+            settle_time: Duration::ZERO,
+            settle_changes: 0,
+            reported_overflows: 0,
+            watch_retry_due: None,
+            watch_retry_backoff: MIN_WATCH_RETRY_INTERVAL,
+            unclosed_writes: HashMap::new(),
             watcher,
         }
     }
 
     fn wait_for_changes(&mut self) -> bool {
-        if self.events.is_empty() {
+        let now = Instant::now();
+        let wakeup_in = self
+            .recrawl_due
+            .into_iter()
+            .chain(self.watch_retry_due)
+            .chain(self.unclosed_writes_due())
+            .min()
+            .map(|due| due.saturating_duration_since(now));
+        if self.events.is_empty() && wakeup_in.is_none() {
             self.watcher
                 .notify
-                .changes
+                .changes()
                 .take(&mut self.pending_changes, || self.watcher.should_wakeup());
+            // nothing was waiting to settle, this starts a new burst
+            self.settle_changes = self.pending_changes.len();
             false
         } else {
-            self.watcher.notify.changes.take_timeout(
+            let settle_time = (!self.events.is_empty()).then(|| self.settle_time());
+            let timeout = match (settle_time, wakeup_in) {
+                (Some(settle_time), Some(wakeup_in)) => min(settle_time, wakeup_in),
+                (Some(timeout), None) | (None, Some(timeout)) => timeout,
+                (None, None) => unreachable!(),
+            };
+            let timed_out = self.watcher.notify.changes().take_timeout(
                 &mut self.pending_changes,
-                self.watcher.state.config.lock().unwrap().settle_time,
+                timeout,
                 || self.watcher.should_wakeup(),
-            )
+            );
+            self.settle_changes += self.pending_changes.len();
+            // we may have only woken up for a deferred recrawl or watch retry
+            let settled =
+                timed_out && settle_time.is_some_and(|settle_time| settle_time <= timeout);
+            if settled {
+                self.adapt_settle_time();
+            }
+            settled
+        }
+    }
+
+    fn settle_time(&self) -> Duration {
+        let config = self.watcher.state.config.lock().unwrap();
+        match config.max_settle_time {
+            Some(max_settle_time) => self
+                .settle_time
+                .min(max_settle_time)
+                .max(config.settle_time),
+            None => config.settle_time,
+        }
+    }
+
+    /// grows the settle time while many changes are observed and shrinks
+    /// it again once things calm down
+    fn adapt_settle_time(&mut self) {
+        let config = self.watcher.state.config.lock().unwrap();
+        if let Some(max_settle_time) = config.max_settle_time {
+            let settle_time = self.settle_time.max(config.settle_time);
+            self.settle_time = if self.settle_changes > config.settle_threshold {
+                (settle_time * 2).min(max_settle_time)
+            } else {
+                settle_time / 2
+            }
+            .max(config.settle_time);
+            if self.settle_time != settle_time {
+                log::debug!("adjusted settle time to {:?}", self.settle_time);
+            }
+        }
+        self.settle_changes = 0;
+    }
+
+    /// Schedules a recrawl. Recrawls requested in rapid succession (usually
+    /// caused by repeated queue overflows) are deferred with exponential
+    /// backoff so the watcher doesn't end up crawling in a loop.
+    fn schedule_recrawl(&mut self) {
+        if self.recrawl_due.is_some() {
+            return;
+        }
+        let config = self.watcher.state.config.lock().unwrap();
+        let now = Instant::now();
+        match self.last_recrawl {
+            Some(last_recrawl) if now < last_recrawl + self.recrawl_backoff => {
+                log::warn!(
+                    "recrawls requested in rapid succession, delaying by {:?}",
+                    self.recrawl_backoff
+                );
+                self.recrawl_due = Some(last_recrawl + self.recrawl_backoff);
+                self.recrawl_backoff = min(self.recrawl_backoff * 2, config.max_recrawl_interval);
+            }
+            _ => {
+                self.recrawl_due = Some(now);
+                self.recrawl_backoff = config.min_recrawl_interval;
+            }
+        }
+    }
+
+    /// Schedules a recrawl and performs it right away, returns by how much
+    /// the backoff would have delayed it.
+    #[cfg(test)]
+    pub fn recrawl_now(&mut self) -> Duration {
+        self.schedule_recrawl();
+        let due = self.recrawl_due.unwrap();
+        let delay = match self.last_recrawl {
+            Some(last) if due > Instant::now() => due - last,
+            _ => Duration::ZERO,
+        };
+        let filter = self.watcher.state.filter();
+        self.recrawl(&*filter);
+        delay
+    }
+
+    /// when the oldest write to a file that is still open is applied anyway
+    fn unclosed_writes_due(&self) -> Option<Instant> {
+        let last_write = self
+            .unclosed_writes
+            .values()
+            .map(|&(last_write, _)| last_write);
+        Some(last_write.min()? + self.settle_time())
+    }
+
+    /// Holds back writes to files that are still open until they are closed.
+    /// Writes are applied anyway once the file wasn't written to for the
+    /// settle time (or right away with `flush`) since the writer may never
+    /// close it.
+    fn defer_unclosed_writes(&mut self, flush: bool) {
+        let now = Instant::now();
+        for change in self.pending_changes.take_awaiting_close() {
+            self.unclosed_writes
+                .insert(change.path, (now, change.timestamp));
+        }
+        if self.unclosed_writes.is_empty() {
+            return;
+        }
+        let settle_time = self.settle_time();
+        let mut expired = Vec::new();
+        self.unclosed_writes
+            .retain(|path, &mut (last_write, timestamp)| {
+                // closed (or otherwise changed), applied with the other changes
+                if self.pending_changes.contains(path) {
+                    return false;
+                }
+                if flush || last_write + settle_time <= now {
+                    expired.push((path.clone(), timestamp));
+                    return false;
+                }
+                true
+            });
+        for (path, timestamp) in expired {
+            self.pending_changes
+                .add_watcher(path, timestamp, pending::Flags::empty());
+        }
+    }
+
+    /// schedules a retry if the backend failed to watch directories because
+    /// the watch limit was reached
+    fn schedule_watch_retry(&mut self) {
+        if self.watch_retry_due.is_none() && self.watcher.notify.pending_watch_retries() != 0 {
+            self.watch_retry_due = Some(Instant::now() + self.watch_retry_backoff);
+        }
+    }
+
+    /// Retries the watches that failed because the watch limit was reached.
+    /// Watches may become available again once other directories are
+    /// removed (by us or other processes).
+    fn retry_watches(&mut self) {
+        self.watch_retry_due = None;
+        let remaining = self.watcher.notify.retry_watches();
+        if remaining == 0 {
+            self.watch_retry_backoff = MIN_WATCH_RETRY_INTERVAL;
+        } else {
+            log::warn!(
+                "{remaining} directories are not watched because the watch limit was reached, retrying in {:?}",
+                self.watch_retry_backoff
+            );
+            self.watch_retry_backoff = min(self.watch_retry_backoff * 2, MAX_WATCH_RETRY_INTERVAL);
         }
     }
 
+    fn recrawl(&mut self, filter: &dyn Filter) {
+        self.watcher
+            .state
+            .recrawls
+            .fetch_add(1, atomic::Ordering::Relaxed);
+        // changes (and recrawl requests) that arrived before the crawl
+        // started are covered by it, only later requests need another crawl
+        self.pending_changes.clear();
+        self.unclosed_writes.clear();
+        let coalesced = self.watcher.notify.changes().lock().take_recrawl();
+        if let Some(cause) = coalesced {
+            log::debug!("merged recrawl request ({cause:?}) into the current recrawl");
+            self.report_overflows();
+            self.report_recrawl(cause);
+        }
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "recrawl",
+            roots = self.roots.len(),
+            nodes = self.tree.node_count()
+        )
+        .entered();
+
+        let mut roots: Vec<_> = self.roots.iter().map(|&(root, _)| root).collect();
+        for root in self.oneshots.iter().filter_map(|it| it.root) {
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+        for root in roots {
+            self.tree.crawl(
+                root,
+                false,
+                filter,
+                &mut self.work_stack,
+                |path, ty, meta, prev, time| self.events.add(path, ty, meta, prev, time),
+                |path| {
+                    if let Err(err) = self.watcher.notify.watch_dir(path.clone()) {
+                        log::error!("failed to watch {path:?}: {err}")
+                    }
+                },
+                |err| self.crawl_errors.push(err),
+            );
+        }
+        self.report_symlink_loops();
+        self.report_crawl_errors();
+        self.recrawl_due = None;
+        self.last_recrawl = Some(Instant::now());
+    }
+
     fn process_notifications(&mut self) {
         let has_notifications = self
             .watcher
@@ -56,104 +360,654 @@ impl Worker {
             .swap(false, atomic::Ordering::Relaxed);
         if has_notifications {
             let notifications = take(&mut *self.watcher.state.notifications.lock().unwrap());
+            for oneshot in notifications.oneshots {
+                self.add_oneshot(oneshot);
+            }
             for root in notifications.roots {
-                let Some(node) = self.tree.add_root(root.path.clone(), root.recursive) else {
-                    (root.notify)(true);
-                    continue;
-                };
-                if let Err(err) = self.watcher.notify.watch_dir(root.path.clone()) {
-                    log::error!("failed to watch {:?}: {err}", root.path);
-                    (root.notify)(false);
-                    continue;
+                let success = self.add_root(root.info);
+                (root.notify)(success);
+            }
+            for query in notifications.queries {
+                query(self);
+            }
+        }
+    }
+
+    fn add_root(&mut self, info: RootInfo) -> bool {
+        if self.active_roots().any(|root| root.covers(&info)) {
+            log::debug!("{:?} is already covered by another root", info.path);
+            // don't replace the options of the root covering it
+            if let Err(i) = self
+                .added_roots
+                .binary_search_by(|root| root.path.cmp(&info.path))
+            {
+                self.added_roots.insert(i, info);
+                self.sync_root_filters();
+            }
+            return true;
+        }
+        let path = info.path.clone();
+        let recursive = info.options.recursive;
+        let Some(node) = self.tree.add_root(path.clone(), &info.options) else {
+            self.add_root_info(info);
+            return true;
+        };
+        // for files the parent directory is watched so that recreating the
+        // file is noticed
+        let watch_path = match path.parent() {
+            Some(parent) if self.tree.is_file_root(node) => {
+                CanonicalPathBuf::assert_canonicalized(parent)
+            }
+            _ => path.clone(),
+        };
+        if let Err(err) = self.watcher.notify.watch_dir(watch_path.clone()) {
+            log::error!("failed to watch {watch_path:?}: {err}");
+            return false;
+        }
+        if watch_path == path {
+            if let Err(err) = self.watcher.notify.watch_root_parent(&path) {
+                log::debug!("failed to watch the parent of {path:?}, falling back to the events of the root: {err}");
+            }
+        }
+        let initial_events = info.options.initial_events;
+        // added before the crawl so that the filter of the root is used
+        self.add_root_info(info);
+        {
+            let config = self.watcher.state.config.lock().unwrap();
+            #[cfg(feature = "parallel")]
+            {
+                self.tree.parallel_crawl = config.parallel_crawl;
+            }
+            self.tree.track_hardlinks = config.track_hardlinks;
+            self.tree.report_vanished_files = config.report_vanished_files;
+        }
+        let filter = self.watcher.state.filter();
+        self.tree.crawl_root(
+            node,
+            recursive,
+            &*filter,
+            |path, ty, meta, prev, time| {
+                if initial_events {
+                    self.events.add(path, ty, meta, prev, time)
                 }
-                let filter = self.watcher.state.config.lock().unwrap().filter.clone();
-                self.tree
-                    .crawl_root(node, root.recursive, &*filter, |path| {
-                        if let Err(err) = self.watcher.notify.watch_dir(path.clone()) {
-                            log::error!("failed to watch {path:?}: {err}")
-                        }
-                    });
-                let i = self
-                    .roots
-                    .partition_point(|&(it, _)| self.tree[it].path < root.path);
-                if root.recursive {
-                    // for recursive roots remove any roots that are children
-                    // and not ignored to avoid duplicate crawls
-                    let mut end = self.roots[i..]
-                        .iter()
-                        .position(|&(it, _)| !root.path.is_parent_of(&self.tree[it].path))
-                        .unwrap_or(self.roots.len());
-                    let mut j = i;
-                    while j < end {
-                        if filter.ignore_path_rec(
-                            self.tree[self.roots[j].0].path.as_std_path(),
-                            Some(true),
-                        ) {
-                            j += 1;
-                        } else {
-                            self.roots.remove(j);
-                            end -= 1;
-                        }
-                    }
-                };
-                self.roots.insert(i, (node, root.recursive));
-                (root.notify)(true);
+            },
+            |path| {
+                if let Err(err) = self.watcher.notify.watch_dir(path.clone()) {
+                    log::error!("failed to watch {path:?}: {err}")
+                }
+            },
+            |err| self.crawl_errors.push(err),
+        );
+        let i = self
+            .roots
+            .partition_point(|&(it, _)| self.tree[it].path < path);
+        if recursive {
+            // for recursive roots remove any roots that are children
+            // and not ignored to avoid duplicate crawls
+            let mut end = self.roots[i..]
+                .iter()
+                .position(|&(it, _)| !path.is_parent_of(&self.tree[it].path))
+                .map_or(self.roots.len(), |end| i + end);
+            let mut j = i;
+            while j < end {
+                let nested = self.tree[self.roots[j].0].path.as_std_path();
+                if filter.ignore_path_rec_until(nested, path.as_std_path(), Some(true)) {
+                    j += 1;
+                } else {
+                    self.roots.remove(j);
+                    end -= 1;
+                }
+            }
+        };
+        self.roots.insert(i, (node, recursive));
+        self.publish_roots();
+        true
+    }
+
+    /// the options of the roots that are actually watched (not nested in
+    /// another root)
+    fn active_roots(&self) -> impl Iterator<Item = &RootInfo> + '_ {
+        self.roots.iter().filter_map(|&(root, _)| {
+            let path = &self.tree[root].path;
+            self.added_roots
+                .binary_search_by(|root| root.path.cmp(path))
+                .ok()
+                .map(|i| &self.added_roots[i])
+        })
+    }
+
+    /// makes the current roots available to `Watcher::watched_roots`
+    fn publish_roots(&self) {
+        let roots = self.active_roots().cloned().collect();
+        *self.watcher.state.roots.lock().unwrap() = roots;
+    }
+
+    /// Stops watching a root added with `add_root`, returns `false` if there
+    /// is no such root.
+    pub fn remove_root(&mut self, path: CanonicalPathBuf) -> bool {
+        let Ok(i) = self
+            .added_roots
+            .binary_search_by(|root| root.path.cmp(&path))
+        else {
+            return false;
+        };
+        self.added_roots.remove(i);
+        self.sync_root_filters();
+        let Some(node) = self.tree.find(&path) else {
+            return true;
+        };
+        // roots nested in a recursive root are covered by the parent root
+        if !self.roots.iter().any(|&(root, _)| root == node) {
+            return true;
+        }
+        self.roots.retain(|&(root, _)| {
+            let root = &self.tree[root].path;
+            *root != path && !path.is_parent_of(root)
+        });
+        let file_root = self.tree.is_file_root(node);
+        self.watcher.notify.unwatch_tree(&path);
+        if !file_root {
+            self.watcher.notify.unwatch_root_parent(&path);
+        }
+        self.tree.remove(node, &mut self.work_stack);
+        if let Some(parent) = path.parent().filter(|_| file_root) {
+            let parent = CanonicalPathBuf::assert_canonicalized(parent);
+            if !self.tree.needs_watch(&parent) {
+                if let Err(err) = self.watcher.notify.unwatch_dir(&parent) {
+                    log::error!("failed to unwatch {parent:?}: {err}");
+                }
+            }
+        }
+        // the directory itself may still be tracked as the child of another root
+        let parent_watched = path
+            .parent()
+            .map(CanonicalPathBuf::assert_canonicalized)
+            .and_then(|parent| self.tree.find(&parent))
+            .is_some_and(|parent| self.tree[parent].watches_children());
+        if parent_watched {
+            self.watcher.notify.changes().lock().add_watcher(
+                path.clone(),
+                SystemTime::now(),
+                pending::Flags::empty(),
+            );
+        }
+        // restore roots that were subsumed by the removed root
+        let nested: Vec<_> = self
+            .added_roots
+            .iter()
+            .filter(|root| path.is_parent_of(&root.path))
+            .cloned()
+            .collect();
+        // nested roots that were added first are not linked to their parent
+        // and survive the removal above
+        for root in &nested {
+            if let Some(node) = self.tree.find(&root.path) {
+                self.tree.remove(node, &mut self.work_stack);
+            }
+        }
+        for mut root in nested {
+            // the contents were already reported when the root was added
+            root.options.initial_events = false;
+            self.add_root(root);
+        }
+        self.publish_roots();
+        true
+    }
+
+    pub fn tombstone_count(&self) -> usize {
+        self.tree.tombstone_count()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.tree.node_count()
+    }
+
+    /// number of nodes (and unused slots) that `compact` would drop
+    pub fn reclaimable_count(&self) -> usize {
+        self.tree.tombstone_count() + self.tree.free_slots()
+    }
+
+    pub fn is_watching(&self, path: &CanonicalPathBuf) -> bool {
+        let Some(is_dir) = self
+            .tree
+            .find(path)
+            .and_then(|node| self.tree[node].is_dir())
+        else {
+            return false;
+        };
+        let filter = self.watcher.state.filter();
+        let root = self
+            .roots
+            .iter()
+            .map(|&(root, _)| &self.tree[root].path)
+            .find(|root| *root == path || root.is_parent_of(path));
+        match root {
+            Some(root) => {
+                !filter.ignore_path_rec_until(path.as_std_path(), root.as_std_path(), Some(is_dir))
+            }
+            None => !filter.ignore_path_rec(path.as_std_path(), Some(is_dir)),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<CanonicalPathBuf> {
+        let mut paths: Vec<_> = self.tree.iter().cloned().collect();
+        paths.sort_unstable();
+        paths
+    }
+
+    pub fn compact(&mut self) -> usize {
+        let mut roots: Vec<_> = self
+            .roots
+            .iter_mut()
+            .map(|(root, _)| root)
+            .chain(self.oneshots.iter_mut().filter_map(|it| it.root.as_mut()))
+            .collect();
+        let reclaimed = self.tree.compact(&mut roots);
+        log::info!("compacted file tree, reclaimed {reclaimed} nodes");
+        reclaimed
+    }
+
+    fn report_symlink_loops(&mut self) {
+        let loops = self.tree.take_symlink_loops();
+        if loops.is_empty() {
+            return;
+        }
+        self.watcher.with_callback(
+            |config| &mut config.symlink_loop_handler,
+            |handler| {
+                for (link, target) in loops {
+                    handler(link.as_std_path(), target.as_std_path());
+                }
+            },
+        );
+    }
+
+    fn report_removed_roots(&mut self) {
+        let removed = self.tree.take_removed_roots();
+        if removed.is_empty() {
+            return;
+        }
+        let added_roots = &self.added_roots;
+        self.watcher.with_callback(
+            |config| &mut config.root_removed_handler,
+            |handler| {
+                // temporary roots of one-shot watches are not reported
+                for root in removed
+                    .iter()
+                    .filter(|root| added_roots.binary_search_by(|it| it.path.cmp(root)).is_ok())
+                {
+                    handler(root.as_std_path());
+                }
+            },
+        );
+    }
+
+    /// invokes the overflow handler if the backend overflowed since the last
+    /// report, recrawls can also have other causes
+    fn report_overflows(&mut self) {
+        let overflows = self.watcher.notify.queue_overflows();
+        if overflows == self.reported_overflows {
+            return;
+        }
+        self.reported_overflows = overflows;
+        self.watcher.with_callback(
+            |config| &mut config.overflow_handler,
+            |handler| handler(overflows),
+        );
+    }
+
+    fn report_recrawl(&mut self, cause: RecrawlCause) {
+        log::debug!("recrawl requested: {cause:?}");
+        self.watcher.with_callback(
+            |config| &mut config.recrawl_handler,
+            |handler| handler(cause),
+        );
+    }
+
+    fn report_crawl_errors(&mut self) {
+        if self.crawl_errors.is_empty() {
+            return;
+        }
+        let crawl_errors = &mut self.crawl_errors;
+        self.watcher.with_callback(
+            |config| &mut config.error_handler,
+            |handler| {
+                for err in crawl_errors.drain(..) {
+                    handler(err);
+                }
+            },
+        );
+        self.crawl_errors.clear();
+    }
+
+    fn add_oneshot(&mut self, oneshot: WatchOnce) {
+        let Some(parent) = oneshot.path.parent() else {
+            log::error!("failed to watch {:?}: no parent directory", oneshot.path);
+            return;
+        };
+        let parent = CanonicalPathBuf::assert_canonicalized(parent);
+        // share the temporary root with other one-shot watches in the same directory
+        let mut root = self
+            .oneshots
+            .iter()
+            .find_map(|it| it.root.filter(|&root| self.tree[root].path == parent));
+        let covered = self
+            .tree
+            .find(&parent)
+            .is_some_and(|node| self.tree[node].watches_children());
+        if root.is_none() && !covered {
+            let Some(node) = self.tree.add_root(parent.clone(), &RootOptions::new()) else {
+                log::error!("failed to watch {:?}", oneshot.path);
+                return;
+            };
+            if let Err(err) = self.watcher.notify.watch_dir(parent.clone()) {
+                log::error!("failed to watch {parent:?}: {err}");
+                self.tree.remove(node, &mut self.work_stack);
+                return;
+            }
+            let filter = self.watcher.state.filter();
+            self.tree.crawl_root(
+                node,
+                false,
+                &*filter,
+                |_, _, _, _, _| (),
+                |_| (),
+                |err| self.crawl_errors.push(err),
+            );
+            root = Some(node);
+        }
+        self.oneshots.push(OneShot {
+            path: oneshot.path,
+            event_types: oneshot.event_types,
+            notify: oneshot.notify,
+            root,
+        });
+    }
+
+    /// Fires any one-shot watches matching `events`. Events that were only
+    /// observed because of a one-shot watch are removed from `events`.
+    fn dispatch_oneshots(&mut self, events: &mut Events) {
+        let has_temporary_roots = self.oneshots.iter().any(|it| it.root.is_some());
+        let mut i = 0;
+        while i < self.oneshots.len() {
+            let oneshot = &self.oneshots[i];
+            let Some(event) = events.iter().find(|event| {
+                event.path == oneshot.path && oneshot.event_types.contains(&event.ty)
+            }) else {
+                i += 1;
+                continue;
+            };
+            let event = event.clone();
+            let oneshot = self.oneshots.swap_remove(i);
+            (oneshot.notify)(event);
+            if let Some(root) = oneshot.root {
+                self.remove_oneshot_root(root);
+            }
+        }
+        if has_temporary_roots {
+            events.retain(|event| self.is_watched(&event.path));
+        }
+    }
+
+    fn remove_oneshot_root(&mut self, root: NodeId) {
+        if self.oneshots.iter().any(|it| it.root == Some(root)) {
+            return;
+        }
+        let path = self.tree[root].path.clone();
+        // the directory might have been added as a proper root in the meantime
+        let still_watched = self.roots.iter().any(|&(it, recursive)| {
+            let it = &self.tree[it].path;
+            *it == path || recursive && it.is_parent_of(&path)
+        });
+        if still_watched {
+            return;
+        }
+        if let Err(err) = self.watcher.notify.unwatch_dir(&path) {
+            log::error!("failed to remove watch for {path:?}: {err}");
+        }
+        self.tree.remove(root, &mut self.work_stack);
+    }
+
+    fn add_root_info(&mut self, info: RootInfo) {
+        match self
+            .added_roots
+            .binary_search_by(|root| root.path.cmp(&info.path))
+        {
+            Ok(i) => self.added_roots[i] = info,
+            Err(i) => self.added_roots.insert(i, info),
+        }
+        self.sync_root_filters();
+    }
+
+    /// makes the filters of the added roots available to the backend and
+    /// the tree, see `RootOptions::filter`
+    fn sync_root_filters(&self) {
+        let mut root_filters: Vec<_> = self
+            .added_roots
+            .iter()
+            .filter_map(|root| Some((root.path.clone(), root.options.filter.clone()?)))
+            .collect();
+        root_filters.sort_by(|(a, _), (b, _)| a.as_std_path().cmp(b.as_std_path()));
+        if root_filters.is_empty() && !self.watcher.state.filter.load().has_roots() {
+            return;
+        }
+        self.watcher
+            .state
+            .filter
+            .rcu(|current| current.with_roots(root_filters.clone()));
+        self.watcher.notify.refresh_config();
+    }
+
+    /// returns the tag of the closest root that contains `path`
+    fn root_tag(&self, path: &CanonicalPathBuf) -> Option<u64> {
+        // ancestors always sort before their children
+        let end = self.added_roots.partition_point(|root| root.path <= *path);
+        self.added_roots[..end]
+            .iter()
+            .rev()
+            .find(|root| root.path == *path || root.path.is_parent_of(path))
+            .and_then(|root| root.options.tag)
+    }
+
+    /// whether `path` is watched by any of the roots added with `add_root`
+    fn is_watched(&self, path: &CannonicalPath) -> bool {
+        let parent = path.parent();
+        self.roots.iter().any(|&(root, recursive)| {
+            let root = &self.tree[root].path;
+            if recursive {
+                root.is_parent_of(path)
+            } else {
+                parent.is_some_and(|parent| root.as_os_str() == parent.as_os_str())
+            }
+        })
+    }
+
+    /// delivers the debounced events to the handlers
+    fn dispatch_events(&mut self) {
+        let mut events = self.events.take();
+        if self
+            .added_roots
+            .iter()
+            .any(|root| root.options.tag.is_some())
+        {
+            for event in events.iter_mut() {
+                event.tag = self.root_tag(&event.path);
             }
         }
+        let (display_root, report_tempfiles, max_batch) = {
+            let config = self.watcher.state.config.lock().unwrap();
+            (
+                config.display_root.clone(),
+                config.report_tempfiles,
+                config.max_batch,
+            )
+        };
+        if let Some(display_root) = display_root {
+            for event in events.iter_mut() {
+                if display_root.is_parent_of(&event.path) {
+                    event.display_offset = display_root.len() + 1;
+                }
+            }
+        }
+        if !self.oneshots.is_empty() {
+            self.dispatch_oneshots(&mut events);
+        }
+        if self.watcher.notify.delete_only() {
+            events.retain(|event| matches!(event.ty, EventType::Delete | EventType::TypeChanged));
+        } else if !report_tempfiles {
+            events.retain(|event| event.ty != EventType::Tempfile);
+        }
+        if events.is_empty() {
+            return;
+        }
+        let batches = match max_batch {
+            Some(max_batch) => events.into_batches(max_batch),
+            None => vec![events],
+        };
+        // the handlers may call into the watcher (which locks the config)
+        // so they must run without holding the lock
+        let mut handlers = take(&mut self.watcher.state.config.lock().unwrap().handlers);
+        for batch in batches {
+            handlers.retain_mut(|handler| handler(batch.clone()));
+        }
+        let mut config = self.watcher.state.config.lock().unwrap();
+        // keep the handlers added in the meantime after the existing ones
+        handlers.append(&mut config.handlers);
+        config.handlers = handlers;
     }
 
     pub fn run(mut self) {
         loop {
+            // the polling backend simply recrawls all roots periodically
+            if self.recrawl_due.is_none() && self.watcher.notify.is_polling() {
+                let interval = self.watcher.state.config.lock().unwrap().poll_interval;
+                self.recrawl_due = Some(Instant::now() + interval);
+            }
+            self.schedule_watch_retry();
             let settled = self.wait_for_changes();
             if self.watcher.notify.is_shutdown() {
+                // free the (potentially large) tree before the remaining
+                // cleanup, which runs user code when dropping the handlers
+                drop(take(&mut self.tree));
+                // drop any outstanding queries so their callers are unblocked
+                drop(take(&mut *self.watcher.state.notifications.lock().unwrap()));
+                // the handlers are never invoked again, dropping them closes
+                // any event channels
+                drop(take(
+                    &mut self.watcher.state.config.lock().unwrap().handlers,
+                ));
                 break;
             }
+            #[cfg(feature = "tracing")]
+            let _span = tracing::trace_span!(
+                "worker_iteration",
+                changes = self.pending_changes.len(),
+                settled
+            )
+            .entered();
+            if self
+                .watch_retry_due
+                .is_some_and(|due| due <= Instant::now())
+            {
+                self.retry_watches();
+            }
             self.process_notifications();
-            if settled {
-                let events = self.events.take();
-                self.watcher
-                    .state
-                    .config
-                    .lock()
-                    .unwrap()
-                    .handlers
-                    .retain_mut(|handler| handler(events.clone()));
+            self.report_symlink_loops();
+            self.report_crawl_errors();
+            if self.watcher.state.paused.load(atomic::Ordering::Relaxed) {
+                // everything is picked up by the recrawl on resume
+                self.pending_changes.clear();
+                self.unclosed_writes.clear();
+                self.recrawl_due = None;
                 continue;
             }
-            let filter = self.watcher.state.config.lock().unwrap().filter.clone();
-            if self.pending_changes.take_recrawl() {
-                #[cfg(test)]
-                self.watcher
-                    .state
-                    .recrawls
-                    .fetch_add(1, atomic::Ordering::Relaxed);
-
-                for &(root, _) in &self.roots {
-                    self.tree.crawl(
-                        root,
-                        &*filter,
-                        &mut self.work_stack,
-                        |path, ty| self.events.add(path, ty),
-                        |path| {
-                            if let Err(err) = self.watcher.notify.watch_dir(path.clone()) {
-                                log::error!("failed to watch {path:?}: {err}")
-                            }
-                        },
-                    );
-                }
+            if settled {
+                self.dispatch_events();
                 continue;
             }
-            self.tree.apply_transaction(
-                &mut self.pending_changes,
-                &*filter,
-                |path, ty| self.events.add(path, ty),
-                &mut self.work_stack,
-                |path| {
-                    if let Err(err) = self.watcher.notify.watch_dir(path.clone()) {
-                        log::error!("failed to watch {path:?}: {err}")
-                    }
-                },
-            );
+            let filter = self.sync_config();
+            // a poll is a snapshot, there is nothing to wait for
+            if self.apply_pending_changes(&*filter, false) && self.watcher.notify.is_polling() {
+                self.dispatch_events();
+            }
+        }
+    }
+
+    /// copies the config used while applying changes, returns the filter
+    fn sync_config(&mut self) -> Arc<dyn Filter> {
+        let config = self.watcher.state.config.lock().unwrap();
+        self.events.defer_empty_creates = config.defer_empty_creates;
+        self.events.coalesce_deletes = config.coalesce_deletes;
+        self.events.min_modified_interval = config.min_modified_interval;
+        self.events.preserve_order = config.preserve_order;
+        self.events.coalesce_policy = config.coalesce_policy.clone();
+        self.events.track_modifications = config.track_modifications;
+        self.tree.hash_contents = config.hash_contents;
+        self.tree.compare_ctime = config.report_metadata_changes;
+        self.tree.report_directories = config.report_directories;
+        self.tree.preserve_order = config.preserve_order;
+        self.tree.track_hardlinks = config.track_hardlinks;
+        drop(config);
+        self.watcher.state.filter()
+    }
+
+    /// Applies the pending changes to the tree (or recrawls if one is due)
+    /// and records the resulting events. Returns whether a recrawl happened.
+    /// With `flush` writes to files that are still open are applied too.
+    fn apply_pending_changes(&mut self, filter: &dyn Filter, flush: bool) -> bool {
+        if let Some(cause) = self.pending_changes.take_recrawl() {
+            #[cfg(feature = "tracing")]
+            tracing::info!(?cause, "recrawl scheduled");
+            self.report_overflows();
+            self.report_recrawl(cause);
+            self.schedule_recrawl();
+        }
+        if self.recrawl_due.is_some_and(|due| due <= Instant::now()) {
+            self.recrawl(filter);
+            return true;
+        }
+        self.defer_unclosed_writes(flush);
+        self.tree.apply_transaction(
+            &mut self.pending_changes,
+            filter,
+            |path, ty, meta, prev, time| self.events.add(path, ty, meta, prev, time),
+            &mut self.work_stack,
+            |path| {
+                if let Err(err) = self.watcher.notify.watch_dir(path.clone()) {
+                    log::error!("failed to watch {path:?}: {err}")
+                }
+            },
+            |err| self.crawl_errors.push(err),
+        );
+        for (from, to) in self.tree.take_moved_dirs() {
+            self.watcher.notify.rename_watches(&from, &to);
+        }
+        let renames = self.pending_changes.take_renames();
+        // a rename must be reported as the deletion of the source
+        if !self.watcher.notify.delete_only() {
+            self.events.add_renames(renames);
+        }
+        self.report_removed_roots();
+        self.report_symlink_loops();
+        self.report_crawl_errors();
+        false
+    }
+
+    /// Applies all changes reported by the backend so far and delivers the
+    /// resulting events right away, including events that are held back.
+    pub fn flush(&mut self) {
+        if self.watcher.state.paused.load(atomic::Ordering::Relaxed) {
+            return;
         }
+        let filter = self.sync_config();
+        // the changes the worker took before answering the query
+        self.apply_pending_changes(&*filter, true);
+        self.watcher
+            .notify
+            .changes()
+            .take_now(&mut self.pending_changes);
+        self.apply_pending_changes(&*filter, true);
+        self.events.release_held();
+        self.dispatch_events();
     }
 }